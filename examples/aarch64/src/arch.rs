@@ -0,0 +1,45 @@
+use core::arch::asm;
+
+/// The IRQ mask bit of `DAIF`.
+const DAIF_I: u64 = 1 << 7;
+
+/// Returns the ID of the current CPU, from the lowest affinity level of `MPIDR_EL1`.
+pub fn hart_id() -> usize {
+    let mpidr: u64;
+    unsafe {
+        asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack));
+    }
+    (mpidr & 0xff) as usize
+}
+
+/// Returns whether IRQs are unmasked.
+pub fn is_interrupt_enable() -> bool {
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack));
+    }
+    daif & DAIF_I == 0
+}
+
+/// Masks IRQs.
+pub fn interrupt_disable() {
+    unsafe {
+        asm!("msr daifset, #2", options(nostack));
+    }
+}
+
+/// Unmasks IRQs.
+pub fn interrupt_enable() {
+    unsafe {
+        asm!("msr daifclr, #2", options(nostack));
+    }
+}
+
+/// Reads the physical counter.
+pub fn read_timer() -> usize {
+    let ticks: u64;
+    unsafe {
+        asm!("isb", "mrs {}, cntpct_el0", out(reg) ticks, options(nomem, nostack));
+    }
+    ticks as usize
+}
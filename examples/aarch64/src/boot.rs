@@ -0,0 +1,117 @@
+use crate::psci::system_off;
+use crate::{main, println};
+use core::arch::global_asm;
+use spin::Mutex;
+use talc::{ClaimOnOom, Span, Talc, Talck};
+
+/// The size of the boot stack.
+pub const STACK_SIZE: usize = 1024 * 64;
+const KERNEL_HEAP_SIZE: usize = 0x26_00000;
+#[link_section = ".bss.stack"]
+static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+#[global_allocator]
+static HEAP_ALLOCATOR: Talck<Mutex<()>, ClaimOnOom> =
+    Talc::new(unsafe { ClaimOnOom::new(Span::from_const_array(core::ptr::addr_of!(KERNEL_HEAP))) })
+        .lock();
+static mut KERNEL_HEAP: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
+
+/// The memory attributes in `MAIR_EL1`: index 0 is device-nGnRE, index 1 normal write-back
+/// cacheable memory.
+const MAIR: u64 = 0x04 | 0xff << 8;
+/// 39-bit virtual addresses through `TTBR0_EL1` with 4 KiB pages, so that translation starts at
+/// level 1, with cacheable inner shareable table walks. `TTBR1_EL1` is unused.
+const TCR: u64 = 25 | 1 << 8 | 1 << 10 | 3 << 12 | 1 << 23 | 1 << 32;
+/// The MMU and the data and instruction caches in `SCTLR_EL1`.
+const SCTLR: u64 = 1 << 0 | 1 << 2 | 1 << 12;
+
+/// Bits of a level 1 block descriptor, which maps 1 GiB.
+const BLOCK: u64 = 0b01;
+const ATTR_DEVICE: u64 = 0 << 2;
+const ATTR_NORMAL: u64 = 1 << 2;
+const INNER_SHAREABLE: u64 = 3 << 8;
+const ACCESSED: u64 = 1 << 10;
+const EXECUTE_NEVER: u64 = 3 << 53;
+
+#[repr(C, align(4096))]
+struct PageTable([u64; 512]);
+
+/// Identity maps the first GiB, where the `virt` machine has its devices, as device memory, and
+/// the 3 GiB after it, where RAM starts, as normal memory.
+///
+/// With the MMU off all of RAM would be device memory too, on which unaligned accesses fault and
+/// the exclusives behind atomics aren't guaranteed to work.
+static IDMAP: PageTable = {
+    let mut table = [0; 512];
+    table[0] = BLOCK | ATTR_DEVICE | ACCESSED | EXECUTE_NEVER;
+    let mut i = 1;
+    while i < 4 {
+        table[i] = (i as u64) << 30 | BLOCK | ATTR_NORMAL | INNER_SHAREABLE | ACCESSED;
+        i += 1;
+    }
+    PageTable(table)
+};
+
+// The kernel entry: turns the MMU on with `IDMAP`, sets up the boot stack and calls
+// `platform_init`. QEMU starts it at EL1 with IRQs masked, and keeps other CPUs off.
+global_asm!(
+    "
+    .section .text.entry
+    .global _start
+_start:
+    adrp x0, {idmap}
+    add x0, x0, :lo12:{idmap}
+    msr ttbr0_el1, x0
+    ldr x0, ={mair}
+    msr mair_el1, x0
+    ldr x0, ={tcr}
+    msr tcr_el1, x0
+    isb
+    tlbi vmalle1
+    dsb nsh
+    isb
+    mrs x0, sctlr_el1
+    ldr x1, ={sctlr}
+    orr x0, x0, x1
+    msr sctlr_el1, x0
+    isb
+    adrp x0, {boot_stack}
+    add x0, x0, :lo12:{boot_stack}
+    ldr x1, ={stack_size}
+    add x0, x0, x1
+    mov sp, x0
+    bl {platform_init}
+    b .
+    ",
+    idmap = sym IDMAP,
+    mair = const MAIR,
+    tcr = const TCR,
+    sctlr = const SCTLR,
+    boot_stack = sym STACK,
+    stack_size = const STACK_SIZE,
+    platform_init = sym platform_init,
+);
+
+extern "C" {
+    fn sbss();
+    fn ebss();
+}
+
+/// Clears `.bss`, which QEMU doesn't do when loading the ELF.
+fn clear_bss() {
+    unsafe {
+        core::slice::from_raw_parts_mut(sbss as usize as *mut u8, ebss as usize - sbss as usize)
+            .fill(0);
+    }
+}
+
+extern "C" fn platform_init() -> ! {
+    clear_bss();
+    main();
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    println!("{}", info);
+    system_off();
+}
@@ -0,0 +1,58 @@
+use core::fmt::{Arguments, Result, Write};
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        let hard_id = crate::arch::hart_id();
+        $crate::console::__print(format_args!("[{}] {}", hard_id, format_args!($($arg)*)))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
+
+#[macro_export]
+macro_rules! iprint {
+    ($($arg:tt)*) => {
+        $crate::console::__print(format_args!("{}", format_args!($($arg)*)))
+    };
+}
+
+/// The PL011 UART of the `virt` machine, which QEMU connects to `-serial`.
+const UART_BASE: usize = 0x0900_0000;
+/// Data register.
+const UARTDR: usize = 0x00;
+/// Flag register, and its transmit FIFO full bit.
+const UARTFR: usize = 0x18;
+const UARTFR_TXFF: u32 = 1 << 5;
+
+/// Writes a byte to the UART, which QEMU has already set up.
+pub fn console_putchar(ch: u8) {
+    let fr = (UART_BASE + UARTFR) as *const u32;
+    let dr = (UART_BASE + UARTDR) as *mut u32;
+    // Safety: the UART registers are identity mapped as device memory, see `boot`.
+    unsafe {
+        while fr.read_volatile() & UARTFR_TXFF != 0 {}
+        dr.write_volatile(ch.into());
+    }
+}
+
+pub struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> Result {
+        s.as_bytes().iter().for_each(|x| {
+            console_putchar(*x);
+        });
+        Ok(())
+    }
+}
+
+pub fn __print(args: Arguments) {
+    Stdout.write_fmt(args).unwrap();
+}
@@ -0,0 +1,61 @@
+//! Runs the device tests of the RISC-V `qemu` crate on the aarch64 `virt` machine, with a GICv2
+//! instead of a PLIC. The tests themselves are shared with that crate; only booting, the console
+//! and interrupts are done here.
+#![no_std]
+#![no_main]
+#![allow(unused)]
+#[macro_use]
+extern crate log;
+
+extern crate alloc;
+
+use core::sync::atomic::AtomicUsize;
+use spin::Lazy;
+
+mod arch;
+mod boot;
+#[macro_use]
+mod console;
+mod psci;
+mod trap;
+
+#[path = "../../../qemu/src/conformance_test.rs"]
+mod conformance_test;
+#[path = "../../../qemu/src/logging.rs"]
+mod logging;
+#[path = "../../../qemu/src/mutex.rs"]
+mod mutex;
+#[path = "../../../qemu/src/my_impl.rs"]
+mod my_impl;
+#[path = "../../../qemu/src/negotiation_test.rs"]
+mod negotiation_test;
+#[path = "../../../qemu/src/new_test.rs"]
+mod new_test;
+
+extern "C" {
+    fn end();
+}
+
+static DMA_PADDR: Lazy<AtomicUsize> = Lazy::new(|| AtomicUsize::new(end as usize));
+
+pub const NET_QUEUE_SIZE: usize = 16;
+pub const NET_BUFFER_LEN: usize = 2048;
+
+/// Where QEMU loads the device tree when booting an ELF kernel without firmware: the start of
+/// RAM. Unlike for a Linux image, it isn't passed in `x0`.
+const DEVICE_TREE_PADDR: usize = 0x4000_0000;
+/// The GICv2 distributor and CPU interface of the `virt` machine.
+const GICD_PADDR: usize = 0x0800_0000;
+const GICC_PADDR: usize = 0x0801_0000;
+
+pub fn main() -> ! {
+    logging::init_logger();
+    trap::ext_interrupt::init_gic(GICD_PADDR, GICC_PADDR);
+    new_test::init_dt(DEVICE_TREE_PADDR);
+    trap::init_trap_subsystem();
+    negotiation_test::test_feature_negotiation();
+    conformance_test::test_conformance();
+    new_test::test_all_devices();
+    info!("test end");
+    psci::system_off();
+}
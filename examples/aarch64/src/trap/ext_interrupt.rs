@@ -0,0 +1,135 @@
+use crate::arch::hart_id;
+use crate::mutex::Mutex;
+use crate::println;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use fdt::node::FdtNode;
+use spin::Once;
+
+pub static GIC: Once<Gic> = Once::new();
+pub static DEVICE_TABLE: Mutex<BTreeMap<usize, Arc<Mutex<dyn DeviceBase>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Distributor registers.
+const GICD_CTLR: usize = 0x000;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+/// CPU interface registers.
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_IAR: usize = 0x00c;
+const GICC_EOIR: usize = 0x010;
+/// The interrupt ID the CPU interface returns when nothing is pending.
+const SPURIOUS_IRQ: u32 = 1023;
+
+/// The interrupt types of the GIC device tree binding.
+const GIC_SPI: u32 = 0;
+const GIC_PPI: u32 = 1;
+
+/// A GICv2, of which only the parts needed to route device interrupts to one CPU are used.
+pub struct Gic {
+    gicd: usize,
+    gicc: usize,
+}
+
+impl Gic {
+    fn write_u32(addr: usize, value: u32) {
+        // Safety: the GIC registers are identity mapped as device memory, see `boot`.
+        unsafe { (addr as *mut u32).write_volatile(value) }
+    }
+
+    fn write_u8(addr: usize, value: u8) {
+        // Safety: as above. The priority and target registers are byte accessible.
+        unsafe { (addr as *mut u8).write_volatile(value) }
+    }
+
+    fn read_u32(addr: usize) -> u32 {
+        // Safety: as above.
+        unsafe { (addr as *const u32).read_volatile() }
+    }
+
+    /// Enables the distributor and the CPU interface, letting through every priority.
+    fn init(&self) {
+        Self::write_u32(self.gicd + GICD_CTLR, 1);
+        Self::write_u32(self.gicc + GICC_PMR, 0xff);
+        Self::write_u32(self.gicc + GICC_CTLR, 1);
+    }
+
+    /// Routes `irq` to `cpu` and enables it. Its trigger mode is left at the reset value, level
+    /// sensitive, which suits virtio-mmio since the line stays up until the driver acks it.
+    fn enable(&self, irq: usize, cpu: usize) {
+        Self::write_u8(self.gicd + GICD_IPRIORITYR + irq, 0xa0);
+        Self::write_u8(self.gicd + GICD_ITARGETSR + irq, 1 << cpu);
+        Self::write_u32(self.gicd + GICD_ISENABLER + irq / 32 * 4, 1 << (irq % 32));
+    }
+
+    /// Acknowledges the highest priority pending interrupt, returning the value to pass to
+    /// [`Self::complete`].
+    fn claim(&self) -> u32 {
+        Self::read_u32(self.gicc + GICC_IAR)
+    }
+
+    fn complete(&self, iar: u32) {
+        Self::write_u32(self.gicc + GICC_EOIR, iar);
+    }
+}
+
+pub fn init_gic(gicd_addr: usize, gicc_addr: usize) {
+    let gic = Gic {
+        gicd: gicd_addr,
+        gicc: gicc_addr,
+    };
+    gic.init();
+    GIC.call_once(|| gic);
+    println!("Init qemu gicv2 success");
+}
+
+/// Returns the GIC interrupt ID of a device tree node's first interrupt.
+///
+/// The GIC binding has three cells per interrupt, the type, the number within the type and the
+/// flags, which `FdtNode::interrupts` doesn't parse. SPIs are numbered from 32 and PPIs from 16.
+pub fn device_irq(node: &FdtNode) -> Option<usize> {
+    let cells = node.property("interrupts")?.value;
+    let cell = |index: usize| {
+        let bytes = cells.get(index * 4..index * 4 + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let number = cell(1)? as usize;
+    match cell(0)? {
+        GIC_SPI => Some(number + 32),
+        GIC_PPI => Some(number + 16),
+        _ => None,
+    }
+}
+
+/// Register a device to the GIC.
+pub fn register_device_irq(irq: usize, device: Arc<Mutex<dyn DeviceBase>>) {
+    let hard_id = hart_id();
+    let mut table = DEVICE_TABLE.lock();
+    table.insert(irq, device);
+    println!("GIC enable irq {} for cpu {}", irq, hard_id);
+    GIC.get().unwrap().enable(irq, hard_id);
+}
+
+pub fn external_interrupt_handler() {
+    let gic = GIC.get().unwrap();
+    let iar = gic.claim();
+    let irq = iar & 0x3ff;
+    if irq == SPURIOUS_IRQ {
+        return;
+    }
+    info!("external_interrupt_handler: irq: {}", irq);
+    let table = DEVICE_TABLE.lock();
+    let device = table
+        .get(&(irq as usize))
+        .or_else(|| panic!("no device for irq {}", irq))
+        .unwrap();
+    info!("find device for irq {}", irq);
+    device.lock().handle_irq();
+    gic.complete(iar);
+}
+
+pub trait DeviceBase: Send + Sync {
+    fn handle_irq(&mut self);
+}
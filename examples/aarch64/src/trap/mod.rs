@@ -0,0 +1,129 @@
+pub mod ext_interrupt;
+
+use crate::{arch, println};
+use core::arch::{asm, global_asm};
+
+/// The registers an exception saves, which the handler may clobber under the AAPCS64.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    /// `x0` to `x18`.
+    pub x: [u64; 19],
+    pub fp: u64,
+    pub lr: u64,
+    pub elr: u64,
+    pub spsr: u64,
+    _pad: u64,
+}
+
+/// The offset of the IRQ entry in each group of four in the vector table, after the synchronous
+/// exception one.
+const IRQ: u64 = 1;
+
+// The vector table. Each entry saves `x0` and `x1`, and passes its index and the frame to
+// `kernel_trap_vector` through `trap_common`, which saves the rest.
+global_asm!(
+    "
+    .macro vector kind
+    .balign 0x80
+    sub sp, sp, #{frame_size}
+    stp x0, x1, [sp]
+    mov x0, #\\kind
+    b trap_common
+    .endm
+
+    .section .text
+    .balign 0x800
+    .global kernel_vectors
+kernel_vectors:
+    vector 0
+    vector 1
+    vector 2
+    vector 3
+    vector 4
+    vector 5
+    vector 6
+    vector 7
+    vector 8
+    vector 9
+    vector 10
+    vector 11
+    vector 12
+    vector 13
+    vector 14
+    vector 15
+
+trap_common:
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x29, [sp, #144]
+    mrs x2, elr_el1
+    stp x30, x2, [sp, #160]
+    mrs x2, spsr_el1
+    str x2, [sp, #176]
+    mov x1, sp
+    bl {handler}
+    ldr x2, [sp, #176]
+    msr spsr_el1, x2
+    ldp x30, x2, [sp, #160]
+    msr elr_el1, x2
+    ldp x18, x29, [sp, #144]
+    ldp x16, x17, [sp, #128]
+    ldp x14, x15, [sp, #112]
+    ldp x12, x13, [sp, #96]
+    ldp x10, x11, [sp, #80]
+    ldp x8, x9, [sp, #64]
+    ldp x6, x7, [sp, #48]
+    ldp x4, x5, [sp, #32]
+    ldp x2, x3, [sp, #16]
+    ldp x0, x1, [sp]
+    add sp, sp, #{frame_size}
+    eret
+    ",
+    frame_size = const core::mem::size_of::<TrapFrame>(),
+    handler = sym kernel_trap_vector,
+);
+
+extern "C" {
+    fn kernel_vectors();
+}
+
+/// Installs the vector table and unmasks IRQs.
+pub fn init_trap_subsystem() {
+    println!("++++ setup interrupt ++++");
+    unsafe {
+        asm!("msr vbar_el1, {}", "isb", in(reg) kernel_vectors as usize as u64);
+    }
+    arch::interrupt_enable();
+    let enable = arch::is_interrupt_enable();
+    println!("++++ setup interrupt done, enable:{:?} ++++", enable);
+}
+
+/// Handles an exception taken at EL1. IRQs stay masked until it returns, so handlers don't nest.
+extern "C" fn kernel_trap_vector(kind: u64, frame: &mut TrapFrame) {
+    assert!(!arch::is_interrupt_enable());
+    match kind % 4 {
+        IRQ => {
+            trace!("[kernel] external interrupt");
+            ext_interrupt::external_interrupt_handler();
+        }
+        _ => {
+            let esr: u64;
+            let far: u64;
+            unsafe {
+                asm!("mrs {}, esr_el1", out(reg) esr, options(nomem, nostack));
+                asm!("mrs {}, far_el1", out(reg) far, options(nomem, nostack));
+            }
+            panic!(
+                "unhandled exception {}: esr {:#x}, far {:#x}, elr {:#x}",
+                kind, esr, far, frame.elr
+            );
+        }
+    }
+}
@@ -0,0 +1,17 @@
+#!/bin/bash
+# Runs clippy on virtio-drivers with each device driver feature enabled on its own, so code shared
+# between drivers stays warning-free whichever of them are compiled in, as well as with every
+# driver and helper feature at once.
+
+set -euo pipefail
+
+drivers=(balloon block console gpu input net scsi socket sound)
+
+cd "$(dirname "$0")/../virtio-drivers"
+for feature in "${drivers[@]}"; do
+	echo "checking --features $feature"
+	cargo clippy --all-targets --no-default-features --features "$feature" -- -D warnings
+done
+echo "checking the default features with the helper features"
+cargo clippy --all-targets --features gpu-draw,input-decoder,input-keymap,completion-ring,std \
+	-- -D warnings
@@ -0,0 +1,40 @@
+#!/bin/bash
+# Builds the `no_panic` example of virtio-drivers with the `no-panic` feature, and fails if any code
+# from the crate itself calls the panicking helpers behind `assert!`, `unwrap()`, `expect()` and
+# out-of-bounds indexing or slicing.
+#
+# Calls from inside `alloc` (e.g. the internals of `BTreeMap`) are not counted, as the crate can't
+# do anything about them.
+
+set -euo pipefail
+
+target=${1:-riscv64imac-unknown-none-elf}
+objdump=${OBJDUMP:-llvm-objdump}
+addr2line=${ADDR2LINE:-llvm-addr2line}
+
+cd "$(dirname "$0")/../virtio-drivers"
+# Keep line tables, so each call can be traced back through inlining to the code it came from.
+CARGO_PROFILE_RELEASE_DEBUG=line-tables-only cargo build --release --target "$target" \
+	--example no_panic --features no-panic,heap-dma
+bin=../target/$target/release/examples/no_panic
+
+helpers='<core::(option::(unwrap|expect)_failed|result::unwrap_failed|panicking::assert_failed|panicking::panic>'
+helpers+='|panicking::panic_bounds_check>'
+# Newer toolchains move the body of these into a nested `::do_panic::runtime` function.
+helpers+='|slice::index::slice_(start_index_len|end_index_len|index_order)_fail(>|::))'
+failed=0
+for addr in $("$objdump" -d --demangle --no-show-raw-insn "$bin" | grep -E "$helpers" |
+	grep -v '>:$' | awk '{ print $1 }' | tr -d :); do
+	# The innermost frame outside the panicking helpers themselves is the code which panics.
+	caller=$("$addr2line" -e "$bin" -i "$addr" |
+		grep -vE 'library/core/src/(option|result|panicking|macros/mod|slice/index)\.rs' | head -n 1)
+	if [[ $caller == */virtio-drivers/src/* ]]; then
+		echo "may panic: $caller"
+		failed=1
+	fi
+done
+
+if [[ $failed == 0 ]]; then
+	echo "no panics found"
+fi
+exit $failed
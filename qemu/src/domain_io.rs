@@ -0,0 +1,206 @@
+//! A [`VirtIoDeviceIo`] for a driver running in an isolated domain which doesn't map the device's
+//! registers, as in Alien, where the driver domain reaches them through a device domain.
+//!
+//! Every register access becomes a fixed-size message in a ring of memory shared by the two
+//! domains. The driver domain posts it and rings a doorbell, which stands in for the domain
+//! switch, and the device domain checks the access against the device's window, performs it and
+//! writes the result back into the same slot. Errors cross the boundary as status codes, so
+//! nothing but plain data is shared.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt::{self, Debug, Formatter};
+use safe_virtio_drivers::error::{VirtIoError, VirtIoResult};
+use safe_virtio_drivers::hal::VirtIoDeviceIo;
+use safe_virtio_drivers::{PhysAddr, VirtAddr};
+use spin::Mutex;
+
+/// The number of messages the ring holds.
+const RING_LEN: usize = 16;
+
+/// The operations of [`Message::op`].
+const OP_READ: u8 = 1;
+const OP_WRITE: u8 = 2;
+
+/// The statuses of [`Message::status`].
+const STATUS_PENDING: u8 = 0;
+const STATUS_OK: u8 = 1;
+/// The access was outside the device's window or not of a supported width.
+const STATUS_INVALID: u8 = 2;
+const STATUS_IO_ERROR: u8 = 3;
+
+/// A register access, as it is laid out in the shared ring.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct Message {
+    op: u8,
+    /// The access width in bytes: 1, 2 or 4.
+    width: u8,
+    status: u8,
+    offset: u32,
+    /// The value to write, or the value read once the access is done.
+    data: u32,
+}
+
+/// The memory shared by the two domains.
+struct Ring {
+    slots: [Message; RING_LEN],
+    /// The number of messages posted by the driver domain.
+    head: usize,
+    /// The number of messages served by the device domain.
+    tail: usize,
+}
+
+/// The device domain's end, which owns the register window.
+struct IoServer {
+    ring: Arc<Mutex<Ring>>,
+    io: Box<dyn VirtIoDeviceIo>,
+    /// The length of the register window, beyond which accesses are refused.
+    len: usize,
+}
+
+impl IoServer {
+    /// Serves every message posted since the last doorbell.
+    fn serve(&self) {
+        let mut ring = self.ring.lock();
+        while ring.tail != ring.head {
+            let slot = ring.tail % RING_LEN;
+            let message = ring.slots[slot];
+            ring.slots[slot] = self.handle(message);
+            ring.tail += 1;
+        }
+    }
+
+    fn handle(&self, mut message: Message) -> Message {
+        let offset = message.offset as usize;
+        let in_window = offset
+            .checked_add(usize::from(message.width))
+            .is_some_and(|end| end <= self.len);
+        let result = match (message.op, message.width) {
+            _ if !in_window => Err(VirtIoError::InvalidParam),
+            (OP_READ, 1) => self.io.read_volatile_u8_at(offset).map(u32::from),
+            (OP_READ, 2) => self.io.read_volatile_u16_at(offset).map(u32::from),
+            (OP_READ, 4) => self.io.read_volatile_u32_at(offset),
+            (OP_WRITE, 1) => self
+                .io
+                .write_volatile_u8_at(offset, message.data as u8)
+                .map(|()| 0),
+            (OP_WRITE, 2) => self
+                .io
+                .write_volatile_u16_at(offset, message.data as u16)
+                .map(|()| 0),
+            (OP_WRITE, 4) => self
+                .io
+                .write_volatile_u32_at(offset, message.data)
+                .map(|()| 0),
+            _ => Err(VirtIoError::InvalidParam),
+        };
+        match result {
+            Ok(data) => {
+                message.status = STATUS_OK;
+                message.data = data;
+            }
+            Err(VirtIoError::InvalidParam) => message.status = STATUS_INVALID,
+            Err(_) => message.status = STATUS_IO_ERROR,
+        }
+        message
+    }
+}
+
+/// The driver domain's end, which forwards register accesses to the device domain.
+pub struct DomainIo {
+    ring: Arc<Mutex<Ring>>,
+    /// Switches to the device domain to serve the ring.
+    doorbell: Box<dyn Fn() + Send + Sync>,
+    paddr: PhysAddr,
+    vaddr: VirtAddr,
+}
+
+impl DomainIo {
+    /// Gives the device domain the `len` bytes of registers of `io`, and returns the driver
+    /// domain's way to reach them.
+    pub fn new(io: Box<dyn VirtIoDeviceIo>, len: usize) -> Self {
+        let ring = Arc::new(Mutex::new(Ring {
+            slots: [Message::default(); RING_LEN],
+            head: 0,
+            tail: 0,
+        }));
+        let (paddr, vaddr) = (io.paddr(), io.vaddr());
+        let server = IoServer {
+            ring: ring.clone(),
+            io,
+            len,
+        };
+        Self {
+            ring,
+            doorbell: Box::new(move || server.serve()),
+            paddr,
+            vaddr,
+        }
+    }
+
+    /// Posts an access, rings the doorbell and returns the result the device domain left in the
+    /// slot.
+    fn call(&self, op: u8, width: u8, offset: usize, data: u32) -> VirtIoResult<u32> {
+        let offset = u32::try_from(offset).map_err(|_| VirtIoError::InvalidParam)?;
+        let slot = {
+            let mut ring = self.ring.lock();
+            if ring.head - ring.tail == RING_LEN {
+                return Err(VirtIoError::QueueFull);
+            }
+            let slot = ring.head % RING_LEN;
+            ring.slots[slot] = Message {
+                op,
+                width,
+                status: STATUS_PENDING,
+                offset,
+                data,
+            };
+            ring.head += 1;
+            slot
+        };
+        (self.doorbell)();
+        let message = self.ring.lock().slots[slot];
+        match message.status {
+            STATUS_OK => Ok(message.data),
+            STATUS_INVALID => Err(VirtIoError::InvalidParam),
+            _ => Err(VirtIoError::IoError),
+        }
+    }
+}
+
+impl Debug for DomainIo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("DomainIo")
+            .field("paddr", &self.paddr)
+            .field("vaddr", &self.vaddr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VirtIoDeviceIo for DomainIo {
+    fn read_volatile_u32_at(&self, off: usize) -> VirtIoResult<u32> {
+        self.call(OP_READ, 4, off, 0)
+    }
+    fn read_volatile_u8_at(&self, off: usize) -> VirtIoResult<u8> {
+        self.call(OP_READ, 1, off, 0).map(|data| data as u8)
+    }
+    fn write_volatile_u32_at(&self, off: usize, data: u32) -> VirtIoResult<()> {
+        self.call(OP_WRITE, 4, off, data).map(drop)
+    }
+    fn write_volatile_u8_at(&self, off: usize, data: u8) -> VirtIoResult<()> {
+        self.call(OP_WRITE, 1, off, data.into()).map(drop)
+    }
+    fn read_volatile_u16_at(&self, off: usize) -> VirtIoResult<u16> {
+        self.call(OP_READ, 2, off, 0).map(|data| data as u16)
+    }
+    fn write_volatile_u16_at(&self, off: usize, data: u16) -> VirtIoResult<()> {
+        self.call(OP_WRITE, 2, off, data.into()).map(drop)
+    }
+    fn paddr(&self) -> PhysAddr {
+        self.paddr
+    }
+    fn vaddr(&self) -> VirtAddr {
+        self.vaddr
+    }
+}
@@ -34,8 +34,11 @@ mod trap;
 #[macro_use]
 mod console;
 mod arch;
+mod conformance_test;
+mod domain_io;
 mod logging;
 mod mutex;
+mod negotiation_test;
 mod new_test;
 mod old_impl;
 mod old_test;
@@ -56,6 +59,8 @@ extern "C" fn main(_hartid: usize, device_tree_paddr: usize) {
     new_test::init_dt(device_tree_paddr);
     // old_test::init_dt(device_tree_paddr);
     trap::init_trap_subsystem();
+    negotiation_test::test_feature_negotiation();
+    conformance_test::test_conformance();
     new_test::test_all_devices();
     // old_test::test_all_devices();
     info!("test end");
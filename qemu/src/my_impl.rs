@@ -1,13 +1,29 @@
 use crate::DMA_PADDR;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::sync::atomic::Ordering;
 use safe_virtio_drivers::error::VirtIoResult;
 use safe_virtio_drivers::hal::{DevicePage, QueuePage, VirtIoDeviceIo};
-use safe_virtio_drivers::queue::{AvailRing, Descriptor, QueueLayout, QueueMutRef, UsedRing};
+use safe_virtio_drivers::queue::{QueueLayout, QueueMutRef};
 use safe_virtio_drivers::{PhysAddr, VirtAddr, PAGE_SIZE};
+use spin::Mutex;
 
 pub struct MyHalImpl;
 
+/// DMA memory given back by dropping a [`Page`], as (paddr, size).
+static FREE_PAGES: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+/// Reuses the most recently freed memory of the same size if there is some, and takes fresh
+/// memory after everything allocated so far otherwise.
+fn alloc_pages(pages: usize) -> usize {
+    let size = PAGE_SIZE * pages;
+    let mut free = FREE_PAGES.lock();
+    match free.iter().rposition(|&(_, len)| len == size) {
+        Some(i) => free.remove(i).0,
+        None => DMA_PADDR.fetch_add(size, Ordering::SeqCst),
+    }
+}
+
 pub struct Page {
     pa: usize,
     size: usize,
@@ -70,14 +86,14 @@ impl VirtIoDeviceIo for SafeIoRegion {
 impl<const SIZE: usize> safe_virtio_drivers::hal::Hal<SIZE> for MyHalImpl {
     #[inline]
     fn dma_alloc(pages: usize) -> Box<dyn QueuePage<SIZE>> {
-        let paddr = DMA_PADDR.fetch_add(PAGE_SIZE * pages, Ordering::SeqCst);
+        let paddr = alloc_pages(pages);
         info!("<dma_alloc>alloc DMA: paddr={:#x}, pages={}", paddr, pages);
         Box::new(Page::new(paddr, PAGE_SIZE * pages))
     }
 
     #[inline]
     fn dma_alloc_buf(pages: usize) -> Box<dyn DevicePage> {
-        let paddr = DMA_PADDR.fetch_add(PAGE_SIZE * pages, Ordering::SeqCst);
+        let paddr = alloc_pages(pages);
         info!(
             "<dma_alloc_buf> alloc DMA: paddr={:#x}, pages={}",
             paddr, pages
@@ -89,6 +105,12 @@ impl<const SIZE: usize> safe_virtio_drivers::hal::Hal<SIZE> for MyHalImpl {
     fn to_paddr(va: usize) -> usize {
         va
     }
+
+    /// Ticks of the `time` CSR.
+    #[inline]
+    fn now() -> u64 {
+        crate::arch::read_timer() as u64
+    }
 }
 
 impl DevicePage for Page {
@@ -113,28 +135,25 @@ impl DevicePage for Page {
     }
 }
 
+impl Drop for Page {
+    fn drop(&mut self) {
+        // Zeroed now, so that it is zeroed when it is handed out again.
+        self.as_mut_slice().fill(0);
+        FREE_PAGES.lock().push((self.pa, self.size));
+    }
+}
+
 impl<const SIZE: usize> QueuePage<SIZE> for Page {
-    fn queue_ref_mut(&mut self, layout: &QueueLayout) -> QueueMutRef<SIZE> {
-        let desc_table_offset = layout.descriptor_table_offset;
-        let table = unsafe {
-            let ptr = (self.pa + desc_table_offset) as *mut Descriptor;
-            core::slice::from_raw_parts_mut(ptr, SIZE)
-        };
-        let avail_ring_offset = layout.avail_ring_offset;
-        let avail_ring = unsafe {
-            let ptr = (self.pa + avail_ring_offset) as *mut AvailRing<SIZE>;
-            &mut *ptr
-        };
-
-        let used_ring_offset = layout.used_ring_offset;
-        let used_ring = unsafe {
-            let ptr = (self.pa + used_ring_offset) as *mut UsedRing<SIZE>;
-            &mut *ptr
-        };
-        QueueMutRef {
-            descriptor_table: table,
-            avail_ring,
-            used_ring,
+    fn queue_ref_mut(&mut self, layout: &QueueLayout<SIZE>) -> QueueMutRef<SIZE> {
+        let pointers = layout.ring_pointers(self.vaddr());
+        // Safety: the pointers are aligned, don't overlap and lie within this page, which nothing
+        // else accesses and which outlives the references.
+        unsafe {
+            QueueMutRef {
+                descriptor_table: core::slice::from_raw_parts_mut(pointers.descriptor_table, SIZE),
+                avail_ring: &mut *pointers.avail_ring,
+                used_ring: &mut *pointers.used_ring,
+            }
         }
     }
 }
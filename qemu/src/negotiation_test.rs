@@ -0,0 +1,1880 @@
+//! Feature negotiation and driver tests against a scripted fake transport, so they don't depend on
+//! which devices QEMU happens to provide.
+
+use crate::conformance_test::{complete_blk_request, complete_request, read_descriptor};
+use crate::my_impl::MyHalImpl;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Waker};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use safe_virtio_drivers::device::balloon::VirtIOBalloon;
+use safe_virtio_drivers::device::block::{
+    BlkFeature, BlockDevice, ThrottleLimits, ThrottledBlk, VirtIOBlk,
+    SUPPORTED_FEATURES as BLK_SUPPORTED_FEATURES,
+};
+use safe_virtio_drivers::device::console::{VirtIOConsole, VirtIoConsoleLogger};
+use safe_virtio_drivers::device::gpu::{Canvas, Format, Rgba};
+use safe_virtio_drivers::device::input::{AbsInfo, DevIDs, VirtIOInput};
+use safe_virtio_drivers::device::net::{
+    Features as NetFeatures, GsoType, NetPoll, NetQueueStats, Status as NetStatus, VirtIONet,
+    VirtIONetRaw, DEFAULT_MTU, ETH_HLEN, NET_HDR_SIZE, OFFLOAD_FEATURES,
+};
+use safe_virtio_drivers::device::scsi::cdb::{self, Capacity};
+use safe_virtio_drivers::device::scsi::sense::{SenseData, SenseKey};
+use safe_virtio_drivers::device::scsi::{ResetReason, ScsiEvent, VirtIOScsi};
+use safe_virtio_drivers::device::sound::{
+    Direction, PcmFeatures, PcmFormat, PcmInfo, PcmRate, SoundEvent, VirtIOSound,
+};
+use safe_virtio_drivers::device::watchdog::{StuckPolicy, Watchdog};
+use safe_virtio_drivers::device::{DeviceIdentity, VirtIoDriver};
+use safe_virtio_drivers::error::{InitStep, VirtIoError, VirtIoResult};
+use safe_virtio_drivers::hal::{
+    BufferDirection, DevicePage, DmaBuf, Hal, QueuePage, VirtIoDeviceIo,
+};
+use safe_virtio_drivers::queue::{OwnedBuffer, QueueInfo, VirtIoQueue};
+use safe_virtio_drivers::transport::mmio::CONFIG_OFFSET;
+use safe_virtio_drivers::transport::{
+    queue_vector, DeviceStatus, DeviceType, InitMilestone, InitObserver, InterruptStatus,
+    Transport, CONFIG_VECTOR,
+};
+use safe_virtio_drivers::{PhysAddr, VirtAddr};
+use spin::Mutex;
+
+/// A config space which reads as the bytes given to [`FakeTransport::with_config`] and zeroes
+/// elsewhere, and ignores writes.
+#[derive(Debug, Default)]
+struct FakeIo {
+    config: RefCell<Vec<u8>>,
+}
+
+impl VirtIoDeviceIo for FakeIo {
+    fn read_volatile_u32_at(&self, off: usize) -> VirtIoResult<u32> {
+        Ok(u32::from_le_bytes([
+            self.read_volatile_u8_at(off)?,
+            self.read_volatile_u8_at(off + 1)?,
+            self.read_volatile_u8_at(off + 2)?,
+            self.read_volatile_u8_at(off + 3)?,
+        ]))
+    }
+    fn read_volatile_u8_at(&self, off: usize) -> VirtIoResult<u8> {
+        Ok(off
+            .checked_sub(CONFIG_OFFSET)
+            .and_then(|index| self.config.borrow().get(index).copied())
+            .unwrap_or(0))
+    }
+    fn write_volatile_u32_at(&self, _off: usize, _data: u32) -> VirtIoResult<()> {
+        Ok(())
+    }
+    fn write_volatile_u8_at(&self, _off: usize, _data: u8) -> VirtIoResult<()> {
+        Ok(())
+    }
+    fn paddr(&self) -> PhysAddr {
+        0
+    }
+    fn vaddr(&self) -> VirtAddr {
+        0
+    }
+}
+
+/// Something the driver did to a [`FakeTransport`], in the order it happened.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Event {
+    Status(DeviceStatus),
+    DriverFeatures(u64),
+    QueueSet {
+        queue: u16,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+    },
+    Notify(u16),
+    QueueVector {
+        queue: u16,
+        vector: u16,
+    },
+    ConfigVector(u16),
+}
+
+/// A transport which records everything the driver writes.
+pub(crate) struct FakeTransport {
+    legacy: bool,
+    device_features: u64,
+    /// Which 32-bit word of the device features the driver reads, as `DeviceFeaturesSel`.
+    device_features_sel: u32,
+    /// Which 32-bit word of the driver features the driver writes, as `DriverFeaturesSel`.
+    driver_features_sel: u32,
+    /// Every word of driver features written, with the value of `DriverFeaturesSel` at the time.
+    pub(crate) driver_feature_words: Vec<(u32, u32)>,
+    /// Whether the device keeps FEATURES_OK set when the driver writes it.
+    accept_features: bool,
+    /// Features which make the device clear FEATURES_OK if the driver writes any of them.
+    rejected_features: u64,
+    pub(crate) driver_features: Option<u64>,
+    status: DeviceStatus,
+    pub(crate) status_history: Vec<DeviceStatus>,
+    pub(crate) events: Vec<Event>,
+    /// How many times the status must be read after a reset before it reads as zero.
+    reset_reads: u32,
+    reset_pending: Cell<u32>,
+    /// The config generation, which [`Self::change_config`] advances.
+    generation: Cell<u32>,
+    /// How many more reads of the config generation find it advanced, as if the device kept
+    /// changing the config space.
+    pub(crate) generation_churn: Cell<u32>,
+    /// Whether the driver wrote a status before the device finished resetting.
+    pub(crate) wrote_during_reset: bool,
+    io: FakeIo,
+    /// The size of the config space the transport reports, if any.
+    config_space_size: Option<usize>,
+    /// The causes of the pending interrupt, cleared when it is acknowledged.
+    pub(crate) interrupt_status: InterruptStatus,
+    init_observer: Option<InitObserver>,
+    /// The most descriptors the device handles in a queue.
+    max_queue_size: u32,
+    /// Whether the device set DEVICE_NEEDS_RESET, until it is reset.
+    needs_reset: Cell<bool>,
+}
+
+impl FakeTransport {
+    pub(crate) fn new(legacy: bool, device_features: u64, accept_features: bool) -> Self {
+        Self {
+            legacy,
+            device_features,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_feature_words: Vec::new(),
+            accept_features,
+            rejected_features: 0,
+            driver_features: None,
+            status: DeviceStatus::empty(),
+            status_history: Vec::new(),
+            events: Vec::new(),
+            reset_reads: 0,
+            reset_pending: Cell::new(0),
+            generation: Cell::new(0),
+            generation_churn: Cell::new(0),
+            wrote_during_reset: false,
+            io: FakeIo::default(),
+            config_space_size: None,
+            interrupt_status: InterruptStatus::empty(),
+            init_observer: None,
+            max_queue_size: 256,
+            needs_reset: Cell::new(false),
+        }
+    }
+
+    /// Makes the device reject any set of driver features including one of `features`.
+    pub(crate) fn rejecting(mut self, features: u64) -> Self {
+        self.rejected_features = features;
+        self
+    }
+
+    /// Makes the device-specific config space read as `bytes` from `offset` on.
+    pub(crate) fn with_config(self, offset: usize, bytes: &[u8]) -> Self {
+        self.change_config(offset, bytes);
+        self
+    }
+
+    /// Changes the device-specific config space from `offset` on, as the host would at runtime.
+    pub(crate) fn change_config(&self, offset: usize, bytes: &[u8]) {
+        let mut config = self.io.config.borrow_mut();
+        if config.len() < offset + bytes.len() {
+            config.resize(offset + bytes.len(), 0);
+        }
+        config[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    /// Makes the transport report a config space of `size` bytes.
+    pub(crate) fn with_config_space_size(mut self, size: usize) -> Self {
+        self.config_space_size = Some(size);
+        self
+    }
+
+    /// Makes the device handle at most `size` descriptors in each queue.
+    pub(crate) fn with_max_queue_size(mut self, size: u32) -> Self {
+        self.max_queue_size = size;
+        self
+    }
+
+    /// Makes the transport tell `observer` about each milestone of initializing the device.
+    pub(crate) fn observed(mut self, observer: InitObserver) -> Self {
+        self.init_observer = Some(observer);
+        self
+    }
+
+    /// Makes the device set DEVICE_NEEDS_RESET, as if its backend restarted.
+    pub(crate) fn request_reset(&self) {
+        self.needs_reset.set(true);
+    }
+
+    /// Reads the word of the device features `DeviceFeaturesSel` selects. A legacy device only
+    /// has the first.
+    fn device_features_word(&self) -> u32 {
+        match self.device_features_sel {
+            0 => self.device_features as u32,
+            1 if !self.legacy => (self.device_features >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    /// Writes the word of the driver features `DriverFeaturesSel` selects.
+    fn write_driver_features_word(&mut self, word: u32) {
+        self.driver_feature_words
+            .push((self.driver_features_sel, word));
+        let shift = 32 * self.driver_features_sel;
+        let features = self.driver_features.unwrap_or(0) & !(u64::from(u32::MAX) << shift);
+        self.driver_features = Some(features | u64::from(word) << shift);
+    }
+
+    /// Makes the device keep reporting its old status for `reads` reads after being reset.
+    pub(crate) fn slow_reset(mut self, reads: u32) -> Self {
+        self.reset_reads = reads;
+        self
+    }
+}
+
+impl Transport for FakeTransport {
+    fn device_type(&self) -> VirtIoResult<DeviceType> {
+        Ok(DeviceType::Block)
+    }
+    fn read_device_features(&mut self) -> VirtIoResult<u64> {
+        // One 32-bit word at a time, as through the MMIO registers.
+        self.device_features_sel = 0;
+        let low = self.device_features_word();
+        self.device_features_sel = 1;
+        let high = self.device_features_word();
+        Ok(u64::from(low) | u64::from(high) << 32)
+    }
+    fn write_driver_features(&mut self, driver_features: u64) -> VirtIoResult<()> {
+        self.events.push(Event::DriverFeatures(driver_features));
+        self.driver_features = Some(0);
+        self.driver_features_sel = 0;
+        self.write_driver_features_word(driver_features as u32);
+        self.driver_features_sel = 1;
+        self.write_driver_features_word((driver_features >> 32) as u32);
+        Ok(())
+    }
+    fn max_queue_size(&mut self, _queue: u16) -> VirtIoResult<u32> {
+        Ok(self.max_queue_size)
+    }
+    fn notify(&mut self, queue: u16) -> VirtIoResult<()> {
+        self.events.push(Event::Notify(queue));
+        Ok(())
+    }
+    fn get_status(&self) -> VirtIoResult<DeviceStatus> {
+        match self.reset_pending.get() {
+            0 if self.needs_reset.get() => Ok(self.status | DeviceStatus::DEVICE_NEEDS_RESET),
+            0 => Ok(self.status),
+            pending => {
+                self.reset_pending.set(pending - 1);
+                Ok(DeviceStatus::DRIVER_OK)
+            }
+        }
+    }
+    fn set_status(&mut self, status: DeviceStatus) -> VirtIoResult<()> {
+        if self.reset_pending.get() != 0 {
+            self.wrote_during_reset = true;
+        }
+        if status.is_empty() {
+            self.reset_pending.set(self.reset_reads);
+            self.needs_reset.set(false);
+        }
+        self.status_history.push(status);
+        self.events.push(Event::Status(status));
+        self.status = status;
+        let rejected = self.driver_features.unwrap_or(0) & self.rejected_features != 0;
+        if !self.accept_features || rejected {
+            self.status.remove(DeviceStatus::FEATURES_OK);
+        }
+        Ok(())
+    }
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) -> VirtIoResult<()> {
+        Ok(())
+    }
+    fn requires_legacy_layout(&self) -> bool {
+        self.legacy
+    }
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        _size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        _device_area: PhysAddr,
+    ) -> VirtIoResult<()> {
+        self.events.push(Event::QueueSet {
+            queue,
+            descriptors,
+            driver_area,
+        });
+        Ok(())
+    }
+    fn queue_unset(&mut self, _queue: u16) -> VirtIoResult<()> {
+        Ok(())
+    }
+    fn queue_used(&mut self, _queue: u16) -> VirtIoResult<bool> {
+        Ok(false)
+    }
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        Ok(core::mem::take(&mut self.interrupt_status))
+    }
+    fn peek_interrupt_status(&self) -> VirtIoResult<InterruptStatus> {
+        Ok(self.interrupt_status)
+    }
+    fn config_generation(&self) -> VirtIoResult<u32> {
+        if self.generation_churn.get() != 0 {
+            self.generation_churn.set(self.generation_churn.get() - 1);
+            self.generation.set(self.generation.get() + 1);
+        }
+        Ok(self.generation.get())
+    }
+    fn set_queue_msix_vector(&mut self, queue: u16, vector: u16) -> VirtIoResult<()> {
+        self.events.push(Event::QueueVector { queue, vector });
+        Ok(())
+    }
+    fn config_msix_vector(&mut self, vector: u16) -> VirtIoResult<()> {
+        self.events.push(Event::ConfigVector(vector));
+        Ok(())
+    }
+    fn io_region(&self) -> &dyn VirtIoDeviceIo {
+        &self.io
+    }
+    fn config_space_size(&self) -> Option<usize> {
+        self.config_space_size
+    }
+    fn init_observer(&self) -> Option<InitObserver> {
+        self.init_observer
+    }
+}
+
+pub(crate) const ACK_DRIVER: DeviceStatus = DeviceStatus::ACKNOWLEDGE.union(DeviceStatus::DRIVER);
+const ACK_DRIVER_FEATURES_OK: DeviceStatus = ACK_DRIVER.union(DeviceStatus::FEATURES_OK);
+
+pub fn test_feature_negotiation() {
+    only_version_1_offered();
+    fewer_features_than_supported();
+    features_opted_out();
+    features_ok_rejected();
+    legacy_high_feature_bits();
+    legacy_ignores_features_ok();
+    blk_refuses_scsi(true);
+    blk_refuses_scsi(false);
+    features_ok_fallback();
+    init_failure_marks_device_failed();
+    feature_dependencies_checked();
+    blk_multiqueue();
+    blk_reset();
+    blk_queue_interrupts();
+    blk_throttle();
+    net_control_queue();
+    net_offloads();
+    net_mtu();
+    net_stats();
+    net_tx_arena();
+    net_poll_mode();
+    input_config_queries();
+    console_recv_deadline();
+    console_logger();
+    dma_freed_on_drop();
+    balloon_without_pages();
+    scsi_events();
+    scsi_parsers();
+    canvas_clipping();
+    canvas_blit_past_edge();
+    queue_returns_owned_buffers();
+    short_queues();
+    scattered_buffers();
+    sound_events();
+    config_space_size();
+    poll_interrupt_leaves_interrupt_pending();
+    config_change();
+    init_milestones();
+    driver_identity_through_trait_object();
+    watchdog_resets_stuck_device();
+    device_needs_reset();
+    async_dropped_before_completion();
+    info!("feature negotiation test finished");
+}
+
+fn only_version_1_offered() {
+    let mut transport = FakeTransport::new(false, BlkFeature::VERSION_1.bits(), true);
+    let negotiated = transport
+        .begin_init(BlkFeature::FLUSH | BlkFeature::VERSION_1)
+        .expect("negotiation failed");
+    assert_eq!(negotiated, BlkFeature::VERSION_1);
+    assert_eq!(
+        transport.driver_features,
+        Some(BlkFeature::VERSION_1.bits())
+    );
+    assert_eq!(
+        transport.status_history,
+        [DeviceStatus::empty(), ACK_DRIVER, ACK_DRIVER_FEATURES_OK]
+    );
+}
+
+fn fewer_features_than_supported() {
+    let offered = BlkFeature::RO | BlkFeature::VERSION_1;
+    let mut transport = FakeTransport::new(false, offered.bits(), true);
+    let negotiated = transport
+        .begin_init(BlkFeature::FLUSH)
+        .expect("negotiation failed");
+    // Nothing the driver supports is offered, so only VERSION_1 may be acked, which a modern
+    // device must always be.
+    assert_eq!(negotiated, BlkFeature::VERSION_1);
+    assert_eq!(
+        transport.driver_features,
+        Some(BlkFeature::VERSION_1.bits())
+    );
+}
+
+fn features_opted_out() {
+    let offered = BlkFeature::FLUSH | BlkFeature::RING_EVENT_IDX | BlkFeature::VERSION_1;
+    let transport = FakeTransport::new(false, offered.bits(), true);
+    let wanted = BLK_SUPPORTED_FEATURES.difference(BlkFeature::FLUSH);
+    let mut blk = VirtIOBlk::<MyHalImpl, _>::new_with_features(transport, wanted)
+        .expect("failed to create blk driver");
+    let negotiated = BlkFeature::RING_EVENT_IDX | BlkFeature::VERSION_1;
+    assert_eq!(blk.negotiated_features(), negotiated);
+    // The same features are negotiated again after a reset.
+    blk.reset().expect("failed to reset");
+    assert_eq!(blk.negotiated_features(), negotiated);
+    assert_eq!(blk.transport().driver_features, Some(negotiated.bits()));
+
+    // Only features the driver supports may be asked for.
+    let transport = FakeTransport::new(false, offered.bits(), true);
+    assert!(matches!(
+        VirtIOBlk::<MyHalImpl, _>::new_with_features(transport, BlkFeature::RO),
+        Err(VirtIoError::InvalidParam)
+    ));
+}
+
+fn features_ok_rejected() {
+    let mut transport = FakeTransport::new(false, BlkFeature::FLUSH.bits(), false);
+    let result = transport.begin_init(BlkFeature::FLUSH);
+    assert_eq!(result, Err(VirtIoError::FeaturesNotAccepted));
+    // The device must be marked as failed rather than left half initialised, without clearing
+    // the bits it still reports.
+    assert_eq!(
+        transport.status_history.last(),
+        Some(&(ACK_DRIVER | DeviceStatus::FAILED))
+    );
+}
+
+fn legacy_high_feature_bits() {
+    // A legacy device only has the first 32 feature bits, so nothing above them may be acked,
+    // not even VERSION_1.
+    let offered = BlkFeature::FLUSH | BlkFeature::RING_PACKED | BlkFeature::VERSION_1;
+    let mut transport = FakeTransport::new(true, offered.bits(), true);
+    let negotiated = transport.begin_init(offered).expect("negotiation failed");
+    assert_eq!(negotiated, BlkFeature::FLUSH);
+    assert_eq!(
+        transport.driver_feature_words,
+        [(0, BlkFeature::FLUSH.bits() as u32), (1, 0)]
+    );
+
+    // A modern device has both words, and both must make it through negotiation.
+    let mut transport = FakeTransport::new(false, offered.bits(), true);
+    let negotiated = transport.begin_init(offered).expect("negotiation failed");
+    assert_eq!(negotiated, offered);
+    assert_eq!(
+        transport.driver_feature_words,
+        [
+            (0, BlkFeature::FLUSH.bits() as u32),
+            (1, (offered.bits() >> 32) as u32)
+        ]
+    );
+}
+
+fn legacy_ignores_features_ok() {
+    // Legacy devices don't implement FEATURES_OK, so it reading back as clear is not an error.
+    let mut transport = FakeTransport::new(true, BlkFeature::FLUSH.bits(), false);
+    let negotiated = transport
+        .begin_init(BlkFeature::FLUSH)
+        .expect("negotiation failed");
+    assert_eq!(negotiated, BlkFeature::FLUSH);
+    assert!(!transport
+        .status_history
+        .iter()
+        .any(|status| status.contains(DeviceStatus::FAILED)));
+}
+
+fn blk_refuses_scsi(legacy: bool) {
+    // Legacy hosts may offer SCSI passthrough, whose requests the driver doesn't build.
+    let mut offered = BlkFeature::SCSI | BlkFeature::FLUSH;
+    if !legacy {
+        offered |= BlkFeature::VERSION_1;
+    }
+    let transport = FakeTransport::new(legacy, offered.bits(), true);
+    let blk = VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver");
+    let negotiated = offered.difference(BlkFeature::SCSI);
+    assert_eq!(blk.negotiated_features(), negotiated);
+    assert_eq!(blk.transport().driver_features, Some(negotiated.bits()));
+}
+
+fn features_ok_fallback() {
+    let offered = BlkFeature::FLUSH | BlkFeature::BARRIER | BlkFeature::VERSION_1;
+    let mut transport =
+        FakeTransport::new(false, offered.bits(), true).rejecting(BlkFeature::FLUSH.bits());
+    let negotiated = transport
+        .begin_init_with_fallback(
+            BlkFeature::VERSION_1,
+            BlkFeature::FLUSH | BlkFeature::BARRIER,
+        )
+        .expect("negotiation failed");
+    assert_eq!(
+        negotiated.features,
+        BlkFeature::BARRIER | BlkFeature::VERSION_1
+    );
+    assert_eq!(negotiated.dropped, BlkFeature::FLUSH);
+    // The device must be reset after being marked as failed, before negotiating again.
+    let failed = transport
+        .status_history
+        .iter()
+        .position(|status| status.contains(DeviceStatus::FAILED))
+        .expect("the rejected attempt wasn't marked as failed");
+    assert_eq!(transport.status_history[failed + 1], DeviceStatus::empty());
+
+    // Required features are never dropped.
+    let mut transport =
+        FakeTransport::new(false, offered.bits(), true).rejecting(BlkFeature::FLUSH.bits());
+    let result = transport.begin_init_with_fallback(BlkFeature::FLUSH, BlkFeature::BARRIER);
+    assert_eq!(result, Err(VirtIoError::FeaturesNotAccepted));
+
+    // The block driver comes up without the rejected feature.
+    let transport =
+        FakeTransport::new(false, offered.bits(), true).rejecting(BlkFeature::FLUSH.bits());
+    let blk = VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver");
+    assert_eq!(
+        blk.negotiated_features(),
+        BlkFeature::BARRIER | BlkFeature::VERSION_1
+    );
+}
+
+fn init_failure_marks_device_failed() {
+    // The error of the step which failed is returned, and the device marked as FAILED without
+    // clearing the bits it still reports.
+    let mut transport = FakeTransport::new(false, BlkFeature::FLUSH.bits(), true);
+    transport
+        .begin_init(BlkFeature::FLUSH)
+        .expect("negotiation failed");
+    let result = transport.init_step(InitStep::QueueSetup, |_| Err::<(), _>(VirtIoError::IoError));
+    assert_eq!(result, Err(VirtIoError::IoError));
+    assert_eq!(
+        transport.status_history.last(),
+        Some(&(ACK_DRIVER_FEATURES_OK | DeviceStatus::FAILED))
+    );
+
+    // A driver returns why it failed, and reports which step it failed at.
+    MILESTONES.lock().clear();
+    let transport = FakeTransport::new(false, BlkFeature::FLUSH.bits(), true)
+        .with_config_space_size(4)
+        .observed(record_milestone);
+    let result = VirtIOBlk::<MyHalImpl, _>::new(transport).map(|_| ());
+    assert_eq!(result, Err(VirtIoError::ConfigSpaceTooSmall));
+    assert_eq!(
+        MILESTONES.lock().last(),
+        Some(&InitMilestone::Failed(InitStep::ReadConfig))
+    );
+    MILESTONES.lock().clear();
+
+    let transport = FakeTransport::new(false, BlkFeature::FLUSH.bits(), false);
+    let result = VirtIOBlk::<MyHalImpl, _>::new(transport).map(|_| ());
+    assert_eq!(result, Err(VirtIoError::FeaturesNotAccepted));
+}
+
+fn feature_dependencies_checked() {
+    // A device offering a feature without the one it depends on can't be driven.
+    let offered = NetFeatures::CTRL_RX | NetFeatures::MAC;
+    let mut transport = FakeTransport::new(false, offered.bits(), true);
+    let result = transport.begin_init(NetFeatures::CTRL_RX | NetFeatures::CTRL_VQ);
+    assert_eq!(
+        result,
+        Err(VirtIoError::MissingFeatureDependency {
+            feature: 18,
+            requires: NetFeatures::CTRL_VQ.bits(),
+        })
+    );
+    assert_eq!(
+        transport.status_history.last(),
+        Some(&(ACK_DRIVER | DeviceStatus::FAILED))
+    );
+
+    // Any one of the features it depends on is enough.
+    let offered = NetFeatures::GUEST_ECN | NetFeatures::GUEST_TSO6 | NetFeatures::GUEST_CSUM;
+    let mut transport = FakeTransport::new(false, offered.bits(), true);
+    let negotiated = transport.begin_init(offered).expect("negotiation failed");
+    assert_eq!(negotiated, offered);
+}
+
+fn net_control_queue() {
+    let offered = NetFeatures::CTRL_VQ | NetFeatures::CTRL_RX | NetFeatures::MQ;
+    // max_virtqueue_pairs
+    let config = (8, &4u16.to_le_bytes());
+
+    // Only drivers asking for the control queue get one.
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config(config.0, config.1);
+    let mut net = VirtIONetRaw::<MyHalImpl, _, 16>::new(transport).expect("failed to create net");
+    assert_eq!(net.control_queue(), None);
+    assert_eq!(net.max_queue_pairs(), 1);
+    assert_eq!(net.set_promiscuous(true), Err(VirtIoError::Unsupported));
+
+    // With several queue pairs, the control queue comes after all of them.
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config(config.0, config.1);
+    let mut net = VirtIONetRaw::<MyHalImpl, _, 16>::new_with_control_queue(transport)
+        .expect("failed to create net");
+    assert_eq!(net.negotiated_features(), offered);
+    assert_eq!(net.max_queue_pairs(), 4);
+    assert_eq!(net.control_queue(), Some(8));
+    let indices: Vec<u16> = net.queues().iter().map(|queue| queue.index).collect();
+    assert_eq!(indices, [0, 1, 8]);
+    // Commands the device didn't offer, or with invalid parameters, never reach the queue.
+    assert_eq!(
+        net.set_mac_address([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+        Err(VirtIoError::Unsupported)
+    );
+    assert_eq!(net.set_queue_pairs(0), Err(VirtIoError::InvalidParam));
+    assert_eq!(net.set_queue_pairs(5), Err(VirtIoError::InvalidParam));
+    assert!(net.stats().iter().all(|stats| stats.in_flight == 0));
+}
+
+fn net_offloads() {
+    let offered = NetFeatures::CSUM | NetFeatures::GUEST_CSUM | NetFeatures::HOST_TSO4;
+
+    // Offloads are only negotiated on request.
+    let transport = FakeTransport::new(false, offered.bits(), true);
+    let net = VirtIONetRaw::<MyHalImpl, _, 16>::new(transport).expect("failed to create net");
+    assert_eq!(net.offloads(), NetFeatures::empty());
+    let transport = FakeTransport::new(false, offered.bits(), true);
+    assert_eq!(
+        VirtIONetRaw::<MyHalImpl, _, 16>::new_with_offloads(transport, NetFeatures::MQ).err(),
+        Some(VirtIoError::InvalidParam)
+    );
+    // TSOv4 can't be negotiated without the checksum offload it relies on.
+    let transport = FakeTransport::new(false, offered.bits(), true);
+    assert_eq!(
+        VirtIONetRaw::<MyHalImpl, _, 16>::new_with_offloads(transport, NetFeatures::HOST_TSO4)
+            .err(),
+        Some(VirtIoError::MissingFeatureDependency {
+            feature: 11,
+            requires: NetFeatures::CSUM.bits(),
+        })
+    );
+
+    let transport = FakeTransport::new(false, offered.bits(), true);
+    let net = VirtIONetRaw::<MyHalImpl, _, 16>::new_with_offloads(transport, OFFLOAD_FEATURES)
+        .expect("failed to create net");
+    assert_eq!(net.offloads(), offered);
+    // Headers may only ask for the offloads the device took.
+    net.tx_header_builder()
+        .checksum(34, 16)
+        .segmentation(GsoType::TCPV4, 1448, 54)
+        .build()
+        .expect("TSOv4 was negotiated");
+    assert_eq!(
+        net.tx_header_builder()
+            .checksum(54, 16)
+            .segmentation(GsoType::TCPV6, 1428, 74)
+            .build(),
+        Err(VirtIoError::Unsupported)
+    );
+
+    // Receive segmentation offload needs buffers big enough for a coalesced packet, which is
+    // checked before the device is touched.
+    let transport = FakeTransport::new(false, offered.bits(), true);
+    let result = VirtIONet::<MyHalImpl, _, 16>::new_with_offloads(
+        transport,
+        2048,
+        NetFeatures::GUEST_CSUM | NetFeatures::GUEST_TSO4,
+    );
+    assert_eq!(result.err(), Some(VirtIoError::InvalidParam));
+}
+
+fn net_mtu() {
+    let offered = NetFeatures::MTU;
+    // mtu
+    let config = (10, &9000u16.to_le_bytes());
+
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config(config.0, config.1);
+    let mut net = VirtIONetRaw::<MyHalImpl, _, 16>::new(transport).expect("failed to create net");
+    assert_eq!(net.negotiated_features(), NetFeatures::MTU);
+    assert_eq!(net.mtu(), 9000);
+    assert_eq!(net.min_rx_buffer_len(), NET_HDR_SIZE + ETH_HLEN + 9000);
+    let mut rx_buf = vec![0u8; 2048];
+    assert_eq!(
+        net.receive_begin(&mut rx_buf),
+        Err(VirtIoError::InvalidParam)
+    );
+
+    // Jumbo frames up to the MTU go out, longer ones are refused rather than truncated.
+    let mut tx_buf = vec![0u8; NET_HDR_SIZE + ETH_HLEN + 9000];
+    net.fill_buffer_header(&mut tx_buf)
+        .expect("failed to fill header");
+    net.transmit_begin(&tx_buf).expect("failed to transmit");
+    tx_buf.push(0);
+    assert_eq!(net.transmit_begin(&tx_buf), Err(VirtIoError::InvalidParam));
+
+    // The receive buffers grow to fit the MTU.
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config(config.0, config.1);
+    let net = VirtIONet::<MyHalImpl, _, 16>::new(transport, 2048).expect("failed to create net");
+    assert_eq!(net.mtu(), 9000);
+
+    // A device reporting an MTU below the minimum is treated as not reporting one.
+    let transport =
+        FakeTransport::new(false, offered.bits(), true).with_config(10, &50u16.to_le_bytes());
+    let net = VirtIONetRaw::<MyHalImpl, _, 16>::new(transport).expect("failed to create net");
+    assert_eq!(net.mtu(), DEFAULT_MTU);
+}
+
+fn net_stats() {
+    let transport = FakeTransport::new(false, NetFeatures::VERSION_1.bits(), true);
+    let mut net = VirtIONetRaw::<MyHalImpl, _, 16>::new(transport).expect("failed to create net");
+    let (rx_info, tx_info) = (net.queues()[0], net.queues()[1]);
+
+    let mut tx_buf = vec![0u8; NET_HDR_SIZE + 60];
+    net.fill_buffer_header(&mut tx_buf)
+        .expect("failed to fill header");
+    let token = net.transmit_begin(&tx_buf).expect("failed to transmit");
+    complete_request(tx_info, token, 0, 0);
+    net.transmit_complete(token).expect("failed to complete");
+    let before = net.net_stats();
+    assert_eq!(
+        before.tx,
+        NetQueueStats {
+            packets: 1,
+            bytes: 60,
+            ..Default::default()
+        }
+    );
+
+    // A buffer too short for the header is refused, and counted as dropped.
+    assert_eq!(
+        net.transmit_begin(&tx_buf[..4]),
+        Err(VirtIoError::InvalidParam)
+    );
+    let mut rx_buf = vec![0u8; net.min_rx_buffer_len()];
+    let token = net.receive_begin(&mut rx_buf).expect("failed to receive");
+    complete_request(rx_info, token, 0, (NET_HDR_SIZE + 42) as u32);
+    assert_eq!(net.receive_complete(token), Ok((NET_HDR_SIZE, 42)));
+    // A completion too short for the header is an error.
+    let token = net.receive_begin(&mut rx_buf).expect("failed to receive");
+    complete_request(rx_info, token, 1, 2);
+    assert_eq!(net.receive_complete(token), Err(VirtIoError::IoError));
+
+    let delta = net.net_stats().delta(&before);
+    assert_eq!(
+        delta.rx,
+        NetQueueStats {
+            packets: 1,
+            bytes: 42,
+            errors: 1,
+            dropped: 0,
+        }
+    );
+    assert_eq!(
+        delta.tx,
+        NetQueueStats {
+            dropped: 1,
+            ..Default::default()
+        }
+    );
+    // 60 bytes in a millisecond is 480 kbit/s, 48% of a 1 Mbit/s link.
+    assert_eq!(before.tx.bits_per_second(1), 480_000);
+    assert_eq!(before.tx.utilization_percent(1, 1), Some(48));
+    assert_eq!(before.tx.utilization_percent(1, 0), None);
+}
+
+/// The fake config space ignores `select`, so each query returns the same data and only the
+/// parsing differs.
+fn net_tx_arena() {
+    let transport = FakeTransport::new(false, NetFeatures::VERSION_1.bits(), true);
+    let mut net =
+        VirtIONet::<MyHalImpl, _, 16>::new(transport, 2048).expect("failed to create net");
+    let tx_info = net.queues()[1];
+    let slot_len = NET_HDR_SIZE + ETH_HLEN + usize::from(DEFAULT_MTU);
+    assert_eq!(
+        net.set_tx_arena(slot_len - 1),
+        Err(VirtIoError::InvalidParam)
+    );
+    net.set_tx_arena(2 * slot_len)
+        .expect("failed to set up arena");
+
+    // The packets are copied into the arena's two slots, one after the other.
+    let first = net.send_nb(&[1; 60]).expect("failed to send");
+    let second = net.send_nb(&[2; 60]).expect("failed to send");
+    let (first_addr, len, _, _) = read_descriptor(tx_info.descriptors, first);
+    assert_eq!(len as usize, NET_HDR_SIZE + 60);
+    let (second_addr, _, _, _) = read_descriptor(tx_info.descriptors, second);
+    assert_eq!(second_addr, first_addr + slot_len as u64);
+    // Safety: the descriptor points to the driver's arena, which is identity mapped, and is only
+    // read.
+    let byte = unsafe { ((first_addr as usize + NET_HDR_SIZE) as *const u8).read_volatile() };
+    assert_eq!(byte, 1);
+
+    // With every slot in use, the next packet is copied into the heap instead.
+    let third = net.send_nb(&[3; 60]).expect("failed to send");
+    let (third_addr, _, _, _) = read_descriptor(tx_info.descriptors, third);
+    assert!(!(first_addr..first_addr + 2 * slot_len as u64).contains(&third_addr));
+
+    // Once the device has sent the first packet, its slot is used again.
+    complete_request(tx_info, first, 0, 0);
+    let fourth = net.send_nb(&[4; 60]).expect("failed to send");
+    let (fourth_addr, _, _, _) = read_descriptor(tx_info.descriptors, fourth);
+    assert_eq!(fourth_addr, first_addr);
+    assert_eq!(net.tx_in_flight(), 3);
+}
+
+fn net_poll_mode() {
+    let transport = FakeTransport::new(false, NetFeatures::VERSION_1.bits(), true);
+    let mut net =
+        VirtIONet::<MyHalImpl, _, 16>::new(transport, 2048).expect("failed to create net");
+    let avail_flags = |info: QueueInfo| {
+        // Safety: the available ring belongs to the driver, is identity mapped, and is only read.
+        unsafe { (info.driver_area as *const u16).read_volatile() }
+    };
+    let (rx_info, tx_info) = (net.queues()[0], net.queues()[1]);
+    // Every receive buffer is in flight, so the device is asked to interrupt until polling.
+    assert_eq!(avail_flags(rx_info), 0);
+    net.set_poll_mode(true).expect("failed to set poll mode");
+    assert!(net.poll_mode());
+    assert_eq!(avail_flags(rx_info), 1);
+    assert_eq!(avail_flags(tx_info), 1);
+
+    // A tick takes every completed receive buffer, even one too short for the header, and
+    // reclaims the transmission.
+    complete_request(rx_info, 0, 0, (NET_HDR_SIZE + 42) as u32);
+    complete_request(rx_info, 1, 1, 2);
+    complete_request(rx_info, 2, 2, (NET_HDR_SIZE + 60) as u32);
+    let token = net.send_nb(&[1; 60]).expect("failed to send");
+    complete_request(tx_info, token, 0, 0);
+    assert_eq!(
+        net.poll(),
+        Ok(NetPoll {
+            received: 3,
+            reclaimed: 1
+        })
+    );
+    assert_eq!(net.polled_rx(), 3);
+    assert_eq!(net.tx_in_flight(), 0);
+    assert_eq!(net.poll(), Ok(NetPoll::default()));
+
+    // The packets come out in the order they arrived, with the short one dropped in its turn.
+    let rx_buf = net.receive().expect("failed to receive");
+    assert_eq!(rx_buf.packet().len(), 42);
+    rx_buf.recycle().expect("failed to recycle");
+    assert_eq!(net.receive().err(), Some(VirtIoError::IoError));
+    let mut data = [0; 60];
+    assert_eq!(net.receive_into(&mut data), Ok(60));
+    assert_eq!(net.polled_rx(), 0);
+    assert_eq!(net.receive().err(), Some(VirtIoError::NotReady));
+
+    // The mode outlives a reset, which loses what was polled.
+    complete_request(rx_info, 3, 3, (NET_HDR_SIZE + 42) as u32);
+    assert_eq!(net.poll().map(|poll| poll.received), Ok(1));
+    net.reset().expect("failed to reset");
+    assert_eq!(net.polled_rx(), 0);
+    assert_eq!(avail_flags(net.queues()[0]), 1);
+    net.set_poll_mode(false).expect("failed to set poll mode");
+    assert_eq!(avail_flags(net.queues()[0]), 0);
+}
+
+fn input_config_queries() {
+    let input_with = |data: &[u8]| {
+        // size, then data
+        let transport = FakeTransport::new(false, 0, true)
+            .with_config(2, &[data.len() as u8])
+            .with_config(8, data);
+        VirtIOInput::<MyHalImpl, _>::new(transport).expect("failed to create input driver")
+    };
+
+    // A trailing NUL some devices include isn't part of the name.
+    let mut input = input_with(b"QEMU Virtio Tablet\0");
+    assert_eq!(input.name().unwrap(), "QEMU Virtio Tablet");
+    assert_eq!(input.serial().unwrap(), "QEMU Virtio Tablet");
+
+    let mut ids = Vec::new();
+    for id in [0x06u16, 0x0627, 0x0003, 0x0001] {
+        ids.extend_from_slice(&id.to_le_bytes());
+    }
+    let mut input = input_with(&ids);
+    assert_eq!(
+        input.ids(),
+        Ok(Some(DevIDs {
+            bustype: 0x06,
+            vendor: 0x0627,
+            product: 0x0003,
+            version: 0x0001,
+        }))
+    );
+    // Too short for an AbsInfo, so the device is broken.
+    assert_eq!(input.abs_info(0), Err(VirtIoError::IoError));
+
+    let mut abs = Vec::new();
+    for value in [0u32, 0x7fff, 0, 0, 0] {
+        abs.extend_from_slice(&value.to_le_bytes());
+    }
+    let mut input = input_with(&abs);
+    assert_eq!(
+        input.abs_info(0),
+        Ok(Some(AbsInfo {
+            min: 0,
+            max: 0x7fff,
+            ..Default::default()
+        }))
+    );
+
+    // Unsupported queries return nothing.
+    let mut input = input_with(&[]);
+    assert_eq!(input.name().unwrap(), "");
+    assert_eq!(input.ids(), Ok(None));
+    assert_eq!(input.abs_info(0), Ok(None));
+    assert_eq!(input.supported_events(0x03), Ok(None));
+}
+
+fn console_recv_deadline() {
+    let transport = FakeTransport::new(false, 0, true);
+    let mut console =
+        VirtIOConsole::<MyHalImpl, _>::new(transport).expect("failed to create console driver");
+    // The fake device never sends anything, so both calls time out, but without queueing a
+    // second receive request.
+    for _ in 0..2 {
+        let deadline = crate::arch::read_timer() as u64 + 1000;
+        assert_eq!(console.try_recv_block_with_deadline(deadline), Ok(None));
+    }
+    let receive_notifications = console
+        .transport()
+        .events
+        .iter()
+        .filter(|&&event| event == Event::Notify(0))
+        .count();
+    assert_eq!(receive_notifications, 1);
+    // A deadline in the past still checks for input once.
+    assert_eq!(console.try_recv_block_with_deadline(0), Ok(None));
+}
+
+fn console_logger() {
+    let transport = FakeTransport::new(false, 0, true);
+    let console =
+        VirtIOConsole::<MyHalImpl, _>::new(transport).expect("failed to create console driver");
+    let logger = VirtIoConsoleLogger::new(LevelFilter::Info);
+    let record = |level, message| {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target("kernel")
+                .args(format_args!("{}", message))
+                .build(),
+        )
+    };
+    // Dropped, as there is no console yet.
+    record(Level::Info, "early");
+    assert!(logger.attach(console).is_none());
+    assert!(!logger.enabled(&Metadata::builder().level(Level::Debug).build()));
+    record(Level::Debug, "too verbose");
+    record(Level::Warn, "disk full");
+    let console = logger.detach().expect("console wasn't attached");
+
+    // Only the warning was sent, in a single request.
+    let info = console.queues()[1];
+    let transmit_notifications = console
+        .transport()
+        .events
+        .iter()
+        .filter(|&&event| event == Event::Notify(1))
+        .count();
+    assert_eq!(transmit_notifications, 1);
+    let (addr, len, _, _) = read_descriptor(info.descriptors, 0);
+    let expected = b"[ WARN kernel] disk full\r\n";
+    assert_eq!(len as usize, expected.len());
+    // Safety: the descriptor points to the driver's transmit buffer, which is identity mapped, and
+    // is only read.
+    let sent: Vec<u8> = (0..expected.len())
+        .map(|i| unsafe { (addr as *const u8).add(i).read_volatile() })
+        .collect();
+    assert_eq!(sent, expected);
+}
+
+fn dma_freed_on_drop() {
+    let transport = FakeTransport::new(false, 0, true);
+    let console =
+        VirtIOConsole::<MyHalImpl, _>::new(transport).expect("failed to create console driver");
+    let pages: Vec<usize> = console
+        .queues()
+        .iter()
+        .map(|info| info.descriptors)
+        .collect();
+    drop(console);
+    // The queue pages went back to the HAL, which hands the last one out again for the next queue
+    // of the same size, zeroed.
+    let mut transport = FakeTransport::new(false, 0, true);
+    let queue = VirtIoQueue::<MyHalImpl, 4>::new(&mut transport, 0).unwrap();
+    assert_eq!(queue.info().descriptors, pages[1]);
+    assert_eq!(read_descriptor(pages[1], 0), (0, 0, 0, 0));
+}
+
+fn balloon_without_pages() {
+    // The host wants 16 pages, but MyHalImpl has none to give.
+    let transport = FakeTransport::new(false, 0, true).with_config(0, &16u32.to_le_bytes());
+    let mut balloon =
+        VirtIOBalloon::<MyHalImpl, _>::new(transport).expect("failed to create balloon driver");
+    assert_eq!(balloon.target_pages(), Ok(16));
+    assert_eq!(balloon.balance(), Ok(0));
+    assert_eq!(balloon.deflate(1), Ok(0));
+    // Nothing was sent to the device.
+    assert!(!balloon
+        .transport()
+        .events
+        .iter()
+        .any(|event| matches!(event, Event::Notify(_))));
+}
+
+fn scsi_events() {
+    // One request queue.
+    let transport = FakeTransport::new(false, 0, true).with_config(0, &1u32.to_le_bytes());
+    let mut scsi =
+        VirtIOScsi::<MyHalImpl, _>::new(transport).expect("failed to create scsi driver");
+    assert_eq!(scsi.poll_event(), Ok(None));
+    // Too long for the request, so it is never sent.
+    assert_eq!(
+        scsi.execute(0, 0, &[0; 33], None),
+        Err(VirtIoError::InvalidParam)
+    );
+
+    // The device reports LUN 3 of target 2 being added, in the first event buffer.
+    let info = scsi.queues()[1];
+    let (addr, len, _, _) = read_descriptor(info.descriptors, 0);
+    assert_eq!(len, 16);
+    let mut event = [0u8; 16];
+    event[0] = 1;
+    event[4..8].copy_from_slice(&[1, 2, 0x40, 3]);
+    event[12] = 1;
+    // Safety: the descriptor points to the driver's live event buffer, which is identity mapped
+    // and the device's to write until it is used.
+    unsafe {
+        for (i, byte) in event.iter().enumerate() {
+            (addr as *mut u8).add(i).write_volatile(*byte);
+        }
+    }
+    complete_request(info, 0, 0, 16);
+    assert_eq!(
+        scsi.poll_event(),
+        Ok(Some(ScsiEvent::TransportReset {
+            target: 2,
+            lun: 3,
+            reason: ResetReason::Rescan,
+        }))
+    );
+    assert_eq!(scsi.poll_event(), Ok(None));
+    // The buffer went back to the device.
+    let event_notifications = scsi
+        .transport()
+        .events
+        .iter()
+        .filter(|&&event| event == Event::Notify(1))
+        .count();
+    assert_eq!(event_notifications, 2);
+}
+
+fn short_queues() {
+    // A device handling 12 descriptors gets the largest power of two that fits.
+    let mut transport = FakeTransport::new(false, 0, true).with_max_queue_size(12);
+    let mut queue = VirtIoQueue::<MyHalImpl, 16>::new(&mut transport, 0).unwrap();
+    assert_eq!(queue.size(), 8);
+    assert_eq!(queue.info().size, 8);
+    assert_eq!(queue.available_desc(), 8);
+    // The used event index follows the last of the 8 entries of the available ring, where the
+    // device looks for it, rather than the 16 the memory has room for.
+    queue.set_event_idx(true);
+    let used_event = queue.info().driver_area + 4 + 2 * 8;
+    // Safety: the available ring is live and identity mapped, see `read_descriptor`, and is only
+    // read.
+    let used_event = unsafe { (used_event as *const u16).read_volatile() };
+    // Nothing is in flight, so interrupts are suppressed until the index wraps.
+    assert_eq!(used_event, u16::MAX);
+    drop(queue);
+
+    // Legacy devices place the rings by the queue size, so the queue can't be shortened.
+    let mut transport = FakeTransport::new(true, 0, true).with_max_queue_size(12);
+    assert!(matches!(
+        VirtIoQueue::<MyHalImpl, 16>::new(&mut transport, 0),
+        Err(VirtIoError::InvalidParam)
+    ));
+    let mut transport = FakeTransport::new(false, 0, true).with_max_queue_size(0);
+    assert!(matches!(
+        VirtIoQueue::<MyHalImpl, 16>::new(&mut transport, 0),
+        Err(VirtIoError::InvalidParam)
+    ));
+
+    // Drivers only give the device as many buffers as fit.
+    let transport = FakeTransport::new(false, 0, true).with_max_queue_size(8);
+    let input = VirtIOInput::<MyHalImpl, _>::new(transport).expect("failed to create input");
+    let event_queue = input.queues()[0];
+    assert_eq!(event_queue.size, 8);
+    // Safety: as above.
+    let avail_idx = unsafe { ((event_queue.driver_area + 2) as *const u16).read_volatile() };
+    assert_eq!(avail_idx, 8);
+}
+
+/// A HAL whose pages are 16 bytes, so buffers are shared with the device in several ranges.
+struct ScatterHal;
+
+impl<const SIZE: usize> Hal<SIZE> for ScatterHal {
+    fn dma_alloc(pages: usize) -> Box<dyn QueuePage<SIZE>> {
+        <MyHalImpl as Hal<SIZE>>::dma_alloc(pages)
+    }
+    fn dma_alloc_buf(pages: usize) -> Box<dyn DevicePage> {
+        <MyHalImpl as Hal<SIZE>>::dma_alloc_buf(pages)
+    }
+    fn to_paddr(va: usize) -> usize {
+        va
+    }
+    fn share(vaddr: VirtAddr, len: usize, _direction: BufferDirection) -> Vec<(PhysAddr, usize)> {
+        let mut ranges = Vec::new();
+        let (mut start, end) = (vaddr, vaddr + len);
+        while start < end {
+            let next = ((start / 16 + 1) * 16).min(end);
+            ranges.push((start, next - start));
+            start = next;
+        }
+        ranges
+    }
+    fn unshare(
+        vaddr: VirtAddr,
+        len: usize,
+        direction: BufferDirection,
+        ranges: &[(PhysAddr, usize)],
+    ) {
+        UNSHARED
+            .lock()
+            .push((vaddr, len, direction, ranges.to_vec()));
+    }
+}
+
+/// The buffers [`ScatterHal`] was asked to unshare, in order.
+static UNSHARED: Mutex<Vec<(VirtAddr, usize, BufferDirection, Vec<(PhysAddr, usize)>)>> =
+    Mutex::new(Vec::new());
+
+fn scattered_buffers() {
+    let mut transport = FakeTransport::new(false, 0, true);
+    let mut queue = VirtIoQueue::<ScatterHal, 16>::new(&mut transport, 0).unwrap();
+    let data: Box<[u8]> = (0..40).collect();
+    let vaddr = data.as_ptr() as VirtAddr;
+    let ranges = <ScatterHal as Hal<16>>::share(vaddr, data.len(), BufferDirection::DriverToDevice);
+    assert!(ranges.len() >= 3);
+    let token = queue
+        .add_with_return_buffers(vec![OwnedBuffer::Read(data)])
+        .expect("failed to add buffer");
+    // Each range takes a descriptor of its own, chained in order.
+    assert_eq!(queue.available_desc(), 16 - ranges.len());
+    let mut index = token;
+    for (i, &(paddr, len)) in ranges.iter().enumerate() {
+        let (addr, desc_len, flags, next) = read_descriptor(queue.info().descriptors, index);
+        assert_eq!((addr, desc_len), (paddr as u64, len as u32));
+        let last = i == ranges.len() - 1;
+        // Only NEXT, as the device reads the buffer.
+        assert_eq!(flags, if last { 0 } else { 1 });
+        index = next;
+    }
+    // Nothing is unshared until the device has used the buffer.
+    assert!(UNSHARED.lock().is_empty());
+    complete_request(queue.info(), token, 0, 0);
+    let (buffers, _) = queue.pop_used_with_buffers(token).unwrap();
+    assert_eq!(
+        UNSHARED.lock().pop(),
+        Some((vaddr, 40, BufferDirection::DriverToDevice, ranges))
+    );
+    assert_eq!(buffers.len(), 1);
+    assert_eq!(queue.available_desc(), 16);
+
+    // A buffer which takes more descriptors than there are is unshared right away.
+    let data = vec![0; 300].into_boxed_slice();
+    assert_eq!(
+        queue.add_with_return_buffers(vec![OwnedBuffer::Write(data)]),
+        Err(VirtIoError::QueueFull)
+    );
+    let (_, len, direction, ranges) = UNSHARED.lock().pop().unwrap();
+    assert_eq!((len, direction), (300, BufferDirection::DeviceToDriver));
+    assert!(ranges.len() > 16);
+
+    // DMA pages are described by the address they were allocated at, without being split.
+    let page = <ScatterHal as Hal<16>>::dma_alloc_buf(1);
+    let buf = DmaBuf::from_page(page.as_ref(), 8..48, BufferDirection::DeviceToDriver).unwrap();
+    assert!(buf.device_writes());
+    assert_eq!(buf.ranges::<ScatterHal, 16>(), vec![(page.paddr() + 8, 40)]);
+    let past_end = page.as_slice().len() + 1;
+    assert_eq!(
+        DmaBuf::from_page(page.as_ref(), 0..past_end, BufferDirection::DriverToDevice),
+        None
+    );
+}
+
+fn sound_events() {
+    // One jack, two streams and no channel maps.
+    let config: Vec<u8> = [1u32, 2, 0].iter().flat_map(|n| n.to_le_bytes()).collect();
+    let transport = FakeTransport::new(false, 0, true).with_config(0, &config);
+    let mut sound =
+        VirtIOSound::<MyHalImpl, _>::new(transport).expect("failed to create sound driver");
+    assert_eq!((sound.jacks(), sound.streams(), sound.chmaps()), (1, 2, 0));
+    // Out of range or empty, so never sent.
+    assert_eq!(sound.jack_info(0, 2), Err(VirtIoError::InvalidParam));
+    assert_eq!(sound.chmap_info(0, 1), Err(VirtIoError::InvalidParam));
+    assert_eq!(sound.pcm_start(2), Err(VirtIoError::InvalidParam));
+    assert_eq!(sound.pcm_write(0, &[]), Err(VirtIoError::InvalidParam));
+    assert_eq!(sound.poll_event(), Ok(None));
+
+    // The device reports something was plugged into the jack, in the first event buffer.
+    let info = sound.queues()[1];
+    let (addr, len, _, _) = read_descriptor(info.descriptors, 0);
+    assert_eq!(len, 8);
+    let event = [0x00, 0x10, 0, 0, 0, 0, 0, 0];
+    // Safety: the descriptor points to the driver's live event buffer, which is identity mapped
+    // and the device's to write until it is used.
+    unsafe {
+        for (i, byte) in event.iter().enumerate() {
+            (addr as *mut u8).add(i).write_volatile(*byte);
+        }
+    }
+    complete_request(info, 0, 0, 8);
+    assert_eq!(
+        sound.poll_event(),
+        Ok(Some(SoundEvent::JackConnected { jack_id: 0 }))
+    );
+    assert_eq!(sound.poll_event(), Ok(None));
+
+    let pcm = PcmInfo {
+        hda_fn_nid: 0,
+        features: PcmFeatures::empty(),
+        formats: 1 << 5,
+        rates: 1 << 7,
+        direction: Direction::Output,
+        channels_min: 1,
+        channels_max: 2,
+    };
+    assert!(pcm.supports_format(PcmFormat::S16));
+    assert!(!pcm.supports_format(PcmFormat::U8));
+    assert!(pcm.supports_rate(PcmRate::Rate48000));
+}
+
+fn scsi_parsers() {
+    assert_eq!(
+        cdb::read_10(0x1234, 8),
+        [0x28, 0, 0, 0, 0x12, 0x34, 0, 0, 8, 0]
+    );
+    let capacity = Capacity::parse_10(&[0, 0, 0x0f, 0xff, 0, 0, 2, 0]).unwrap();
+    assert_eq!(capacity.blocks(), 0x1000);
+    assert_eq!(capacity.bytes(), 0x1000 * 512);
+    // Peripheral addressing of LUN 0 and flat space addressing of LUN 0x123.
+    let mut luns = vec![0, 0, 0, 16, 0, 0, 0, 0];
+    luns.extend_from_slice(&[0; 8]);
+    luns.extend_from_slice(&[0x41, 0x23, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(cdb::parse_luns(&luns), [0, 0x123]);
+
+    // NOT READY, MEDIUM NOT PRESENT in the fixed format.
+    let mut sense = [0u8; 18];
+    sense[0] = 0x70;
+    sense[2] = 0x02;
+    sense[7] = 10;
+    sense[12] = 0x3a;
+    let parsed = SenseData::parse(&sense).unwrap();
+    assert_eq!(parsed.key, SenseKey::NotReady);
+    assert!(parsed.is_medium_not_present());
+    // UNIT ATTENTION in the descriptor format.
+    let parsed = SenseData::parse(&[0x72, 0x06, 0x29, 0x00]).unwrap();
+    assert_eq!(
+        (parsed.key, parsed.asc, parsed.ascq),
+        (SenseKey::UnitAttention, 0x29, 0)
+    );
+    assert_eq!(SenseData::parse(&[0x7f]), None);
+}
+
+fn canvas_clipping() {
+    // A 4x3 framebuffer with 8 bytes of padding after each row.
+    const PITCH: usize = 4 * 4 + 8;
+    let mut buf = [0u8; PITCH * 3];
+    let red = Rgba::rgb(0xff, 0, 0);
+    {
+        let mut canvas = Canvas::new(&mut buf, 4, 3, PITCH, Format::R8G8B8A8UNORM)
+            .expect("failed to create canvas");
+        // Rectangles hanging off the bottom right corner only fill what is on screen, however far
+        // they reach.
+        canvas.fill_rect(2, 1, 10, 10, red).expect("failed to fill");
+        canvas
+            .fill_rect(3, 2, u32::MAX, u32::MAX, red)
+            .expect("failed to fill");
+        canvas
+            .fill_rect(u32::MAX, u32::MAX, 1, 1, red)
+            .expect("failed to fill");
+    }
+    for (y, row) in buf.chunks(PITCH).enumerate() {
+        for (x, pixel) in row[..16].chunks(4).enumerate() {
+            let expected = if x >= 2 && y >= 1 {
+                [0xff, 0, 0, 0xff]
+            } else {
+                [0; 4]
+            };
+            assert_eq!(pixel, expected, "pixel ({}, {})", x, y);
+        }
+        assert_eq!(row[16..], [0; 8], "padding of row {}", y);
+    }
+
+    // Sizes which overflow are rejected rather than wrapping around.
+    assert!(matches!(
+        Canvas::new(&mut buf, 4, 3, usize::MAX, Format::R8G8B8A8UNORM),
+        Err(VirtIoError::InvalidParam)
+    ));
+}
+
+fn canvas_blit_past_edge() {
+    let mut buf = [0u8; 16 * 4];
+    // A 3x2 image whose pixels are numbered in their red channel.
+    let mut src = [0u8; 12 * 2];
+    for (i, pixel) in src.chunks_mut(4).enumerate() {
+        pixel.copy_from_slice(&[i as u8, 0x10, 0x20, 0xff]);
+    }
+    {
+        let mut canvas = Canvas::new(&mut buf, 4, 4, 16, Format::B8G8R8A8UNORM)
+            .expect("failed to create canvas");
+        // Only the top left 2x1 pixels of the image are on screen.
+        canvas
+            .blit_rgba(2, 3, &src, 3, 2, 12)
+            .expect("failed to blit");
+        canvas
+            .blit_rgba(u32::MAX, u32::MAX, &src, 3, 2, 12)
+            .expect("failed to blit");
+        assert_eq!(
+            canvas.blit_rgba(0, 0, &src[..20], 3, 2, 12),
+            Err(VirtIoError::InvalidParam)
+        );
+        assert_eq!(
+            canvas.blit_rgba(0, 0, &src, 3, 2, usize::MAX),
+            Err(VirtIoError::InvalidParam)
+        );
+    }
+    for (i, pixel) in buf.chunks(4).enumerate() {
+        let expected = match i {
+            // Blue, green, red and alpha in memory order.
+            14 => [0x20, 0x10, 0, 0xff],
+            15 => [0x20, 0x10, 1, 0xff],
+            _ => [0; 4],
+        };
+        assert_eq!(pixel, expected, "pixel {}", i);
+    }
+}
+
+/// Checks buffers given to the queue come back with their token, and only once the device has
+/// used them.
+fn queue_returns_owned_buffers() {
+    let mut transport = FakeTransport::new(false, 0, true);
+    let mut queue = VirtIoQueue::<MyHalImpl, 4>::new(&mut transport, 0).unwrap();
+    let request: Box<[u8]> = Box::new([1, 2, 3]);
+    let token = queue
+        .add_with_return_buffers(vec![
+            OwnedBuffer::Read(request.clone()),
+            OwnedBuffer::Write(Box::new([0; 8])),
+        ])
+        .unwrap();
+    assert_eq!(
+        queue.pop_used_with_buffers(token),
+        Err(VirtIoError::NotReady)
+    );
+    assert_eq!(
+        queue.pop_used_with_buffers(token + 1),
+        Err(VirtIoError::WrongToken)
+    );
+
+    complete_request(queue.info(), token, 0, 8);
+    let (buffers, len) = queue.pop_used_with_buffers(token).unwrap();
+    assert_eq!(len, 8);
+    assert_eq!(
+        buffers,
+        [
+            OwnedBuffer::Read(request),
+            OwnedBuffer::Write(Box::new([0; 8]))
+        ]
+    );
+    assert_eq!(
+        queue.pop_used_with_buffers(token),
+        Err(VirtIoError::WrongToken)
+    );
+}
+
+fn blk_multiqueue() {
+    let offered = BlkFeature::MQ | BlkFeature::VERSION_1;
+    // num_queues
+    let config = (0x22, &4u16.to_le_bytes());
+
+    // Only drivers asking for more queues negotiate MQ.
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config(config.0, config.1);
+    let blk = VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver");
+    assert_eq!(blk.negotiated_features(), BlkFeature::VERSION_1);
+    assert_eq!(blk.num_queues(), 1);
+
+    // The driver uses no more queues than it asked for, nor than the device has.
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config(config.0, config.1);
+    let blk = VirtIOBlk::<MyHalImpl, _>::new_with_queues(transport, 8)
+        .expect("failed to create blk driver");
+    assert_eq!(blk.num_queues(), 4);
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config(config.0, config.1);
+    let mut blk = VirtIOBlk::<MyHalImpl, _>::new_with_queues(transport, 3)
+        .expect("failed to create blk driver");
+    assert_eq!(blk.negotiated_features(), offered);
+    assert_eq!(blk.num_queues(), 3);
+    let indices: Vec<u16> = blk.queues().iter().map(|queue| queue.index).collect();
+    assert_eq!(indices, [0, 1, 2]);
+    assert_eq!(blk.stats().len(), 3);
+
+    // Hints wrap around the queues, and tokens stay unique across them.
+    let mut first = [0u8; 512];
+    let mut second = [0u8; 512];
+    let on_first = blk
+        .read_blocks_nb_on(0, 0, &mut first)
+        .expect("failed to submit read");
+    let on_last = blk
+        .read_blocks_nb_on(5, 1, &mut second)
+        .expect("failed to submit read");
+    assert_ne!(on_first, on_last);
+    assert_eq!(
+        blk.transport().events[blk.transport().events.len() - 2..],
+        [Event::Notify(0), Event::Notify(2)]
+    );
+    let in_flight: Vec<usize> = blk.stats().iter().map(|stats| stats.in_flight).collect();
+    assert_eq!(in_flight, [1, 0, 1]);
+    // The fake device never completes anything.
+    assert_eq!(
+        blk.complete_read(on_last, &mut second),
+        Err(VirtIoError::NotReady)
+    );
+}
+
+fn blk_queue_interrupts() {
+    let offered = BlkFeature::MQ | BlkFeature::VERSION_1;
+    // num_queues
+    let config = (0x22, &2u16.to_le_bytes());
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config(config.0, config.1);
+    let mut blk = VirtIOBlk::<MyHalImpl, _>::new_with_queues(transport, 2)
+        .expect("failed to create blk driver");
+    // Configuration changes and each queue get their own vector.
+    let vectors: Vec<Event> = blk
+        .transport()
+        .events
+        .iter()
+        .copied()
+        .filter(|event| matches!(event, Event::QueueVector { .. } | Event::ConfigVector(_)))
+        .collect();
+    assert_eq!(
+        vectors,
+        [
+            Event::ConfigVector(CONFIG_VECTOR),
+            Event::QueueVector {
+                queue: 0,
+                vector: queue_vector(0)
+            },
+            Event::QueueVector {
+                queue: 1,
+                vector: queue_vector(1)
+            },
+        ]
+    );
+
+    // Only the queue whose vector fired is looked at.
+    let mut buf = [0u8; 512];
+    let token = blk
+        .read_blocks_nb_on(1, 0, &mut buf)
+        .expect("failed to submit read");
+    // The first request on a queue uses its first descriptor.
+    complete_blk_request(blk.queues()[1], 0, 0);
+    assert_eq!(blk.handle_queue_interrupt(0), Ok(false));
+    assert_eq!(blk.handle_queue_interrupt(1), Ok(true));
+    assert_eq!(blk.handle_queue_interrupt(1), Ok(false));
+    assert_eq!(blk.peek_used(), Some(token));
+    assert_eq!(
+        blk.handle_queue_interrupt(2),
+        Err(VirtIoError::InvalidParam)
+    );
+    blk.complete_read(token, &mut buf)
+        .expect("failed to complete read");
+}
+
+fn blk_reset() {
+    let offered = BlkFeature::MQ | BlkFeature::VERSION_1;
+    // num_queues
+    let config = (0x22, &4u16.to_le_bytes());
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config(config.0, config.1);
+    let mut blk = VirtIOBlk::<MyHalImpl, _>::new_with_queues(transport, 2)
+        .expect("failed to create blk driver");
+    let mut buf = [0u8; 512];
+    let token = blk
+        .read_blocks_nb(0, &mut buf)
+        .expect("failed to submit read");
+
+    // The device is reset and brought up again with as many queues as before, and the request
+    // which never completed is forgotten.
+    let events = blk.transport().events.len();
+    blk.reset().expect("failed to reset blk driver");
+    let transport = blk.transport();
+    assert_eq!(
+        transport.events[events],
+        Event::Status(DeviceStatus::empty())
+    );
+    let queues: Vec<u16> = transport.events[events..]
+        .iter()
+        .filter_map(|event| match event {
+            Event::QueueSet { queue, .. } => Some(*queue),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(queues, [0, 1]);
+    assert!(transport
+        .status_history
+        .last()
+        .unwrap()
+        .contains(DeviceStatus::DRIVER_OK));
+    assert_eq!(blk.negotiated_features(), offered);
+    let in_flight: Vec<usize> = blk.stats().iter().map(|stats| stats.in_flight).collect();
+    assert_eq!(in_flight, [0, 0]);
+    assert_eq!(
+        blk.complete_read(token, &mut buf),
+        Err(VirtIoError::WrongToken)
+    );
+    blk.read_blocks_nb(0, &mut buf)
+        .expect("failed to submit read after reset");
+}
+
+/// A block device which completes every request right away, counting them.
+#[derive(Default)]
+struct CountingDisk {
+    requests: usize,
+}
+
+impl BlockDevice for CountingDisk {
+    fn capacity(&self) -> VirtIoResult<u64> {
+        Ok(64)
+    }
+    fn readonly(&self) -> bool {
+        false
+    }
+    fn read_blocks(&mut self, _sector: usize, _buf: &mut [u8]) -> VirtIoResult<()> {
+        self.requests += 1;
+        Ok(())
+    }
+    fn write_blocks(&mut self, _sector: usize, _buf: &[u8]) -> VirtIoResult<()> {
+        self.requests += 1;
+        Ok(())
+    }
+    fn flush(&mut self) -> VirtIoResult<()> {
+        Ok(())
+    }
+}
+
+/// The time of the clock given to [`ThrottledBlk`], which only moves when the test says so.
+static FAKE_NOW: AtomicU64 = AtomicU64::new(0);
+
+fn fake_now() -> u64 {
+    FAKE_NOW.load(Ordering::Relaxed)
+}
+
+fn blk_throttle() {
+    let limits = ThrottleLimits {
+        period: 100,
+        ops_per_period: Some(2),
+        bytes_per_period: Some(1024),
+    };
+    assert!(ThrottledBlk::new(
+        CountingDisk::default(),
+        ThrottleLimits {
+            period: 0,
+            ..limits
+        },
+        fake_now
+    )
+    .is_err());
+    let mut blk = ThrottledBlk::new(CountingDisk::default(), limits, fake_now)
+        .expect("failed to create throttle");
+    let mut buf = [0u8; 512];
+
+    // A burst of one period's worth goes through at once, then the next request has to wait.
+    blk.read_blocks(0, &mut buf).expect("failed to read");
+    blk.write_blocks(1, &buf).expect("failed to write");
+    assert_eq!(blk.delay(512), 50);
+    assert_eq!(
+        blk.read_blocks_before(2, &mut buf, fake_now() + 49),
+        Err(VirtIoError::NotReady)
+    );
+    assert_eq!(blk.inner().requests, 2);
+    FAKE_NOW.fetch_add(50, Ordering::Relaxed);
+    blk.read_blocks_before(2, &mut buf, fake_now())
+        .expect("failed to read");
+    assert_eq!(blk.inner().requests, 3);
+
+    // A request larger than the bucket waits for it to fill, and overdraws it.
+    assert_eq!(blk.delay(4096), 100);
+    FAKE_NOW.fetch_add(100, Ordering::Relaxed);
+    blk.write_blocks(0, &[0; 4096]).expect("failed to write");
+    assert_eq!(blk.delay(512), 350);
+}
+
+fn config_space_size() {
+    let offered = BlkFeature::MQ | BlkFeature::VERSION_1;
+    // num_queues
+    let config = (0x22, &4u16.to_le_bytes());
+
+    // Fields beyond the config space read as absent, rather than as whatever is there.
+    let transport = FakeTransport::new(false, offered.bits(), true)
+        .with_config(config.0, config.1)
+        .with_config_space_size(0x22);
+    let blk = VirtIOBlk::<MyHalImpl, _>::new_with_queues(transport, 8)
+        .expect("failed to create blk driver");
+    assert_eq!(blk.negotiated_features(), offered);
+    assert_eq!(blk.num_queues(), 1);
+    let transport = FakeTransport::new(false, offered.bits(), true)
+        .with_config(config.0, config.1)
+        .with_config_space_size(0x24);
+    let blk = VirtIOBlk::<MyHalImpl, _>::new_with_queues(transport, 8)
+        .expect("failed to create blk driver");
+    assert_eq!(blk.num_queues(), 4);
+
+    // Without room for the capacity, the device can't be used at all.
+    let transport = FakeTransport::new(false, offered.bits(), true).with_config_space_size(4);
+    let result = VirtIOBlk::<MyHalImpl, _>::new(transport).map(|_| ());
+    assert_eq!(result, Err(VirtIoError::ConfigSpaceTooSmall));
+}
+
+fn poll_interrupt_leaves_interrupt_pending() {
+    let pending = InterruptStatus::USED_RING_UPDATE | InterruptStatus::CONFIGURATION_CHANGE;
+    let mut transport = FakeTransport::new(false, BlkFeature::VERSION_1.bits(), true);
+    transport.interrupt_status = pending;
+    let mut blk = VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver");
+    // Polling doesn't acknowledge anything, so the interrupt is still there once it is routed.
+    assert_eq!(blk.poll_interrupt(), Ok(true));
+    assert_eq!(blk.poll_interrupt(), Ok(true));
+    assert_eq!(blk.transport().peek_interrupt_status(), Ok(pending));
+    assert_eq!(blk.ack_interrupt(), Ok(pending));
+    assert_eq!(blk.poll_interrupt(), Ok(false));
+}
+
+fn config_change() {
+    // capacity
+    let transport = FakeTransport::new(false, BlkFeature::VERSION_1.bits(), true)
+        .with_config(0, &8u64.to_le_bytes());
+    let mut blk = VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver");
+    blk.transport().change_config(0, &16u64.to_le_bytes());
+    assert_eq!(blk.capacity(), Ok(8));
+    assert_eq!(blk.handle_config_change(), Ok(Some(16)));
+    assert_eq!(blk.capacity(), Ok(16));
+    assert_eq!(blk.handle_config_change(), Ok(None));
+    // The capacity is read again until the config space holds still while it is read.
+    blk.transport().generation_churn.set(3);
+    assert_eq!(blk.recheck_capacity(), Ok(16));
+    assert_eq!(blk.transport().generation_churn.get(), 0);
+
+    // Only a device with the status field can say the link is down.
+    let offered = NetFeatures::STATUS | NetFeatures::VERSION_1;
+    let transport = FakeTransport::new(false, offered.bits(), true);
+    let mut net = VirtIONetRaw::<MyHalImpl, _, 16>::new(transport).expect("failed to create net");
+    assert_eq!(net.handle_config_change(), Ok(NetStatus::empty()));
+    net.transport().change_config(6, &1u16.to_le_bytes());
+    assert_eq!(net.handle_config_change(), Ok(NetStatus::LINK_UP));
+    let transport = FakeTransport::new(false, NetFeatures::VERSION_1.bits(), true);
+    let mut net = VirtIONetRaw::<MyHalImpl, _, 16>::new(transport).expect("failed to create net");
+    assert_eq!(net.handle_config_change(), Ok(NetStatus::LINK_UP));
+}
+
+/// The milestones [`record_milestone`] was told about.
+static MILESTONES: Mutex<Vec<InitMilestone>> = Mutex::new(Vec::new());
+
+fn record_milestone(milestone: InitMilestone) {
+    MILESTONES.lock().push(milestone);
+}
+
+fn init_milestones() {
+    let offered = BlkFeature::FLUSH | BlkFeature::VERSION_1;
+    let transport = FakeTransport::new(false, offered.bits(), true).observed(record_milestone);
+    let blk = VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver");
+    let info = blk.queues()[0];
+    {
+        let milestones = MILESTONES.lock();
+        assert_eq!(milestones.len(), 3);
+        assert_eq!(
+            milestones[0],
+            InitMilestone::FeaturesNegotiated {
+                offered: offered.bits(),
+                negotiated: blk.negotiated_features().bits(),
+            }
+        );
+        assert_eq!(
+            milestones[1],
+            InitMilestone::QueueProgrammed {
+                queue: 0,
+                size: info.size.into(),
+                descriptors: info.descriptors,
+                driver_area: info.driver_area,
+                device_area: info.device_area,
+            }
+        );
+        assert_eq!(milestones[2], InitMilestone::DriverOk);
+    }
+    drop(blk);
+
+    // A device which never accepts any features reports where it stopped.
+    MILESTONES.lock().clear();
+    let transport = FakeTransport::new(false, offered.bits(), false).observed(record_milestone);
+    assert!(VirtIOBlk::<MyHalImpl, _>::new(transport).is_err());
+    assert_eq!(
+        MILESTONES.lock().as_slice(),
+        [InitMilestone::Failed(InitStep::Negotiation)]
+    );
+    MILESTONES.lock().clear();
+}
+
+fn driver_identity_through_trait_object() {
+    let transport = FakeTransport::new(false, BlkFeature::FLUSH.bits(), true);
+    let mut driver: Box<dyn VirtIoDriver> =
+        Box::new(VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver"));
+    assert_eq!(
+        driver.identity(),
+        DeviceIdentity {
+            device_type: DeviceType::Block,
+            bus_addr: 0,
+            features: BlkFeature::FLUSH.bits(),
+        }
+    );
+    let stats = driver.stats();
+    assert_eq!(stats.len(), 1);
+    assert_eq!((stats[0].index, stats[0].in_flight), (0, 0));
+    assert!(driver
+        .ack_interrupt()
+        .expect("failed to ack interrupt")
+        .is_empty());
+}
+
+fn watchdog_resets_stuck_device() {
+    // The fake device never completes anything.
+    let transport = FakeTransport::new(false, BlkFeature::VERSION_1.bits(), true);
+    let mut blk = VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver");
+    let mut watchdog = Watchdog::new();
+    watchdog.watch(&blk, 10, StuckPolicy::Reset);
+    // An idle queue is never stuck, however long it stays idle.
+    assert!(watchdog
+        .tick(0, [&mut blk as &mut dyn VirtIoDriver])
+        .is_empty());
+    assert!(watchdog
+        .tick(100, [&mut blk as &mut dyn VirtIoDriver])
+        .is_empty());
+
+    let mut buf = [0u8; 512];
+    blk.read_blocks_nb(0, &mut buf)
+        .expect("failed to submit read");
+    assert!(watchdog
+        .tick(105, [&mut blk as &mut dyn VirtIoDriver])
+        .is_empty());
+    let stuck = watchdog.tick(110, [&mut blk as &mut dyn VirtIoDriver]);
+    assert_eq!(stuck.len(), 1);
+    assert_eq!(
+        (stuck[0].queue, stuck[0].in_flight, stuck[0].stalled_for),
+        (0, 1, 10)
+    );
+    assert_eq!(stuck[0].reset, Some(Ok(())));
+    assert_eq!(
+        blk.transport().status_history.last(),
+        Some(&DeviceStatus::empty())
+    );
+    // The reset driver is left alone until it is watched again.
+    assert!(watchdog
+        .tick(200, [&mut blk as &mut dyn VirtIoDriver])
+        .is_empty());
+}
+
+fn device_needs_reset() {
+    // The fake device never completes anything, as if its backend went away.
+    let transport = FakeTransport::new(false, BlkFeature::VERSION_1.bits(), true);
+    let mut blk = VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver");
+    assert_eq!(blk.check_needs_reset(), Ok(()));
+    blk.transport().request_reset();
+    assert_eq!(blk.check_needs_reset(), Err(VirtIoError::NeedsReset));
+
+    // A blocking request gives up instead of waiting forever.
+    let mut buf = [0u8; 512];
+    assert_eq!(blk.read_blocks(0, &mut buf), Err(VirtIoError::NeedsReset));
+    let mut watchdog = Watchdog::new();
+    watchdog.watch(&blk, 10, StuckPolicy::Report);
+    watchdog.tick(0, [&mut blk as &mut dyn VirtIoDriver]);
+    let stuck = watchdog.tick(10, [&mut blk as &mut dyn VirtIoDriver]);
+    assert_eq!(stuck.len(), 1);
+    assert!(stuck[0].needs_reset);
+    assert_eq!(stuck[0].reset, None);
+
+    // Resetting the driver brings the device back.
+    blk.reset().expect("failed to reset blk driver");
+    assert_eq!(blk.check_needs_reset(), Ok(()));
+    assert_eq!(blk.stats()[0].in_flight, 0);
+
+    // The console's blocking receive gives up too, as does a send waiting for a free transmit
+    // buffer, which the fake device never hands back.
+    let transport = FakeTransport::new(false, 0, true);
+    let mut console =
+        VirtIOConsole::<MyHalImpl, _>::new(transport).expect("failed to create console driver");
+    console.transport().request_reset();
+    assert_eq!(console.recv_block(), Err(VirtIoError::NeedsReset));
+    assert_eq!(
+        (0..16).try_for_each(|_| console.send(b'x')),
+        Err(VirtIoError::NeedsReset)
+    );
+}
+
+fn async_dropped_before_completion() {
+    let mut cx = Context::from_waker(Waker::noop());
+
+    // Dropping a read the device has since completed takes the request back, so the buffer is no
+    // longer the device's once the borrow ends.
+    let transport = FakeTransport::new(false, BlkFeature::VERSION_1.bits(), true);
+    let mut blk = VirtIOBlk::<MyHalImpl, _>::new(transport).expect("failed to create blk driver");
+    let info = blk.queues()[0];
+    let mut buf = [0u8; 512];
+    {
+        let mut read = pin!(blk.read_blocks_async(0, &mut buf));
+        assert!(read.as_mut().poll(&mut cx).is_pending());
+        complete_blk_request(info, 0, 0);
+    }
+    assert_eq!(blk.stats()[0].in_flight, 0);
+    blk.read_blocks_nb(0, &mut buf)
+        .expect("failed to submit read after dropping the future");
+
+    // A packet may never arrive, so dropping a receive resets the device instead of waiting.
+    let transport = FakeTransport::new(false, NetFeatures::VERSION_1.bits(), true);
+    let mut net = VirtIONetRaw::<MyHalImpl, _, 16>::new(transport).expect("failed to create net");
+    let events = net.transport().events.len();
+    let mut rx_buf = vec![0u8; net.min_rx_buffer_len()];
+    {
+        let mut receive = pin!(net.receive_async(&mut rx_buf));
+        assert!(receive.as_mut().poll(&mut cx).is_pending());
+    }
+    assert_eq!(
+        net.transport().events[events..]
+            .iter()
+            .filter(|event| **event == Event::Status(DeviceStatus::empty()))
+            .count(),
+        1
+    );
+    assert!(net
+        .transport()
+        .status_history
+        .last()
+        .unwrap()
+        .contains(DeviceStatus::DRIVER_OK));
+    assert!(net.stats().iter().all(|stats| stats.in_flight == 0));
+
+    // A transmission the device has completed is popped like the read.
+    let tx_info = net.queues()[1];
+    let mut tx_buf = vec![0u8; NET_HDR_SIZE + 60];
+    net.fill_buffer_header(&mut tx_buf)
+        .expect("failed to fill header");
+    {
+        let mut transmit = pin!(net.transmit_async(&tx_buf));
+        assert!(transmit.as_mut().poll(&mut cx).is_pending());
+        complete_request(tx_info, 0, 0, 0);
+    }
+    assert!(net.stats().iter().all(|stats| stats.in_flight == 0));
+}
@@ -2,7 +2,7 @@ use crate::arch;
 use crate::mutex::Mutex;
 use crate::old_impl::HalImpl as MyHalImpl;
 use crate::old_impl::HalImpl;
-use crate::trap::ext_interrupt::{register_device_to_plic, DeviceBase};
+use crate::trap::ext_interrupt::{register_device_irq, DeviceBase};
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
@@ -90,14 +90,14 @@ fn virtio_device(transport: MmioTransport, irq: usize) {
             let mut blk = VirtIOBlk::<HalImpl, MmioTransport>::new(transport)
                 .expect("failed to create blk driver");
             let blk = Arc::new(Mutex::new(blk));
-            // register_device_to_plic(irq,blk.clone());
+            // register_device_irq(irq,blk.clone());
             BLK.call_once(|| blk);
         }
         DeviceType::Input => {
             let mut input = VirtIOInput::<HalImpl, MmioTransport>::new(transport)
                 .expect("input driver create failed");
             let input = Arc::new(Mutex::new(input));
-            // register_device_to_plic(irq,input.clone());
+            // register_device_irq(irq,input.clone());
             let mut inputs = INPUTS.lock();
             inputs.push(input.clone());
         }
@@ -105,14 +105,14 @@ fn virtio_device(transport: MmioTransport, irq: usize) {
             let mut console = VirtIOConsole::<HalImpl, MmioTransport>::new(transport)
                 .expect("failed to create console driver");
             let console = Arc::new(Mutex::new(console));
-            // register_device_to_plic(irq,console.clone());
+            // register_device_irq(irq,console.clone());
             CONSOLE.call_once(|| console);
         }
         DeviceType::GPU => {
             let mut gpu = VirtIOGpu::<HalImpl, MmioTransport>::new(transport)
                 .expect("failed to create gpu driver");
             let gpu = Arc::new(Mutex::new(gpu));
-            // register_device_to_plic(irq,gpu.clone());
+            // register_device_irq(irq,gpu.clone());
             GPU.call_once(|| gpu);
         }
         DeviceType::Network => {
@@ -124,7 +124,7 @@ fn virtio_device(transport: MmioTransport, irq: usize) {
                     )
                     .expect("failed to create net driver");
                 let net = Arc::new(Mutex::new(net));
-                register_device_to_plic(irq, net.clone());
+                register_device_irq(irq, net.clone());
                 NET_RAW.call_once(|| net);
             }
             #[cfg(feature = "tcp")]
@@ -135,7 +135,7 @@ fn virtio_device(transport: MmioTransport, irq: usize) {
                 )
                 .expect("failed to create net driver");
                 let net = Arc::new(Mutex::new(net));
-                register_device_to_plic(irq, net.clone());
+                register_device_irq(irq, net.clone());
                 NET.call_once(|| net);
             }
         }
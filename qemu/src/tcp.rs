@@ -54,7 +54,7 @@ impl<T: Transport> Device for DeviceWrapper<T> {
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
         let mut v = vec![0u8; NET_BUFFER_LEN];
-        match self.inner.borrow_mut().receive(&mut v) {
+        match self.inner.borrow_mut().receive_into(&mut v) {
             Ok(len) => {
                 v.resize(len, 0);
                 Some((
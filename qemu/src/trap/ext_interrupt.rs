@@ -3,6 +3,7 @@ use crate::mutex::Mutex;
 use crate::println;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use fdt::node::FdtNode;
 use plic::{Mode, PLIC};
 use spin::Once;
 
@@ -17,8 +18,13 @@ pub fn init_plic(plic_addr: usize) {
     println!("Init qemu plic success");
 }
 
+/// Returns the interrupt of a device tree node, whose interrupt parent is the PLIC.
+pub fn device_irq(node: &FdtNode) -> Option<usize> {
+    node.interrupts()?.next()
+}
+
 /// Register a device to PLIC.
-pub fn register_device_to_plic(irq: usize, device: Arc<Mutex<dyn DeviceBase>>) {
+pub fn register_device_irq(irq: usize, device: Arc<Mutex<dyn DeviceBase>>) {
     let hard_id = hart_id();
     let mut table = DEVICE_TABLE.lock();
     table.insert(irq, device);
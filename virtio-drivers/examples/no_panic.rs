@@ -0,0 +1,173 @@
+//! A bare-metal binary which drives every device type with the `no-panic` feature enabled.
+//!
+//! It is never run, only built and checked for references to the panicking helpers behind
+//! `assert!`, `unwrap()` and `expect()`, by `make check-no-panic` in the `qemu` directory. Built
+//! for a hosted target, e.g. by `cargo test --all-features`, it is an empty program instead.
+
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+
+#[cfg(target_os = "none")]
+extern crate alloc;
+
+#[cfg(not(target_os = "none"))]
+fn main() {}
+
+#[cfg(target_os = "none")]
+mod bare_metal {
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::cell::UnsafeCell;
+    use core::ptr::null_mut;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use virtio_drivers::device::block::{VirtIOBlk, SECTOR_SIZE};
+    use virtio_drivers::device::console::VirtIOConsole;
+    use virtio_drivers::device::gpu::VirtIOGpu;
+    use virtio_drivers::device::input::VirtIOInput;
+    use virtio_drivers::device::net::VirtIONet;
+    use virtio_drivers::device::VirtIoDriver;
+    use virtio_drivers::error::VirtIoResult;
+    use virtio_drivers::hal::VirtIoDeviceIo;
+    use virtio_drivers::heap::HeapHal;
+    use virtio_drivers::transport::mmio::MmioTransport;
+    use virtio_drivers::transport::{DeviceType, Transport};
+    use virtio_drivers::{PhysAddr, VirtAddr};
+
+    /// The MMIO regions of the virtio devices on the QEMU `virt` machine.
+    const VIRTIO_MMIO_BASE: usize = 0x1000_1000;
+    const VIRTIO_MMIO_SIZE: usize = 0x1000;
+    const VIRTIO_MMIO_COUNT: usize = 8;
+
+    const HEAP_SIZE: usize = 0x10_0000;
+    const NET_QUEUE_SIZE: usize = 16;
+    const NET_BUFFER_LEN: usize = 2048;
+
+    #[no_mangle]
+    extern "C" fn _start() -> ! {
+        for i in 0..VIRTIO_MMIO_COUNT {
+            let io = Box::new(Mmio(VIRTIO_MMIO_BASE + i * VIRTIO_MMIO_SIZE));
+            if let Ok(transport) = MmioTransport::new(io) {
+                let _ = exercise(transport);
+            }
+        }
+        #[allow(clippy::empty_loop)]
+        loop {}
+    }
+
+    /// Makes a few requests to the device, so the driver code they reach is linked in.
+    fn exercise(transport: MmioTransport) -> VirtIoResult<()> {
+        match transport.device_type()? {
+            DeviceType::Block => {
+                let mut blk = VirtIOBlk::<HeapHal, _>::new(transport)?;
+                let mut buf = [0; SECTOR_SIZE];
+                blk.read_blocks(0, &mut buf)?;
+                blk.write_blocks(0, &buf)?;
+                blk.flush()?;
+                blk.shutdown()
+            }
+            DeviceType::Console => {
+                let mut console = VirtIOConsole::<HeapHal, _>::new(transport)?;
+                console.send_slice(b"hello\n")?;
+                console.recv(true)?;
+                console.shutdown()
+            }
+            DeviceType::GPU => {
+                let mut gpu = VirtIOGpu::<HeapHal, _>::new(transport)?;
+                let fb = gpu.setup_framebuffer()?;
+                gpu.flush(&fb)?;
+                gpu.shutdown()
+            }
+            DeviceType::Input => {
+                let mut input = VirtIOInput::<HeapHal, _>::new(transport)?;
+                input.pop_pending_event()?;
+                input.shutdown()
+            }
+            DeviceType::Network => {
+                let mut net =
+                    VirtIONet::<HeapHal, _, NET_QUEUE_SIZE>::new(transport, NET_BUFFER_LEN)?;
+                let buf = vec![0; NET_BUFFER_LEN];
+                net.send(&buf)?;
+                net.receive()?.recycle()?;
+                net.shutdown()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Device registers accessed by address.
+    #[derive(Debug)]
+    struct Mmio(usize);
+
+    impl VirtIoDeviceIo for Mmio {
+        fn read_volatile_u32_at(&self, off: usize) -> VirtIoResult<u32> {
+            // Safe because the address is in the device's MMIO region.
+            Ok(unsafe { ((self.0 + off) as *const u32).read_volatile() })
+        }
+        fn read_volatile_u8_at(&self, off: usize) -> VirtIoResult<u8> {
+            Ok(unsafe { ((self.0 + off) as *const u8).read_volatile() })
+        }
+        fn write_volatile_u32_at(&self, off: usize, data: u32) -> VirtIoResult<()> {
+            unsafe { ((self.0 + off) as *mut u32).write_volatile(data) };
+            Ok(())
+        }
+        fn write_volatile_u8_at(&self, off: usize, data: u8) -> VirtIoResult<()> {
+            unsafe { ((self.0 + off) as *mut u8).write_volatile(data) };
+            Ok(())
+        }
+        fn paddr(&self) -> PhysAddr {
+            self.0
+        }
+        fn vaddr(&self) -> VirtAddr {
+            self.0
+        }
+    }
+
+    /// An allocator which never frees, enough to give the drivers their DMA memory.
+    struct BumpAllocator {
+        heap: UnsafeCell<[u8; HEAP_SIZE]>,
+        next: AtomicUsize,
+    }
+
+    // Safe because the offset of the next free byte is updated atomically.
+    unsafe impl Sync for BumpAllocator {}
+
+    unsafe impl GlobalAlloc for BumpAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let base = self.heap.get() as usize;
+            let mut next = self.next.load(Ordering::Relaxed);
+            loop {
+                let start = (base + next).next_multiple_of(layout.align()) - base;
+                let end = start + layout.size();
+                if end > HEAP_SIZE {
+                    return null_mut();
+                }
+                match self.next.compare_exchange_weak(
+                    next,
+                    end,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return (base + start) as *mut u8,
+                    Err(current) => next = current,
+                }
+            }
+        }
+
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: BumpAllocator = BumpAllocator {
+        heap: UnsafeCell::new([0; HEAP_SIZE]),
+        next: AtomicUsize::new(0),
+    };
+
+    // With `std` enabled too, e.g. by `--all-features`, its panic handler is used instead.
+    #[cfg(not(feature = "std"))]
+    #[panic_handler]
+    fn panic(_info: &core::panic::PanicInfo) -> ! {
+        #[allow(clippy::empty_loop)]
+        loop {}
+    }
+}
@@ -0,0 +1,307 @@
+//! Driver for the VirtIO traditional memory balloon device.
+
+mod ty;
+
+use crate::device::{DeviceIdentity, VirtIoDriver};
+use crate::error::{expect_ok, InitStep, VirtIoError, VirtIoResult};
+use crate::hal::{DmaBuf, Hal, MemoryRequirements};
+use crate::queue::{EventSuppression, QueueInfo, QueueStats, VirtIoQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
+use crate::volatile::{ReadVolatile, WriteVolatile};
+use crate::PhysAddr;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::mem::size_of;
+use ty::*;
+
+pub use ty::BalloonFeatures;
+
+const QUEUE_INFLATE: u16 = 0;
+const QUEUE_DEFLATE: u16 = 1;
+const QUEUE_SIZE: usize = 2;
+/// The most page frame numbers sent in one request, as Linux does.
+const PFNS_PER_REQUEST: usize = 256;
+/// The features [`VirtIOBalloon::new`] negotiates if the device offers them.
+///
+/// The host is told before pages leave the balloon, so the driver works whether or not the device
+/// insists on it. Deflating on OOM only needs the guest to be allowed to call
+/// [`VirtIOBalloon::deflate`] on its own.
+pub const SUPPORTED_FEATURES: BalloonFeatures =
+    BalloonFeatures::MUST_TELL_HOST.union(BalloonFeatures::DEFLATE_ON_OOM);
+
+/// A memory balloon, through which the host asks the guest to give up memory and later returns
+/// it.
+///
+/// The host sets a target size in the config space and raises a configuration change interrupt.
+/// The kernel then calls [`Self::balance`], which takes pages from the OS with
+/// [`Hal::balloon_alloc_page`] and hands them to the host, or gives them back with
+/// [`Hal::balloon_free_page`], until the balloon has the size the host asked for.
+///
+/// Dropping the driver doesn't give the pages in the balloon back to the OS, as the host may not
+/// back them any more. Deflate it fully first to get them back.
+pub struct VirtIOBalloon<H: Hal<QUEUE_SIZE>, T: Transport> {
+    transport: T,
+    negotiated_features: BalloonFeatures,
+    config: BalloonConfig,
+    inflate_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    deflate_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    /// Copied from the queues when they were created, for [`Self::queues`].
+    queue_info: Vec<QueueInfo>,
+    /// The physical addresses of the pages in the balloon.
+    pages: Vec<PhysAddr>,
+    /// The page frame numbers of a request, in little-endian.
+    pfns: Box<[u32; PFNS_PER_REQUEST]>,
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOBalloon<H, T> {
+    /// The memory [`Self::new`] allocates: the inflate and deflate queues and a buffer for the
+    /// page frame numbers of a request.
+    ///
+    /// The pages in the balloon come from [`Hal::balloon_alloc_page`] and aren't counted.
+    pub const fn memory_requirements() -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(2)
+            .with_shared_heap(PFNS_PER_REQUEST * size_of::<u32>())
+    }
+
+    /// Create a new VirtIO balloon driver, with an empty balloon.
+    pub fn new(transport: T) -> VirtIoResult<Self> {
+        Self::new_with_features(transport, SUPPORTED_FEATURES)
+    }
+
+    /// Creates a new VirtIO balloon driver which only negotiates the features in `wanted`, e.g.
+    /// without [`BalloonFeatures::DEFLATE_ON_OOM`] if the guest never deflates on its own.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `wanted` isn't a subset of
+    /// [`SUPPORTED_FEATURES`].
+    pub fn new_with_features(mut transport: T, wanted: BalloonFeatures) -> VirtIoResult<Self> {
+        if !SUPPORTED_FEATURES.contains(wanted) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let negotiated_features =
+            transport.init_step(InitStep::Negotiation, |t| t.begin_init(wanted))?;
+        let config = BalloonConfig::default();
+        let (inflate_queue, deflate_queue) = transport.init_step(InitStep::QueueSetup, |t| {
+            Ok((
+                VirtIoQueue::new(t, QUEUE_INFLATE)?,
+                VirtIoQueue::new(t, QUEUE_DEFLATE)?,
+            ))
+        })?;
+        transport.init_step(InitStep::DriverOk, T::finish_init)?;
+
+        let queue_info = vec![inflate_queue.info(), deflate_queue.info()];
+        Ok(Self {
+            transport,
+            negotiated_features,
+            config,
+            inflate_queue,
+            deflate_queue,
+            queue_info,
+            pages: Vec::new(),
+            pfns: Box::new([0; PFNS_PER_REQUEST]),
+        })
+    }
+
+    /// Acknowledges an interrupt, returning its causes, which are empty if there was none.
+    ///
+    /// The device interrupts with [`InterruptStatus::CONFIGURATION_CHANGE`] when the host changes
+    /// the target size, after which [`Self::balance`] should be called.
+    pub fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        self.transport.ack_interrupt()
+    }
+
+    /// Like [`Self::ack_interrupt`], but only checks whether the host changed the target size
+    /// without acknowledging the interrupt.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        Ok(self
+            .transport
+            .peek_interrupt_status()?
+            .contains(InterruptStatus::CONFIGURATION_CHANGE))
+    }
+
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> BalloonFeatures {
+        self.negotiated_features
+    }
+
+    /// Returns the transport, e.g. to read the device status from a power management hook.
+    ///
+    /// Only shared access is given, so the device can't be reconfigured behind the driver's back.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        &self.queue_info
+    }
+
+    /// Returns the number of 4 KiB pages the host wants in the balloon.
+    pub fn target_pages(&self) -> VirtIoResult<u32> {
+        self.config.num_pages.read(self.transport.io_region())
+    }
+
+    /// Returns the number of 4 KiB pages in the balloon.
+    pub fn actual_pages(&self) -> u32 {
+        self.pages.len() as u32
+    }
+
+    /// Inflates or deflates the balloon towards the size the host asked for, and returns the
+    /// number of pages it now holds.
+    ///
+    /// Inflating stops early if [`Hal::balloon_alloc_page`] runs out of pages; the next call
+    /// tries again.
+    pub fn balance(&mut self) -> VirtIoResult<u32> {
+        let target = self.target_pages()?;
+        let actual = self.actual_pages();
+        match target.cmp(&actual) {
+            Ordering::Greater => {
+                self.inflate((target - actual) as usize)?;
+            }
+            Ordering::Less => {
+                self.deflate((actual - target) as usize)?;
+            }
+            Ordering::Equal => {}
+        }
+        Ok(self.actual_pages())
+    }
+
+    /// Takes up to `count` pages from the OS and gives them to the host, and returns how many it
+    /// took.
+    pub fn inflate(&mut self, count: usize) -> VirtIoResult<usize> {
+        let mut inflated = 0;
+        while inflated < count {
+            let batch = (count - inflated).min(PFNS_PER_REQUEST);
+            let mut len = 0;
+            while len < batch {
+                let Some(paddr) = H::balloon_alloc_page() else {
+                    break;
+                };
+                let Ok(pfn) = u32::try_from(paddr / BALLOON_PAGE_SIZE) else {
+                    // The device only takes 32-bit page frame numbers.
+                    warn!("Balloon page {:#x} is out of reach of the device", paddr);
+                    H::balloon_free_page(paddr);
+                    break;
+                };
+                self.pfns[len] = pfn.to_le();
+                self.pages.push(paddr);
+                len += 1;
+            }
+            if len == 0 {
+                break;
+            }
+            self.send_pfns(QUEUE_INFLATE, len, self.pages.len())?;
+            inflated += len;
+            if len < batch {
+                break;
+            }
+        }
+        if inflated < count {
+            info!("Balloon inflated by {} of {} pages", inflated, count);
+        }
+        Ok(inflated)
+    }
+
+    /// Tells the host that up to `count` pages leave the balloon, gives them back to the OS, and
+    /// returns how many there were.
+    ///
+    /// Unless [`BalloonFeatures::DEFLATE_ON_OOM`] was negotiated, this should only be called
+    /// when the host lowered the target size, e.g. through [`Self::balance`].
+    pub fn deflate(&mut self, count: usize) -> VirtIoResult<usize> {
+        let mut deflated = 0;
+        while deflated < count && !self.pages.is_empty() {
+            let len = (count - deflated)
+                .min(PFNS_PER_REQUEST)
+                .min(self.pages.len());
+            let start = self.pages.len() - len;
+            for (pfn, paddr) in self.pfns.iter_mut().zip(self.pages.iter().skip(start)) {
+                *pfn = ((paddr / BALLOON_PAGE_SIZE) as u32).to_le();
+            }
+            self.send_pfns(QUEUE_DEFLATE, len, start)?;
+            // Only free the pages once the host knows, as it may need to be told first.
+            for paddr in self.pages.drain(start..) {
+                H::balloon_free_page(paddr);
+            }
+            deflated += len;
+        }
+        Ok(deflated)
+    }
+
+    /// Sends the first `len` page frame numbers of `self.pfns` on the given queue, waits for the
+    /// device to take them, and then reports `actual` pages in the balloon in the config space.
+    fn send_pfns(&mut self, queue: u16, len: usize, actual: usize) -> VirtIoResult<()> {
+        let queue = if queue == QUEUE_INFLATE {
+            &mut self.inflate_queue
+        } else {
+            &mut self.deflate_queue
+        };
+        let pfns = self.pfns.get(..len).ok_or(VirtIoError::InvalidParam)?;
+        queue.add_notify_wait_pop(&mut self.transport, vec![DmaBuf::readable(pfns)])?;
+        self.config
+            .actual
+            .write(actual as u32, self.transport.io_region())
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIoDriver for VirtIOBalloon<H, T> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::MemoryBallooning
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        VirtIOBalloon::ack_interrupt(self)
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        VirtIOBalloon::poll_interrupt(self)
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            device_type: DeviceType::MemoryBallooning,
+            bus_addr: self.transport.io_region().paddr(),
+            features: self.negotiated_features.bits(),
+        }
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        vec![self.inflate_queue.stats(), self.deflate_queue.stats()]
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        match queue {
+            QUEUE_INFLATE => self.inflate_queue.set_event_suppression(suppression),
+            QUEUE_DEFLATE => self.deflate_queue.set_event_suppression(suppression),
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.transport.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.transport.reset()
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> Drop for VirtIOBalloon<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        expect_ok(
+            self.transport.queue_unset(QUEUE_INFLATE),
+            "failed to unset inflate queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_DEFLATE),
+            "failed to unset deflate queue",
+        );
+    }
+}
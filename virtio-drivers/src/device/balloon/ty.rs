@@ -0,0 +1,50 @@
+use crate::transport::DeviceFeatures;
+use crate::volatile::virtio_config;
+use bitflags::bitflags;
+
+virtio_config! {
+    pub(crate) struct BalloonConfig {
+        /// The number of pages the host wants in the balloon.
+        pub(crate) num_pages: ReadOnly<u32> @ 0x0,
+        /// The number of pages the driver has put in the balloon.
+        pub(crate) actual: ReadWrite<u32> @ 0x4,
+    }
+}
+
+/// The size of the pages the balloon counts in, whatever the guest's page size.
+pub(crate) const BALLOON_PAGE_SIZE: usize = 4096;
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct BalloonFeatures: u64 {
+        /// The host must be told before pages are taken out of the balloon.
+        const MUST_TELL_HOST        = 1 << 0;
+        /// A statistics queue is present.
+        const STATS_VQ              = 1 << 1;
+        /// The guest may deflate the balloon when it runs out of memory.
+        const DEFLATE_ON_OOM        = 1 << 2;
+        const FREE_PAGE_HINT        = 1 << 3;
+        const PAGE_POISON           = 1 << 4;
+        const PAGE_REPORTING        = 1 << 5;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+format_flags!(BalloonFeatures);
+
+impl DeviceFeatures for BalloonFeatures {}
@@ -1,173 +1,1186 @@
-use crate::error::VirtIoResult;
-use crate::hal::Hal;
-use crate::queue::{DescFlag, Descriptor, VirtIoQueue};
+use crate::error::{expect_ok, InitStep, VirtIoError, VirtIoResult};
+use crate::hal::{DevicePage, DmaBuf, Hal, MemoryRequirements};
+use crate::pages;
+use crate::queue::{Buffer, EventSuppression, QueueInfo, QueueStats, VirtIoQueue};
 
 use crate::volatile::ReadVolatile;
 
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::vec;
+use alloc::vec::Vec;
 
-use crate::transport::Transport;
-use core::mem::size_of_val;
+use crate::device::common::{or_needs_reset, request_response};
+use crate::device::{DeviceIdentity, VirtIoDriver};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
+use core::fmt;
+use core::mem::size_of;
 
-use log::info;
 use ty::*;
 
+mod throttle;
 mod ty;
 
-const SUPPORTED_FEATURES: BlkFeature = BlkFeature::FLUSH;
-const QUEUE_SIZE: usize = 16;
+pub use throttle::{ThrottleLimits, ThrottledBlk};
+pub use ty::{BlkFeature, DeviceId, DEVICE_ID_LEN};
+
+/// The features [`VirtIOBlk::new`] negotiates if the device offers them, see
+/// [`VirtIOBlk::new_with_features`].
+pub const SUPPORTED_FEATURES: BlkFeature = BlkFeature::FLUSH
+    .union(BlkFeature::BARRIER)
+    .union(BlkFeature::SIZE_MAX)
+    .union(BlkFeature::SEG_MAX)
+    .union(BlkFeature::DISCARD)
+    .union(BlkFeature::WRITE_ZEROES)
+    .union(BlkFeature::RING_EVENT_IDX);
+/// Features which are never negotiated even if offered.
+///
+/// Legacy devices which negotiated `SCSI` accept SCSI command requests, whose layout has extra
+/// fields between the data and the status byte. The driver never builds those, and refusing the
+/// feature keeps the device from assuming that layout for anything it is sent.
+const REFUSED_FEATURES: BlkFeature = BlkFeature::SCSI;
+/// The most descriptors each request queue has, which a [`Hal`] for the driver is generic over.
+pub const QUEUE_SIZE: usize = 16;
 pub const SECTOR_SIZE: usize = 512;
 
+/// The most request queues a driver uses, so that the tokens of all of them fit in a `u16`.
+pub const MAX_QUEUES: u16 = (u16::MAX as usize / QUEUE_SIZE) as u16;
+
 pub struct VirtIOBlk<H: Hal<QUEUE_SIZE>, T: Transport> {
     transport: T,
-    queue: VirtIoQueue<H, QUEUE_SIZE>,
+    /// The request queues, with the virtqueue index of each being its index here.
+    queues: Vec<VirtIoQueue<H, QUEUE_SIZE>>,
+    /// Copied from the queues when they were created, for [`Self::queues`].
+    queue_info: Vec<QueueInfo>,
     capacity: u64,
     negotiated_features: BlkFeature,
+    /// The features to negotiate if the device offers them, kept for [`Self::reset`].
+    supported_features: BlkFeature,
+    /// The most request queues to use, kept for [`Self::reset`].
+    max_queues: u16,
+    /// Requests submitted by [`Self::read_blocks_nb_on`] and [`Self::write_blocks_nb_on`] which
+    /// haven't been completed yet, by token.
+    in_flight: BTreeMap<u16, Box<InFlight>>,
+    /// The most data segments a request may have, from `seg_max` and the queue size.
+    max_segments: usize,
+    /// The longest a single data segment may be, from `size_max`.
+    max_segment_size: usize,
+    /// The most sectors a single discard request may cover.
+    max_discard_sectors: u32,
+    /// The most sectors a single write zeroes request may cover.
+    max_write_zeroes_sectors: u32,
+    /// DMA buffer for [`Self::device_id`] to receive the ID in, allocated when first needed.
+    id_dma: Option<Box<dyn DevicePage>>,
 }
 
-impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOBlk<H, T> {
-    /// Create a new VirtIO-Blk driver.
-    pub fn new(mut transport: T) -> VirtIoResult<Self> {
-        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES)?;
-        let io_region = transport.io_region();
-        // read config
+/// The parts of a non-blocking request which the driver owns, boxed so they stay at the address
+/// given to the device until the request is completed.
+struct InFlight {
+    request: BlkReq,
+    resp: BlkRespStatus,
+    /// The address and length of the caller's buffer, to check the same one is passed back.
+    data: (usize, usize),
+}
+
+/// What the driver reads from the config space when it is created.
+struct Limits {
+    capacity: u64,
+    max_segments: usize,
+    max_segment_size: usize,
+    max_discard_sectors: u32,
+    max_write_zeroes_sectors: u32,
+    num_queues: u16,
+}
+
+impl Limits {
+    fn read(
+        transport: &impl Transport,
+        negotiated_features: BlkFeature,
+        max_queues: u16,
+    ) -> VirtIoResult<Self> {
         let config = BlkConfig::default();
-        let capacity = ((config.capacity_high.read(io_region)? as u64) << 32)
-            | (config.capacity_low.read(io_region)? as u64);
+        // Only the capacity is always present, the other fields depend on features.
+        transport.check_config_space(size_of::<u64>())?;
+        let capacity = transport.read_config_consistent(|t| config.capacity.read(t.io_region()))?;
         info!("block device size: {}KB", capacity / 2);
-        let queue = VirtIoQueue::new(&mut transport, 0)?;
-        transport.finish_init()?;
+        if negotiated_features.contains(BlkFeature::BARRIER)
+            && !negotiated_features.contains(BlkFeature::FLUSH)
+        {
+            warn!("legacy block device only offers barriers, flush will be emulated with one");
+        }
+        let has = |feature| negotiated_features.contains(feature);
+        // Besides the data, every request needs a descriptor for its header and one for its status.
+        let mut max_segments = QUEUE_SIZE - 2;
+        if let Some(seg_max) = config
+            .seg_max
+            .read_optional(transport, has(BlkFeature::SEG_MAX))?
+        {
+            max_segments = max_segments.min((seg_max as usize).max(1));
+        }
+        // A device without a limit may leave it at 0.
+        let max_segment_size = match config
+            .size_max
+            .read_optional(transport, has(BlkFeature::SIZE_MAX))?
+        {
+            None | Some(0) => u32::MAX as usize,
+            Some(size_max) => size_max as usize,
+        };
+        // A limit of 0 is invalid, so ignore it rather than never sending the request.
+        let max_discard_sectors = match config
+            .max_discard_sectors
+            .read_optional(transport, has(BlkFeature::DISCARD))?
+        {
+            None | Some(0) => u32::MAX,
+            Some(max) => max,
+        };
+        let max_write_zeroes_sectors = match config
+            .max_write_zeroes_sectors
+            .read_optional(transport, has(BlkFeature::WRITE_ZEROES))?
+        {
+            None | Some(0) => u32::MAX,
+            Some(max) => max,
+        };
+        let num_queues = config
+            .num_queues
+            .read_optional(transport, has(BlkFeature::MQ))?
+            .map_or(1, |num_queues| num_queues.clamp(1, max_queues));
         Ok(Self {
-            transport,
-            queue,
             capacity,
+            max_segments,
+            max_segment_size,
+            max_discard_sectors,
+            max_write_zeroes_sectors,
+            num_queues,
+        })
+    }
+}
+
+/// Buffers for adjacent sectors which are sent as a single request by the batch functions.
+struct Merged<'a> {
+    sector: usize,
+    /// The buffers, in sector order.
+    data: Vec<Buffer<'a>>,
+    len: usize,
+    segments: usize,
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOBlk<H, T> {
+    /// The most descriptors each request queue has, as the device may offer fewer.
+    pub const QUEUE_SIZE: usize = QUEUE_SIZE;
+
+    /// The virtqueue index of the first request queue. The others follow it, up to
+    /// [`Self::num_queues`].
+    pub const FIRST_QUEUE: u16 = 0;
+
+    /// The memory [`Self::new`] allocates: a single request queue, plus the ID buffer of
+    /// [`Self::device_id`].
+    pub const fn memory_requirements() -> MemoryRequirements {
+        Self::memory_requirements_with_queues(1)
+    }
+
+    /// The most memory [`Self::new_with_queues`] allocates for up to `max_queues` request queues,
+    /// plus the ID buffer of [`Self::device_id`].
+    pub const fn memory_requirements_with_queues(max_queues: u16) -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(max_queues as usize).with_buffer(DEVICE_ID_LEN)
+    }
+
+    /// Create a new VirtIO-Blk driver.
+    pub fn new(transport: T) -> VirtIoResult<Self> {
+        Self::new_with_queues(transport, 1)
+    }
+
+    /// Creates a new VirtIO-Blk driver which uses up to `max_queues` request queues, if the device
+    /// offers [`BlkFeature::MQ`], so that requests from different CPUs don't contend for one.
+    ///
+    /// Requests go to the queue chosen by the `queue_hint` of e.g. [`Self::read_blocks_on`]. At
+    /// most [`MAX_QUEUES`] queues are used, and at least one even if `max_queues` is 0.
+    pub fn new_with_queues(transport: T, max_queues: u16) -> VirtIoResult<Self> {
+        Self::with_features(transport, max_queues, SUPPORTED_FEATURES)
+    }
+
+    /// Creates a new VirtIO-Blk driver which only negotiates the features in `wanted`, those of
+    /// them the device offers, e.g. to benchmark without [`BlkFeature::FLUSH`].
+    ///
+    /// `wanted` must be a subset of [`SUPPORTED_FEATURES`], or this returns
+    /// [`VirtIoError::InvalidParam`] before touching the device. Check
+    /// [`Self::negotiated_features`] for which the device accepted.
+    pub fn new_with_features(transport: T, wanted: BlkFeature) -> VirtIoResult<Self> {
+        if !SUPPORTED_FEATURES.contains(wanted) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Self::with_features(transport, 1, wanted)
+    }
+
+    fn with_features(
+        mut transport: T,
+        max_queues: u16,
+        supported_features: BlkFeature,
+    ) -> VirtIoResult<Self> {
+        let max_queues = max_queues.clamp(1, MAX_QUEUES);
+        let (negotiated_features, limits, queues) =
+            Self::setup(&mut transport, max_queues, supported_features)?;
+        let queue_info = queues.iter().map(VirtIoQueue::info).collect();
+        Ok(Self {
+            transport,
+            queues,
+            queue_info,
+            capacity: limits.capacity,
             negotiated_features,
+            supported_features,
+            max_queues,
+            in_flight: BTreeMap::new(),
+            max_segments: limits.max_segments,
+            max_segment_size: limits.max_segment_size,
+            max_discard_sectors: limits.max_discard_sectors,
+            max_write_zeroes_sectors: limits.max_write_zeroes_sectors,
+            id_dma: None,
         })
     }
 
+    /// Negotiates features, reads the config space and sets up the request queues, through to
+    /// `DRIVER_OK`.
+    fn setup(
+        transport: &mut T,
+        max_queues: u16,
+        supported_features: BlkFeature,
+    ) -> VirtIoResult<(BlkFeature, Limits, Vec<VirtIoQueue<H, QUEUE_SIZE>>)> {
+        let mut supported = supported_features.difference(REFUSED_FEATURES);
+        if max_queues > 1 {
+            supported |= BlkFeature::MQ;
+        }
+        // Every supported feature is optional, so a device which rejects some still comes up.
+        let negotiated_features = transport
+            .init_step(InitStep::Negotiation, |t| {
+                t.begin_init_with_fallback(BlkFeature::empty(), supported)
+            })?
+            .features;
+        let mut limits = transport.init_step(InitStep::ReadConfig, |t| {
+            Limits::read(t, negotiated_features, max_queues)
+        })?;
+        let event_idx = negotiated_features.contains(BlkFeature::RING_EVENT_IDX);
+        let queues = transport.init_step(InitStep::QueueSetup, |t| {
+            let queues = (0..limits.num_queues)
+                .map(|index| {
+                    let mut queue = VirtIoQueue::new(t, index)?;
+                    queue.set_event_idx(event_idx);
+                    Ok(queue)
+                })
+                .collect::<VirtIoResult<Vec<_>>>()?;
+            // The device may have shortened the queues, which must still fit a header, a status
+            // and at least one segment.
+            let shortest = queues.iter().map(VirtIoQueue::size).min().unwrap_or(0);
+            limits.max_segments = limits
+                .max_segments
+                .min(usize::from(shortest).saturating_sub(2));
+            if limits.max_segments == 0 {
+                return Err(VirtIoError::InvalidParam);
+            }
+            Ok(queues)
+        })?;
+        transport.init_step(InitStep::DriverOk, T::finish_init)?;
+        Ok((negotiated_features, limits, queues))
+    }
+
+    /// Resets the device and initializes it again, e.g. to recover once it has set
+    /// `DEVICE_NEEDS_RESET`, without recreating the transport.
+    ///
+    /// Features are negotiated and the config space is read again, so the capacity may change.
+    /// Requests in flight are abandoned: the device won't complete them, and their tokens are no
+    /// longer valid. If this fails the device is marked `FAILED`, and the driver may only be reset
+    /// again or dropped.
+    pub fn reset(&mut self) -> VirtIoResult<()> {
+        // The device must let go of the old queues before they are freed.
+        self.transport.reset()?;
+        self.in_flight.clear();
+        let (negotiated_features, limits, queues) = Self::setup(
+            &mut self.transport,
+            self.max_queues,
+            self.supported_features,
+        )?;
+        self.queue_info = queues.iter().map(VirtIoQueue::info).collect();
+        self.queues = queues;
+        self.capacity = limits.capacity;
+        self.negotiated_features = negotiated_features;
+        self.max_segments = limits.max_segments;
+        self.max_segment_size = limits.max_segment_size;
+        self.max_discard_sectors = limits.max_discard_sectors;
+        self.max_write_zeroes_sectors = limits.max_write_zeroes_sectors;
+        Ok(())
+    }
+
     /// Gets the capacity of the block device, in 512 byte ([`SECTOR_SIZE`]) sectors.
     pub fn capacity(&self) -> VirtIoResult<u64> {
         Ok(self.capacity)
     }
 
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> BlkFeature {
+        self.negotiated_features
+    }
+
+    /// Returns the transport, e.g. to read the device status from a power management hook.
+    ///
+    /// Only shared access is given, so the device can't be reconfigured behind the driver's back.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        &self.queue_info
+    }
+
+    /// Returns how many request queues the driver uses.
+    pub fn num_queues(&self) -> u16 {
+        self.queues.len() as u16
+    }
+
     /// Returns true if the block device is read-only, or false if it allows writes.
     pub fn readonly(&self) -> bool {
         self.negotiated_features.contains(BlkFeature::RO)
     }
 
-    /// Acknowledges a pending interrupt, if any.
-    ///
-    /// Returns true if there was an interrupt to acknowledge.
-    pub fn ack_interrupt(&mut self) -> VirtIoResult<bool> {
-        self.transport.ack_interrupt()
-    }
-
-    /// Sends the given request to the device and waits for a response, including the given data.
-    fn request_read(&mut self, request: BlkReq, data: &mut [u8]) -> VirtIoResult<()> {
-        let resp = BlkRespStatus::default();
-        let req = Descriptor::new::<QUEUE_SIZE, H>(
-            &request as *const _ as _,
-            size_of_val(&request) as _,
-            DescFlag::NEXT,
-        );
-        let data = Descriptor::new::<QUEUE_SIZE, H>(
-            data.as_ptr() as _,
-            data.len() as _,
-            DescFlag::NEXT | DescFlag::WRITE,
-        );
-        let res = Descriptor::new::<QUEUE_SIZE, H>(
-            &resp as *const _ as _,
-            size_of_val(&resp) as _,
-            DescFlag::WRITE,
-        );
-        self.queue
-            .add_notify_wait_pop(&mut self.transport, vec![req, data, res])?;
-        debug_assert_eq!(resp, BlkRespStatus::OK);
-        resp.into()
-    }
-
-    /// Sends the given request and data to the device and waits for a response.
-    fn request_write(&mut self, request: BlkReq, data: &[u8]) -> VirtIoResult<()> {
-        let resp = BlkRespStatus::default();
-        let req = Descriptor::new::<QUEUE_SIZE, H>(
-            &request as *const _ as _,
-            size_of_val(&request) as _,
-            DescFlag::NEXT,
-        );
-        let data =
-            Descriptor::new::<QUEUE_SIZE, H>(data.as_ptr() as _, data.len() as _, DescFlag::NEXT);
-        let res = Descriptor::new::<QUEUE_SIZE, H>(
-            &resp as *const _ as _,
-            size_of_val(&resp) as _,
-            DescFlag::WRITE,
-        );
-        let _len = self
-            .queue
-            .add_notify_wait_pop(&mut self.transport, vec![req, data, res])?;
-        debug_assert_eq!(resp, BlkRespStatus::OK);
-        resp.into()
+    /// Acknowledges a pending interrupt, if any, and collects the requests the device completed.
+    ///
+    /// Returns the causes of the interrupt, which are empty if there was none. On
+    /// [`InterruptStatus::CONFIGURATION_CHANGE`], [`Self::handle_config_change`] picks up a
+    /// resized disk.
+    pub fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        let status = self.transport.ack_interrupt()?;
+        if status.contains(InterruptStatus::USED_RING_UPDATE) {
+            for queue in &mut self.queues {
+                queue.collect_used();
+            }
+        }
+        Ok(status)
+    }
+
+    /// Handles an interrupt with [`InterruptStatus::CONFIGURATION_CHANGE`], returning the new
+    /// capacity in sectors if the host resized the disk.
+    pub fn handle_config_change(&mut self) -> VirtIoResult<Option<u64>> {
+        let old = self.capacity;
+        let capacity = self.recheck_capacity()?;
+        Ok((capacity != old).then_some(capacity))
+    }
+
+    /// Reads the capacity from the config space again and returns it in sectors, e.g. to let a
+    /// filesystem grow after the host resized the disk.
+    ///
+    /// [`Self::capacity`] returns the capacity read when the driver was created or last
+    /// rechecked.
+    pub fn recheck_capacity(&mut self) -> VirtIoResult<u64> {
+        let config = BlkConfig::default();
+        let capacity = self
+            .transport
+            .read_config_consistent(|t| config.capacity.read(t.io_region()))?;
+        if capacity != self.capacity {
+            info!(
+                "block device resized from {}KB to {}KB",
+                self.capacity / 2,
+                capacity / 2
+            );
+            self.capacity = capacity;
+        }
+        Ok(capacity)
+    }
+
+    /// Collects the requests the device completed if its interrupt status says it used buffers,
+    /// without acknowledging the interrupt.
+    ///
+    /// This is for polling the device before its interrupt is routed, e.g. during early boot.
+    /// Once it is, [`Self::ack_interrupt`] handles the interrupt which is still pending.
+    ///
+    /// Returns true if the device used buffers.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        let used = self
+            .transport
+            .peek_interrupt_status()?
+            .contains(InterruptStatus::USED_RING_UPDATE);
+        if used {
+            for queue in &mut self.queues {
+                queue.collect_used();
+            }
+        }
+        Ok(used)
+    }
+
+    /// Processes the completions on a single queue, for transports which route the interrupts of
+    /// each queue to its own vector, see [`queue_vector`](crate::transport::queue_vector).
+    ///
+    /// Returns whether the device completed any requests on the queue since the last check, which
+    /// [`Self::peek_used`] then returns, or [`VirtIoError::InvalidParam`] if there is no such
+    /// queue.
+    pub fn handle_queue_interrupt(&mut self, queue: u16) -> VirtIoResult<bool> {
+        let queue = self
+            .queues
+            .get_mut(usize::from(queue))
+            .ok_or(VirtIoError::InvalidParam)?;
+        Ok(queue.collect_used() > 0)
+    }
+
+    /// Sends the given request to the device on the given queue and waits for a response,
+    /// including the given data.
+    fn request_read(&mut self, queue: u16, request: BlkReq, data: &mut [u8]) -> VirtIoResult<()> {
+        let data = self.data_descriptors(vec![Buffer::Write(data)])?;
+        self.request_on(queue, request, data)
+    }
+
+    /// Reads or writes `data` from `sector` on, with as many requests as it takes to fit the data
+    /// in the segments a request may have, waiting for each before sending the next.
+    ///
+    /// Each request transfers whole sectors, so a segment may be split between two requests. The
+    /// total length must be a multiple of [`SECTOR_SIZE`]. Returns [`VirtIoError::InvalidParam`]
+    /// if a request can't hold even one sector, because the data is split into too many short
+    /// buffers.
+    fn transfer(
+        &mut self,
+        queue: u16,
+        type_: BlkReqType,
+        mut sector: usize,
+        data: Vec<Buffer>,
+    ) -> VirtIoResult<()> {
+        let mut segments = VecDeque::new();
+        for mut buffer in data {
+            while !buffer.is_empty() {
+                let len = buffer.len().min(self.max_segment_size);
+                let (segment, rest) = buffer.split_at(len);
+                segments.push_back(segment);
+                buffer = rest;
+            }
+        }
+        while !segments.is_empty() {
+            let count = segments.len().min(self.max_segments);
+            let mut request: Vec<Buffer> = segments.drain(..count).collect();
+            // Whatever doesn't make up a whole sector goes back to be sent with the next request.
+            let len: usize = request.iter().map(Buffer::len).sum();
+            let mut excess = len % SECTOR_SIZE;
+            while excess > 0 {
+                let last = request.pop().ok_or(VirtIoError::InvalidParam)?;
+                if last.len() <= excess {
+                    excess -= last.len();
+                    segments.push_front(last);
+                } else {
+                    let keep_len = last.len() - excess;
+                    let (keep, rest) = last.split_at(keep_len);
+                    request.push(keep);
+                    segments.push_front(rest);
+                    excess = 0;
+                }
+            }
+            if request.is_empty() {
+                return Err(VirtIoError::InvalidParam);
+            }
+            let len: usize = request.iter().map(Buffer::len).sum();
+            let descriptors = request.into_iter().map(DmaBuf::from).collect();
+            self.request_on(queue, BlkReq::new(type_, sector as u64), descriptors)?;
+            sector += len / SECTOR_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Sends the given request with the given data descriptors to the device on the given queue,
+    /// and waits for its status.
+    fn request_on(
+        &mut self,
+        queue: u16,
+        request: BlkReq,
+        data: Vec<DmaBuf<'_>>,
+    ) -> VirtIoResult<()> {
+        request_response(
+            &mut self.queues[usize::from(queue)],
+            &mut self.transport,
+            &request,
+            data,
+            BlkRespStatus::default(),
+            VirtIoResult::from,
+        )
+    }
+
+    /// Builds the descriptor chain of a request: the header, each data buffer split into
+    /// segments no longer than `size_max`, and the status.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if the data needs more segments than a request may
+    /// have.
+    fn chain<'a>(
+        &self,
+        request: &'a BlkReq,
+        data: Vec<Buffer<'a>>,
+        resp: &'a mut BlkRespStatus,
+    ) -> VirtIoResult<Vec<DmaBuf<'a>>> {
+        let mut descriptors = vec![DmaBuf::readable(request)];
+        descriptors.extend(self.data_descriptors(data)?);
+        descriptors.push(DmaBuf::writable(resp));
+        Ok(descriptors)
+    }
+
+    /// Builds the descriptors for the data of a request, with each buffer split into segments no
+    /// longer than `size_max`.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if the data needs more segments than a request may
+    /// have.
+    fn data_descriptors<'a>(&self, data: Vec<Buffer<'a>>) -> VirtIoResult<Vec<DmaBuf<'a>>> {
+        let mut descriptors = Vec::new();
+        for mut buffer in data {
+            while !buffer.is_empty() {
+                let len = buffer.len().min(self.max_segment_size);
+                let (segment, rest) = buffer.split_at(len);
+                descriptors.push(DmaBuf::from(segment));
+                buffer = rest;
+            }
+        }
+        if descriptors.len() > self.max_segments {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Ok(descriptors)
+    }
+
+    /// Returns how many segments a data buffer of the given length is split into.
+    fn segments(&self, len: usize) -> usize {
+        len.div_ceil(self.max_segment_size)
+    }
+
+    /// Checks that `buf` holds a whole number of sectors, and at least one.
+    fn check_buf_len(buf: &[u8]) -> VirtIoResult<()> {
+        if buf.is_empty() || !buf.len().is_multiple_of(SECTOR_SIZE) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Ok(())
     }
 
     /// Gets the device ID.
     ///
-    /// The ID is written as ASCII into the given buffer, which must be 20 bytes long, and the used
-    /// length returned.
-    pub fn device_id(&mut self, id: &mut [u8; 20]) -> VirtIoResult<usize> {
-        self.request_read(BlkReq::new(BlkReqType::GetId, 0), id)?;
-        let length = id.iter().position(|&x| x == 0).unwrap_or(20);
-        Ok(length)
+    /// The device writes it into a DMA buffer of the driver, allocated on the first call, so it
+    /// doesn't need access to the caller's memory.
+    pub fn device_id(&mut self) -> VirtIoResult<DeviceId> {
+        let mut dma = self
+            .id_dma
+            .take()
+            .unwrap_or_else(|| H::dma_alloc_buf(pages(DEVICE_ID_LEN)));
+        let buffer: &mut [u8; DEVICE_ID_LEN] = dma
+            .as_mut_slice()
+            .get_mut(..DEVICE_ID_LEN)
+            .and_then(|buffer| buffer.try_into().ok())
+            .ok_or(VirtIoError::DmaError)?;
+        // A shorter ID is terminated by the zeroes the device leaves alone.
+        buffer.fill(0);
+        let result = self.request_read(0, BlkReq::new(BlkReqType::GetId, 0), buffer);
+        let id = DeviceId::from_buffer(buffer);
+        self.id_dma = Some(dma);
+        result.map(|()| id)
     }
 
     /// Reads one or more blocks into the given buffer.
     ///
-    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`], otherwise
+    /// [`VirtIoError::InvalidParam`] is returned. A buffer too large for one request, by the
+    /// device's `size_max` and `seg_max`, is read with several.
     ///
     /// Blocks until the read completes or there is an error.
     pub fn read_blocks(&mut self, sector: usize, buf: &mut [u8]) -> VirtIoResult<()> {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_read(BlkReq::new(BlkReqType::In, sector as u64), buf)
+        self.read_blocks_on(0, sector, buf)
+    }
+
+    /// Like [`Self::read_blocks`], but sends the request on the queue chosen by `queue_hint`.
+    ///
+    /// Any hint is valid, and callers on different CPUs should pass different ones, e.g. the
+    /// CPU's number, to spread their requests across the queues.
+    pub fn read_blocks_on(
+        &mut self,
+        queue_hint: usize,
+        sector: usize,
+        buf: &mut [u8],
+    ) -> VirtIoResult<()> {
+        Self::check_buf_len(buf)?;
+        let queue = self.queue_for(queue_hint);
+        self.transfer(queue, BlkReqType::In, sector, vec![Buffer::Write(buf)])
     }
-    /// assert_eq!(buf.len() % 512, 0)
+
+    /// Reads blocks starting at `sector` into several buffers, filled one after another.
+    ///
+    /// Each buffer gets its own data segments, so e.g. the pages of a page cache can be filled
+    /// without a contiguous bounce buffer. The buffers may have any lengths, but their total must
+    /// be a non-zero multiple of [`SECTOR_SIZE`], otherwise [`VirtIoError::InvalidParam`] is
+    /// returned. If they need more segments than the device's `seg_max` allows, they are read
+    /// with several requests.
+    ///
+    /// Blocks until the read completes, and returns the total number of bytes read.
+    pub fn read_blocks_vectored(
+        &mut self,
+        sector: usize,
+        bufs: &mut [&mut [u8]],
+    ) -> VirtIoResult<usize> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if len == 0 || !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let data = bufs.iter_mut().map(|buf| Buffer::Write(buf)).collect();
+        self.transfer(0, BlkReqType::In, sector, data)?;
+        Ok(len)
+    }
+
+    /// Writes one or more blocks from the given buffer.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`], otherwise
+    /// [`VirtIoError::InvalidParam`] is returned. A buffer too large for one request is written
+    /// with several, as for [`Self::read_blocks`].
+    ///
+    /// Returns [`VirtIoError::Unsupported`] without sending a request if the device is
+    /// [read-only](Self::readonly).
     pub fn write_blocks(&mut self, sector: usize, buf: &[u8]) -> VirtIoResult<()> {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_write(BlkReq::new(BlkReqType::Out, sector as u64), buf)
+        self.write_blocks_on(0, sector, buf)
+    }
+
+    /// Like [`Self::write_blocks`], but sends the request on the queue chosen by `queue_hint`, see
+    /// [`Self::read_blocks_on`].
+    pub fn write_blocks_on(
+        &mut self,
+        queue_hint: usize,
+        sector: usize,
+        buf: &[u8],
+    ) -> VirtIoResult<()> {
+        Self::check_buf_len(buf)?;
+        if self.readonly() {
+            return Err(VirtIoError::Unsupported);
+        }
+        let queue = self.queue_for(queue_hint);
+        self.transfer(queue, BlkReqType::Out, sector, vec![Buffer::Read(buf)])
+    }
+
+    /// Writes blocks starting at `sector` from several buffers, one after another, like
+    /// [`Self::read_blocks_vectored`].
+    ///
+    /// Returns [`VirtIoError::Unsupported`] without sending a request if the device is
+    /// [read-only](Self::readonly). Blocks until the write completes, and returns the total number
+    /// of bytes written.
+    pub fn write_blocks_vectored(&mut self, sector: usize, bufs: &[&[u8]]) -> VirtIoResult<usize> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if len == 0 || !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        if self.readonly() {
+            return Err(VirtIoError::Unsupported);
+        }
+        let data = bufs.iter().map(|buf| Buffer::Read(buf)).collect();
+        self.transfer(0, BlkReqType::Out, sector, data)?;
+        Ok(len)
+    }
+
+    /// Submits a request to read one or more blocks into the given buffer, without waiting for it
+    /// to complete.
+    ///
+    /// Returns a token identifying the request, or [`VirtIoError::QueueFull`] if there are not
+    /// enough free descriptors. Once the device has handled the request, e.g. after
+    /// [`Self::ack_interrupt`] reported an interrupt, the caller must call [`Self::complete_read`]
+    /// with the token and the same buffer before using the data.
+    ///
+    /// The buffer is written by the device until the request is completed, so the caller must not
+    /// access it in the meantime.
+    pub fn read_blocks_nb(&mut self, sector: usize, buf: &mut [u8]) -> VirtIoResult<u16> {
+        self.read_blocks_nb_on(0, sector, buf)
     }
 
+    /// Like [`Self::read_blocks_nb`], but submits the request on the queue chosen by
+    /// `queue_hint`, see [`Self::read_blocks_on`].
+    ///
+    /// Tokens are unique across all queues, so the request is completed the same way.
+    pub fn read_blocks_nb_on(
+        &mut self,
+        queue_hint: usize,
+        sector: usize,
+        buf: &mut [u8],
+    ) -> VirtIoResult<u16> {
+        Self::check_buf_len(buf)?;
+        let queue = self.queue_for(queue_hint);
+        self.submit(
+            queue,
+            BlkReq::new(BlkReqType::In, sector as u64),
+            Buffer::Write(buf),
+        )
+    }
+
+    /// Submits a request to write one or more blocks from the given buffer, without waiting for it
+    /// to complete.
+    ///
+    /// Like [`Self::read_blocks_nb`], except that the request must be completed with
+    /// [`Self::complete_write`]. Returns [`VirtIoError::Unsupported`] if the device is
+    /// [read-only](Self::readonly).
+    pub fn write_blocks_nb(&mut self, sector: usize, buf: &[u8]) -> VirtIoResult<u16> {
+        self.write_blocks_nb_on(0, sector, buf)
+    }
+
+    /// Like [`Self::write_blocks_nb`], but submits the request on the queue chosen by
+    /// `queue_hint`, see [`Self::read_blocks_nb_on`].
+    pub fn write_blocks_nb_on(
+        &mut self,
+        queue_hint: usize,
+        sector: usize,
+        buf: &[u8],
+    ) -> VirtIoResult<u16> {
+        Self::check_buf_len(buf)?;
+        if self.readonly() {
+            return Err(VirtIoError::Unsupported);
+        }
+        let queue = self.queue_for(queue_hint);
+        self.submit(
+            queue,
+            BlkReq::new(BlkReqType::Out, sector as u64),
+            Buffer::Read(buf),
+        )
+    }
+
+    /// Returns the index of the queue to use for the given hint.
+    fn queue_for(&self, queue_hint: usize) -> u16 {
+        (queue_hint % self.queues.len()) as u16
+    }
+
+    /// Splits a token returned to the caller into the index of its queue and the queue's token.
+    fn split_token(token: u16) -> (usize, u16) {
+        (usize::from(token) / QUEUE_SIZE, token % QUEUE_SIZE as u16)
+    }
+
+    /// Returns the token of a request submitted by [`Self::read_blocks_nb`],
+    /// [`Self::write_blocks_nb`] or their `_on` variants which the device has handled, without
+    /// completing it, or [`None`] if the device hasn't handled any.
+    ///
+    /// This lets an interrupt handler find which requests to complete instead of trying each
+    /// one. The same token is returned until it is completed.
+    pub fn peek_used(&mut self) -> Option<u16> {
+        self.queues
+            .iter_mut()
+            .enumerate()
+            .find_map(|(index, queue)| {
+                let token = queue.peek_used()?;
+                Some(index as u16 * QUEUE_SIZE as u16 + token)
+            })
+    }
+
+    /// Completes a read submitted by [`Self::read_blocks_nb`] or [`Self::read_blocks_nb_on`],
+    /// returning the device's status.
+    ///
+    /// Returns [`VirtIoError::NotReady`] if the device hasn't handled it yet, in which case it can
+    /// be completed later, or [`VirtIoError::InvalidParam`] if `buf` isn't the buffer it was
+    /// submitted with.
+    pub fn complete_read(&mut self, token: u16, buf: &mut [u8]) -> VirtIoResult<()> {
+        self.complete(token, buf)
+    }
+
+    /// Completes a write submitted by [`Self::write_blocks_nb`] or [`Self::write_blocks_nb_on`],
+    /// returning the device's status.
+    ///
+    /// Returns errors like [`Self::complete_read`].
+    pub fn complete_write(&mut self, token: u16, buf: &[u8]) -> VirtIoResult<()> {
+        self.complete(token, buf)
+    }
+
+    /// Reads one or more blocks into the given buffer, yielding to the executor until the
+    /// device completes the read.
+    ///
+    /// The task is woken when [`Self::ack_interrupt`] finds the request completed, so the
+    /// interrupt handler must call it for the future to make progress. If the future is dropped
+    /// before it completes, dropping it blocks until the device has written to `buf`, or resets
+    /// the device if it asks to be reset instead, so the device never accesses `buf` afterwards.
+    pub async fn read_blocks_async(&mut self, sector: usize, buf: &mut [u8]) -> VirtIoResult<()> {
+        let token = self.read_blocks_nb(sector, buf)?;
+        let guard = CancelOnDrop { blk: self, token };
+        let (queue, queue_token) = Self::split_token(token);
+        guard.blk.queues[queue].wait_used(queue_token).await?;
+        guard.blk.complete_read(token, buf)
+    }
+
+    /// Writes one or more blocks from the given buffer, yielding to the executor until the
+    /// device completes the write.
+    ///
+    /// Woken and cancelled like [`Self::read_blocks_async`].
+    pub async fn write_blocks_async(&mut self, sector: usize, buf: &[u8]) -> VirtIoResult<()> {
+        let token = self.write_blocks_nb(sector, buf)?;
+        let guard = CancelOnDrop { blk: self, token };
+        let (queue, queue_token) = Self::split_token(token);
+        guard.blk.queues[queue].wait_used(queue_token).await?;
+        guard.blk.complete_write(token, buf)
+    }
+
+    /// Takes back a request whose future was dropped, see [`VirtIoQueue::cancel`].
+    fn cancel(&mut self, token: u16) {
+        let (queue, queue_token) = Self::split_token(token);
+        self.queues[queue].cancel(&mut self.transport, queue_token);
+        self.in_flight.remove(&token);
+    }
+
+    /// Reads several buffers, merging those for adjacent sectors into single requests.
+    ///
+    /// Each entry is a start sector and a buffer, as passed to [`Self::read_blocks`]. Buffers
+    /// whose sectors directly follow each other, in any order, are read by one request with a
+    /// segment per buffer, up to the `seg_max` and `size_max` limits of the device. The merged
+    /// requests are kept in flight together as far as the queue has room.
+    ///
+    /// Blocks until every read completes, and returns how many requests were sent, or the first
+    /// error. Returns [`VirtIoError::InvalidParam`] without sending anything if a buffer isn't a
+    /// whole number of sectors or two buffers overlap on the device.
+    pub fn read_blocks_batch(&mut self, reads: &mut [(usize, &mut [u8])]) -> VirtIoResult<usize> {
+        let buffers = reads
+            .iter_mut()
+            .map(|(sector, buf)| (*sector, Buffer::Write(buf)))
+            .collect();
+        self.batch(BlkReqType::In, buffers)
+    }
+
+    /// Writes several buffers, merging those for adjacent sectors into single requests.
+    ///
+    /// This saves most of the per-request overhead for filesystems which issue many small
+    /// sequential writes. Buffers are merged and errors returned like for
+    /// [`Self::read_blocks_batch`], and [`VirtIoError::Unsupported`] is returned if the device is
+    /// [read-only](Self::readonly).
+    pub fn write_blocks_batch(&mut self, writes: &[(usize, &[u8])]) -> VirtIoResult<usize> {
+        if self.readonly() {
+            return Err(VirtIoError::Unsupported);
+        }
+        let buffers = writes
+            .iter()
+            .map(|(sector, buf)| (*sector, Buffer::Read(buf)))
+            .collect();
+        self.batch(BlkReqType::Out, buffers)
+    }
+
+    /// Adds a request with the given data buffer to the given queue and notifies the device,
+    /// keeping the header and status until [`Self::complete`].
+    fn submit(&mut self, queue: u16, request: BlkReq, data: Buffer) -> VirtIoResult<u16> {
+        let mut in_flight = Box::new(InFlight {
+            request,
+            resp: BlkRespStatus::default(),
+            data: (data.addr(), data.len()),
+        });
+        let descriptors = self.chain(&in_flight.request, vec![data], &mut in_flight.resp)?;
+        let virtqueue = &mut self.queues[usize::from(queue)];
+        let token = queue * QUEUE_SIZE as u16 + virtqueue.add(descriptors)?;
+        self.in_flight.insert(token, in_flight);
+        if virtqueue.should_notify() {
+            self.transport.notify(queue)?;
+        }
+        Ok(token)
+    }
+
+    /// Pops a request added by [`Self::submit`] and returns its status.
+    fn complete(&mut self, token: u16, data: &[u8]) -> VirtIoResult<()> {
+        let in_flight = self.in_flight.get(&token).ok_or(VirtIoError::WrongToken)?;
+        if in_flight.data != (data.as_ptr() as usize, data.len()) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let (queue, queue_token) = Self::split_token(token);
+        self.queues[queue].pop_used(queue_token)?;
+        let in_flight = self
+            .in_flight
+            .remove(&token)
+            .ok_or(VirtIoError::WrongToken)?;
+        self.status(in_flight.resp)
+    }
+
+    /// Turns the status of a completed request into its result.
+    fn status(&self, resp: BlkRespStatus) -> VirtIoResult<()> {
+        VirtIoResult::from(resp).map_err(|e| or_needs_reset(&self.transport, e))
+    }
+
+    /// Merges the given buffers, each paired with its first sector, into as few requests as
+    /// possible, sends them on the first queue and waits for all of them.
+    fn batch(
+        &mut self,
+        type_: BlkReqType,
+        mut buffers: Vec<(usize, Buffer)>,
+    ) -> VirtIoResult<usize> {
+        buffers.sort_unstable_by_key(|&(sector, _)| sector);
+        let mut merged: Vec<Merged> = Vec::new();
+        for (sector, buffer) in buffers {
+            let len = buffer.len();
+            if len == 0 || !len.is_multiple_of(SECTOR_SIZE) {
+                return Err(VirtIoError::InvalidParam);
+            }
+            let segments = self.segments(len);
+            if let Some(last) = merged.last_mut() {
+                let end = last.sector + last.len / SECTOR_SIZE;
+                if sector < end {
+                    return Err(VirtIoError::InvalidParam);
+                }
+                if sector == end
+                    && last.segments + segments <= self.max_segments
+                    && last.len + len <= u32::MAX as usize
+                {
+                    last.data.push(buffer);
+                    last.len += len;
+                    last.segments += segments;
+                    continue;
+                }
+            }
+            if segments > self.max_segments {
+                return Err(VirtIoError::InvalidParam);
+            }
+            merged.push(Merged {
+                sector,
+                data: vec![buffer],
+                len,
+                segments,
+            });
+        }
+
+        let requests = merged.len();
+        let mut result = Ok(());
+        let mut pending: VecDeque<(u16, Box<InFlight>)> = VecDeque::new();
+        for request in merged {
+            // Make room by waiting for the oldest requests, which an empty queue always has.
+            while self.queues[0].available_desc() < request.segments + 2 {
+                let Some((token, in_flight)) = pending.pop_front() else {
+                    break;
+                };
+                result = result
+                    .and(self.wait_for(token))
+                    .and_then(|()| self.status(in_flight.resp));
+            }
+            let mut in_flight = Box::new(InFlight {
+                request: BlkReq::new(type_, request.sector as u64),
+                resp: BlkRespStatus::default(),
+                data: (request.data[0].addr(), request.len),
+            });
+            let added = self
+                .chain(&in_flight.request, request.data, &mut in_flight.resp)
+                .and_then(|descriptors| self.queues[0].add(descriptors));
+            match added {
+                Ok(token) => pending.push_back((token, in_flight)),
+                Err(e) => {
+                    result = result.and(Err(e));
+                    break;
+                }
+            }
+            if self.queues[0].should_notify() {
+                if let Err(e) = self.transport.notify(0) {
+                    result = result.and(Err(e));
+                    break;
+                }
+            }
+        }
+        // The buffers must not be given back while the device may still access them, so wait for
+        // everything which was sent even after an error.
+        for (token, in_flight) in pending {
+            result = result
+                .and(self.wait_for(token))
+                .and_then(|()| self.status(in_flight.resp));
+        }
+        result.map(|()| requests)
+    }
+
+    /// Waits for the device to handle a request sent by [`Self::batch`], and pops it.
+    fn wait_for(&mut self, token: u16) -> VirtIoResult<()> {
+        self.queues[0].wait_for(&self.transport, token)?;
+        self.queues[0].pop_used(token)?;
+        Ok(())
+    }
+
+    /// Tells the device that `count` sectors from `sector` on are no longer used, e.g. for TRIM,
+    /// so it may deallocate them. Reading them afterwards returns unspecified data.
+    ///
+    /// Ranges longer than the device's `max_discard_sectors` are sent as several requests, and
+    /// this blocks until all of them complete. Returns [`VirtIoError::Unsupported`] if the device
+    /// doesn't offer [`BlkFeature::DISCARD`] or is [read-only](Self::readonly), or
+    /// [`VirtIoError::InvalidParam`] if `count` is 0.
+    pub fn discard(&mut self, sector: usize, count: u32) -> VirtIoResult<()> {
+        self.discard_or_write_zeroes(
+            BlkReqType::Discard,
+            BlkFeature::DISCARD,
+            self.max_discard_sectors,
+            sector,
+            count,
+        )
+    }
+
+    /// Sets `count` sectors from `sector` on to zeroes, without transferring a buffer of them.
+    ///
+    /// Like [`Self::discard`], except that the device must offer [`BlkFeature::WRITE_ZEROES`] and
+    /// requests are limited to `max_write_zeroes_sectors`.
+    pub fn write_zeroes(&mut self, sector: usize, count: u32) -> VirtIoResult<()> {
+        self.discard_or_write_zeroes(
+            BlkReqType::WriteZeroes,
+            BlkFeature::WRITE_ZEROES,
+            self.max_write_zeroes_sectors,
+            sector,
+            count,
+        )
+    }
+
+    /// Sends requests of the given type for the range, each covering at most `max_sectors`.
+    fn discard_or_write_zeroes(
+        &mut self,
+        type_: BlkReqType,
+        feature: BlkFeature,
+        max_sectors: u32,
+        sector: usize,
+        count: u32,
+    ) -> VirtIoResult<()> {
+        if !self.negotiated_features.contains(feature) || self.readonly() {
+            return Err(VirtIoError::Unsupported);
+        }
+        if count == 0 {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let mut sector = sector as u64;
+        let end = sector + u64::from(count);
+        while sector < end {
+            let num_sectors = (end - sector).min(max_sectors.into()) as u32;
+            // The range is in the data, so the sector in the header is unused.
+            let request = BlkReq::new(type_, 0);
+            let segment = BlkDiscardWriteZeroes::new(sector, num_sectors);
+            let data = vec![DmaBuf::readable(&segment)];
+            self.request_on(0, request, data)?;
+            sector += u64::from(num_sectors);
+        }
+        Ok(())
+    }
+
+    /// Flushes any writes cached by the device to the backing storage.
+    ///
+    /// Legacy hosts which predate [`BlkFeature::FLUSH`] may only offer [`BlkFeature::BARRIER`],
+    /// in which case an empty barrier write is sent instead. If the host rejects it
+    /// [`VirtIoError::Unsupported`] is returned rather
+    /// than pretending the data is durable.
+    ///
+    /// A device offering neither has no volatile write cache, so there is nothing to flush.
     pub fn flush(&mut self) -> VirtIoResult<()> {
         if self.negotiated_features.contains(BlkFeature::FLUSH) {
             self.request(BlkReq::new(BlkReqType::Flush, 0))
+        } else if self.negotiated_features.contains(BlkFeature::BARRIER) {
+            self.request(BlkReq::new(BlkReqType::Out, 0).barrier())
+                .inspect_err(|e| {
+                    warn!(
+                        "barrier flush failed: {:?}, negotiated features {:?}",
+                        e, self.negotiated_features
+                    )
+                })
         } else {
             Ok(())
         }
     }
     /// Sends the given request to the device and waits for a response, with no extra data.
     fn request(&mut self, request: BlkReq) -> VirtIoResult<()> {
-        let resp = BlkRespStatus::default();
-        let desc_vec = vec![
-            Descriptor::new::<QUEUE_SIZE, H>(
-                &request as *const _ as _,
-                size_of_val(&request) as _,
-                DescFlag::NEXT,
-            ),
-            Descriptor::new::<QUEUE_SIZE, H>(
-                &resp as *const _ as _,
-                size_of_val(&resp) as _,
-                DescFlag::WRITE,
-            ),
-        ];
-        self.queue
-            .add_notify_wait_pop(&mut self.transport, desc_vec)?;
-        debug_assert_eq!(resp, BlkRespStatus::OK);
-        resp.into()
+        // Flushes cover writes completed on any queue, so the first one is as good as any.
+        self.request_on(0, request, Vec::new())
+    }
+}
+
+/// The blocking operations of a block device, so wrappers like [`ThrottledBlk`] can stand in for
+/// a [`VirtIOBlk`].
+///
+/// Sectors are [`SECTOR_SIZE`] bytes, and buffers must be a non-zero multiple of that.
+pub trait BlockDevice {
+    /// Returns the capacity of the device, in sectors.
+    fn capacity(&self) -> VirtIoResult<u64>;
+
+    /// Returns whether the device refuses writes.
+    fn readonly(&self) -> bool;
+
+    /// Reads one or more sectors into `buf`, blocking until it is done.
+    fn read_blocks(&mut self, sector: usize, buf: &mut [u8]) -> VirtIoResult<()>;
+
+    /// Writes one or more sectors from `buf`, blocking until it is done.
+    fn write_blocks(&mut self, sector: usize, buf: &[u8]) -> VirtIoResult<()>;
+
+    /// Flushes any writes cached by the device to the backing storage.
+    fn flush(&mut self) -> VirtIoResult<()>;
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> BlockDevice for VirtIOBlk<H, T> {
+    fn capacity(&self) -> VirtIoResult<u64> {
+        VirtIOBlk::capacity(self)
+    }
+
+    fn readonly(&self) -> bool {
+        VirtIOBlk::readonly(self)
+    }
+
+    fn read_blocks(&mut self, sector: usize, buf: &mut [u8]) -> VirtIoResult<()> {
+        VirtIOBlk::read_blocks(self, sector, buf)
+    }
+
+    fn write_blocks(&mut self, sector: usize, buf: &[u8]) -> VirtIoResult<()> {
+        VirtIOBlk::write_blocks(self, sector, buf)
+    }
+
+    fn flush(&mut self) -> VirtIoResult<()> {
+        VirtIOBlk::flush(self)
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIoDriver for VirtIOBlk<H, T> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        VirtIOBlk::ack_interrupt(self)
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        VirtIOBlk::poll_interrupt(self)
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            device_type: DeviceType::Block,
+            bus_addr: self.transport.io_region().paddr(),
+            features: self.negotiated_features.bits(),
+        }
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        self.queues.iter().map(VirtIoQueue::stats).collect()
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        self.queues
+            .get_mut(usize::from(queue))
+            .ok_or(VirtIoError::InvalidParam)?
+            .set_event_suppression(suppression);
+        Ok(())
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.transport.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.transport.reset()
+    }
+
+    fn dump_state(&self, mut out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(
+            out,
+            "{:?}, {} requests in flight",
+            self.identity(),
+            self.in_flight.len()
+        )?;
+        for queue in &self.queues {
+            queue.dump_state(&mut out)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cancels the request of an async read or write when its future is dropped, before the borrow
+/// of the buffer ends. Once the request has been completed this does nothing.
+struct CancelOnDrop<'a, H: Hal<QUEUE_SIZE>, T: Transport> {
+    blk: &'a mut VirtIOBlk<H, T>,
+    token: u16,
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> Drop for CancelOnDrop<'_, H, T> {
+    fn drop(&mut self) {
+        self.blk.cancel(self.token);
     }
 }
 
 impl<H: Hal<QUEUE_SIZE>, T: Transport> Drop for VirtIOBlk<H, T> {
     fn drop(&mut self) {
-        self.transport
-            .queue_unset(0)
-            .expect("failed to unset queue");
+        for index in 0..self.queues.len() as u16 {
+            expect_ok(self.transport.queue_unset(index), "failed to unset queue");
+        }
     }
 }
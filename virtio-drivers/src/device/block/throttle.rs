@@ -0,0 +1,213 @@
+//! Rate limiting of block requests from background work, so that bulk operations like a scrub or a
+//! backup leave the device some room for latency sensitive requests sharing its queues.
+
+use super::BlockDevice;
+use crate::error::{VirtIoError, VirtIoResult};
+
+/// The rates a [`ThrottledBlk`] lets requests through at, in the units of its clock.
+///
+/// Each limit allows a burst of up to one `period`'s worth at once. A request larger than that is
+/// still sent once nothing was sent for a whole period, and what it overdrew is paid back by
+/// waiting longer before the next one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ThrottleLimits {
+    /// How long the rates below are measured over, e.g. a second's worth of clock ticks.
+    pub period: u64,
+    /// How many read and write requests may be sent each `period`, or `None` for no limit.
+    pub ops_per_period: Option<u64>,
+    /// How many bytes may be read and written each `period`, or `None` for no limit.
+    pub bytes_per_period: Option<u64>,
+}
+
+/// A token bucket, with its level counted in tokens times clock ticks so that partial refills
+/// aren't rounded away.
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    /// Below zero if a request larger than the bucket overdrew it.
+    level: i128,
+    capacity: i128,
+}
+
+impl Bucket {
+    fn new(rate: u64, period: u64) -> Self {
+        let capacity = i128::from(rate) * i128::from(period);
+        Self {
+            rate,
+            level: capacity,
+            capacity,
+        }
+    }
+
+    fn refill(&mut self, elapsed: u64) {
+        let added = i128::from(self.rate) * i128::from(elapsed);
+        self.level = (self.level + added).min(self.capacity);
+    }
+
+    /// How long until the bucket holds `amount` tokens, or is full if it can't hold that many.
+    fn wait(&self, amount: u64, period: u64) -> u64 {
+        let needed = (i128::from(amount) * i128::from(period)).min(self.capacity);
+        if self.level >= needed {
+            0
+        } else {
+            let rate = i128::from(self.rate);
+            ((needed - self.level + rate - 1) / rate)
+                .try_into()
+                .unwrap_or(u64::MAX)
+        }
+    }
+
+    fn take(&mut self, amount: u64, period: u64) {
+        self.level -= i128::from(amount) * i128::from(period);
+    }
+}
+
+/// Wraps a [`BlockDevice`] to cap how many requests and bytes go through it, for background work
+/// sharing a device with more urgent requests.
+///
+/// Reads and writes wait until the limits allow them, spinning on `clock`, which should be the
+/// same one [`Hal::now`](crate::hal::Hal::now) returns. A clock which never advances, like the
+/// default one, stalls every request once the first burst is used up. Flushes aren't limited.
+///
+/// The deadline versions, e.g. [`Self::read_blocks_before`], give up instead of waiting past a
+/// deadline.
+pub struct ThrottledBlk<D: BlockDevice> {
+    inner: D,
+    clock: fn() -> u64,
+    period: u64,
+    ops: Option<Bucket>,
+    bytes: Option<Bucket>,
+    /// When the buckets were last refilled.
+    last: u64,
+}
+
+impl<D: BlockDevice> ThrottledBlk<D> {
+    /// Limits requests to `inner` to `limits`, measured with `clock`.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if the period or either rate is 0.
+    pub fn new(inner: D, limits: ThrottleLimits, clock: fn() -> u64) -> VirtIoResult<Self> {
+        if limits.period == 0
+            || limits.ops_per_period == Some(0)
+            || limits.bytes_per_period == Some(0)
+        {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Ok(Self {
+            inner,
+            clock,
+            period: limits.period,
+            ops: limits
+                .ops_per_period
+                .map(|rate| Bucket::new(rate, limits.period)),
+            bytes: limits
+                .bytes_per_period
+                .map(|rate| Bucket::new(rate, limits.period)),
+            last: clock(),
+        })
+    }
+
+    /// Returns the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Returns the wrapped device, e.g. to send a request which shouldn't be limited.
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    /// Removes the limits, returning the wrapped device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Returns how long from now a request of `len` bytes may be sent, or 0 if it may be sent
+    /// right away.
+    pub fn delay(&mut self, len: usize) -> u64 {
+        let now = (self.clock)();
+        let elapsed = now.saturating_sub(self.last);
+        self.last = self.last.max(now);
+        let ops = self.ops.as_mut().map(|bucket| (bucket, 1));
+        let bytes = self.bytes.as_mut().map(|bucket| (bucket, len as u64));
+        ops.into_iter()
+            .chain(bytes)
+            .map(|(bucket, amount)| {
+                bucket.refill(elapsed);
+                bucket.wait(amount, self.period)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Like [`BlockDevice::read_blocks`], but returns [`VirtIoError::NotReady`] without reading
+    /// anything if the limits wouldn't let the request through before `deadline`.
+    pub fn read_blocks_before(
+        &mut self,
+        sector: usize,
+        buf: &mut [u8],
+        deadline: u64,
+    ) -> VirtIoResult<()> {
+        self.admit(buf.len(), Some(deadline))?;
+        self.inner.read_blocks(sector, buf)
+    }
+
+    /// Like [`BlockDevice::write_blocks`], but returns [`VirtIoError::NotReady`] without writing
+    /// anything if the limits wouldn't let the request through before `deadline`.
+    pub fn write_blocks_before(
+        &mut self,
+        sector: usize,
+        buf: &[u8],
+        deadline: u64,
+    ) -> VirtIoResult<()> {
+        self.admit(buf.len(), Some(deadline))?;
+        self.inner.write_blocks(sector, buf)
+    }
+
+    /// Waits until a request of `len` bytes may be sent, then takes it from the buckets.
+    fn admit(&mut self, len: usize, deadline: Option<u64>) -> VirtIoResult<()> {
+        loop {
+            let delay = self.delay(len);
+            if delay == 0 {
+                break;
+            }
+            if let Some(deadline) = deadline {
+                if self.last.saturating_add(delay) > deadline {
+                    return Err(VirtIoError::NotReady);
+                }
+            }
+            core::hint::spin_loop();
+        }
+        if let Some(ops) = &mut self.ops {
+            ops.take(1, self.period);
+        }
+        if let Some(bytes) = &mut self.bytes {
+            bytes.take(len as u64, self.period);
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for ThrottledBlk<D> {
+    fn capacity(&self) -> VirtIoResult<u64> {
+        self.inner.capacity()
+    }
+
+    fn readonly(&self) -> bool {
+        self.inner.readonly()
+    }
+
+    fn read_blocks(&mut self, sector: usize, buf: &mut [u8]) -> VirtIoResult<()> {
+        self.admit(buf.len(), None)?;
+        self.inner.read_blocks(sector, buf)
+    }
+
+    fn write_blocks(&mut self, sector: usize, buf: &[u8]) -> VirtIoResult<()> {
+        self.admit(buf.len(), None)?;
+        self.inner.write_blocks(sector, buf)
+    }
+
+    fn flush(&mut self) -> VirtIoResult<()> {
+        self.inner.flush()
+    }
+}
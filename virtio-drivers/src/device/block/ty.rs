@@ -1,6 +1,7 @@
+use crate::endian::{Le32, Le64};
 use crate::error::{VirtIoError, VirtIoResult};
-use crate::transport::mmio::CONFIG_OFFSET;
-use crate::volatile::ReadWrite;
+use crate::transport::DeviceFeatures;
+use crate::volatile::virtio_config;
 use bitflags::bitflags;
 
 bitflags! {
@@ -59,8 +60,12 @@ bitflags! {
     }
 }
 
+format_flags!(BlkFeature);
+
+impl DeviceFeatures for BlkFeature {}
+
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum BlkReqType {
     /// read
     In = 0,
@@ -68,25 +73,63 @@ pub enum BlkReqType {
     Out = 1,
     Flush = 4,
     GetId = 8,
+    #[allow(dead_code)]
     GetLifetime = 10,
     Discard = 11,
     WriteZeroes = 13,
+    #[allow(dead_code)]
     SecureErase = 14,
 }
 
+/// Legacy flag on the request type asking the device to complete all previously submitted
+/// requests before this one, and this one before any later request.
+const BLK_T_BARRIER: u32 = 0x8000_0000;
+
+/// The header of every request.
+///
+/// Legacy devices without `ANY_LAYOUT` expect the header, the data and the status byte in
+/// separate descriptors, which is how every request is sent.
 #[repr(C)]
 #[derive(Debug)]
 pub struct BlkReq {
-    type_: BlkReqType,
-    reserved: u32,
-    sector: u64,
+    type_: Le32,
+    /// Called `ioprio` by legacy devices, which may use it as a priority. Always 0.
+    reserved: Le32,
+    sector: Le64,
 }
 impl BlkReq {
     pub fn new(t: BlkReqType, sector: u64) -> Self {
         Self {
-            type_: t,
-            reserved: 0,
-            sector,
+            type_: Le32::new(t as u32),
+            reserved: Le32::new(0),
+            sector: sector.into(),
+        }
+    }
+
+    /// Marks the request as a barrier, for legacy devices which negotiated
+    /// [`BlkFeature::BARRIER`].
+    pub fn barrier(mut self) -> Self {
+        self.type_ = Le32::new(self.type_.get() | BLK_T_BARRIER);
+        self
+    }
+}
+
+/// The range of sectors a discard or write zeroes request applies to, sent as its data.
+#[repr(C)]
+#[derive(Debug)]
+pub struct BlkDiscardWriteZeroes {
+    sector: Le64,
+    num_sectors: Le32,
+    /// Bit 0 allows a write zeroes request to deallocate the sectors. Always 0.
+    flags: Le32,
+}
+
+impl BlkDiscardWriteZeroes {
+    pub fn new(sector: u64, num_sectors: u32) -> Self {
+        Self {
+            sector: sector.into(),
+            num_sectors: num_sectors.into(),
+            flags: Le32::new(0),
         }
     }
 }
@@ -124,21 +167,67 @@ impl From<BlkRespStatus> for VirtIoResult<()> {
     }
 }
 
-#[derive(Debug, Default)]
-pub struct BlkConfig {
-    pub(super) capacity_low: ReadWrite<CONFIG_OFFSET, u32>,
-    pub(super) capacity_high: ReadWrite<{ CONFIG_OFFSET + 0x4 }, u32>,
-    pub(super) size_max: ReadWrite<{ CONFIG_OFFSET + 0x8 }, u32>,
-    pub(super) seg_max: ReadWrite<{ CONFIG_OFFSET + 0xc }, u32>,
-    // cylinders: ReadWrite<{ CONFIG_OFFSET +  }>,
-    // heads: ReadWrite<u8>,
-    // sectors: ReadWrite<u8>,
-    pub(super) geometry: ReadWrite<{ CONFIG_OFFSET + 0x10 }, u32>,
-    pub(super) blk_size: ReadWrite<{ CONFIG_OFFSET + 0x14 }, u32>,
-    // physical_block_exp: ReadWrite<u8>,
-    // alignment_offset: ReadWrite<u8>,
-    // min_io_size: ReadWrite<u16>,
-    pub(super) topology: ReadWrite<{ CONFIG_OFFSET + 0x18 }, u32>,
-    pub(super) opt_io_size: ReadWrite<{ CONFIG_OFFSET + 0x1c }, u32>,
-    // ...
+virtio_config! {
+    pub struct BlkConfig {
+        pub(super) capacity: ReadOnly<u64> @ 0x0,
+        pub(super) size_max: ReadOnly<u32> @ 0x8,
+        pub(super) seg_max: ReadOnly<u32> @ 0xc,
+        pub(super) num_queues: ReadOnly<u16> @ 0x22,
+        pub(super) max_discard_sectors: ReadOnly<u32> @ 0x24,
+        pub(super) max_write_zeroes_sectors: ReadOnly<u32> @ 0x30,
+        // ...
+    }
+}
+
+/// The length of the buffer a device writes its ID into, see [`DeviceId`].
+///
+/// Ref: 5.2.6 Device Operation
+pub const DEVICE_ID_LEN: usize = 20;
+
+/// The ID string of a block device, as returned by
+/// [`VirtIOBlk::device_id`](super::VirtIOBlk::device_id).
+///
+/// The device fills in up to [`DEVICE_ID_LEN`] bytes, and only terminates the ID with a NUL if it
+/// is shorter than that.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DeviceId {
+    bytes: [u8; DEVICE_ID_LEN],
+    len: usize,
+}
+
+impl DeviceId {
+    /// Takes the ID from the buffer the device wrote it into, up to the first NUL if any.
+    pub(super) fn from_buffer(buffer: &[u8; DEVICE_ID_LEN]) -> Self {
+        let len = buffer
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(DEVICE_ID_LEN);
+        Self {
+            bytes: *buffer,
+            len,
+        }
+    }
+
+    /// Returns the bytes of the ID, without any NUL terminator.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.get(..self.len).unwrap_or(&self.bytes)
+    }
+
+    /// Returns the ID as a string, or `None` if the device sent something other than UTF-8.
+    ///
+    /// The spec doesn't say what the ID holds, but devices usually send ASCII, e.g. a serial
+    /// number.
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(self.as_bytes()).ok()
+    }
+
+    /// Returns the length of the ID in bytes, at most [`DEVICE_ID_LEN`].
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the device sent an empty ID.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 }
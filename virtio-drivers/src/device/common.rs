@@ -0,0 +1,46 @@
+//! Helpers shared by the device drivers.
+
+use crate::error::{VirtIoError, VirtIoResult};
+use crate::hal::{DmaBuf, Hal};
+use crate::queue::VirtIoQueue;
+use crate::transport::Transport;
+use alloc::vec::Vec;
+
+/// Sends a request made of a header for the device to read, the given data descriptors and a
+/// response for the device to fill in, blocks until the device has used it, then passes the
+/// response to `check` to turn it into the result.
+///
+/// This is the shape of most synchronous requests, e.g. a block request with its status byte or a
+/// GPU command with its response header. The device may not fill in the response if it fails, so
+/// `response` should start out as a value `check` rejects.
+///
+/// If `check` fails and the device has set DEVICE_NEEDS_RESET, [`VirtIoError::NeedsReset`] is
+/// returned instead, see [`or_needs_reset`].
+pub(crate) fn request_response<H, T, Req, Resp, R, const SIZE: usize>(
+    queue: &mut VirtIoQueue<H, SIZE>,
+    transport: &mut T,
+    request: &Req,
+    data: Vec<DmaBuf<'_>>,
+    mut response: Resp,
+    check: impl FnOnce(Resp) -> VirtIoResult<R>,
+) -> VirtIoResult<R>
+where
+    H: Hal<SIZE>,
+    T: Transport,
+    Req: ?Sized,
+{
+    let mut descriptors = Vec::with_capacity(data.len() + 2);
+    descriptors.push(DmaBuf::readable(request));
+    descriptors.extend(data);
+    descriptors.push(DmaBuf::writable(&mut response));
+    queue.add_notify_wait_pop(transport, descriptors)?;
+    check(response).map_err(|e| or_needs_reset(transport, e))
+}
+
+/// Returns [`VirtIoError::NeedsReset`] instead of `error` if the device has asked to be reset.
+///
+/// A device whose backend went away, e.g. because it restarted on the host, may fail requests with
+/// an I/O error and also set DEVICE_NEEDS_RESET, which tells the caller how to recover.
+pub(crate) fn or_needs_reset<T: Transport>(transport: &T, error: VirtIoError) -> VirtIoError {
+    transport.check_needs_reset().err().unwrap_or(error)
+}
@@ -0,0 +1,148 @@
+//! A fixed-size ring for handing completions from an interrupt handler to the thread which
+//! submitted the requests, without a lock around the whole driver.
+//!
+//! The interrupt handler finds used buffers (e.g. with [`VirtIOBlk::peek_used`]) and pushes their
+//! tokens through a [`Producer`], and the submitting thread drains them through the
+//! [`Consumer`]. There may only be one of each, which [`CompletionRing::split`] enforces.
+//!
+//! [`VirtIOBlk::peek_used`]: super::block::VirtIOBlk::peek_used
+
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// A completed request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Completion {
+    /// The token returned when the request was submitted.
+    pub token: u16,
+    /// The number of bytes the device wrote, or 0 if the driver doesn't report it.
+    pub len: u32,
+}
+
+struct Slot {
+    token: AtomicU32,
+    len: AtomicU32,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            token: AtomicU32::new(0),
+            len: AtomicU32::new(0),
+        }
+    }
+}
+
+/// A single-producer single-consumer ring of up to `N` completions.
+///
+/// `N` must be a power of two, so the indices can wrap around.
+pub struct CompletionRing<const N: usize> {
+    slots: [Slot; N],
+    /// The number of completions ever popped, only written by the consumer.
+    head: AtomicUsize,
+    /// The number of completions ever pushed, only written by the producer.
+    tail: AtomicUsize,
+}
+
+impl<const N: usize> CompletionRing<N> {
+    /// Creates an empty ring.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` isn't a power of two.
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "ring size must be a power of two");
+        Self {
+            slots: [const { Slot::new() }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Splits the ring into its two ends.
+    ///
+    /// To use them from an interrupt handler the ring has to live for the rest of the program,
+    /// e.g. by leaking a `Box` of it.
+    pub fn split(&mut self) -> (Producer<'_, N>, Consumer<'_, N>) {
+        (Producer { ring: self }, Consumer { ring: self })
+    }
+
+    /// The number of completions which have been pushed but not popped yet.
+    pub fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    /// Whether there are no completions waiting.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<const N: usize> Default for CompletionRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The end of a [`CompletionRing`] which completions are pushed into, e.g. by an interrupt
+/// handler.
+pub struct Producer<'a, const N: usize> {
+    ring: &'a CompletionRing<N>,
+}
+
+impl<const N: usize> Producer<'_, N> {
+    /// Pushes a completion, or gives it back if the ring is full.
+    ///
+    /// Never blocks, so it may be called from an interrupt handler.
+    pub fn push(&mut self, completion: Completion) -> Result<(), Completion> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        // Acquire, so the consumer has finished reading a slot before it is overwritten.
+        if tail.wrapping_sub(self.ring.head.load(Ordering::Acquire)) == N {
+            return Err(completion);
+        }
+        let slot = &self.ring.slots[tail % N];
+        slot.token.store(completion.token.into(), Ordering::Relaxed);
+        slot.len.store(completion.len, Ordering::Relaxed);
+        // Release, so the consumer sees the slot once it sees the new tail.
+        self.ring
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// The ring this is the producer of.
+    pub fn ring(&self) -> &CompletionRing<N> {
+        self.ring
+    }
+}
+
+/// The end of a [`CompletionRing`] which completions are popped from, by the thread which
+/// submitted the requests.
+pub struct Consumer<'a, const N: usize> {
+    ring: &'a CompletionRing<N>,
+}
+
+impl<const N: usize> Consumer<'_, N> {
+    /// Pops the oldest completion, if there is one.
+    pub fn pop(&mut self) -> Option<Completion> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        if head == self.ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = &self.ring.slots[head % N];
+        let completion = Completion {
+            token: slot.token.load(Ordering::Relaxed) as u16,
+            len: slot.len.load(Ordering::Relaxed),
+        };
+        self.ring
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(completion)
+    }
+
+    /// The ring this is the consumer of.
+    pub fn ring(&self) -> &CompletionRing<N> {
+        self.ring
+    }
+}
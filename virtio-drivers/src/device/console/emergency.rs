@@ -0,0 +1,63 @@
+//! Output through the console's emergency write register, for panic handlers and early boot.
+
+use super::ty::{ConsoleConfig, ConsoleFeatures};
+use crate::error::{InitStep, VirtIoError, VirtIoResult};
+use crate::hal::VirtIoDeviceIo;
+use crate::transport::Transport;
+use crate::volatile::WriteVolatile;
+use core::fmt;
+
+/// Writes to a console device through its `emerg_wr` config field, one byte at a time.
+///
+/// This needs no queues, DMA memory or allocation, so it keeps working when the normal driver
+/// can't be used, e.g. because its lock is held by the code which panicked.
+pub struct PanicConsole<'a> {
+    io_region: &'a dyn VirtIoDeviceIo,
+    config: ConsoleConfig,
+}
+
+impl<'a> PanicConsole<'a> {
+    /// Uses the registers of a console which already negotiated
+    /// [`ConsoleFeatures::EMERG_WRITE`], such as one driven by [`VirtIOConsole`] on a device
+    /// offering it.
+    ///
+    /// Writes are silently dropped by devices which didn't negotiate it.
+    ///
+    /// [`VirtIOConsole`]: super::VirtIOConsole
+    pub fn new(io_region: &'a dyn VirtIoDeviceIo) -> Self {
+        Self {
+            io_region,
+            config: ConsoleConfig::default(),
+        }
+    }
+
+    /// Initializes an otherwise unused console device with no queues, just so it can be written
+    /// to in an emergency, e.g. before the full driver is set up.
+    ///
+    /// Returns [`VirtIoError::Unsupported`] if the device doesn't offer
+    /// [`ConsoleFeatures::EMERG_WRITE`].
+    pub fn init<T: Transport>(transport: &'a mut T) -> VirtIoResult<Self> {
+        let negotiated_features = transport.init_step(InitStep::Negotiation, |t| {
+            t.begin_init(ConsoleFeatures::EMERG_WRITE)
+        })?;
+        if !negotiated_features.contains(ConsoleFeatures::EMERG_WRITE) {
+            return Err(VirtIoError::Unsupported);
+        }
+        transport.init_step(InitStep::DriverOk, T::finish_init)?;
+        Ok(Self::new(transport.io_region()))
+    }
+
+    /// Writes the given bytes to the console.
+    pub fn write_bytes(&self, bytes: &[u8]) -> VirtIoResult<()> {
+        for &b in bytes {
+            self.config.emerg_wr.write(b as u32, self.io_region)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Write for PanicConsole<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
@@ -0,0 +1,120 @@
+//! A [`log::Log`] implementation which writes records to a virtio console.
+
+use super::{VirtIOConsole, QUEUE_SIZE};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use alloc::string::String;
+use core::fmt::Write;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use spin::Mutex;
+
+/// The console being logged to, and the buffer records are formatted into.
+struct Inner<H: Hal<QUEUE_SIZE>, T: Transport> {
+    console: Option<VirtIOConsole<H, T>>,
+    /// Kept between records, so formatting one doesn't allocate once it has grown large enough.
+    line: String,
+}
+
+/// Routes the [`log`] crate's records to a [`VirtIOConsole`], one line per record.
+///
+/// It is meant to live in a `static`, so setting it up takes two lines:
+///
+/// ```ignore
+/// static LOGGER: VirtIoConsoleLogger<HalImpl, MmioTransport> =
+///     VirtIoConsoleLogger::new(LevelFilter::Info);
+/// LOGGER.init(console)?;
+/// ```
+///
+/// Each record is formatted into a buffer and sent as a whole, so records from different CPUs
+/// never interleave. Sending only waits if every transmit buffer of the console is still in use;
+/// [`Log::flush`] waits until the device has taken everything.
+///
+/// The console is behind a spin lock, so nothing may log to it from an interrupt handler which can
+/// interrupt a record being written on the same CPU. Records this crate logs while the lock is
+/// held, e.g. about an error of the console itself, are dropped rather than deadlocking.
+pub struct VirtIoConsoleLogger<H: Hal<QUEUE_SIZE>, T: Transport> {
+    inner: Mutex<Inner<H, T>>,
+    level: LevelFilter,
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIoConsoleLogger<H, T> {
+    /// Creates a logger for records up to `level`, with no console attached yet.
+    pub const fn new(level: LevelFilter) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                console: None,
+                line: String::new(),
+            }),
+            level,
+        }
+    }
+
+    /// Attaches `console`, returning the one it replaces if there was one.
+    ///
+    /// Records logged while no console is attached are dropped.
+    pub fn attach(&self, console: VirtIOConsole<H, T>) -> Option<VirtIOConsole<H, T>> {
+        self.inner.lock().console.replace(console)
+    }
+
+    /// Detaches the console, e.g. to reset it or to read from it, returning it if there was one.
+    pub fn detach(&self) -> Option<VirtIOConsole<H, T>> {
+        self.inner.lock().console.take()
+    }
+
+    /// Runs `f` on the attached console, if there is one, e.g. to read input from it without
+    /// detaching it.
+    pub fn with_console<R>(&self, f: impl FnOnce(&mut VirtIOConsole<H, T>) -> R) -> Option<R> {
+        self.inner.lock().console.as_mut().map(f)
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport + Send + 'static> VirtIoConsoleLogger<H, T> {
+    /// Attaches `console` and makes this the logger of the [`log`] crate, with its maximum level
+    /// set to the one given to [`Self::new`].
+    ///
+    /// Returns an error, without attaching the console, if a logger was already set.
+    pub fn init(&'static self, console: VirtIOConsole<H, T>) -> Result<(), SetLoggerError> {
+        log::set_logger(self)?;
+        self.attach(console);
+        log::set_max_level(self.level);
+        Ok(())
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport + Send> Log for VirtIoConsoleLogger<H, T> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // The console itself logs through this crate, possibly while the lock is held.
+        let mut inner = if record.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            match self.inner.try_lock() {
+                Some(inner) => inner,
+                None => return,
+            }
+        } else {
+            self.inner.lock()
+        };
+        let Inner { console, line } = &mut *inner;
+        let Some(console) = console else {
+            return;
+        };
+        line.clear();
+        // Ended with CRLF, so the lines also show up right on a terminal in raw mode.
+        let (level, target) = (record.level(), record.target());
+        if write!(line, "[{:>5} {}] {}\r\n", level, target, record.args()).is_ok() {
+            // There is nowhere to report the error to.
+            let _ = console.send_slice(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(console) = &mut self.inner.lock().console {
+            let _ = console.flush_tx();
+        }
+    }
+}
@@ -1,59 +1,194 @@
+mod emergency;
+#[cfg(feature = "console-log")]
+mod logger;
 mod ty;
 
-use crate::error::VirtIoResult;
-use crate::hal::Hal;
-use crate::queue::{DescFlag, Descriptor, VirtIoQueue};
-use crate::transport::Transport;
-use crate::volatile::ReadVolatile;
+use crate::device::{DeviceIdentity, VirtIoDriver};
+use crate::error::{expect_ok, InitStep, VirtIoError, VirtIoResult};
+use crate::hal::{DmaBuf, Hal, MemoryRequirements};
+use crate::queue::{EventSuppression, NeedsResetCheck, QueueInfo, QueueStats, VirtIoQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
 use crate::PAGE_SIZE;
 use alloc::boxed::Box;
 use alloc::vec;
-use log::info;
+use alloc::vec::Vec;
+use core::fmt;
 use ty::*;
 
+pub use emergency::PanicConsole;
+#[cfg(feature = "console-log")]
+pub use logger::VirtIoConsoleLogger;
+pub use ty::ConsoleFeatures;
+
 const QUEUE_RECEIVEQ_PORT_0: u16 = 0;
 const QUEUE_TRANSMITQ_PORT_0: u16 = 1;
-const QUEUE_SIZE: usize = 2;
-const SUPPORTED_FEATURES: ConsoleFeatures = ConsoleFeatures::empty();
+const QUEUE_SIZE: usize = 4;
+/// The number of transmit requests which may be outstanding at the same time.
+///
+/// Every transmit request uses a single descriptor, so the whole transmit queue can be in flight.
+const TX_SLOTS: usize = QUEUE_SIZE;
+/// The features [`VirtIOConsole::new`] negotiates if the device offers them.
+///
+/// Emergency writes are negotiated so that a [`PanicConsole`] can share the device.
+pub const SUPPORTED_FEATURES: ConsoleFeatures =
+    ConsoleFeatures::SIZE.union(ConsoleFeatures::EMERG_WRITE);
+
+/// A transmit buffer owned by the driver, and the token of the request using it if any.
+struct TxSlot {
+    buf: Box<[u8; PAGE_SIZE]>,
+    token: Option<u16>,
+}
 
 pub struct VirtIOConsole<H: Hal<QUEUE_SIZE>, T: Transport> {
     transport: T,
+    negotiated_features: ConsoleFeatures,
+    /// The features to negotiate if the device offers them, kept for [`Self::reset`].
+    supported_features: ConsoleFeatures,
     config_space: ConsoleConfig,
     receiveq: VirtIoQueue<H, QUEUE_SIZE>,
     transmitq: VirtIoQueue<H, QUEUE_SIZE>,
+    /// Copied from the queues when they were created, for [`Self::queues`].
+    queue_info: Vec<QueueInfo>,
     queue_buf_rx: Box<[u8; PAGE_SIZE]>,
     cursor: usize,
     pending_len: usize,
     /// The token of the outstanding receive request, if there is one.
     receive_token: Option<u16>,
+    /// Transmit buffers, each of which may back one outstanding transmit request.
+    tx_slots: [TxSlot; TX_SLOTS],
 }
 
 impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOConsole<H, T> {
+    /// The memory [`Self::new`] allocates: the receive and transmit queues, and a page for
+    /// receiving plus one per transmit slot.
+    pub const fn memory_requirements() -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(2).with_shared_heap((1 + TX_SLOTS) * PAGE_SIZE)
+    }
+
     /// Create a new VirtIO console driver.
-    pub fn new(mut transport: T) -> VirtIoResult<Self> {
-        let _negotiated_features = transport.begin_init(SUPPORTED_FEATURES)?;
-        let config_space = ConsoleConfig::default();
-        let receiveq = VirtIoQueue::new(&mut transport, QUEUE_RECEIVEQ_PORT_0)?;
-        let transmitq = VirtIoQueue::new(&mut transport, QUEUE_TRANSMITQ_PORT_0)?;
-        transport.finish_init()?;
+    pub fn new(transport: T) -> VirtIoResult<Self> {
+        Self::new_with_features(transport, SUPPORTED_FEATURES)
+    }
+
+    /// Creates a new VirtIO console driver which only negotiates the features in `wanted`, e.g.
+    /// without [`ConsoleFeatures::EMERG_WRITE`] if nothing will use a [`PanicConsole`].
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `wanted` isn't a subset of
+    /// [`SUPPORTED_FEATURES`].
+    pub fn new_with_features(mut transport: T, wanted: ConsoleFeatures) -> VirtIoResult<Self> {
+        if !SUPPORTED_FEATURES.contains(wanted) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let (negotiated_features, receiveq, transmitq) = Self::setup(&mut transport, wanted)?;
+        let queue_info = vec![receiveq.info(), transmitq.info()];
         Ok(Self {
             transport,
-            config_space,
+            negotiated_features,
+            supported_features: wanted,
+            config_space: ConsoleConfig::default(),
             receiveq,
             transmitq,
+            queue_info,
             queue_buf_rx: Box::new([0; PAGE_SIZE]),
             cursor: 0,
             pending_len: 0,
             receive_token: None,
+            tx_slots: core::array::from_fn(|_| TxSlot {
+                buf: Box::new([0; PAGE_SIZE]),
+                token: None,
+            }),
         })
     }
 
+    /// Negotiates features and sets up the queues, through to `DRIVER_OK`.
+    fn setup(
+        transport: &mut T,
+        supported_features: ConsoleFeatures,
+    ) -> VirtIoResult<(
+        ConsoleFeatures,
+        VirtIoQueue<H, QUEUE_SIZE>,
+        VirtIoQueue<H, QUEUE_SIZE>,
+    )> {
+        let negotiated_features = transport
+            .init_step(InitStep::Negotiation, |t| {
+                t.begin_init_with_fallback(ConsoleFeatures::empty(), supported_features)
+            })?
+            .features;
+        let (receiveq, transmitq) = transport.init_step(InitStep::QueueSetup, |t| {
+            Ok((
+                VirtIoQueue::new(t, QUEUE_RECEIVEQ_PORT_0)?,
+                VirtIoQueue::new(t, QUEUE_TRANSMITQ_PORT_0)?,
+            ))
+        })?;
+        transport.init_step(InitStep::DriverOk, T::finish_init)?;
+        Ok((negotiated_features, receiveq, transmitq))
+    }
+
+    /// Resets the device and initializes it again, e.g. to recover once it has set
+    /// `DEVICE_NEEDS_RESET`, without recreating the transport.
+    ///
+    /// Received characters which haven't been read yet and output which hasn't been sent yet are
+    /// lost. If this fails the device is marked `FAILED`, and the driver may only be reset again
+    /// or dropped.
+    pub fn reset(&mut self) -> VirtIoResult<()> {
+        // The device must let go of the old queues before they are freed.
+        self.transport.reset()?;
+        self.receive_token = None;
+        self.cursor = 0;
+        self.pending_len = 0;
+        for slot in &mut self.tx_slots {
+            slot.token = None;
+        }
+        let (negotiated_features, receiveq, transmitq) =
+            Self::setup(&mut self.transport, self.supported_features)?;
+        self.queue_info = vec![receiveq.info(), transmitq.info()];
+        self.negotiated_features = negotiated_features;
+        self.receiveq = receiveq;
+        self.transmitq = transmitq;
+        Ok(())
+    }
+
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> ConsoleFeatures {
+        self.negotiated_features
+    }
+
+    /// Returns the transport, e.g. to read the device status from a power management hook.
+    ///
+    /// Only shared access is given, so the device can't be reconfigured behind the driver's back.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        &self.queue_info
+    }
+
     /// Returns a struct with information about the console device, such as the number of rows and columns.
+    ///
+    /// The size is only known if [`ConsoleFeatures::SIZE`] was negotiated and is 0 x 0 otherwise.
+    /// Likewise, `max_ports` is 1 unless the device supports [`ConsoleFeatures::MULTIPORT`].
     pub fn info(&self) -> VirtIoResult<ConsoleInfo> {
-        let io_region = self.transport.io_region();
-        let columns = self.config_space.cols.read(io_region)?;
-        let rows = self.config_space.rows.read(io_region)?;
-        let max_ports = self.config_space.max_nr_ports.read(io_region)?;
+        let has_size = self.negotiated_features.contains(ConsoleFeatures::SIZE);
+        let columns = self
+            .config_space
+            .cols
+            .read_optional(&self.transport, has_size)?;
+        let rows = self
+            .config_space
+            .rows
+            .read_optional(&self.transport, has_size)?;
+        let (columns, rows) = columns.zip(rows).unwrap_or((0, 0));
+        let max_ports = self
+            .config_space
+            .max_nr_ports
+            .read_optional(
+                &self.transport,
+                self.negotiated_features
+                    .contains(ConsoleFeatures::MULTIPORT),
+            )?
+            .unwrap_or(1);
         Ok(ConsoleInfo {
             rows,
             columns,
@@ -66,24 +201,14 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOConsole<H, T> {
         // if receive_token is None, it means there is no outstanding receive request.
         // if cursor == pending_len, it means all data has been received.
         if self.receive_token.is_none() && self.cursor == self.pending_len {
-            info!("poll_retrieve");
             // Safe because the buffer lasts at least as long as the queue, and there are no other
             // outstanding requests using the buffer.
-            let req = Descriptor::new::<QUEUE_SIZE, H>(
-                self.queue_buf_rx.as_ptr() as _,
-                self.queue_buf_rx.len() as _,
-                DescFlag::WRITE,
-            );
-            // let token = self.receiveq.add(vec![req])?;
-            let l = self
-                .receiveq
-                .add_notify_wait_pop(&mut self.transport, vec![req])?;
-            // if self.receiveq.should_notify() {
-            //     info!("notify QUEUE_RECEIVEQ_PORT_0");
-            //     self.transport.notify(QUEUE_RECEIVEQ_PORT_0)?;
-            // }
-            info!("poll_retrieve: l: {:?}", l);
-            self.receive_token = Some(0);
+            let req = DmaBuf::writable(&mut self.queue_buf_rx[..]);
+            let token = self.receiveq.add(vec![req])?;
+            if self.receiveq.should_notify() {
+                self.transport.notify(QUEUE_RECEIVEQ_PORT_0)?;
+            }
+            self.receive_token = Some(token);
         }
         Ok(())
     }
@@ -94,15 +219,9 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOConsole<H, T> {
     fn finish_receive(&mut self) -> VirtIoResult<bool> {
         let mut flag = false;
         if let Some(receive_token) = self.receive_token {
-            let peek_used = self.receiveq.peek_used();
-            info!(
-                "finish_receive: receive_token: {:?}, peek_used: {:?}",
-                receive_token, peek_used
-            );
-            if self.receive_token == self.receiveq.peek_used() {
+            if self.receiveq.can_pop(receive_token)? {
                 let len = self.receiveq.pop_used(receive_token)?;
                 flag = true;
-                assert_ne!(len, 0);
                 self.cursor = 0;
                 self.pending_len = len as usize;
                 // Clear `receive_token` so that when the buffer is used up the next call to
@@ -129,12 +248,36 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOConsole<H, T> {
         Ok(Some(ch))
     }
 
+    /// Returns the next character from the console, waiting for one until [`Hal::now`] reaches
+    /// `deadline`, or `Ok(None)` if none was received by then.
+    ///
+    /// Giving up doesn't lose input: the receive request stays queued, and a character arriving
+    /// later is returned by the next receive. A loop can thus wait for input with a short deadline
+    /// and check for other events, like a pending Ctrl-C, in between.
+    pub fn try_recv_block_with_deadline(&mut self, deadline: u64) -> VirtIoResult<Option<u8>> {
+        loop {
+            self.finish_receive()?;
+            self.poll_retrieve()?;
+            if self.cursor != self.pending_len {
+                let ch = self.queue_buf_rx[self.cursor];
+                self.cursor += 1;
+                return Ok(Some(ch));
+            }
+            if H::now() >= deadline {
+                return Ok(None);
+            }
+            H::wait_for_used();
+        }
+    }
+
     pub fn recv_block(&mut self) -> VirtIoResult<u8> {
+        let mut check = NeedsResetCheck::default();
         loop {
             self.finish_receive()?;
             self.poll_retrieve()?;
             if self.cursor == self.pending_len {
-                // info!("cursor == pending_len");
+                check.tick(&self.transport)?;
+                H::wait_for_used();
                 continue;
             }
             let ch = self.queue_buf_rx[self.cursor];
@@ -143,38 +286,214 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOConsole<H, T> {
         }
     }
 
+    /// Returns the next character from the console, yielding to the executor until one is
+    /// received.
+    ///
+    /// The task is woken when [`Self::ack_interrupt`] finds the receive request completed, so the
+    /// interrupt handler must call it for the future to make progress.
+    pub async fn recv_async(&mut self) -> VirtIoResult<u8> {
+        loop {
+            self.finish_receive()?;
+            self.poll_retrieve()?;
+            if self.cursor != self.pending_len {
+                let ch = self.queue_buf_rx[self.cursor];
+                self.cursor += 1;
+                return Ok(ch);
+            }
+            if let Some(token) = self.receive_token {
+                self.receiveq.wait_used(token).await?;
+            }
+        }
+    }
+
     /// Sends a character to the console.
+    ///
+    /// This doesn't wait for the device to consume the character, see [`Self::send_slice`].
     pub fn send(&mut self, chr: u8) -> VirtIoResult<()> {
-        let buf: [u8; 1] = [chr];
-        let desc =
-            Descriptor::new::<QUEUE_SIZE, H>(buf.as_ptr() as _, buf.len() as _, DescFlag::EMPTY);
-        self.transmitq
-            .add_notify_wait_pop(&mut self.transport, vec![desc])?;
-        info!("send char: {:?}", chr as char);
+        self.send_slice(&[chr])
+    }
+
+    /// Sends a slice of bytes to the console.
+    ///
+    /// The data is copied into one of the driver's transmit buffers, so up to `TX_SLOTS` sends may
+    /// be outstanding at the same time. This only blocks if every buffer is still in use by the
+    /// device, until one of them completes.
+    pub fn send_slice(&mut self, data: &[u8]) -> VirtIoResult<()> {
+        let mut check = NeedsResetCheck::default();
+        for chunk in data.chunks(PAGE_SIZE) {
+            let slot = loop {
+                self.reclaim_tx()?;
+                // The queue may have fewer descriptors than there are slots.
+                let mut slots = self.tx_slots.iter().take(self.transmitq.size().into());
+                if let Some(slot) = slots.position(|s| s.token.is_none()) {
+                    break slot;
+                }
+                check.tick(&self.transport)?;
+                H::wait_for_used();
+            };
+            let slot = self
+                .tx_slots
+                .get_mut(slot)
+                .ok_or(VirtIoError::InvalidParam)?;
+            let buf = slot
+                .buf
+                .get_mut(..chunk.len())
+                .ok_or(VirtIoError::InvalidParam)?;
+            buf.copy_from_slice(chunk);
+            let desc = DmaBuf::readable(&*buf);
+            let token = self.transmitq.add(vec![desc])?;
+            slot.token = Some(token);
+            if self.transmitq.should_notify() {
+                self.transport.notify(QUEUE_TRANSMITQ_PORT_0)?;
+            }
+        }
         Ok(())
     }
 
+    /// Blocks until the device has consumed all outstanding transmit requests.
+    pub fn flush_tx(&mut self) -> VirtIoResult<()> {
+        let mut check = NeedsResetCheck::default();
+        while self.tx_slots.iter().any(|s| s.token.is_some()) {
+            if self.reclaim_tx()? == 0 {
+                check.tick(&self.transport)?;
+                H::wait_for_used();
+            }
+        }
+        Ok(())
+    }
+
+    /// Completes every finished transmit request, freeing its buffer for reuse.
+    ///
+    /// Returns the number of requests completed.
+    fn reclaim_tx(&mut self) -> VirtIoResult<usize> {
+        let mut reclaimed = 0;
+        for slot in self.tx_slots.iter_mut() {
+            if let Some(token) = slot.token {
+                if self.transmitq.can_pop(token)? {
+                    self.transmitq.pop_used(token)?;
+                    slot.token = None;
+                    reclaimed += 1;
+                }
+            }
+        }
+        Ok(reclaimed)
+    }
+
     /// Acknowledges a pending interrupt, if any, and completes the outstanding finished read
     /// request if there is one.
     ///
     /// Returns true if new data has been received.
+    ///
+    /// A [`InterruptStatus::CONFIGURATION_CHANGE`] is only reported through
+    /// [`VirtIoDriver::ack_interrupt`], after which [`Self::handle_config_change`] returns the new
+    /// size.
     pub fn ack_interrupt(&mut self) -> VirtIoResult<bool> {
-        if !self.transport.ack_interrupt()? {
+        if self.transport.ack_interrupt()?.is_empty() {
+            return Ok(false);
+        }
+        self.finish_receive()
+    }
+
+    /// Reads the size again after an interrupt with [`InterruptStatus::CONFIGURATION_CHANGE`],
+    /// e.g. because the host terminal was resized.
+    pub fn handle_config_change(&mut self) -> VirtIoResult<ConsoleInfo> {
+        self.info()
+    }
+
+    /// Like [`Self::ack_interrupt`], but only checks the interrupt status without acknowledging
+    /// it, for polling the device before its interrupt is routed.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        if !self
+            .transport
+            .peek_interrupt_status()?
+            .contains(InterruptStatus::USED_RING_UPDATE)
+        {
             return Ok(false);
         }
         self.finish_receive()
     }
+
+    /// Processes completions on a single queue, for transports which deliver a separate interrupt
+    /// per queue.
+    ///
+    /// Returns true if new data has been received.
+    pub fn handle_queue_interrupt(&mut self, queue: u16) -> VirtIoResult<bool> {
+        match queue {
+            QUEUE_RECEIVEQ_PORT_0 => self.finish_receive(),
+            QUEUE_TRANSMITQ_PORT_0 => self.reclaim_tx().map(|_| false),
+            _ => Err(VirtIoError::InvalidParam),
+        }
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> fmt::Write for VirtIOConsole<H, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.send_slice(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIoDriver for VirtIOConsole<H, T> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Console
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        let status = self.transport.ack_interrupt()?;
+        if !status.is_empty() {
+            self.finish_receive()?;
+        }
+        Ok(status)
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        VirtIOConsole::poll_interrupt(self)
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            device_type: DeviceType::Console,
+            bus_addr: self.transport.io_region().paddr(),
+            features: self.negotiated_features.bits(),
+        }
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        vec![self.receiveq.stats(), self.transmitq.stats()]
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        match queue {
+            QUEUE_RECEIVEQ_PORT_0 => self.receiveq.set_event_suppression(suppression),
+            QUEUE_TRANSMITQ_PORT_0 => self.transmitq.set_event_suppression(suppression),
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.transport.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.transport.reset()
+    }
 }
 
 impl<H: Hal<QUEUE_SIZE>, T: Transport> Drop for VirtIOConsole<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport
-            .queue_unset(QUEUE_RECEIVEQ_PORT_0)
-            .expect("failed to unset receive queue");
-        self.transport
-            .queue_unset(QUEUE_TRANSMITQ_PORT_0)
-            .expect("failed to unset transmit queue")
+        expect_ok(
+            self.transport.queue_unset(QUEUE_RECEIVEQ_PORT_0),
+            "failed to unset receive queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_TRANSMITQ_PORT_0),
+            "failed to unset transmit queue",
+        );
     }
 }
@@ -1,12 +1,13 @@
-use crate::transport::mmio::CONFIG_OFFSET;
-use crate::volatile::{ReadOnly, WriteOnly};
+use crate::transport::DeviceFeatures;
+use crate::volatile::virtio_config;
 use bitflags::bitflags;
-#[derive(Debug, Default)]
-pub struct ConsoleConfig {
-    pub(super) cols: ReadOnly<CONFIG_OFFSET, u16>,
-    pub(super) rows: ReadOnly<{ CONFIG_OFFSET + 2 }, u16>,
-    pub(super) max_nr_ports: ReadOnly<{ CONFIG_OFFSET + 4 }, u32>,
-    pub(super) emerg_wr: WriteOnly<{ CONFIG_OFFSET + 8 }, u32>,
+virtio_config! {
+    pub struct ConsoleConfig {
+        pub(super) cols: ReadOnly<u16> @ 0x0,
+        pub(super) rows: ReadOnly<u16> @ 0x2,
+        pub(super) max_nr_ports: ReadOnly<u32> @ 0x4,
+        pub(super) emerg_wr: WriteOnly<u32> @ 0x8,
+    }
 }
 
 /// Information about a console device, read from its configuration space.
@@ -44,3 +45,7 @@ bitflags! {
         const NOTIFICATION_DATA     = 1 << 38;
     }
 }
+
+format_flags!(ConsoleFeatures);
+
+impl DeviceFeatures for ConsoleFeatures {}
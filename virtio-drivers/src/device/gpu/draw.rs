@@ -0,0 +1,190 @@
+//! Software drawing helpers on top of a GPU framebuffer.
+
+use super::Format;
+use crate::error::{VirtIoError, VirtIoResult};
+
+/// A color with 8 bits per channel.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Rgba {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Rgba {
+    /// Create an opaque color.
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b, a: 0xff }
+    }
+}
+
+/// A view of a framebuffer which knows its dimensions, row pitch and pixel format.
+///
+/// Every drawing operation is clipped to the framebuffer, so callers can pass rectangles which are
+/// partly off screen.
+pub struct Canvas<'a> {
+    buf: &'a mut [u8],
+    width: u32,
+    height: u32,
+    /// The number of bytes between the start of two consecutive rows.
+    pitch: usize,
+    format: Format,
+}
+
+impl<'a> Canvas<'a> {
+    /// Wrap `buf` as a framebuffer of `width` x `height` pixels in the given format.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `pitch` is too small for a row or `buf` is too
+    /// small for all rows, or if either size overflows.
+    pub fn new(
+        buf: &'a mut [u8],
+        width: u32,
+        height: u32,
+        pitch: usize,
+        format: Format,
+    ) -> VirtIoResult<Self> {
+        let row_len = (width as usize).checked_mul(format.bytes_per_pixel());
+        let len = pitch.checked_mul(height as usize);
+        match (row_len, len) {
+            (Some(row_len), Some(len)) if pitch >= row_len && buf.len() >= len => {}
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        Ok(Self {
+            buf,
+            width,
+            height,
+            pitch,
+            format,
+        })
+    }
+
+    /// The width of the framebuffer in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// The height of the framebuffer in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The pixel format of the framebuffer.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Set a single pixel, ignoring coordinates outside the framebuffer.
+    pub fn put_pixel(&mut self, x: u32, y: u32, color: Rgba) {
+        if x < self.width && y < self.height {
+            let offset = y as usize * self.pitch + x as usize * self.format.bytes_per_pixel();
+            self.write_pixel(offset, color);
+        }
+    }
+
+    /// Fill a rectangle with a single color.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if the offset of a pixel overflows.
+    pub fn fill_rect(
+        &mut self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        color: Rgba,
+    ) -> VirtIoResult<()> {
+        let (x_end, y_end) = self.clip(x, y, width, height);
+        for row in y..y_end {
+            for col in x..x_end {
+                let offset = self.pixel_offset(col, row)?;
+                self.write_pixel(offset, color);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill the whole framebuffer with a single color.
+    pub fn clear(&mut self, color: Rgba) -> VirtIoResult<()> {
+        self.fill_rect(0, 0, self.width, self.height, color)
+    }
+
+    /// Copy an image in `R8G8B8A8` byte order to position (`x`, `y`), converting it to the
+    /// framebuffer format.
+    ///
+    /// `src_pitch` is the number of bytes between two rows of `src`. Returns
+    /// [`VirtIoError::InvalidParam`] if `src` is too small for the given dimensions, or if an
+    /// offset into either buffer overflows.
+    pub fn blit_rgba(
+        &mut self,
+        x: u32,
+        y: u32,
+        src: &[u8],
+        src_width: u32,
+        src_height: u32,
+        src_pitch: usize,
+    ) -> VirtIoResult<()> {
+        let row_len = (src_width as usize).checked_mul(4);
+        let len = src_pitch.checked_mul(src_height as usize);
+        match (row_len, len) {
+            (Some(row_len), Some(len)) if src_pitch >= row_len && src.len() >= len => {}
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        let (x_end, y_end) = self.clip(x, y, src_width, src_height);
+        for row in y..y_end {
+            for col in x..x_end {
+                let s = offset(col - x, row - y, src_pitch, 4)?;
+                let Some(&[r, g, b, a]) = src.get(s..).and_then(<[u8]>::first_chunk) else {
+                    return Err(VirtIoError::InvalidParam);
+                };
+                let color = Rgba { r, g, b, a };
+                let offset = self.pixel_offset(col, row)?;
+                self.write_pixel(offset, color);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the exclusive end coordinates of the given rectangle, clipped to the framebuffer.
+    fn clip(&self, x: u32, y: u32, width: u32, height: u32) -> (u32, u32) {
+        (
+            x.saturating_add(width).min(self.width),
+            y.saturating_add(height).min(self.height),
+        )
+    }
+
+    /// Returns the offset of the pixel at (`x`, `y`) in the framebuffer.
+    fn pixel_offset(&self, x: u32, y: u32) -> VirtIoResult<usize> {
+        offset(x, y, self.pitch, self.format.bytes_per_pixel())
+    }
+
+    fn write_pixel(&mut self, offset: usize, color: Rgba) {
+        let (r, g, b, a) = self.format.channel_offsets();
+        let Some(pixel) = self
+            .buf
+            .get_mut(offset..offset + self.format.bytes_per_pixel())
+        else {
+            return;
+        };
+        let channels = [
+            (Some(r), color.r),
+            (Some(g), color.g),
+            (Some(b), color.b),
+            (a, color.a),
+        ];
+        for (channel, value) in channels {
+            if let Some(byte) = channel.and_then(|channel| pixel.get_mut(channel)) {
+                *byte = value;
+            }
+        }
+    }
+}
+
+/// Returns the offset of the pixel at (`x`, `y`) in a buffer with the given pitch and pixel size,
+/// or [`VirtIoError::InvalidParam`] if it overflows.
+fn offset(x: u32, y: u32, pitch: usize, bpp: usize) -> VirtIoResult<usize> {
+    (y as usize)
+        .checked_mul(pitch)
+        .zip((x as usize).checked_mul(bpp))
+        .and_then(|(row, col)| row.checked_add(col))
+        .ok_or(VirtIoError::InvalidParam)
+}
@@ -1,18 +1,30 @@
+#[cfg(feature = "gpu-draw")]
+mod draw;
 mod ty;
-use crate::error::{VirtIoError, VirtIoResult};
-use crate::hal::{DevicePage, Hal};
+use crate::device::common::request_response;
+use crate::device::{DeviceIdentity, VirtIoDriver};
+use crate::endian::Le32;
+use crate::error::{expect_ok, InitStep, VirtIoError, VirtIoResult};
+use crate::hal::{DevicePage, DmaBuf, Hal, MemoryRequirements};
 use crate::pages;
-use crate::queue::{DescFlag, Descriptor, VirtIoQueue};
-use crate::transport::Transport;
-use crate::volatile::ReadVolatile;
+use crate::queue::{EventSuppression, QueueInfo, QueueStats, VirtIoQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
+use crate::volatile::{ReadVolatile, WriteVolatile};
 use alloc::boxed::Box;
 use alloc::vec;
-use core::mem::size_of_val;
-use log::info;
+use alloc::vec::Vec;
 use ty::*;
 
-const QUEUE_SIZE: usize = 2;
-const SUPPORTED_FEATURES: Features = Features::empty(); // Features::RING_EVENT_IDX;
+#[cfg(feature = "gpu-draw")]
+pub use draw::{Canvas, Rgba};
+pub use ty::{Features, Format, MAX_CAPSETS, MAX_EDID_SIZE, MAX_SCANOUTS};
+
+/// Enough for one command with its response at a time, which is all the driver ever sends.
+pub const QUEUE_SIZE: usize = 2;
+/// The features [`VirtIOGpu::new`] negotiates if the device offers them.
+pub const SUPPORTED_FEATURES: Features = Features::EDID; // Features::RING_EVENT_IDX;
+/// The pixel format of the framebuffer created by [`VirtIOGpu::setup_framebuffer`].
+pub const FRAMEBUFFER_FORMAT: Format = Format::B8G8R8A8UNORM;
 
 /// A virtio based graphics adapter.
 ///
@@ -21,96 +33,446 @@ const SUPPORTED_FEATURES: Features = Features::empty(); // Features::RING_EVENT_
 /// a gpu with 3D support on the host machine.
 /// In 2D mode the virtio-gpu device provides support for ARGB Hardware cursors
 /// and multiple scanouts (aka heads).
+///
+/// Commands are sent one at a time and each queue only has room for one. A command which doesn't
+/// fit fails with [`VirtIoError::QueueFull`] before anything is sent to the device.
 pub struct VirtIOGpu<H: Hal<QUEUE_SIZE>, T: Transport> {
     transport: T,
-    rect: Option<Rect>,
-    /// DMA area of frame buffer.
-    frame_buffer_dma: Option<Box<dyn DevicePage>>,
+    negotiated_features: Features,
+    /// The features to negotiate if the device offers them, kept for [`Self::reset`].
+    supported_features: Features,
     /// DMA area of cursor image buffer.
     cursor_buffer_dma: Option<Box<dyn DevicePage>>,
     /// Queue for sending control commands.
     control_queue: VirtIoQueue<H, QUEUE_SIZE>,
     /// Queue for sending cursor commands.
     cursor_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    /// Copied from the queues when they were created, for [`Self::queues`].
+    queue_info: Vec<QueueInfo>,
     config: GpuConfig,
+    /// From the config space, between 1 and [`MAX_SCANOUTS`].
+    num_scanouts: u32,
+    /// From the config space, at most [`MAX_CAPSETS`].
+    num_capsets: u32,
+}
+
+/// What [`VirtIOGpu::setup`] negotiates, reads and allocates: everything but the transport.
+struct Setup<H: Hal<QUEUE_SIZE>> {
+    negotiated_features: Features,
+    control_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    cursor_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    num_scanouts: u32,
+    num_capsets: u32,
+}
+
+/// A capability set of the device, describing what a 3D rendering protocol like virgl supports,
+/// see [`VirtIOGpu::capsets`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CapsetInfo {
+    /// The `VIRTIO_GPU_CAPSET_*` ID of the set.
+    pub id: u32,
+    /// The newest version of the set the device has.
+    pub max_version: u32,
+    /// The size of the set in bytes, at most.
+    pub max_size: u32,
+}
+
+/// A display output of the device, see [`VirtIOGpu::scanouts`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Scanout {
+    /// The ID to pass to e.g. [`VirtIOGpu::setup_framebuffer_on`].
+    pub id: u32,
+    /// The horizontal position of the scanout on the host's desktop.
+    pub x: u32,
+    /// The vertical position of the scanout on the host's desktop.
+    pub y: u32,
+    /// The width the host prefers, in pixels.
+    pub width: u32,
+    /// The height the host prefers, in pixels.
+    pub height: u32,
+    /// Whether the host has the display enabled.
+    pub enabled: bool,
 }
 
 impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
+    /// The most descriptors each queue has, as the device may offer fewer.
+    pub const QUEUE_SIZE: usize = QUEUE_SIZE;
+    /// The virtqueue index of the control queue.
+    pub const CONTROL_QUEUE: u16 = QUEUE_TRANSMIT;
+    /// The virtqueue index of the cursor queue.
+    pub const CURSOR_QUEUE: u16 = QUEUE_CURSOR;
+    /// The number of virtqueues the driver uses.
+    pub const NUM_QUEUES: u16 = 2;
+
+    /// The memory [`Self::new`] allocates, plus the cursor image buffer of
+    /// [`Self::setup_cursor`].
+    ///
+    /// The framebuffer depends on the resolution, see [`Self::framebuffer_requirements`].
+    pub const fn memory_requirements() -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(Self::NUM_QUEUES as usize)
+            .with_buffer((CURSOR_RECT.width() * CURSOR_RECT.height() * 4) as usize)
+    }
+
+    /// The memory [`Self::setup_framebuffer`] allocates for a display of the given resolution.
+    pub const fn framebuffer_requirements(width: u32, height: u32) -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(0)
+            .with_buffer(width as usize * height as usize * FRAMEBUFFER_FORMAT.bytes_per_pixel())
+    }
+
     /// Create a new VirtIO-GPU driver.
-    pub fn new(mut transport: T) -> VirtIoResult<Self> {
-        let _negotiated_features = transport.begin_init(SUPPORTED_FEATURES)?;
-        let io_region = transport.io_region();
-        // read config
-        let config = GpuConfig::default();
-        let events_read = config.events_read.read(io_region)?;
-        let num_scanouts = config.num_scanouts.read(io_region)?;
-        info!(
-            "events_read: {:#x}, num_scanouts: {:#x}",
-            events_read, num_scanouts
-        );
-        let control_queue = VirtIoQueue::new(&mut transport, QUEUE_TRANSMIT)?;
-        let cursor_queue = VirtIoQueue::new(&mut transport, QUEUE_CURSOR)?;
-        transport.finish_init()?;
+    pub fn new(transport: T) -> VirtIoResult<Self> {
+        Self::new_with_features(transport, SUPPORTED_FEATURES)
+    }
 
+    /// Creates a new VirtIO-GPU driver which only negotiates the features in `wanted`, those of
+    /// them the device offers.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `wanted` isn't a subset of
+    /// [`SUPPORTED_FEATURES`]. Without [`Features::EDID`], [`Self::get_edid`] is unsupported.
+    pub fn new_with_features(mut transport: T, wanted: Features) -> VirtIoResult<Self> {
+        if !SUPPORTED_FEATURES.contains(wanted) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let setup = Self::setup(&mut transport, wanted)?;
         Ok(Self {
             transport,
-            rect: None,
-            frame_buffer_dma: None,
+            queue_info: vec![setup.control_queue.info(), setup.cursor_queue.info()],
+            negotiated_features: setup.negotiated_features,
+            supported_features: wanted,
             cursor_buffer_dma: None,
+            control_queue: setup.control_queue,
+            cursor_queue: setup.cursor_queue,
+            config: GpuConfig::default(),
+            num_scanouts: setup.num_scanouts,
+            num_capsets: setup.num_capsets,
+        })
+    }
+
+    /// Negotiates features, reads the config space and sets up the queues, through to
+    /// `DRIVER_OK`.
+    fn setup(transport: &mut T, supported_features: Features) -> VirtIoResult<Setup<H>> {
+        let negotiated_features =
+            transport.init_step(InitStep::Negotiation, |t| t.begin_init(supported_features))?;
+        // read config
+        let config = GpuConfig::default();
+        let (num_scanouts, num_capsets) = transport.init_step(InitStep::ReadConfig, |t| {
+            let snapshot = config.snapshot(t.io_region())?;
+            info!(
+                "events_read: {:#x}, num_scanouts: {:#x}, num_capsets: {:#x}",
+                snapshot.events_read, snapshot.num_scanouts, snapshot.num_capsets
+            );
+            if snapshot.num_capsets > MAX_CAPSETS as u32 {
+                warn!(
+                    "Device claims {} capsets, only querying {}",
+                    snapshot.num_capsets, MAX_CAPSETS
+                );
+            }
+            Ok((
+                snapshot.num_scanouts.clamp(1, MAX_SCANOUTS as u32),
+                snapshot.num_capsets.min(MAX_CAPSETS as u32),
+            ))
+        })?;
+        let (control_queue, cursor_queue) = transport.init_step(InitStep::QueueSetup, |t| {
+            Ok((
+                VirtIoQueue::new(t, QUEUE_TRANSMIT)?,
+                VirtIoQueue::new(t, QUEUE_CURSOR)?,
+            ))
+        })?;
+        transport.init_step(InitStep::DriverOk, T::finish_init)?;
+        Ok(Setup {
+            negotiated_features,
             control_queue,
             cursor_queue,
-            config,
+            num_scanouts,
+            num_capsets,
         })
     }
-    /// Acknowledge interrupt.
-    pub fn ack_interrupt(&mut self) -> VirtIoResult<bool> {
-        self.transport.ack_interrupt()
+
+    /// Resets the device and initializes it again, e.g. to recover once it has set
+    /// `DEVICE_NEEDS_RESET`, without recreating the transport.
+    ///
+    /// The device forgets every resource, so framebuffers and the cursor have to be set up again.
+    /// If this fails the device is marked `FAILED`, and the driver may only be reset again or
+    /// dropped.
+    pub fn reset(&mut self) -> VirtIoResult<()> {
+        // The device must let go of the old queues and the cursor image before they are freed.
+        self.transport.reset()?;
+        self.cursor_buffer_dma = None;
+        let setup = Self::setup(&mut self.transport, self.supported_features)?;
+        self.queue_info = vec![setup.control_queue.info(), setup.cursor_queue.info()];
+        self.negotiated_features = setup.negotiated_features;
+        self.control_queue = setup.control_queue;
+        self.cursor_queue = setup.cursor_queue;
+        self.num_scanouts = setup.num_scanouts;
+        self.num_capsets = setup.num_capsets;
+        Ok(())
+    }
+
+    /// Acknowledge interrupt, returning its causes, which are empty if there was none.
+    ///
+    /// On [`InterruptStatus::CONFIGURATION_CHANGE`], [`Self::handle_config_change`] says whether
+    /// displays were plugged or unplugged.
+    pub fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        let status = self.transport.ack_interrupt()?;
+        if status.contains(InterruptStatus::USED_RING_UPDATE) {
+            self.control_queue.collect_used();
+        }
+        Ok(status)
+    }
+
+    /// Handles an interrupt with [`InterruptStatus::CONFIGURATION_CHANGE`], returning true if
+    /// displays were plugged, unplugged or resized on the host.
+    ///
+    /// In that case [`Self::scanouts`] returns the new resolutions, and framebuffers may have to
+    /// be set up again.
+    pub fn handle_config_change(&mut self) -> VirtIoResult<bool> {
+        let events = self.config.events_read.read(self.transport.io_region())?;
+        if events != 0 {
+            self.config
+                .events_clear
+                .write(events, self.transport.io_region())?;
+        }
+        Ok(events & EVENT_DISPLAY != 0)
+    }
+
+    /// Like [`Self::ack_interrupt`], but only checks the interrupt status without acknowledging
+    /// it, for polling the device before its interrupt is routed.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        let used = self
+            .transport
+            .peek_interrupt_status()?
+            .contains(InterruptStatus::USED_RING_UPDATE);
+        if used {
+            self.control_queue.collect_used();
+        }
+        Ok(used)
+    }
+
+    /// Processes the completions on a single queue, for transports which route the interrupts of
+    /// each queue to its own vector, see [`queue_vector`](crate::transport::queue_vector).
+    ///
+    /// Commands are waited for as they are sent, so this only records that the device answered.
+    /// Returns whether it did since the last check, or [`VirtIoError::InvalidParam`] if there is
+    /// no such queue.
+    pub fn handle_queue_interrupt(&mut self, queue: u16) -> VirtIoResult<bool> {
+        let queue = match queue {
+            QUEUE_TRANSMIT => &mut self.control_queue,
+            QUEUE_CURSOR => &mut self.cursor_queue,
+            _ => return Err(VirtIoError::InvalidParam),
+        };
+        Ok(queue.collect_used() > 0)
     }
 
-    /// Get the resolution (width, height).
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> Features {
+        self.negotiated_features
+    }
+
+    /// Returns the transport, e.g. to read the device status from a power management hook.
+    ///
+    /// Only shared access is given, so the device can't be reconfigured behind the driver's back.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        &self.queue_info
+    }
+
+    /// Returns how many scanouts the device has, each of which may show a framebuffer.
+    pub fn num_scanouts(&self) -> u32 {
+        self.num_scanouts
+    }
+
+    /// Returns every scanout of the device, with the resolution the host prefers for it.
+    pub fn scanouts(&mut self) -> VirtIoResult<Vec<Scanout>> {
+        let display_info = self.get_display_info()?;
+        Ok(display_info
+            .pmodes
+            .iter()
+            .take(self.num_scanouts as usize)
+            .zip(0..)
+            .map(|(mode, id)| Scanout {
+                id,
+                x: mode.rect.x(),
+                y: mode.rect.y(),
+                width: mode.rect.width(),
+                height: mode.rect.height(),
+                enabled: mode.enabled.get() != 0,
+            })
+            .collect())
+    }
+
+    /// Get the resolution (width, height) of the first scanout.
     pub fn resolution(&mut self) -> VirtIoResult<(u32, u32)> {
         let display_info = self.get_display_info()?;
-        Ok((display_info.rect.width, display_info.rect.height))
+        let rect = display_info.pmodes[0].rect;
+        Ok((rect.width(), rect.height()))
+    }
+
+    /// Setup framebuffer for the first scanout, see [`Self::setup_framebuffer_on`].
+    pub fn setup_framebuffer(&mut self) -> VirtIoResult<FrameBuffer> {
+        self.setup_framebuffer_on(0)
     }
 
-    /// Setup framebuffer
-    pub fn setup_framebuffer(&mut self) -> VirtIoResult<&mut [u8]> {
+    /// Sets up a framebuffer for the given scanout, at the resolution the host prefers for it.
+    ///
+    /// The returned [`FrameBuffer`] owns its memory, so it can be drawn into while the driver is
+    /// used for other requests. Pass it to [`Self::flush`] to show its contents.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if the device doesn't have the scanout.
+    pub fn setup_framebuffer_on(&mut self, scanout_id: u32) -> VirtIoResult<FrameBuffer> {
+        self.check_scanout(scanout_id)?;
         // get display info
         let display_info = self.get_display_info()?;
-        info!("=> {:?}", display_info);
-        self.rect = Some(display_info.rect);
+        let mode = display_info.pmodes[scanout_id as usize];
+        info!("scanout {} => {:?}", scanout_id, mode);
+        // The resource only covers this scanout, whatever its position on the host's desktop.
+        let rect = Rect::new(0, 0, mode.rect.width(), mode.rect.height());
+        let resource_id = RESOURCE_ID_FB + scanout_id;
 
         // create resource 2d
-        self.resource_create_2d(
-            RESOURCE_ID_FB,
-            display_info.rect.width,
-            display_info.rect.height,
-        )?;
+        self.resource_create_2d(resource_id, rect.width(), rect.height())?;
 
         // alloc continuous pages for the frame buffer
-        let size = display_info.rect.width * display_info.rect.height * 4;
+        let size = rect.width() * rect.height() * 4;
         let frame_buffer_dma = H::dma_alloc_buf(pages(size as usize));
 
         // resource_attach_backing
-        self.resource_attach_backing(RESOURCE_ID_FB, frame_buffer_dma.paddr() as u64, size)?;
+        self.resource_attach_backing(resource_id, frame_buffer_dma.paddr() as u64, size)?;
 
         // map frame buffer to screen
-        self.set_scanout(display_info.rect, SCANOUT_ID, RESOURCE_ID_FB)?;
-        self.frame_buffer_dma = Some(frame_buffer_dma);
-        let buf = self.frame_buffer_dma.as_mut().unwrap().as_mut_slice();
-        Ok(buf)
+        self.set_scanout(rect, scanout_id, resource_id)?;
+        Ok(FrameBuffer {
+            dma: frame_buffer_dma,
+            rect,
+            resource_id,
+            scanout_id,
+        })
+    }
+
+    /// Reads the EDID of the display on the given scanout into `out`, and returns its length.
+    ///
+    /// Returns [`VirtIoError::Unsupported`] if [`Features::EDID`] wasn't negotiated, and
+    /// [`VirtIoError::InvalidParam`] if the device doesn't have the scanout or the EDID doesn't
+    /// fit in `out`. It is at most [`MAX_EDID_SIZE`] bytes long.
+    pub fn get_edid(&mut self, scanout_id: u32, out: &mut [u8]) -> VirtIoResult<usize> {
+        if !self.negotiated_features.contains(Features::EDID) {
+            return Err(VirtIoError::Unsupported);
+        }
+        self.check_scanout(scanout_id)?;
+        let req = GetEdid {
+            header: CtrlHeader::with_type(Command::GET_EDID),
+            scanout: scanout_id.into(),
+            _padding: Le32::new(0),
+        };
+        let rsp: RespEdid = self.request(req, RespEdid::default())?;
+        rsp.header.check_type(Command::OK_EDID)?;
+        let len = (rsp.size.get() as usize).min(MAX_EDID_SIZE);
+        out.get_mut(..len)
+            .ok_or(VirtIoError::InvalidParam)?
+            .copy_from_slice(&rsp.edid[..len]);
+        Ok(len)
+    }
+
+    /// Returns the capability sets of the device.
+    ///
+    /// They describe the 3D protocols the host renders, so a device without 3D support has none.
+    /// Only the first [`MAX_CAPSETS`] are returned, however many the device claims to have.
+    /// In 2D mode resources can only be created in the RGB formats of [`Format`], so a guest
+    /// decoder has to convert YUV frames before presenting them.
+    pub fn capsets(&mut self) -> VirtIoResult<Vec<CapsetInfo>> {
+        (0..self.num_capsets)
+            .map(|index| {
+                let req = GetCapsetInfo {
+                    header: CtrlHeader::with_type(Command::GET_CAPSET_INFO),
+                    capset_index: index.into(),
+                    _padding: Le32::new(0),
+                };
+                let rsp: RespCapsetInfo = self.request(req, RespCapsetInfo::default())?;
+                rsp.header.check_type(Command::OK_CAPSET_INFO)?;
+                Ok(CapsetInfo {
+                    id: rsp.capset_id.get(),
+                    max_version: rsp.capset_max_version.get(),
+                    max_size: rsp.capset_max_size.get(),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns [`VirtIoError::InvalidParam`] unless the device has the given scanout.
+    fn check_scanout(&self, scanout_id: u32) -> VirtIoResult<()> {
+        if scanout_id >= self.num_scanouts {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Ok(())
     }
 
     /// Flush framebuffer to screen.
-    pub fn flush(&mut self) -> VirtIoResult<()> {
-        let rect = self.rect.ok_or(VirtIoError::NotReady)?;
+    ///
+    /// The device only reads the framebuffer memory while handling this request, so borrowing
+    /// `fb` here guarantees the memory is still alive when it does.
+    pub fn flush(&mut self, fb: &FrameBuffer) -> VirtIoResult<()> {
         // copy data from guest to host
-        self.transfer_to_host_2d(rect, 0, RESOURCE_ID_FB)?;
+        self.transfer_to_host_2d(fb.rect, 0, fb.resource_id)?;
         // flush data to screen
-        self.resource_flush(rect, RESOURCE_ID_FB)?;
+        self.resource_flush(fb.rect, fb.resource_id)?;
         Ok(())
     }
 
+    /// Like [`Self::flush`], but only copies and shows the `width` by `height` pixels with their
+    /// top left corner at (`x`, `y`), e.g. the part of the screen a compositor redrew.
+    ///
+    /// A rectangle reaching past the right or bottom edge of `fb` is clipped to it. One which is
+    /// empty or starts outside `fb` is logged with the framebuffer's size and refused with
+    /// [`VirtIoError::InvalidParam`], where the device would only answer with an error code that
+    /// doesn't say which coordinates were wrong.
+    pub fn flush_rect(
+        &mut self,
+        fb: &FrameBuffer,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> VirtIoResult<()> {
+        let rect = fb.clip(x, y, width, height)?;
+        // The offset of the first pixel of the rectangle in the framebuffer memory.
+        let offset = y as usize * fb.pitch() + x as usize * FRAMEBUFFER_FORMAT.bytes_per_pixel();
+        self.transfer_to_host_2d(rect, offset as u64, fb.resource_id)?;
+        self.resource_flush(rect, fb.resource_id)
+    }
+
+    /// Takes `fb` off its scanout and releases it: the resource on the host, and the memory
+    /// backing it, which goes back to the HAL.
+    ///
+    /// Dropping a framebuffer only frees its memory, leaving the resource on the host with the
+    /// last flushed image on screen, so setting up its scanout again fails until the device is
+    /// reset.
+    pub fn release_framebuffer(&mut self, fb: FrameBuffer) -> VirtIoResult<()> {
+        // A resource id of 0 disables the scanout.
+        self.set_scanout(Rect::new(0, 0, 0, 0), fb.scanout_id, 0)?;
+        self.resource_detach_backing(fb.resource_id)?;
+        self.resource_unref(fb.resource_id)
+    }
+
+    /// Shows `fb` on screen and copies what is shown into `out`, row by row in
+    /// [`FRAMEBUFFER_FORMAT`]. Returns the number of bytes written.
+    ///
+    /// 2D mode has no command to transfer a resource back from the host, so this reads the guest
+    /// copy. Flushing first makes sure it matches the host copy being displayed.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `out` is smaller than the framebuffer.
+    pub fn screenshot(&mut self, fb: &FrameBuffer, out: &mut [u8]) -> VirtIoResult<usize> {
+        let pixels = fb.as_slice();
+        let out = out
+            .get_mut(..pixels.len())
+            .ok_or(VirtIoError::InvalidParam)?;
+        self.flush(fb)?;
+        out.copy_from_slice(pixels);
+        Ok(pixels.len())
+    }
+
     /// Set the pointer shape and position.
     pub fn setup_cursor(
         &mut self,
@@ -120,7 +482,7 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
         hot_x: u32,
         hot_y: u32,
     ) -> VirtIoResult<()> {
-        let size = CURSOR_RECT.width * CURSOR_RECT.height * 4;
+        let size = CURSOR_RECT.width() * CURSOR_RECT.height() * 4;
         if cursor_image.len() != size as usize {
             return Err(VirtIoError::InvalidParam);
         }
@@ -128,7 +490,11 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
         let buf = cursor_buffer_dma.as_mut_slice();
         buf.copy_from_slice(cursor_image);
 
-        self.resource_create_2d(RESOURCE_ID_CURSOR, CURSOR_RECT.width, CURSOR_RECT.height)?;
+        self.resource_create_2d(
+            RESOURCE_ID_CURSOR,
+            CURSOR_RECT.width(),
+            CURSOR_RECT.height(),
+        )?;
         self.resource_attach_backing(RESOURCE_ID_CURSOR, cursor_buffer_dma.paddr() as u64, size)?;
         self.transfer_to_host_2d(CURSOR_RECT, 0, RESOURCE_ID_CURSOR)?;
         self.update_cursor(
@@ -150,31 +516,48 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
         Ok(())
     }
 
+    /// Checks that a command of `descriptors` descriptors fits in `queue` right now, so it fails
+    /// with a clear error instead of partway through being added.
+    fn admit(queue: &VirtIoQueue<H, QUEUE_SIZE>, descriptors: usize) -> VirtIoResult<()> {
+        let size = usize::from(queue.size());
+        if descriptors > size {
+            warn!(
+                "GPU command needs {} descriptors, but the queues only have {}",
+                descriptors, size
+            );
+            return Err(VirtIoError::QueueFull);
+        }
+        let free = queue.available_desc();
+        if free < descriptors {
+            // Commands are synchronous, so descriptors are only missing if an earlier command
+            // failed after being added, e.g. because notifying the device failed.
+            warn!(
+                "GPU queue has {} of {} descriptors free, an earlier command never completed; \
+                 reset the device to recover",
+                free, size
+            );
+            return Err(VirtIoError::QueueFull);
+        }
+        Ok(())
+    }
+
     /// Send a request to the device and block for a response.
     fn request<Req: Sized, Rsp: Sized>(&mut self, req: Req, rsp: Rsp) -> VirtIoResult<Rsp> {
-        // self.queue_buf_send.copy_from_slice(req.as_slice());
-        let req = Descriptor::new::<QUEUE_SIZE, H>(
-            &req as *const _ as _,
-            size_of_val(&req) as _,
-            DescFlag::NEXT,
-        );
-        let res = Descriptor::new::<QUEUE_SIZE, H>(
-            &rsp as *const _ as _,
-            size_of_val(&rsp) as _,
-            DescFlag::WRITE,
-        );
-        self.control_queue
-            .add_notify_wait_pop(&mut self.transport, vec![req, res])?;
-        Ok(rsp)
+        Self::admit(&self.control_queue, 2)?;
+        request_response(
+            &mut self.control_queue,
+            &mut self.transport,
+            &req,
+            Vec::new(),
+            rsp,
+            Ok,
+        )
     }
 
     /// Send a mouse cursor operation request to the device and block for a response.
     fn cursor_request<Req: Sized>(&mut self, req: Req) -> VirtIoResult<()> {
-        let req = Descriptor::new::<QUEUE_SIZE, H>(
-            &req as *const _ as _,
-            size_of_val(&req) as _,
-            DescFlag::EMPTY,
-        );
+        Self::admit(&self.cursor_queue, 1)?;
+        let req = DmaBuf::readable(&req);
         self.cursor_queue
             .add_notify_wait_pop(&mut self.transport, vec![req])?;
         Ok(())
@@ -188,10 +571,10 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
     ) -> VirtIoResult<()> {
         let req = ResourceCreate2D {
             header: CtrlHeader::with_type(Command::RESOURCE_CREATE_2D),
-            resource_id,
-            format: Format::B8G8R8A8UNORM,
-            width,
-            height,
+            resource_id: resource_id.into(),
+            format: Le32::new(FRAMEBUFFER_FORMAT as u32),
+            width: width.into(),
+            height: height.into(),
         };
         let rsp: CtrlHeader = self.request(req, CtrlHeader::default())?;
         rsp.check_type(Command::OK_NODATA)
@@ -201,8 +584,8 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
         let req = SetScanout {
             header: CtrlHeader::with_type(Command::SET_SCANOUT),
             rect,
-            scanout_id,
-            resource_id,
+            scanout_id: scanout_id.into(),
+            resource_id: resource_id.into(),
         };
         let rsp = self.request(req, CtrlHeader::default())?;
         rsp.check_type(Command::OK_NODATA)
@@ -212,8 +595,8 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
         let req = ResourceFlush {
             header: CtrlHeader::with_type(Command::RESOURCE_FLUSH),
             rect,
-            resource_id,
-            _padding: 0,
+            resource_id: resource_id.into(),
+            _padding: Le32::new(0),
         };
         let rsp = self.request(req, CtrlHeader::default())?;
         rsp.check_type(Command::OK_NODATA)
@@ -228,9 +611,9 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
         let req = TransferToHost2D {
             header: CtrlHeader::with_type(Command::TRANSFER_TO_HOST_2D),
             rect,
-            offset,
-            resource_id,
-            _padding: 0,
+            offset: offset.into(),
+            resource_id: resource_id.into(),
+            _padding: Le32::new(0),
         };
         let rsp = self.request(req, CtrlHeader::default())?;
         rsp.check_type(Command::OK_NODATA)
@@ -244,16 +627,37 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
     ) -> VirtIoResult<()> {
         let req = ResourceAttachBacking {
             header: CtrlHeader::with_type(Command::RESOURCE_ATTACH_BACKING),
-            resource_id,
-            nr_entries: 1,
-            addr: paddr,
-            length,
-            _padding: 0,
+            resource_id: resource_id.into(),
+            nr_entries: Le32::new(1),
+            addr: paddr.into(),
+            length: length.into(),
+            _padding: Le32::new(0),
         };
         let rsp = self.request(req, CtrlHeader::default())?;
         rsp.check_type(Command::OK_NODATA)
     }
 
+    fn resource_detach_backing(&mut self, resource_id: u32) -> VirtIoResult<()> {
+        let req = ResourceDetachBacking {
+            header: CtrlHeader::with_type(Command::RESOURCE_DETACH_BACKING),
+            resource_id: resource_id.into(),
+            _padding: Le32::new(0),
+        };
+        let rsp = self.request(req, CtrlHeader::default())?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    fn resource_unref(&mut self, resource_id: u32) -> VirtIoResult<()> {
+        let req = ResourceUnref {
+            header: CtrlHeader::with_type(Command::RESOURCE_UNREF),
+            resource_id: resource_id.into(),
+            _padding: Le32::new(0),
+        };
+        let rsp = self.request(req, CtrlHeader::default())?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn update_cursor(
         &mut self,
         resource_id: u32,
@@ -271,15 +675,15 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
                 CtrlHeader::with_type(Command::UPDATE_CURSOR)
             },
             pos: CursorPos {
-                scanout_id,
-                x: pos_x,
-                y: pos_y,
-                _padding: 0,
+                scanout_id: scanout_id.into(),
+                x: pos_x.into(),
+                y: pos_y.into(),
+                _padding: Le32::new(0),
             },
-            resource_id,
-            hot_x,
-            hot_y,
-            _padding: 0,
+            resource_id: resource_id.into(),
+            hot_x: hot_x.into(),
+            hot_y: hot_y.into(),
+            _padding: Le32::new(0),
         };
         self.cursor_request(req)
     }
@@ -294,15 +698,142 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOGpu<H, T> {
     }
 }
 
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIoDriver for VirtIOGpu<H, T> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::GPU
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        VirtIOGpu::ack_interrupt(self)
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        VirtIOGpu::poll_interrupt(self)
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            device_type: DeviceType::GPU,
+            bus_addr: self.transport.io_region().paddr(),
+            features: self.negotiated_features.bits(),
+        }
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        vec![self.control_queue.stats(), self.cursor_queue.stats()]
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        match queue {
+            QUEUE_TRANSMIT => self.control_queue.set_event_suppression(suppression),
+            QUEUE_CURSOR => self.cursor_queue.set_event_suppression(suppression),
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.transport.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.transport.reset()
+    }
+}
+
+/// A framebuffer created by [`VirtIOGpu::setup_framebuffer`].
+///
+/// Pixels are stored row by row in [`FRAMEBUFFER_FORMAT`], without padding between rows. Dropping
+/// it frees its memory but leaves the last flushed image on screen, see
+/// [`VirtIOGpu::release_framebuffer`].
+pub struct FrameBuffer {
+    /// DMA area of frame buffer.
+    dma: Box<dyn DevicePage>,
+    rect: Rect,
+    resource_id: u32,
+    scanout_id: u32,
+}
+
+impl FrameBuffer {
+    /// The scanout the framebuffer is shown on.
+    pub fn scanout_id(&self) -> u32 {
+        self.scanout_id
+    }
+
+    /// Clips a rectangle to the framebuffer, see [`VirtIOGpu::flush_rect`].
+    fn clip(&self, x: u32, y: u32, width: u32, height: u32) -> VirtIoResult<Rect> {
+        let (fb_width, fb_height) = (self.width(), self.height());
+        if width == 0 || height == 0 || x >= fb_width || y >= fb_height {
+            warn!(
+                "Rect of {}x{} at ({}, {}) is empty or outside the {}x{} framebuffer of scanout {}",
+                width, height, x, y, fb_width, fb_height, self.scanout_id
+            );
+            return Err(VirtIoError::InvalidParam);
+        }
+        Ok(Rect::new(
+            x,
+            y,
+            width.min(fb_width - x),
+            height.min(fb_height - y),
+        ))
+    }
+
+    /// The width of the framebuffer in pixels.
+    pub fn width(&self) -> u32 {
+        self.rect.width()
+    }
+
+    /// The height of the framebuffer in pixels.
+    pub fn height(&self) -> u32 {
+        self.rect.height()
+    }
+
+    /// The number of bytes between the start of two consecutive rows.
+    pub fn pitch(&self) -> usize {
+        self.rect.width() as usize * FRAMEBUFFER_FORMAT.bytes_per_pixel()
+    }
+
+    /// The pixels of the framebuffer.
+    pub fn as_slice(&self) -> &[u8] {
+        let len = self.pitch() * self.rect.height() as usize;
+        self.dma.as_slice().get(..len).unwrap_or_default()
+    }
+
+    /// The pixels of the framebuffer, for drawing into.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let len = self.pitch() * self.rect.height() as usize;
+        self.dma.as_mut_slice().get_mut(..len).unwrap_or_default()
+    }
+
+    /// Returns a [`Canvas`] for drawing into the framebuffer.
+    #[cfg(feature = "gpu-draw")]
+    pub fn canvas(&mut self) -> VirtIoResult<Canvas<'_>> {
+        let (width, height, pitch) = (self.rect.width(), self.rect.height(), self.pitch());
+        Canvas::new(
+            self.as_mut_slice(),
+            width,
+            height,
+            pitch,
+            FRAMEBUFFER_FORMAT,
+        )
+    }
+}
+
 impl<H: Hal<QUEUE_SIZE>, T: Transport> Drop for VirtIOGpu<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport
-            .queue_unset(QUEUE_TRANSMIT)
-            .expect("failed to unset transmit queue");
-        self.transport
-            .queue_unset(QUEUE_CURSOR)
-            .expect("failed to unset cursor queue");
+        expect_ok(
+            self.transport.queue_unset(QUEUE_TRANSMIT),
+            "failed to unset transmit queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_CURSOR),
+            "failed to unset cursor queue",
+        );
     }
 }
@@ -1,25 +1,29 @@
+use crate::endian::{Le32, Le64};
 use crate::error::{VirtIoError, VirtIoResult};
-use crate::transport::mmio::CONFIG_OFFSET;
-use crate::volatile::{ReadOnly, ReadWrite, WriteOnly};
+use crate::transport::DeviceFeatures;
+use crate::volatile::virtio_config;
 use bitflags::bitflags;
-#[repr(C)]
-#[derive(Debug, Default)]
-pub struct GpuConfig {
-    /// Signals pending events to the driver。
-    pub(crate) events_read: ReadOnly<CONFIG_OFFSET, u32>,
+virtio_config! {
+    pub struct GpuConfig {
+        /// Signals pending events to the driver。
+        pub(crate) events_read: ReadOnly<u32> @ 0x0,
 
-    /// Clears pending events in the device.
-    pub(crate) events_clear: WriteOnly<{ CONFIG_OFFSET + 4 }, u32>,
+        /// Clears pending events in the device.
+        pub(crate) events_clear: WriteOnly<u32> @ 0x4,
 
-    /// Specifies the maximum number of scanouts supported by the device.
-    ///
-    /// Minimum value is 1, maximum value is 16.
-    pub(crate) num_scanouts: ReadWrite<{ CONFIG_OFFSET + 8 }, u32>,
-    pub(crate) num_capsets: ReadWrite<{ CONFIG_OFFSET + 12 }, u32>,
+        /// Specifies the maximum number of scanouts supported by the device.
+        ///
+        /// Minimum value is 1, maximum value is 16.
+        pub(crate) num_scanouts: ReadWrite<u32> @ 0x8,
+        pub(crate) num_capsets: ReadWrite<u32> @ 0xc,
+    }
+
+    /// The readable fields of [`GpuConfig`].
+    => pub(crate) struct GpuConfigSnapshot;
 }
 
 /// Display configuration has changed.
-const EVENT_DISPLAY: u32 = 1 << 0;
+pub(crate) const EVENT_DISPLAY: u32 = 1 << 0;
 
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -47,63 +51,59 @@ bitflags! {
     }
 }
 
+format_flags!(Features);
+
+impl DeviceFeatures for Features {}
+
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub struct Command(u32);
+pub struct Command(Le32);
 
 impl Command {
-    pub(super) const GET_DISPLAY_INFO: Command = Command(0x100);
-    pub(super) const RESOURCE_CREATE_2D: Command = Command(0x101);
-    pub(super) const RESOURCE_UNREF: Command = Command(0x102);
-    pub(super) const SET_SCANOUT: Command = Command(0x103);
-    pub(super) const RESOURCE_FLUSH: Command = Command(0x104);
-    pub(super) const TRANSFER_TO_HOST_2D: Command = Command(0x105);
-    pub(super) const RESOURCE_ATTACH_BACKING: Command = Command(0x106);
-    pub(super) const RESOURCE_DETACH_BACKING: Command = Command(0x107);
-    pub(super) const GET_CAPSET_INFO: Command = Command(0x108);
-    pub(super) const GET_CAPSET: Command = Command(0x109);
-    pub(super) const GET_EDID: Command = Command(0x10a);
-
-    pub(super) const UPDATE_CURSOR: Command = Command(0x300);
-    pub(super) const MOVE_CURSOR: Command = Command(0x301);
-
-    pub(super) const OK_NODATA: Command = Command(0x1100);
-    pub(super) const OK_DISPLAY_INFO: Command = Command(0x1101);
-    pub(super) const OK_CAPSET_INFO: Command = Command(0x1102);
-    pub(super) const OK_CAPSET: Command = Command(0x1103);
-    pub(super) const OK_EDID: Command = Command(0x1104);
-
-    pub(super) const ERR_UNSPEC: Command = Command(0x1200);
-    pub(super) const ERR_OUT_OF_MEMORY: Command = Command(0x1201);
-    pub(super) const ERR_INVALID_SCANOUT_ID: Command = Command(0x1202);
+    pub(super) const GET_DISPLAY_INFO: Command = Command(Le32::new(0x100));
+    pub(super) const RESOURCE_CREATE_2D: Command = Command(Le32::new(0x101));
+    pub(super) const RESOURCE_UNREF: Command = Command(Le32::new(0x102));
+    pub(super) const SET_SCANOUT: Command = Command(Le32::new(0x103));
+    pub(super) const RESOURCE_FLUSH: Command = Command(Le32::new(0x104));
+    pub(super) const TRANSFER_TO_HOST_2D: Command = Command(Le32::new(0x105));
+    pub(super) const RESOURCE_ATTACH_BACKING: Command = Command(Le32::new(0x106));
+    pub(super) const RESOURCE_DETACH_BACKING: Command = Command(Le32::new(0x107));
+    pub(super) const GET_CAPSET_INFO: Command = Command(Le32::new(0x108));
+    pub(super) const GET_EDID: Command = Command(Le32::new(0x10a));
+
+    pub(super) const UPDATE_CURSOR: Command = Command(Le32::new(0x300));
+    pub(super) const MOVE_CURSOR: Command = Command(Le32::new(0x301));
+
+    pub(super) const OK_NODATA: Command = Command(Le32::new(0x1100));
+    pub(super) const OK_DISPLAY_INFO: Command = Command(Le32::new(0x1101));
+    pub(super) const OK_CAPSET_INFO: Command = Command(Le32::new(0x1102));
+    pub(super) const OK_EDID: Command = Command(Le32::new(0x1104));
 }
 
 impl Default for Command {
     fn default() -> Self {
-        Command(0)
+        Command(Le32::new(0))
     }
 }
 
-const GPU_FLAG_FENCE: u32 = 1 << 0;
-
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct CtrlHeader {
     hdr_type: Command,
-    flags: u32,
-    fence_id: u64,
-    ctx_id: u32,
-    _padding: u32,
+    flags: Le32,
+    fence_id: Le64,
+    ctx_id: Le32,
+    _padding: Le32,
 }
 
 impl CtrlHeader {
     pub(super) fn with_type(hdr_type: Command) -> CtrlHeader {
         CtrlHeader {
             hdr_type,
-            flags: 0,
-            fence_id: 0,
-            ctx_id: 0,
-            _padding: 0,
+            flags: Le32::new(0),
+            fence_id: Le64::new(0),
+            ctx_id: Le32::new(0),
+            _padding: Le32::new(0),
         }
     }
 
@@ -119,47 +119,186 @@ impl CtrlHeader {
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Rect {
-    x: u32,
-    y: u32,
-    pub(super) width: u32,
-    pub(super) height: u32,
+    x: Le32,
+    y: Le32,
+    width: Le32,
+    height: Le32,
+}
+
+impl Rect {
+    pub(super) const fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x: Le32::new(x),
+            y: Le32::new(y),
+            width: Le32::new(width),
+            height: Le32::new(height),
+        }
+    }
+
+    pub(super) const fn width(&self) -> u32 {
+        self.width.get()
+    }
+
+    pub(super) const fn height(&self) -> u32 {
+        self.height.get()
+    }
+
+    pub(super) const fn x(&self) -> u32 {
+        self.x.get()
+    }
+
+    pub(super) const fn y(&self) -> u32 {
+        self.y.get()
+    }
+}
+
+/// The most scanouts a device may have.
+pub const MAX_SCANOUTS: usize = 16;
+
+/// The most capability sets [`VirtIOGpu::capsets`](super::VirtIOGpu::capsets) asks a device about.
+/// The spec defines far fewer capset IDs, so a device claiming more is broken.
+pub const MAX_CAPSETS: usize = 16;
+
+/// The largest EDID blob a device returns.
+pub const MAX_EDID_SIZE: usize = 1024;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DisplayOne {
+    pub(super) rect: Rect,
+    pub(super) enabled: Le32,
+    flags: Le32,
 }
 
 #[repr(C)]
 #[derive(Clone, Debug, Default)]
 pub struct RespDisplayInfo {
     pub(super) header: CtrlHeader,
-    pub(super) rect: Rect,
-    enabled: u32,
-    flags: u32,
+    pub(super) pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct GetEdid {
+    pub(crate) header: CtrlHeader,
+    pub(crate) scanout: Le32,
+    pub(crate) _padding: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct RespEdid {
+    pub(super) header: CtrlHeader,
+    pub(super) size: Le32,
+    _padding: Le32,
+    pub(super) edid: [u8; MAX_EDID_SIZE],
+}
+
+impl Default for RespEdid {
+    fn default() -> Self {
+        Self {
+            header: CtrlHeader::default(),
+            size: Le32::new(0),
+            _padding: Le32::new(0),
+            edid: [0; MAX_EDID_SIZE],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct GetCapsetInfo {
+    pub(crate) header: CtrlHeader,
+    pub(crate) capset_index: Le32,
+    pub(crate) _padding: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct RespCapsetInfo {
+    pub(super) header: CtrlHeader,
+    pub(super) capset_id: Le32,
+    pub(super) capset_max_version: Le32,
+    pub(super) capset_max_size: Le32,
+    _padding: Le32,
 }
 
 #[repr(C)]
 #[derive(Debug)]
 pub struct ResourceCreate2D {
     pub(crate) header: CtrlHeader,
-    pub(crate) resource_id: u32,
-    pub(crate) format: Format,
-    pub(crate) width: u32,
-    pub(crate) height: u32,
+    pub(crate) resource_id: Le32,
+    pub(crate) format: Le32,
+    pub(crate) width: Le32,
+    pub(crate) height: Le32,
 }
 
+/// Pixel formats of 2D resources.
+///
+/// The letters name the channels in memory byte order, e.g. `B8G8R8A8UNORM` stores blue first.
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Format {
     B8G8R8A8UNORM = 1,
+    B8G8R8X8UNORM = 2,
+    A8R8G8B8UNORM = 3,
+    X8R8G8B8UNORM = 4,
+    R8G8B8A8UNORM = 67,
+    X8B8G8R8UNORM = 68,
+    A8B8G8R8UNORM = 121,
+    R8G8B8X8UNORM = 134,
+}
+
+impl Format {
+    /// The number of bytes used by one pixel.
+    pub const fn bytes_per_pixel(&self) -> usize {
+        4
+    }
+
+    /// Byte offsets of the red, green and blue channels within a pixel, and of the alpha channel
+    /// if the format has one.
+    pub const fn channel_offsets(&self) -> (usize, usize, usize, Option<usize>) {
+        match self {
+            Format::B8G8R8A8UNORM => (2, 1, 0, Some(3)),
+            Format::B8G8R8X8UNORM => (2, 1, 0, None),
+            Format::A8R8G8B8UNORM => (1, 2, 3, Some(0)),
+            Format::X8R8G8B8UNORM => (1, 2, 3, None),
+            Format::R8G8B8A8UNORM => (0, 1, 2, Some(3)),
+            Format::X8B8G8R8UNORM => (3, 2, 1, None),
+            Format::A8B8G8R8UNORM => (3, 2, 1, Some(0)),
+            Format::R8G8B8X8UNORM => (0, 1, 2, None),
+        }
+    }
 }
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct ResourceAttachBacking {
     pub(crate) header: CtrlHeader,
-    pub(crate) resource_id: u32,
-    pub(crate) nr_entries: u32, // always 1
-    pub(crate) addr: u64,
-    pub(crate) length: u32,
-    pub(crate) _padding: u32,
+    pub(crate) resource_id: Le32,
+    pub(crate) nr_entries: Le32, // always 1
+    pub(crate) addr: Le64,
+    pub(crate) length: Le32,
+    pub(crate) _padding: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ResourceDetachBacking {
+    pub(crate) header: CtrlHeader,
+    pub(crate) resource_id: Le32,
+    pub(crate) _padding: Le32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ResourceUnref {
+    pub(crate) header: CtrlHeader,
+    pub(crate) resource_id: Le32,
+    pub(crate) _padding: Le32,
 }
 
 #[repr(C)]
@@ -167,8 +306,8 @@ pub struct ResourceAttachBacking {
 pub struct SetScanout {
     pub(crate) header: CtrlHeader,
     pub(crate) rect: Rect,
-    pub(crate) scanout_id: u32,
-    pub(crate) resource_id: u32,
+    pub(crate) scanout_id: Le32,
+    pub(crate) resource_id: Le32,
 }
 
 #[repr(C)]
@@ -176,9 +315,9 @@ pub struct SetScanout {
 pub struct TransferToHost2D {
     pub(crate) header: CtrlHeader,
     pub(crate) rect: Rect,
-    pub(crate) offset: u64,
-    pub(crate) resource_id: u32,
-    pub(crate) _padding: u32,
+    pub(crate) offset: Le64,
+    pub(crate) resource_id: Le32,
+    pub(crate) _padding: Le32,
 }
 
 #[repr(C)]
@@ -186,17 +325,17 @@ pub struct TransferToHost2D {
 pub struct ResourceFlush {
     pub(crate) header: CtrlHeader,
     pub(crate) rect: Rect,
-    pub(crate) resource_id: u32,
-    pub(crate) _padding: u32,
+    pub(crate) resource_id: Le32,
+    pub(crate) _padding: Le32,
 }
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct CursorPos {
-    pub(crate) scanout_id: u32,
-    pub(crate) x: u32,
-    pub(crate) y: u32,
-    pub(crate) _padding: u32,
+    pub(crate) scanout_id: Le32,
+    pub(crate) x: Le32,
+    pub(crate) y: Le32,
+    pub(crate) _padding: Le32,
 }
 
 #[repr(C)]
@@ -204,22 +343,19 @@ pub struct CursorPos {
 pub struct UpdateCursor {
     pub(crate) header: CtrlHeader,
     pub(crate) pos: CursorPos,
-    pub(crate) resource_id: u32,
-    pub(crate) hot_x: u32,
-    pub(crate) hot_y: u32,
-    pub(crate) _padding: u32,
+    pub(crate) resource_id: Le32,
+    pub(crate) hot_x: Le32,
+    pub(crate) hot_y: Le32,
+    pub(crate) _padding: Le32,
 }
 
 pub const QUEUE_TRANSMIT: u16 = 0;
 pub const QUEUE_CURSOR: u16 = 1;
 
+/// The scanout the cursor is shown on.
 pub const SCANOUT_ID: u32 = 0;
+/// The resource of the framebuffer of scanout 0, those of the others follow it.
 pub const RESOURCE_ID_FB: u32 = 0xbabe;
 pub const RESOURCE_ID_CURSOR: u32 = 0xdade;
 
-pub const CURSOR_RECT: Rect = Rect {
-    x: 0,
-    y: 0,
-    width: 64,
-    height: 64,
-};
+pub const CURSOR_RECT: Rect = Rect::new(0, 0, 64, 64);
@@ -0,0 +1,271 @@
+//! Decoding of the raw evdev events from keyboards, mice and tablets into key presses and
+//! pointer motion, one report at a time.
+
+use super::InputEvent;
+use alloc::vec::Vec;
+
+/// Event types.
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+/// Codes of `EV_SYN` events.
+const SYN_REPORT: u16 = 0;
+const SYN_DROPPED: u16 = 3;
+/// Codes of `EV_REL` events.
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+/// Codes of `EV_ABS` events.
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+/// Event values of `EV_KEY` events.
+const KEY_RELEASED: u32 = 0;
+const KEY_PRESSED: u32 = 1;
+const KEY_REPEATED: u32 = 2;
+
+macro_rules! keys {
+    ($($(#[$doc:meta])* $name:ident = $code:literal,)*) => {
+        /// A key or button, by its evdev code.
+        ///
+        /// Only the keys of a common keyboard and the mouse and touch buttons are named, the rest
+        /// are [`Key::Other`].
+        #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+        pub enum Key {
+            $($(#[$doc])* $name,)*
+            /// A key without a name here, by its evdev code.
+            Other(u16),
+        }
+
+        impl Key {
+            /// Returns the key with the evdev code `code`.
+            pub const fn from_code(code: u16) -> Self {
+                match code {
+                    $($code => Self::$name,)*
+                    _ => Self::Other(code),
+                }
+            }
+
+            /// Returns the evdev code of the key.
+            pub const fn code(self) -> u16 {
+                match self {
+                    $(Self::$name => $code,)*
+                    Self::Other(code) => code,
+                }
+            }
+        }
+    };
+}
+
+keys! {
+    Esc = 1,
+    Num1 = 2,
+    Num2 = 3,
+    Num3 = 4,
+    Num4 = 5,
+    Num5 = 6,
+    Num6 = 7,
+    Num7 = 8,
+    Num8 = 9,
+    Num9 = 10,
+    Num0 = 11,
+    Minus = 12,
+    Equal = 13,
+    Backspace = 14,
+    Tab = 15,
+    Q = 16,
+    W = 17,
+    E = 18,
+    R = 19,
+    T = 20,
+    Y = 21,
+    U = 22,
+    I = 23,
+    O = 24,
+    P = 25,
+    LeftBrace = 26,
+    RightBrace = 27,
+    Enter = 28,
+    LeftCtrl = 29,
+    A = 30,
+    S = 31,
+    D = 32,
+    F = 33,
+    G = 34,
+    H = 35,
+    J = 36,
+    K = 37,
+    L = 38,
+    Semicolon = 39,
+    Apostrophe = 40,
+    Grave = 41,
+    LeftShift = 42,
+    Backslash = 43,
+    Z = 44,
+    X = 45,
+    C = 46,
+    V = 47,
+    B = 48,
+    N = 49,
+    M = 50,
+    Comma = 51,
+    Dot = 52,
+    Slash = 53,
+    RightShift = 54,
+    KpAsterisk = 55,
+    LeftAlt = 56,
+    Space = 57,
+    CapsLock = 58,
+    F1 = 59,
+    F2 = 60,
+    F3 = 61,
+    F4 = 62,
+    F5 = 63,
+    F6 = 64,
+    F7 = 65,
+    F8 = 66,
+    F9 = 67,
+    F10 = 68,
+    F11 = 87,
+    F12 = 88,
+    RightCtrl = 97,
+    RightAlt = 100,
+    Home = 102,
+    Up = 103,
+    PageUp = 104,
+    Left = 105,
+    Right = 106,
+    End = 107,
+    Down = 108,
+    PageDown = 109,
+    Insert = 110,
+    Delete = 111,
+    LeftMeta = 125,
+    RightMeta = 126,
+    /// The left mouse button.
+    BtnLeft = 0x110,
+    /// The right mouse button.
+    BtnRight = 0x111,
+    /// The middle mouse button.
+    BtnMiddle = 0x112,
+    /// Contact with a touchscreen or tablet.
+    BtnTouch = 0x14a,
+}
+
+/// An event decoded by [`InputDecoder`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodedEvent {
+    /// A key or button was pressed, or a held key repeated.
+    KeyPress(Key),
+    /// A key or button was released.
+    KeyRelease(Key),
+    /// A mouse moved, by the sum of the movements in the report.
+    RelMotion { dx: i32, dy: i32 },
+    /// A tablet or touchscreen reported a new position. Axes which didn't change are `None`.
+    AbsMotion { x: Option<i32>, y: Option<i32> },
+    /// A wheel turned, by the sum of the steps in the report. Positive `vertical` is away from
+    /// the user and positive `horizontal` is to the right.
+    Wheel { vertical: i32, horizontal: i32 },
+    /// The end of a report: the events before it happened at the same time.
+    SynReport,
+}
+
+/// Turns the stream of events from an input device into [`DecodedEvent`]s.
+///
+/// Devices send the changes of a moment as a report of several events ending with `SYN_REPORT`.
+/// The decoder holds them back until then, so a report's motion is returned as a single event
+/// rather than one per axis, and a report cut short by `SYN_DROPPED` is discarded.
+#[derive(Clone, Debug, Default)]
+pub struct InputDecoder {
+    keys: Vec<DecodedEvent>,
+    dx: i32,
+    dy: i32,
+    x: Option<i32>,
+    y: Option<i32>,
+    wheel: i32,
+    hwheel: i32,
+    /// Whether events were dropped, so everything until the next `SYN_REPORT` is incomplete.
+    dropped: bool,
+}
+
+impl InputDecoder {
+    /// Creates a decoder with no report in progress.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the next event from the device.
+    ///
+    /// Returns nothing until the event ends a report, then the report's key events in the order
+    /// they were sent, followed by its motion and wheel events, if any, and
+    /// [`DecodedEvent::SynReport`]. Events of other types, like `EV_MSC`, are ignored.
+    pub fn feed(&mut self, event: &InputEvent) -> Vec<DecodedEvent> {
+        // Values are signed in evdev.
+        let value = event.value as i32;
+        match (event.event_type, event.code) {
+            (EV_SYN, SYN_REPORT) => return self.finish_report(),
+            (EV_SYN, SYN_DROPPED) => {
+                self.clear();
+                self.dropped = true;
+            }
+            (EV_KEY, code) => {
+                let key = Key::from_code(code);
+                match event.value {
+                    KEY_PRESSED | KEY_REPEATED => self.keys.push(DecodedEvent::KeyPress(key)),
+                    KEY_RELEASED => self.keys.push(DecodedEvent::KeyRelease(key)),
+                    _ => {}
+                }
+            }
+            (EV_REL, REL_X) => self.dx = self.dx.saturating_add(value),
+            (EV_REL, REL_Y) => self.dy = self.dy.saturating_add(value),
+            (EV_REL, REL_WHEEL) => self.wheel = self.wheel.saturating_add(value),
+            (EV_REL, REL_HWHEEL) => self.hwheel = self.hwheel.saturating_add(value),
+            (EV_ABS, ABS_X) => self.x = Some(value),
+            (EV_ABS, ABS_Y) => self.y = Some(value),
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn finish_report(&mut self) -> Vec<DecodedEvent> {
+        if core::mem::take(&mut self.dropped) {
+            self.clear();
+            return Vec::new();
+        }
+        let mut events = core::mem::take(&mut self.keys);
+        if self.dx != 0 || self.dy != 0 {
+            events.push(DecodedEvent::RelMotion {
+                dx: self.dx,
+                dy: self.dy,
+            });
+        }
+        if self.x.is_some() || self.y.is_some() {
+            events.push(DecodedEvent::AbsMotion {
+                x: self.x,
+                y: self.y,
+            });
+        }
+        if self.wheel != 0 || self.hwheel != 0 {
+            events.push(DecodedEvent::Wheel {
+                vertical: self.wheel,
+                horizontal: self.hwheel,
+            });
+        }
+        events.push(DecodedEvent::SynReport);
+        self.clear();
+        events
+    }
+
+    /// Forgets the report in progress.
+    fn clear(&mut self) {
+        let mut keys = core::mem::take(&mut self.keys);
+        keys.clear();
+        *self = Self {
+            keys,
+            ..Self::default()
+        };
+    }
+}
@@ -0,0 +1,185 @@
+//! Translation of evdev key codes into characters, tracking modifier keys.
+
+use super::InputEvent;
+use bitflags::bitflags;
+
+/// `EV_KEY`, the event type of key presses and releases.
+const EV_KEY: u16 = 0x01;
+/// Event values of `EV_KEY` events.
+const KEY_RELEASED: u32 = 0;
+const KEY_PRESSED: u32 = 1;
+const KEY_REPEATED: u32 = 2;
+
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_RIGHTALT: u16 = 100;
+
+bitflags! {
+    /// The modifier keys which are currently held, and whether caps lock is on.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Modifiers: u8 {
+        const LEFT_SHIFT    = 1 << 0;
+        const RIGHT_SHIFT   = 1 << 1;
+        const LEFT_CTRL     = 1 << 2;
+        const RIGHT_CTRL    = 1 << 3;
+        const LEFT_ALT      = 1 << 4;
+        const RIGHT_ALT     = 1 << 5;
+        const CAPS_LOCK     = 1 << 6;
+    }
+}
+
+format_flags!(Modifiers);
+
+impl Modifiers {
+    /// Whether either shift key is held.
+    pub fn shift(&self) -> bool {
+        self.intersects(Self::LEFT_SHIFT | Self::RIGHT_SHIFT)
+    }
+
+    /// Whether either control key is held.
+    pub fn ctrl(&self) -> bool {
+        self.intersects(Self::LEFT_CTRL | Self::RIGHT_CTRL)
+    }
+
+    /// Whether either alt key is held.
+    pub fn alt(&self) -> bool {
+        self.intersects(Self::LEFT_ALT | Self::RIGHT_ALT)
+    }
+}
+
+/// Characters produced by each key code, indexed by the code.
+///
+/// A `'\0'` entry means the key doesn't produce a character.
+#[derive(Clone, Copy, Debug)]
+pub struct Keymap {
+    /// Characters without shift.
+    pub normal: &'static [char],
+    /// Characters with shift.
+    pub shifted: &'static [char],
+}
+
+/// The US QWERTY layout, covering the main block of keys.
+pub const US: Keymap = Keymap {
+    normal: &[
+        '\0', '\x1b', '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', '-', '=', '\x08', '\t',
+        'q', 'w', 'e', 'r', 't', 'y', 'u', 'i', 'o', 'p', '[', ']', '\n', '\0', 'a', 's', 'd', 'f',
+        'g', 'h', 'j', 'k', 'l', ';', '\'', '`', '\0', '\\', 'z', 'x', 'c', 'v', 'b', 'n', 'm',
+        ',', '.', '/', '\0', '*', '\0', ' ',
+    ],
+    shifted: &[
+        '\0', '\x1b', '!', '@', '#', '$', '%', '^', '&', '*', '(', ')', '_', '+', '\x08', '\t',
+        'Q', 'W', 'E', 'R', 'T', 'Y', 'U', 'I', 'O', 'P', '{', '}', '\n', '\0', 'A', 'S', 'D', 'F',
+        'G', 'H', 'J', 'K', 'L', ':', '"', '~', '\0', '|', 'Z', 'X', 'C', 'V', 'B', 'N', 'M', '<',
+        '>', '?', '\0', '*', '\0', ' ',
+    ],
+};
+
+/// A key press decoded by [`KeyboardDecoder`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyPress {
+    /// The evdev key code.
+    pub code: u16,
+    /// The character the key produces with the current modifiers, if any.
+    ///
+    /// With control held, letters produce the corresponding control character, e.g. `'\x03'` for
+    /// Ctrl+C.
+    pub ch: Option<char>,
+    /// The modifiers at the time of the press.
+    pub modifiers: Modifiers,
+    /// Whether this is an auto-repeat of a held key.
+    pub repeat: bool,
+}
+
+/// Turns the stream of events from a keyboard into key presses.
+#[derive(Clone, Debug)]
+pub struct KeyboardDecoder {
+    keymap: Keymap,
+    modifiers: Modifiers,
+}
+
+impl KeyboardDecoder {
+    /// Create a decoder using the given layout, such as [`US`].
+    pub const fn new(keymap: Keymap) -> Self {
+        Self {
+            keymap,
+            modifiers: Modifiers::empty(),
+        }
+    }
+
+    /// The current state of the modifier keys.
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Feeds the next event from the device.
+    ///
+    /// Returns the key press for key down and auto-repeat events of keys other than modifiers,
+    /// and `None` for everything else.
+    pub fn feed(&mut self, event: &InputEvent) -> Option<KeyPress> {
+        if event.event_type != EV_KEY {
+            return None;
+        }
+        let pressed = match event.value {
+            KEY_RELEASED => false,
+            KEY_PRESSED | KEY_REPEATED => true,
+            _ => return None,
+        };
+        let modifier = match event.code {
+            KEY_LEFTSHIFT => Modifiers::LEFT_SHIFT,
+            KEY_RIGHTSHIFT => Modifiers::RIGHT_SHIFT,
+            KEY_LEFTCTRL => Modifiers::LEFT_CTRL,
+            KEY_RIGHTCTRL => Modifiers::RIGHT_CTRL,
+            KEY_LEFTALT => Modifiers::LEFT_ALT,
+            KEY_RIGHTALT => Modifiers::RIGHT_ALT,
+            KEY_CAPSLOCK => {
+                if event.value == KEY_PRESSED {
+                    self.modifiers.toggle(Modifiers::CAPS_LOCK);
+                }
+                return None;
+            }
+            _ => Modifiers::empty(),
+        };
+        if !modifier.is_empty() {
+            self.modifiers.set(modifier, pressed);
+            return None;
+        }
+        if !pressed {
+            return None;
+        }
+        Some(KeyPress {
+            code: event.code,
+            ch: self.translate(event.code),
+            modifiers: self.modifiers,
+            repeat: event.value == KEY_REPEATED,
+        })
+    }
+
+    fn translate(&self, code: u16) -> Option<char> {
+        let normal = *self.keymap.normal.get(code as usize)?;
+        let shifted = self
+            .keymap
+            .shifted
+            .get(code as usize)
+            .copied()
+            .unwrap_or(normal);
+        // Caps lock only affects letters, and shift undoes it.
+        let shift = if normal.is_ascii_alphabetic() {
+            self.modifiers.shift() != self.modifiers.contains(Modifiers::CAPS_LOCK)
+        } else {
+            self.modifiers.shift()
+        };
+        let ch = if shift { shifted } else { normal };
+        if ch == '\0' {
+            return None;
+        }
+        if self.modifiers.ctrl() && ch.is_ascii_alphabetic() {
+            return Some((ch.to_ascii_lowercase() as u8 - b'a' + 1) as char);
+        }
+        Some(ch)
+    }
+}
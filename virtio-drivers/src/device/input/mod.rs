@@ -1,19 +1,27 @@
 use core::mem::size_of;
 
-use crate::error::VirtIoResult;
-use crate::hal::Hal;
-use crate::queue::{DescFlag, Descriptor, VirtIoQueue};
-use crate::transport::Transport;
+use crate::device::{DeviceIdentity, VirtIoDriver};
+use crate::error::{expect_ok, InitStep, VirtIoError, VirtIoResult};
+use crate::hal::{DmaBuf, Hal, MemoryRequirements};
+use crate::queue::{EventSuppression, QueueInfo, QueueStats, VirtIoQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
 use crate::volatile::{ReadVolatile, WriteVolatile};
-use alloc::{boxed::Box, vec};
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
 
+#[cfg(feature = "input-decoder")]
+pub mod decoder;
+#[cfg(feature = "input-keymap")]
+pub mod keymap;
 mod ty;
 
 use ty::*;
 
+pub use ty::{AbsInfo, DevIDs, InputBitmap, InputConfigSelect, InputEvent, InputFeature};
+
 const QUEUE_EVENT: u16 = 0;
 const QUEUE_STATUS: u16 = 1;
-const SUPPORTED_FEATURES: InputFeature = InputFeature::empty(); // InputFeature::RING_EVENT_IDX;
+/// The features [`VirtIOInput::new`] negotiates if the device offers them.
+pub const SUPPORTED_FEATURES: InputFeature = InputFeature::empty(); // InputFeature::RING_EVENT_IDX;
 
 // a parameter that can change
 const QUEUE_SIZE: usize = 32;
@@ -25,59 +33,181 @@ const QUEUE_SIZE: usize = 32;
 /// making pass-through implementations on top of evdev easy.
 pub struct VirtIOInput<H: Hal<QUEUE_SIZE>, T: Transport> {
     transport: T,
+    negotiated_features: InputFeature,
+    /// The features to negotiate if the device offers them, kept for [`Self::reset`].
+    supported_features: InputFeature,
     event_queue: VirtIoQueue<H, QUEUE_SIZE>,
     status_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    /// Copied from the queues when they were created, for [`Self::queues`].
+    queue_info: Vec<QueueInfo>,
     event_buf: Box<[InputEvent; QUEUE_SIZE]>,
 }
 
 impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOInput<H, T> {
-    /// Create a new VirtIO-Input driver.
-    pub fn new(mut transport: T) -> VirtIoResult<Self> {
-        transport.begin_init(SUPPORTED_FEATURES)?;
-        let event_buf = Box::new([InputEvent::default(); QUEUE_SIZE]);
-
-        let mut event_queue = VirtIoQueue::new(&mut transport, QUEUE_EVENT)?;
-        let status_queue = VirtIoQueue::new(&mut transport, QUEUE_STATUS)?;
-        for (i, event) in event_buf.iter().enumerate() {
-            // Safe because the buffer lasts as long as the queue.
-            // let token = unsafe { event_queue.add(&[], &mut [event.as_bytes_mut()])? };
-            let token = event_queue.add(vec![Descriptor::new::<QUEUE_SIZE, H>(
-                event as *const InputEvent as _,
-                size_of::<InputEvent>() as _,
-                DescFlag::WRITE,
-            )])?;
-            assert_eq!(token, i as _);
-        }
-        if event_queue.should_notify() {
-            transport.notify(QUEUE_EVENT)?;
-        }
+    /// The memory [`Self::new`] allocates: the event and status queues, and a buffer for each
+    /// event slot.
+    pub const fn memory_requirements() -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(2)
+            .with_shared_heap(QUEUE_SIZE * size_of::<InputEvent>())
+    }
 
-        transport.finish_init()?;
+    /// Create a new VirtIO-Input driver.
+    pub fn new(transport: T) -> VirtIoResult<Self> {
+        Self::new_with_features(transport, SUPPORTED_FEATURES)
+    }
 
+    /// Creates a new VirtIO-Input driver which only negotiates the features in `wanted`, those of
+    /// them the device offers.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `wanted` isn't a subset of
+    /// [`SUPPORTED_FEATURES`], which is empty for now.
+    pub fn new_with_features(mut transport: T, wanted: InputFeature) -> VirtIoResult<Self> {
+        if !SUPPORTED_FEATURES.contains(wanted) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let mut event_buf = Box::new([InputEvent::default(); QUEUE_SIZE]);
+        let (negotiated_features, event_queue, status_queue) =
+            Self::setup(&mut transport, wanted, &mut event_buf)?;
+        let queue_info = vec![event_queue.info(), status_queue.info()];
         Ok(VirtIOInput {
             transport,
+            negotiated_features,
+            supported_features: wanted,
             event_queue,
             status_queue,
+            queue_info,
             event_buf,
         })
     }
 
-    /// Acknowledge interrupt and process events.
-    pub fn ack_interrupt(&mut self) -> VirtIoResult<bool> {
-        self.transport.ack_interrupt()
+    /// Negotiates features, sets up the queues and gives the device a buffer for each event slot,
+    /// through to `DRIVER_OK`.
+    fn setup(
+        transport: &mut T,
+        supported_features: InputFeature,
+        event_buf: &mut [InputEvent; QUEUE_SIZE],
+    ) -> VirtIoResult<(
+        InputFeature,
+        VirtIoQueue<H, QUEUE_SIZE>,
+        VirtIoQueue<H, QUEUE_SIZE>,
+    )> {
+        let negotiated_features =
+            transport.init_step(InitStep::Negotiation, |t| t.begin_init(supported_features))?;
+
+        let (mut event_queue, status_queue) = transport.init_step(InitStep::QueueSetup, |t| {
+            Ok((
+                VirtIoQueue::new(t, QUEUE_EVENT)?,
+                VirtIoQueue::new(t, QUEUE_STATUS)?,
+            ))
+        })?;
+        transport.init_step(InitStep::InitialBuffers, |_| {
+            // Only as many buffers as the queue turned out to have room for.
+            let events = usize::from(event_queue.size());
+            for (i, event) in event_buf.iter_mut().enumerate().take(events) {
+                // Safe because the buffer lasts as long as the queue.
+                let token = event_queue.add(vec![DmaBuf::writable(event)])?;
+                if token != i as u16 {
+                    return Err(VirtIoError::WrongToken);
+                }
+            }
+            Ok(())
+        })?;
+        // Buffers may be added early, but notifications have to wait for DRIVER_OK.
+        transport.init_step(InitStep::DriverOk, T::finish_init)?;
+        transport.init_step(InitStep::InitialBuffers, |t| {
+            if event_queue.should_notify() {
+                t.notify(QUEUE_EVENT)?;
+            }
+            Ok(())
+        })?;
+        Ok((negotiated_features, event_queue, status_queue))
+    }
+
+    /// Resets the device and initializes it again, e.g. to recover once it has set
+    /// `DEVICE_NEEDS_RESET`, without recreating the transport.
+    ///
+    /// Events which haven't been popped yet are lost. If this fails the device is marked `FAILED`,
+    /// and the driver may only be reset again or dropped.
+    pub fn reset(&mut self) -> VirtIoResult<()> {
+        // The device must let go of the old queues before they are freed.
+        self.transport.reset()?;
+        let (negotiated_features, event_queue, status_queue) = Self::setup(
+            &mut self.transport,
+            self.supported_features,
+            &mut self.event_buf,
+        )?;
+        self.queue_info = vec![event_queue.info(), status_queue.info()];
+        self.negotiated_features = negotiated_features;
+        self.event_queue = event_queue;
+        self.status_queue = status_queue;
+        Ok(())
+    }
+
+    /// Acknowledge interrupt and process events, returning the causes of the interrupt.
+    pub fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        let status = self.transport.ack_interrupt()?;
+        if status.contains(InterruptStatus::USED_RING_UPDATE) {
+            self.event_queue.collect_used();
+        }
+        Ok(status)
+    }
+
+    /// Like [`Self::ack_interrupt`], but only checks the interrupt status without acknowledging
+    /// it, for polling the device before its interrupt is routed.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        let used = self
+            .transport
+            .peek_interrupt_status()?
+            .contains(InterruptStatus::USED_RING_UPDATE);
+        if used {
+            self.event_queue.collect_used();
+        }
+        Ok(used)
+    }
+
+    /// Processes the completions on a single queue, for transports which route the interrupts of
+    /// each queue to its own vector, see [`queue_vector`](crate::transport::queue_vector).
+    ///
+    /// Returns whether the device used any buffers of the queue since the last check, which for
+    /// the event queue means there are new events to pop, or [`VirtIoError::InvalidParam`] if
+    /// there is no such queue.
+    pub fn handle_queue_interrupt(&mut self, queue: u16) -> VirtIoResult<bool> {
+        let queue = match queue {
+            QUEUE_EVENT => &mut self.event_queue,
+            QUEUE_STATUS => &mut self.status_queue,
+            _ => return Err(VirtIoError::InvalidParam),
+        };
+        Ok(queue.collect_used() > 0)
+    }
+
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> InputFeature {
+        self.negotiated_features
+    }
+
+    /// Returns the transport, e.g. to read the device status from a power management hook.
+    ///
+    /// Only shared access is given, so the device can't be reconfigured behind the driver's back.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        &self.queue_info
     }
 
     /// Pop the pending event.
     pub fn pop_pending_event(&mut self) -> VirtIoResult<Option<InputEvent>> {
         if let Some(token) = self.event_queue.peek_used() {
             let _ = self.event_queue.pop_used(token)?;
-            let event_saved = self.event_buf[token as usize];
-            let new_token = self.event_queue.add(vec![Descriptor::new::<QUEUE_SIZE, H>(
-                &self.event_buf[token as usize] as *const InputEvent as _,
-                size_of::<InputEvent>() as _,
-                DescFlag::WRITE,
-            )])?;
-            assert_eq!(new_token, token);
+            let event_saved = self.event_buf[token as usize].to_native();
+            let new_token = self
+                .event_queue
+                .add(vec![DmaBuf::writable(&mut self.event_buf[token as usize])])?;
+            if new_token != token {
+                return Err(VirtIoError::WrongToken);
+            }
             if self.event_queue.should_notify() {
                 self.transport.notify(QUEUE_EVENT)?;
             }
@@ -87,8 +217,37 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOInput<H, T> {
         }
     }
 
+    /// Returns the next event, yielding to the executor until the device sends one.
+    ///
+    /// The task is woken when [`Self::ack_interrupt`] finds an event, so the interrupt handler
+    /// must call it for the future to make progress.
+    pub async fn next_event_async(&mut self) -> VirtIoResult<InputEvent> {
+        loop {
+            if let Some(event) = self.pop_pending_event()? {
+                return Ok(event);
+            }
+            self.event_queue.wait_any_used().await;
+        }
+    }
+
+    /// Sends an output event to the device through the status queue, e.g. `EV_LED` to turn on a
+    /// keyboard's caps lock light or `EV_FF` for force feedback, and waits for the device to take
+    /// it.
+    ///
+    /// Devices ignore events they don't support.
+    pub fn send_status(&mut self, event: InputEvent) -> VirtIoResult<()> {
+        let event = event.to_le();
+        self.status_queue
+            .add_notify_wait_pop(&mut self.transport, vec![DmaBuf::readable(&event)])?;
+        Ok(())
+    }
+
     /// Query a specific piece of information by `select` and `subsel`, and write
     /// result to `out`, return the result size.
+    ///
+    /// A size of 0 means the device doesn't support the query. A size larger than the 128 bytes
+    /// of config data is clamped to them, and [`VirtIoError::InvalidParam`] is returned if `out`
+    /// can't hold the result.
     pub fn query_config_select(
         &mut self,
         select: InputConfigSelect,
@@ -99,22 +258,155 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOInput<H, T> {
         let io_region = self.transport.io_region();
         config.select.write(select as _, io_region)?;
         config.subsel.write(subsel, io_region)?;
-        let size = config.size.read(io_region)?;
+        let size = config.size.read(io_region)?.min(CONFIG_DATA_SIZE as u8);
+        let out = out
+            .get_mut(..usize::from(size))
+            .ok_or(VirtIoError::InvalidParam)?;
         let data = config.data.read(io_region)?;
-        out[..size as usize].copy_from_slice(&data[..size as usize]);
+        out.copy_from_slice(&data[..usize::from(size)]);
         Ok(size)
     }
+
+    /// Queries `select` and `subsel`, returning the data in a buffer with its size, which is 0 if
+    /// the device doesn't support the query.
+    fn query(
+        &mut self,
+        select: InputConfigSelect,
+        subsel: u8,
+    ) -> VirtIoResult<([u8; CONFIG_DATA_SIZE], usize)> {
+        let mut data = [0; CONFIG_DATA_SIZE];
+        let size = self.query_config_select(select, subsel, &mut data)?;
+        Ok((data, usize::from(size)))
+    }
+
+    /// Queries a string, which is empty if the device doesn't support the query.
+    fn query_string(&mut self, select: InputConfigSelect) -> VirtIoResult<String> {
+        let (data, size) = self.query(select, 0)?;
+        let data = data.get(..size).ok_or(VirtIoError::IoError)?;
+        // The string isn't meant to be NUL-terminated, but some devices include the terminator.
+        let data = data.split(|&b| b == 0).next().unwrap_or_default();
+        Ok(String::from_utf8_lossy(data).into_owned())
+    }
+
+    /// Returns the name of the device, or an empty string if it doesn't report one.
+    pub fn name(&mut self) -> VirtIoResult<String> {
+        self.query_string(InputConfigSelect::IdName)
+    }
+
+    /// Returns the serial number of the device, or an empty string if it doesn't report one.
+    pub fn serial(&mut self) -> VirtIoResult<String> {
+        self.query_string(InputConfigSelect::IdSerial)
+    }
+
+    /// Returns the bus, vendor, product and version IDs of the device, or `None` if it doesn't
+    /// report them.
+    pub fn ids(&mut self) -> VirtIoResult<Option<DevIDs>> {
+        let (data, size) = self.query(InputConfigSelect::IdDevids, 0)?;
+        if size == 0 {
+            return Ok(None);
+        }
+        DevIDs::read_from(data.get(..size).ok_or(VirtIoError::IoError)?).map(Some)
+    }
+
+    /// Returns the range and resolution of the absolute axis `axis`, an `ABS_*` code, or `None`
+    /// if the device doesn't have it.
+    ///
+    /// The axes the device has are listed by `supported_events(EV_ABS)`.
+    pub fn abs_info(&mut self, axis: u8) -> VirtIoResult<Option<AbsInfo>> {
+        let (data, size) = self.query(InputConfigSelect::AbsInfo, axis)?;
+        if size == 0 {
+            return Ok(None);
+        }
+        AbsInfo::read_from(data.get(..size).ok_or(VirtIoError::IoError)?).map(Some)
+    }
+
+    /// Queries a bitmap, returning `None` if the device doesn't support the query.
+    fn query_bitmap(
+        &mut self,
+        select: InputConfigSelect,
+        subsel: u8,
+    ) -> VirtIoResult<Option<InputBitmap>> {
+        let (data, size) = self.query(select, subsel)?;
+        let data = data.get(..size).ok_or(VirtIoError::IoError)?;
+        Ok((size != 0).then(|| InputBitmap::new(data)))
+    }
+
+    /// Returns the input properties of the device, as `INPUT_PROP_*` bits.
+    ///
+    /// A device which doesn't report any has none, so this is empty rather than `None`.
+    pub fn properties(&mut self) -> VirtIoResult<InputBitmap> {
+        Ok(self
+            .query_bitmap(InputConfigSelect::PropBits, 0)?
+            .unwrap_or(InputBitmap::new(&[])))
+    }
+
+    /// Returns the codes the device may send for the `EV_*` event type, or `None` if it doesn't
+    /// send events of that type at all.
+    ///
+    /// For example, `supported_events(EV_KEY)` lists the keys of a keyboard.
+    pub fn supported_events(&mut self, event_type: u8) -> VirtIoResult<Option<InputBitmap>> {
+        self.query_bitmap(InputConfigSelect::EvBits, event_type)
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIoDriver for VirtIOInput<H, T> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Input
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        VirtIOInput::ack_interrupt(self)
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        VirtIOInput::poll_interrupt(self)
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            device_type: DeviceType::Input,
+            bus_addr: self.transport.io_region().paddr(),
+            features: self.negotiated_features.bits(),
+        }
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        vec![self.event_queue.stats(), self.status_queue.stats()]
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        match queue {
+            QUEUE_EVENT => self.event_queue.set_event_suppression(suppression),
+            QUEUE_STATUS => self.status_queue.set_event_suppression(suppression),
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.transport.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.transport.reset()
+    }
 }
 
 impl<H: Hal<QUEUE_SIZE>, T: Transport> Drop for VirtIOInput<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport
-            .queue_unset(QUEUE_EVENT)
-            .expect("failed to unset event queue");
-        self.transport
-            .queue_unset(QUEUE_STATUS)
-            .expect("failed to unset status queue");
+        expect_ok(
+            self.transport.queue_unset(QUEUE_EVENT),
+            "failed to unset event queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_STATUS),
+            "failed to unset status queue",
+        );
     }
 }
@@ -1,7 +1,9 @@
 use crate::common::Array;
-use crate::transport::mmio::CONFIG_OFFSET;
-use crate::volatile::{ReadOnly, WriteOnly};
+use crate::error::{VirtIoError, VirtIoResult};
+use crate::transport::DeviceFeatures;
+use crate::volatile::virtio_config;
 use bitflags::bitflags;
+use core::mem::size_of;
 
 /// Select value used for [`VirtIOInput::query_config_select()`].
 #[repr(u8)]
@@ -29,39 +31,153 @@ pub enum InputConfigSelect {
     AbsInfo = 0x12,
 }
 
+/// Information about an absolute axis, returned by [`InputConfigSelect::AbsInfo`].
+///
+/// The fields mirror `struct input_absinfo` of evdev, except that there's no current value.
 #[repr(C)]
-#[derive(Debug)]
-struct AbsInfo {
-    min: u32,
-    max: u32,
-    fuzz: u32,
-    flat: u32,
-    res: u32,
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AbsInfo {
+    /// The minimum value of the axis.
+    pub min: u32,
+    /// The maximum value of the axis.
+    pub max: u32,
+    /// The noise the device filters out of the reported values.
+    pub fuzz: u32,
+    /// The size of the dead zone around the center, in which values are reported as the center.
+    pub flat: u32,
+    /// The resolution, in units per millimeter, or per radian for rotational axes.
+    pub res: u32,
 }
 
-#[repr(C)]
-#[derive(Debug)]
-struct DevIDs {
-    bustype: u16,
-    vendor: u16,
-    product: u16,
-    version: u16,
+impl AbsInfo {
+    /// Parses the config data returned by the device.
+    ///
+    /// Returns [`VirtIoError::IoError`] if the device returned less than a whole struct.
+    pub(super) fn read_from(source: &[u8]) -> VirtIoResult<Self> {
+        if source.len() < size_of::<Self>() {
+            return Err(VirtIoError::IoError);
+        }
+        let u32_at =
+            |i: usize| u32::from_le_bytes([source[i], source[i + 1], source[i + 2], source[i + 3]]);
+        Ok(Self {
+            min: u32_at(0),
+            max: u32_at(4),
+            fuzz: u32_at(8),
+            flat: u32_at(12),
+            res: u32_at(16),
+        })
+    }
 }
 
+/// Identifiers of the device, returned by [`InputConfigSelect::IdDevids`].
+///
+/// The fields mirror `struct input_id` of evdev.
 #[repr(C)]
-#[derive(Debug, Default)]
-pub(crate) struct InputConfig {
-    pub(crate) select: WriteOnly<CONFIG_OFFSET, u8>, // 0-1
-    pub(crate) subsel: WriteOnly<{ CONFIG_OFFSET + 0x1 }, u8>, // 1-2
-    pub(crate) size: ReadOnly<{ CONFIG_OFFSET + 0x2 }, u8>, // 2-3
-    // _reversed: [ReadOnly<u8>; 5],               // 3-8
-    pub(crate) data: ReadOnly<0x8, Array<128, u8>>, // 8-136
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DevIDs {
+    /// The bus the device is attached to, as a `BUS_*` constant.
+    pub bustype: u16,
+    /// The vendor ID.
+    pub vendor: u16,
+    /// The product ID.
+    pub product: u16,
+    /// The product version.
+    pub version: u16,
+}
+
+impl DevIDs {
+    /// Parses the config data returned by the device.
+    ///
+    /// Returns [`VirtIoError::IoError`] if the device returned less than a whole struct.
+    pub(super) fn read_from(source: &[u8]) -> VirtIoResult<Self> {
+        if source.len() < size_of::<Self>() {
+            return Err(VirtIoError::IoError);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([source[i], source[i + 1]]);
+        Ok(Self {
+            bustype: u16_at(0),
+            vendor: u16_at(2),
+            product: u16_at(4),
+            version: u16_at(6),
+        })
+    }
+}
+
+/// The size of the data in [`InputConfig`], which is the most a query can return.
+pub(crate) const CONFIG_DATA_SIZE: usize = 128;
+
+virtio_config! {
+    pub(crate) struct InputConfig {
+        pub(crate) select: WriteOnly<u8> @ 0x0,
+        pub(crate) subsel: WriteOnly<u8> @ 0x1,
+        pub(crate) size: ReadOnly<u8> @ 0x2,
+        // 5 reserved bytes
+        pub(crate) data: ReadOnly<Array<CONFIG_DATA_SIZE, u8>> @ 0x8,
+    }
+}
+
+/// A bitmap returned by [`InputConfigSelect::PropBits`] or [`InputConfigSelect::EvBits`], in
+/// which bit `n` is set if property or event code `n` is supported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InputBitmap {
+    bytes: [u8; CONFIG_DATA_SIZE],
+    len: u8,
+}
+
+impl InputBitmap {
+    /// Creates a bitmap from the bytes the device returned, of which there must be at most
+    /// [`CONFIG_DATA_SIZE`].
+    pub(crate) fn new(data: &[u8]) -> Self {
+        let mut bytes = [0; CONFIG_DATA_SIZE];
+        for (byte, &value) in bytes.iter_mut().zip(data) {
+            *byte = value;
+        }
+        Self {
+            bytes,
+            len: data.len().min(CONFIG_DATA_SIZE) as u8,
+        }
+    }
+
+    /// Returns the bitmap as the device returned it, with bit `n` in bit `n % 8` of byte `n / 8`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.len)]
+    }
+
+    /// Returns whether bit `code` is set. Bits past the end of the bitmap are never set.
+    pub fn contains(&self, code: u16) -> bool {
+        self.as_bytes()
+            .get(usize::from(code / 8))
+            .is_some_and(|byte| byte & (1 << (code % 8)) != 0)
+    }
+
+    /// Returns whether no bit is set.
+    pub fn is_empty(&self) -> bool {
+        self.as_bytes().iter().all(|&byte| byte == 0)
+    }
+
+    /// Returns the number of bits set.
+    pub fn count(&self) -> usize {
+        self.as_bytes()
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Returns an iterator over the set bits, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.as_bytes().iter().enumerate().flat_map(|(i, &byte)| {
+            (0..8)
+                .filter(move |bit| byte & (1 << bit) != 0)
+                .map(move |bit| (i * 8 + bit) as u16)
+        })
+    }
 }
 
 /// Both queues use the same `virtio_input_event` struct. `type`, `code` and `value`
 /// are filled according to the Linux input layer (evdev) interface.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct InputEvent {
     /// Event type.
     pub event_type: u16,
@@ -71,9 +187,29 @@ pub struct InputEvent {
     pub value: u32,
 }
 
+impl InputEvent {
+    /// Converts an event as the device wrote it, in little-endian, to native endianness.
+    pub(super) fn to_native(self) -> Self {
+        Self {
+            event_type: u16::from_le(self.event_type),
+            code: u16::from_le(self.code),
+            value: u32::from_le(self.value),
+        }
+    }
+
+    /// Converts an event to little-endian, for the device to read.
+    pub(super) fn to_le(self) -> Self {
+        Self {
+            event_type: self.event_type.to_le(),
+            code: self.code.to_le(),
+            value: self.value.to_le(),
+        }
+    }
+}
+
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
-    pub(crate) struct InputFeature: u64 {
+    pub struct InputFeature: u64 {
         // device independent
         const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
         const ANY_LAYOUT            = 1 << 27; // legacy
@@ -91,3 +227,7 @@ bitflags! {
         const NOTIFICATION_DATA     = 1 << 38;
     }
 }
+
+format_flags!(InputFeature);
+
+impl DeviceFeatures for InputFeature {}
@@ -1,5 +1,133 @@
+use crate::error::VirtIoResult;
+use crate::queue::{EventSuppression, QueueStats};
+use crate::transport::{DeviceType, InterruptStatus};
+use crate::PhysAddr;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(feature = "balloon")]
+pub mod balloon;
+#[cfg(feature = "block")]
 pub mod block;
+#[cfg(any(feature = "block", feature = "gpu", feature = "net"))]
+mod common;
+#[cfg(feature = "completion-ring")]
+pub mod completion;
+#[cfg(feature = "console")]
 pub mod console;
+#[cfg(feature = "gpu")]
 pub mod gpu;
+#[cfg(feature = "input")]
 pub mod input;
+#[cfg(feature = "net")]
 pub mod net;
+#[cfg(feature = "scsi")]
+pub mod scsi;
+pub mod set;
+#[cfg(feature = "socket")]
+pub mod socket;
+#[cfg(feature = "sound")]
+pub mod sound;
+pub mod watchdog;
+
+/// Which device a driver is for, see [`VirtIoDriver::identity`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceIdentity {
+    pub device_type: DeviceType,
+    /// The physical address of the device's registers, as in [`set::DeviceLocation::bus_addr`].
+    pub bus_addr: PhysAddr,
+    /// The raw bits of the features negotiated with the device.
+    pub features: u64,
+}
+
+/// Operations common to every device driver.
+///
+/// The trait is object safe, so drivers for different kinds of devices can be kept together, e.g.
+/// in a [`set::DeviceSet`] of `Box<dyn VirtIoDriver>`, and interrupts dispatched to them without
+/// knowing their concrete types.
+pub trait VirtIoDriver {
+    /// The type of device the driver is for.
+    fn device_type(&self) -> DeviceType;
+
+    /// Acknowledges a pending interrupt and handles whatever the device completed.
+    ///
+    /// Returns the causes of the interrupt, which are empty if there was none. If they include
+    /// [`InterruptStatus::CONFIGURATION_CHANGE`], the driver's `handle_config_change`, if it has
+    /// one, says what changed.
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus>;
+
+    /// Handles whatever the device completed if its interrupt status says it used buffers, but
+    /// leaves the interrupt pending, for polling before interrupts are set up.
+    ///
+    /// Returns the same as the driver's own `poll_interrupt`.
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool>;
+
+    /// Returns the type, location and negotiated features of the device.
+    fn identity(&self) -> DeviceIdentity;
+
+    /// Returns the counters of each virtqueue used by the driver.
+    fn stats(&self) -> Vec<QueueStats>;
+
+    /// Sets which notifications the virtqueue with the given index suppresses, e.g. to poll a
+    /// queue which would otherwise interrupt.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`](crate::error::VirtIoError::InvalidParam) if the
+    /// driver doesn't use a queue with that index.
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()>;
+
+    /// Returns [`VirtIoError::NeedsReset`](crate::error::VirtIoError::NeedsReset) if the device
+    /// asked to be reset, e.g. because its backend on the host restarted.
+    ///
+    /// Such a device stops using buffers, so blocking requests return the error too after a
+    /// while. The driver can recover with its `reset`, if it has one, or be shut down and probed
+    /// again.
+    fn check_needs_reset(&self) -> VirtIoResult<()>;
+
+    /// Resets the device, so it no longer accesses any memory shared with it.
+    ///
+    /// Requests still in flight are abandoned. The driver must not be used afterwards, except to
+    /// drop it.
+    fn shutdown(&mut self) -> VirtIoResult<()>;
+
+    /// Writes a description of the driver's state for debugging, e.g. when the device stopped
+    /// responding.
+    ///
+    /// By default this is the identity and queue counters; drivers may also dump their rings.
+    fn dump_state(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "{:?}", self.identity())?;
+        for stats in self.stats() {
+            writeln!(out, "{:?}", stats)?;
+        }
+        Ok(())
+    }
+}
+
+/// Shuts down all of the given devices, e.g. before kexec or a soft reboot, so the next kernel
+/// doesn't inherit devices doing DMA into memory it reclaims.
+///
+/// Devices which write into buffers of their own accord, like network, input and socket devices,
+/// are reset first, and block devices last. Every device is reset even if resetting an earlier one
+/// failed; the first error is returned.
+pub fn shutdown_all<'a>(
+    devices: impl IntoIterator<Item = &'a mut dyn VirtIoDriver>,
+) -> VirtIoResult<()> {
+    let mut devices: Vec<_> = devices.into_iter().collect();
+    devices.sort_by_key(|device| match device.device_type() {
+        DeviceType::Network | DeviceType::Input | DeviceType::Socket => 0,
+        DeviceType::Block => 2,
+        _ => 1,
+    });
+    let mut result = Ok(());
+    for device in devices {
+        let res = device.shutdown();
+        if result.is_ok() {
+            result = res;
+        }
+    }
+    result
+}
@@ -0,0 +1,76 @@
+//! The DMA region [`VirtIONet`](super::VirtIONet) can copy packets into for transmission, see
+//! [`VirtIONet::set_tx_arena`](super::VirtIONet::set_tx_arena).
+
+use crate::hal::{DevicePage, Hal};
+use crate::pages;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// A DMA buffer split into slots of the same length, each holding one packet at a time.
+pub(super) struct TxArena {
+    page: Box<dyn DevicePage>,
+    slot_len: usize,
+    slots: usize,
+    /// The slots which no transmission is using, by index.
+    free: Vec<usize>,
+}
+
+impl TxArena {
+    /// Allocates `size` bytes, split into as many slots of `slot_len` bytes as fit, but no more
+    /// than `max_slots`.
+    ///
+    /// Returns `None` if not even one slot fits.
+    pub(super) fn new<H: Hal<SIZE>, const SIZE: usize>(
+        size: usize,
+        slot_len: usize,
+        max_slots: usize,
+    ) -> Option<Self> {
+        let slots = size.checked_div(slot_len)?.min(max_slots);
+        if slots == 0 {
+            return None;
+        }
+        Some(Self {
+            page: H::dma_alloc_buf(pages(slots * slot_len)),
+            slot_len,
+            slots,
+            // Reversed, so slots are handed out from the start of the page.
+            free: (0..slots).rev().collect(),
+        })
+    }
+
+    pub(super) fn page(&self) -> &dyn DevicePage {
+        self.page.as_ref()
+    }
+
+    /// Takes a free slot, if there is one and `len` bytes fit in it, and returns its index.
+    pub(super) fn alloc(&mut self, len: usize) -> Option<usize> {
+        if len > self.slot_len {
+            return None;
+        }
+        self.free.pop()
+    }
+
+    /// Gives back a slot taken by [`Self::alloc`].
+    pub(super) fn free(&mut self, slot: usize) {
+        self.free.push(slot);
+    }
+
+    /// Frees every slot, once the device can no longer be using any of them.
+    pub(super) fn free_all(&mut self) {
+        self.free = (0..self.slots).rev().collect();
+    }
+
+    /// The first `len` bytes of `slot`, as offsets into the page.
+    pub(super) fn range(&self, slot: usize, len: usize) -> Range<usize> {
+        let start = slot * self.slot_len;
+        start..start + len
+    }
+
+    /// The first `len` bytes of `slot`, to copy a packet into, or `None` if they are outside the
+    /// arena.
+    pub(super) fn slot_mut(&mut self, slot: usize, len: usize) -> Option<&mut [u8]> {
+        let range = self.range(slot, len);
+        self.page.as_mut_slice().get_mut(range)
+    }
+}
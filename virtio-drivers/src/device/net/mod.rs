@@ -1,16 +1,34 @@
 //! Driver for VirtIO network devices.
 
+mod arena;
+#[cfg(feature = "smoltcp")]
+mod phy;
 mod raw;
+mod stats;
 mod ty;
+mod vlan;
 
 extern crate alloc;
 use crate::{
-    error::{VirtIoError, VirtIoResult},
-    hal::Hal,
-    transport::Transport,
+    device::{DeviceIdentity, VirtIoDriver},
+    error::{expect_ok, InitStep, VirtIoError, VirtIoResult},
+    hal::{Hal, MemoryRequirements},
+    queue::{EventSuppression, NeedsResetCheck, QueueInfo, QueueStats},
+    transport::{DeviceType, InterruptStatus, Transport},
 };
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
 use alloc::vec::Vec;
+use arena::TxArena;
+use core::ops::Range;
 pub use raw::VirtIONetRaw;
+pub use stats::{NetQueueStats, NetStats};
+pub use ty::{
+    Features, Flags, GsoType, NetTxHeaderBuilder, RxChecksum, Status, VirtioNetHdr,
+    CONTROL_FEATURES, DEFAULT_MTU, ETH_HLEN, MIN_MTU, MIN_TSO_BUFFER_LEN, NET_HDR_SIZE,
+    OFFLOAD_FEATURES, SUPPORTED_FEATURES, VLAN_FEATURES,
+};
+pub use vlan::{parse_vlan_tag, strip_vlan_tag, VlanTag};
 
 /// Driver for a VirtIO network device.
 ///
@@ -28,40 +46,289 @@ pub use raw::VirtIONetRaw;
 pub struct VirtIONet<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> {
     inner: VirtIONetRaw<H, T, QUEUE_SIZE>,
     rx_buffers: [Vec<u8>; QUEUE_SIZE],
+    /// Copies of the packets queued by [`Self::send_nb`] which the device hasn't finished with,
+    /// keyed by token. Declared after `inner`, so they outlive the queues on drop.
+    tx_buffers: BTreeMap<u16, TxCopy>,
+    /// Where [`Self::send_nb`] copies packets to if set, see [`Self::set_tx_arena`]. Declared
+    /// after `inner` for the same reason.
+    tx_arena: Option<TxArena>,
+    /// A receive buffer whose packet was lent out without a handle to recycle it, which the next
+    /// [`Self::receive`] gives back to the device first.
+    deferred_rx: Option<u16>,
+    /// The tokens of the receive buffers [`Self::poll`] took from the device, with the length of
+    /// the packet in each or the error completing it, which [`Self::receive`] returns before
+    /// asking the device for more. They are kept in order, as the buffers have to be given back
+    /// in the order they were taken for their tokens to stay the same.
+    polled_rx: VecDeque<(u16, VirtIoResult<usize>)>,
+    /// Whether the queues are polled rather than interrupting, see [`Self::set_poll_mode`].
+    poll_mode: bool,
 }
 
 impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE> {
+    /// The most descriptors each queue has, and receive buffers, as the device may offer fewer.
+    pub const QUEUE_SIZE: usize = QUEUE_SIZE;
+    /// The virtqueue index of the receive queue.
+    pub const RECEIVE_QUEUE: u16 = VirtIONetRaw::<H, T, QUEUE_SIZE>::RECEIVE_QUEUE;
+    /// The virtqueue index of the transmit queue.
+    pub const TRANSMIT_QUEUE: u16 = VirtIONetRaw::<H, T, QUEUE_SIZE>::TRANSMIT_QUEUE;
+    /// The virtqueue index of the control queue, if [`Self::num_queues`] includes it.
+    pub const CONTROL_QUEUE: u16 = VirtIONetRaw::<H, T, QUEUE_SIZE>::CONTROL_QUEUE;
+
+    /// The memory [`Self::new`] allocates with the given receive buffer length: the queues of
+    /// [`VirtIONetRaw`], plus a receive buffer for each queue slot. A transmit arena comes on top,
+    /// see [`Self::tx_arena_requirements`].
+    pub const fn memory_requirements(buf_len: usize) -> MemoryRequirements {
+        VirtIONetRaw::<H, T, QUEUE_SIZE>::memory_requirements()
+            .with_shared_heap(QUEUE_SIZE * buf_len)
+    }
+
+    /// The memory [`Self::set_tx_arena`] allocates for an arena of `size` bytes, at most.
+    pub const fn tx_arena_requirements(size: usize) -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(0).with_buffer(size)
+    }
+
     /// Create a new VirtIO-Net driver.
+    ///
+    /// The receive buffers are `buf_len` bytes long, or longer if the device's MTU needs it, see
+    /// [`VirtIONetRaw::min_rx_buffer_len`].
     pub fn new(transport: T, buf_len: usize) -> VirtIoResult<Self> {
-        let mut inner = VirtIONetRaw::new(transport)?;
+        Self::from_raw(VirtIONetRaw::new(transport)?, buf_len)
+    }
+
+    /// Create a new VirtIO-Net driver which also negotiates VLAN filtering, see
+    /// [`VirtIONetRaw::new_with_vlan_filtering`].
+    pub fn new_with_vlan_filtering(transport: T, buf_len: usize) -> VirtIoResult<Self> {
+        Self::from_raw(VirtIONetRaw::new_with_vlan_filtering(transport)?, buf_len)
+    }
+
+    /// Create a new VirtIO-Net driver which also negotiates the control queue, see
+    /// [`VirtIONetRaw::new_with_control_queue`].
+    pub fn new_with_control_queue(transport: T, buf_len: usize) -> VirtIoResult<Self> {
+        Self::from_raw(VirtIONetRaw::new_with_control_queue(transport)?, buf_len)
+    }
+
+    /// Create a new VirtIO-Net driver which also negotiates the given offloads, see
+    /// [`VirtIONetRaw::new_with_offloads`].
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] before touching the device if receive segmentation
+    /// offload is asked for but `buf_len` is less than [`MIN_TSO_BUFFER_LEN`].
+    pub fn new_with_offloads(
+        transport: T,
+        buf_len: usize,
+        offloads: Features,
+    ) -> VirtIoResult<Self> {
+        if offloads.intersects(Features::GUEST_TSO4 | Features::GUEST_TSO6)
+            && buf_len < MIN_TSO_BUFFER_LEN
+        {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Self::from_raw(
+            VirtIONetRaw::new_with_offloads(transport, offloads)?,
+            buf_len,
+        )
+    }
+
+    /// Create a new VirtIO-Net driver which only negotiates the features in `wanted`, see
+    /// [`VirtIONetRaw::new_with_features`].
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] before touching the device if receive segmentation
+    /// offload is asked for but `buf_len` is less than [`MIN_TSO_BUFFER_LEN`].
+    pub fn new_with_features(transport: T, buf_len: usize, wanted: Features) -> VirtIoResult<Self> {
+        if wanted.intersects(Features::GUEST_TSO4 | Features::GUEST_TSO6)
+            && buf_len < MIN_TSO_BUFFER_LEN
+        {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Self::from_raw(VirtIONetRaw::new_with_features(transport, wanted)?, buf_len)
+    }
 
+    fn from_raw(mut inner: VirtIONetRaw<H, T, QUEUE_SIZE>, buf_len: usize) -> VirtIoResult<Self> {
         const NONE_BUF: Vec<u8> = Vec::new();
         let mut rx_buffers = [NONE_BUF; QUEUE_SIZE];
-        for (i, rx_buf) in rx_buffers.iter_mut().enumerate() {
+        Self::add_rx_buffers(&mut inner, &mut rx_buffers, buf_len)?;
+
+        Ok(VirtIONet {
+            inner,
+            rx_buffers,
+            tx_buffers: BTreeMap::new(),
+            tx_arena: None,
+            deferred_rx: None,
+            polled_rx: VecDeque::new(),
+            poll_mode: false,
+        })
+    }
+
+    /// Gives every receive buffer to the device, after resizing it to `buf_len` bytes or the least
+    /// the MTU needs, whichever is more.
+    fn add_rx_buffers(
+        inner: &mut VirtIONetRaw<H, T, QUEUE_SIZE>,
+        rx_buffers: &mut [Vec<u8>; QUEUE_SIZE],
+        buf_len: usize,
+    ) -> VirtIoResult<()> {
+        // Buffers too short for the MTU would be refused, and packets truncated if they weren't.
+        let buf_len = buf_len.max(inner.min_rx_buffer_len());
+        // Only as many buffers as the receive queue turned out to have room for.
+        let slots = usize::from(inner.queues()[usize::from(Self::RECEIVE_QUEUE)].size);
+        for (i, rx_buf) in rx_buffers.iter_mut().enumerate().take(slots) {
             rx_buf.resize(buf_len, 0);
             // Safe because the buffer lives as long as the queue.
-            let token = inner.receive_begin(rx_buf.as_mut())?;
-            assert_eq!(token, i as u16);
+            let error = match inner.receive_begin(rx_buf.as_mut()) {
+                Ok(token) if token == i as u16 => continue,
+                Ok(_) => VirtIoError::WrongToken,
+                Err(e) => e,
+            };
+            return Err(inner.fail_init(InitStep::InitialBuffers, error));
+        }
+        Ok(())
+    }
+
+    /// Resets the device and initializes it again, see [`VirtIONetRaw::reset`], then gives it
+    /// every receive buffer again.
+    ///
+    /// Packets which were received but not yet returned by [`Self::receive`], and any still
+    /// queued for sending, are lost.
+    pub fn reset(&mut self) -> VirtIoResult<()> {
+        self.inner.reset()?;
+        self.tx_buffers.clear();
+        if let Some(arena) = &mut self.tx_arena {
+            arena.free_all();
+        }
+        self.deferred_rx = None;
+        self.polled_rx.clear();
+        let buf_len = self.rx_buffers.iter().map(Vec::len).max().unwrap_or(0);
+        Self::add_rx_buffers(&mut self.inner, &mut self.rx_buffers, buf_len)?;
+        // The new queues start out asking for interrupts again.
+        self.set_poll_mode(self.poll_mode)
+    }
+
+    /// Asks the device not to interrupt for either queue if `enabled`, for a kernel with no
+    /// interrupt controller to route the device's interrupts through, which calls [`Self::poll`]
+    /// from a timer instead. Turning it off asks for interrupts again.
+    ///
+    /// The mode is kept across [`Self::reset`].
+    pub fn set_poll_mode(&mut self, enabled: bool) -> VirtIoResult<()> {
+        let suppression = if enabled {
+            EventSuppression::Always
+        } else {
+            EventSuppression::default()
+        };
+        for queue in [Self::RECEIVE_QUEUE, Self::TRANSMIT_QUEUE] {
+            self.inner.set_event_suppression(queue, suppression)?;
+        }
+        self.poll_mode = enabled;
+        Ok(())
+    }
+
+    /// Returns whether the queues are polled, see [`Self::set_poll_mode`].
+    pub fn poll_mode(&self) -> bool {
+        self.poll_mode
+    }
+
+    /// Does what an interrupt handler would, for a kernel which calls it from a timer tick in
+    /// [poll mode](Self::set_poll_mode): takes every packet the device has received since the
+    /// last call, to be returned by [`Self::receive`] later, and frees the buffers of completed
+    /// transmissions like [`Self::reclaim_tx`].
+    ///
+    /// Only the used rings are read, so a tick costs no register access. A packet too short for
+    /// its header is still taken, and dropped by [`Self::receive`] when its turn comes.
+    pub fn poll(&mut self) -> VirtIoResult<NetPoll> {
+        let mut received = 0;
+        while let Some((token, _)) = self.inner.can_recv()? {
+            let packet = match self.inner.receive_complete(token) {
+                Ok((_, pkt_len)) => Ok(pkt_len),
+                Err(VirtIoError::IoError) => Err(VirtIoError::IoError),
+                Err(e) => return Err(e),
+            };
+            self.polled_rx.push_back((token, packet));
+            received += 1;
         }
+        let reclaimed = self.reclaim_tx()?;
+        Ok(NetPoll {
+            received,
+            reclaimed,
+        })
+    }
 
-        Ok(VirtIONet { inner, rx_buffers })
+    /// Returns how many packets [`Self::poll`] took from the device which [`Self::receive`]
+    /// hasn't returned yet.
+    pub fn polled_rx(&self) -> usize {
+        self.polled_rx.len()
     }
 
-    /// Acknowledge interrupt.
-    pub fn ack_interrupt(&mut self) -> VirtIoResult<bool> {
+    /// Acknowledge interrupt, see [`VirtIONetRaw::ack_interrupt`].
+    pub fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
         self.inner.ack_interrupt()
     }
 
-    /// Disable interrupts.
+    /// Reads the link status, see [`VirtIONetRaw::handle_config_change`].
+    pub fn handle_config_change(&mut self) -> VirtIoResult<Status> {
+        self.inner.handle_config_change()
+    }
+
+    /// Like [`Self::ack_interrupt`], but only checks the interrupt status without acknowledging
+    /// it, for polling the device before its interrupt is routed.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        self.inner.poll_interrupt()
+    }
+
+    /// Processes the completions on a single queue, see [`VirtIONetRaw::handle_queue_interrupt`].
+    pub fn handle_queue_interrupt(&mut self, queue: u16) -> VirtIoResult<bool> {
+        self.inner.handle_queue_interrupt(queue)
+    }
+
+    // Disable interrupts.
     // pub fn disable_interrupts(&mut self) -> VirtIoResult<()> {
     //     self.inner.disable_interrupts()
     // }
 
-    /// Enable interrupts.
+    // Enable interrupts.
     // pub fn enable_interrupts(&mut self) -> VirtIoResult<()> {
     //     self.inner.disable_interrupts()
     // }
 
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> Features {
+        self.inner.negotiated_features()
+    }
+
+    /// Returns the transport, see [`VirtIONetRaw::transport`].
+    pub fn transport(&self) -> &T {
+        self.inner.transport()
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        self.inner.queues()
+    }
+
+    /// Returns how many virtqueues the driver uses: 3 if it has a control queue, otherwise 2.
+    pub fn num_queues(&self) -> u16 {
+        self.inner.num_queues()
+    }
+
+    /// Returns the checksum and segmentation offloads negotiated with the device, see
+    /// [`VirtIONetRaw::offloads`].
+    pub fn offloads(&self) -> Features {
+        self.inner.offloads()
+    }
+
+    /// Returns a builder for a transmit header requesting offloads, for
+    /// [`Self::send_with_header`] and [`Self::send_nb_with_header`].
+    pub fn tx_header_builder(&self) -> NetTxHeaderBuilder {
+        self.inner.tx_header_builder()
+    }
+
+    /// Returns the MTU the device reported, or [`DEFAULT_MTU`] if it didn't.
+    pub fn mtu(&self) -> u16 {
+        self.inner.mtu()
+    }
+
+    /// Returns the packet counters of the receive and transmit queues, see
+    /// [`VirtIONetRaw::net_stats`].
+    pub fn net_stats(&self) -> NetStats {
+        self.inner.net_stats()
+    }
+
     /// Get MAC address.
     pub fn mac_address(&self) -> VirtIoResult<[u8; 6]> {
         self.inner.mac_address()
@@ -73,26 +340,136 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T,
     }
 
     /// Whether can receive packet. If can, return (token, packet length).
-    pub fn can_recv(&self) -> VirtIoResult<Option<(u16, usize)>> {
+    pub fn can_recv(&mut self) -> VirtIoResult<Option<(u16, usize)>> {
         self.inner.can_recv()
     }
 
-    /// Receives a `[u8]` from network and return length. If currently no data, returns an
-    /// error with type [`Error::NotReady`].
+    /// Lets packets tagged with the given VLAN ID through the device's VLAN filter.
+    pub fn vlan_filter_add(&mut self, vid: u16) -> VirtIoResult<()> {
+        self.inner.vlan_filter_add(vid)
+    }
+
+    /// Removes the given VLAN ID from the device's VLAN filter.
+    pub fn vlan_filter_remove(&mut self, vid: u16) -> VirtIoResult<()> {
+        self.inner.vlan_filter_remove(vid)
+    }
+
+    /// Turns promiscuous mode on or off, see [`VirtIONetRaw::set_promiscuous`].
+    pub fn set_promiscuous(&mut self, on: bool) -> VirtIoResult<()> {
+        self.inner.set_promiscuous(on)
+    }
+
+    /// Turns all-multicast mode on or off, see [`VirtIONetRaw::set_allmulti`].
+    pub fn set_allmulti(&mut self, on: bool) -> VirtIoResult<()> {
+        self.inner.set_allmulti(on)
+    }
+
+    /// Programs the device's MAC address, see [`VirtIONetRaw::set_mac_address`].
+    pub fn set_mac_address(&mut self, mac: [u8; 6]) -> VirtIoResult<()> {
+        self.inner.set_mac_address(mac)
+    }
+
+    /// Tells the device how many queue pairs to use, see [`VirtIONetRaw::set_queue_pairs`].
+    pub fn set_queue_pairs(&mut self, pairs: u16) -> VirtIoResult<()> {
+        self.inner.set_queue_pairs(pairs)
+    }
+
+    /// Receives a packet without copying it, by lending out the receive buffer it arrived in. If
+    /// currently no data, returns an error with type [`VirtIoError::NotReady`].
     ///
-    /// It will try to pop a buffer that completed data reception in the
-    /// NIC queue.
-    pub fn receive(&mut self, data: &mut [u8]) -> VirtIoResult<usize> {
-        if let Some((token, _)) = self.inner.can_recv()? {
-            let rx_buf = &mut self.rx_buffers[token as usize];
-
-            let (hdr_len, pkt_len) = self.inner.receive_complete(token)?;
-            (data[0..pkt_len]).copy_from_slice(&rx_buf[hdr_len..(hdr_len + pkt_len)]);
-            let new_token = self.inner.receive_begin(rx_buf)?;
-            assert_eq!(new_token, token);
-            Ok(pkt_len)
-        } else {
-            Err(VirtIoError::NotReady)
+    /// The header is validated and the checksum completed like [`Self::receive_with_header`]
+    /// does, and packets with a malformed header are dropped the same way. The buffer goes back to
+    /// the device when the returned [`RxBuffer`] is recycled or dropped, so the driver can't be
+    /// used until then.
+    pub fn receive(&mut self) -> VirtIoResult<RxBuffer<'_, H, T, QUEUE_SIZE>> {
+        if let Some(token) = self.deferred_rx.take() {
+            self.recycle_rx(token)?;
+        }
+        let (token, hdr_len, pkt_len) = match self.polled_rx.pop_front() {
+            Some((token, Ok(pkt_len))) => (token, NET_HDR_SIZE, pkt_len),
+            Some((token, Err(e))) => {
+                self.recycle_rx(token)?;
+                return Err(e);
+            }
+            None => {
+                let Some((token, _)) = self.inner.can_recv()? else {
+                    return Err(VirtIoError::NotReady);
+                };
+                let (hdr_len, pkt_len) = self.inner.receive_complete(token)?;
+                (token, hdr_len, pkt_len)
+            }
+        };
+        let rx_buf = &mut self.rx_buffers[token as usize];
+        let packet = hdr_len..hdr_len + pkt_len;
+        let header = self.inner.receive_header(rx_buf, pkt_len).and_then(|hdr| {
+            hdr.complete_checksum(&mut rx_buf[packet.clone()])?;
+            Ok(hdr)
+        });
+        match header {
+            Ok(header) => Ok(RxBuffer {
+                net: self,
+                token,
+                recycled: false,
+                packet,
+                header,
+            }),
+            Err(e) => {
+                // Give the buffer back to the device even if the packet was dropped.
+                self.inner.record_rx_drop();
+                self.recycle_rx(token)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Like [`Self::receive`], but copies the packet into `data` and returns its length, so the
+    /// receive buffer goes straight back to the device.
+    pub fn receive_into(&mut self, data: &mut [u8]) -> VirtIoResult<usize> {
+        self.receive_with_header(data).map(|(len, _)| len)
+    }
+
+    /// Like [`Self::receive_into`], but also returns the header the device wrote before the
+    /// packet.
+    ///
+    /// The header is validated against the negotiated features, and if the device left the
+    /// checksum partial it is completed in software, so the packet in `data` is always fully
+    /// checksummed. A packet with a malformed header is dropped with [`VirtIoError::IoError`],
+    /// and the next call receives the next packet. So is a packet which doesn't fit in `data`,
+    /// with [`VirtIoError::InvalidParam`].
+    pub fn receive_with_header(&mut self, data: &mut [u8]) -> VirtIoResult<(usize, VirtioNetHdr)> {
+        let rx_buf = self.receive()?;
+        let packet = rx_buf.packet();
+        data.get_mut(..packet.len())
+            .ok_or(VirtIoError::InvalidParam)?
+            .copy_from_slice(packet);
+        let received = (packet.len(), *rx_buf.header());
+        rx_buf.recycle()?;
+        Ok(received)
+    }
+
+    /// Gives the receive buffer with the given token back to the device.
+    fn recycle_rx(&mut self, token: u16) -> VirtIoResult<()> {
+        let new_token = self
+            .inner
+            .receive_begin(&mut self.rx_buffers[token as usize])?;
+        if new_token != token {
+            return Err(VirtIoError::WrongToken);
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::receive_into`], but strips the outermost 802.1Q tag from the packet and
+    /// returns it alongside the length of the untagged packet.
+    ///
+    /// Untagged packets are received unchanged, with `None` for the tag.
+    pub fn receive_untagged(&mut self, data: &mut [u8]) -> VirtIoResult<(usize, Option<VlanTag>)> {
+        let len = self.receive_into(data)?;
+        match strip_vlan_tag(data.get_mut(..len).ok_or(VirtIoError::InvalidParam)?) {
+            Some((tag, offset)) => {
+                data.copy_within(offset..len, 0);
+                Ok((len - offset, Some(tag)))
+            }
+            None => Ok((len, None)),
         }
     }
 
@@ -101,4 +478,253 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T,
     pub fn send(&mut self, tx_buf: &[u8]) -> VirtIoResult<()> {
         self.inner.send(tx_buf)
     }
+
+    /// Sends a packet with the given header, e.g. one asking for offloads, and blocks until the
+    /// request completed. See [`VirtIONetRaw::send_with_header`].
+    pub fn send_with_header(&mut self, header: &VirtioNetHdr, packet: &[u8]) -> VirtIoResult<()> {
+        self.inner.send_with_header(header, packet)
+    }
+
+    /// Queues a packet for transmission without waiting for the device, and returns its token.
+    ///
+    /// The packet is copied, so `packet` can be reused straight away. Transmissions which
+    /// completed since the last call are reclaimed first; if the queue is still full this returns
+    /// [`VirtIoError::QueueFull`].
+    pub fn send_nb(&mut self, packet: &[u8]) -> VirtIoResult<u16> {
+        self.send_nb_with_header(&VirtioNetHdr::default(), packet)
+    }
+
+    /// Queues a packet for transmission like [`Self::send_nb`], with the given header, e.g. one
+    /// asking for offloads.
+    ///
+    /// Returns [`VirtIoError::Unsupported`] if the header asks for offloads which weren't
+    /// negotiated, see [`VirtioNetHdr::validate_tx`].
+    pub fn send_nb_with_header(
+        &mut self,
+        header: &VirtioNetHdr,
+        packet: &[u8],
+    ) -> VirtIoResult<u16> {
+        self.reclaim_tx()?;
+        let len = NET_HDR_SIZE + packet.len();
+        if let Some(arena) = &mut self.tx_arena {
+            if let Some(slot) = arena.alloc(len) {
+                let result = arena
+                    .slot_mut(slot, len)
+                    .ok_or(VirtIoError::InvalidParam)
+                    .and_then(|tx_buf| write_tx_buf(tx_buf, header, packet))
+                    .and_then(|()| {
+                        self.inner
+                            .transmit_begin_dma(arena.page(), arena.range(slot, len))
+                    });
+                return match result {
+                    Ok(token) => {
+                        self.tx_buffers.insert(token, TxCopy::Arena(slot));
+                        Ok(token)
+                    }
+                    Err(e) => {
+                        arena.free(slot);
+                        Err(e)
+                    }
+                };
+            }
+        }
+        let mut tx_buf = vec![0; len];
+        write_tx_buf(&mut tx_buf, header, packet)?;
+        let token = self.inner.transmit_begin(&tx_buf)?;
+        self.tx_buffers.insert(token, TxCopy::Heap(tx_buf));
+        Ok(token)
+    }
+
+    /// Makes [`Self::send_nb`] copy packets into a DMA buffer of up to `size` bytes, allocated
+    /// once with [`Hal::dma_alloc_buf`], or back into the heap if `size` is 0.
+    ///
+    /// Heap copies are shared with the device through [`Hal::share`] one by one, which is
+    /// costly where that maps them into an IOMMU, e.g. with `ACCESS_PLATFORM`. The arena is given
+    /// to the device by the physical address it was allocated at instead, so sending a packet
+    /// costs the copy alone. It is split into slots for a header and a frame of the MTU, at most
+    /// one per queue slot; packets which don't fit in one, or sent while every slot is in use,
+    /// are still copied into the heap.
+    ///
+    /// Waits for the packets already queued to be sent first, see [`Self::flush_tx`]. Returns
+    /// [`VirtIoError::InvalidParam`] if `size` isn't 0 but is too small for a single slot.
+    pub fn set_tx_arena(&mut self, size: usize) -> VirtIoResult<()> {
+        let slot_len = NET_HDR_SIZE + ETH_HLEN + usize::from(self.inner.mtu());
+        if size != 0 && size < slot_len {
+            return Err(VirtIoError::InvalidParam);
+        }
+        self.flush_tx()?;
+        self.tx_arena = TxArena::new::<H, QUEUE_SIZE>(size, slot_len, QUEUE_SIZE);
+        Ok(())
+    }
+
+    /// Frees the buffers of the transmissions queued by [`Self::send_nb`] which the device has
+    /// completed, and returns how many there were.
+    pub fn reclaim_tx(&mut self) -> VirtIoResult<usize> {
+        let mut reclaimed = 0;
+        let tokens: Vec<u16> = self.tx_buffers.keys().copied().collect();
+        for token in tokens {
+            if self.inner.poll_transmit(token)? {
+                self.inner.transmit_complete(token)?;
+                match self.tx_buffers.remove(&token) {
+                    Some(TxCopy::Heap(_buf)) => {}
+                    Some(TxCopy::Arena(slot)) => {
+                        if let Some(arena) = &mut self.tx_arena {
+                            arena.free(slot);
+                        }
+                    }
+                    None => {}
+                }
+                reclaimed += 1;
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Returns the number of transmissions queued by [`Self::send_nb`] which haven't been
+    /// reclaimed yet.
+    pub fn tx_in_flight(&self) -> usize {
+        self.tx_buffers.len()
+    }
+
+    /// Blocks until the device has completed every transmission queued by [`Self::send_nb`].
+    pub fn flush_tx(&mut self) -> VirtIoResult<()> {
+        let mut check = NeedsResetCheck::default();
+        while !self.tx_buffers.is_empty() {
+            if self.reclaim_tx()? == 0 {
+                check.tick(self.inner.transport())?;
+                H::wait_for_used();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What one [`VirtIONet::poll`] found the device had done since the last.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NetPoll {
+    /// The packets taken from the receive queue, for [`VirtIONet::receive`] to return, including
+    /// any it will drop as malformed.
+    pub received: usize,
+    /// The transmissions whose buffers were freed.
+    pub reclaimed: usize,
+}
+
+/// Where [`VirtIONet::send_nb`] copied a packet to.
+enum TxCopy {
+    /// A buffer of its own, which is freed once the device has sent it.
+    Heap(Vec<u8>),
+    /// A slot of the transmit arena, which goes back to it once the device has sent it.
+    Arena(usize),
+}
+
+/// Writes `header` followed by `packet` into `tx_buf`, which must have room for both.
+fn write_tx_buf(tx_buf: &mut [u8], header: &VirtioNetHdr, packet: &[u8]) -> VirtIoResult<()> {
+    let (header_buf, packet_buf) = tx_buf
+        .split_at_mut_checked(NET_HDR_SIZE)
+        .ok_or(VirtIoError::InvalidParam)?;
+    header.write_to(header_buf)?;
+    packet_buf
+        .get_mut(..packet.len())
+        .ok_or(VirtIoError::InvalidParam)?
+        .copy_from_slice(packet);
+    Ok(())
+}
+
+/// A packet received by [`VirtIONet::receive`], still in the driver's receive buffer.
+///
+/// The buffer is given back to the device by [`Self::recycle`], or when the handle is dropped.
+pub struct RxBuffer<'a, H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> {
+    net: &'a mut VirtIONet<H, T, QUEUE_SIZE>,
+    token: u16,
+    /// Whether [`Self::recycle`] already gave the buffer back.
+    recycled: bool,
+    /// Where the packet is in the buffer, after the header.
+    packet: Range<usize>,
+    header: VirtioNetHdr,
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> RxBuffer<'_, H, T, QUEUE_SIZE> {
+    /// Returns the packet, without the header.
+    pub fn packet(&self) -> &[u8] {
+        &self.net.rx_buffers[usize::from(self.token)][self.packet.clone()]
+    }
+
+    /// Returns the packet mutably, e.g. to rewrite it in place before passing it on.
+    pub fn packet_mut(&mut self) -> &mut [u8] {
+        &mut self.net.rx_buffers[usize::from(self.token)][self.packet.clone()]
+    }
+
+    /// Returns the header the device wrote before the packet.
+    pub fn header(&self) -> &VirtioNetHdr {
+        &self.header
+    }
+
+    /// Gives the buffer back to the device, reporting any error rather than handling it like
+    /// dropping the handle does.
+    pub fn recycle(mut self) -> VirtIoResult<()> {
+        self.recycled = true;
+        self.net.recycle_rx(self.token)
+    }
+
+    /// Leaves the buffer for the next [`VirtIONet::receive`] to recycle, so the packet can be lent
+    /// out without the handle. Returns the token of the buffer and where the packet is in it.
+    #[cfg(feature = "smoltcp")]
+    fn defer(mut self) -> (u16, Range<usize>) {
+        self.recycled = true;
+        self.net.deferred_rx = Some(self.token);
+        (self.token, self.packet.clone())
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> Drop
+    for RxBuffer<'_, H, T, QUEUE_SIZE>
+{
+    fn drop(&mut self) {
+        if !self.recycled {
+            expect_ok(
+                self.net.recycle_rx(self.token),
+                "failed to recycle receive buffer",
+            );
+        }
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIoDriver
+    for VirtIONet<H, T, QUEUE_SIZE>
+{
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Network
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        self.inner.ack_interrupt()
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        self.inner.poll_interrupt()
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        self.inner.identity()
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        self.inner.stats()
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        self.inner.set_event_suppression(queue, suppression)
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.inner.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.inner.shutdown()
+    }
 }
@@ -0,0 +1,98 @@
+//! [`smoltcp`] integration for [`VirtIONet`].
+
+use super::{VirtIONet, VirtIONetRaw, ETH_HLEN};
+use crate::error::VirtIoError;
+use crate::hal::Hal;
+use crate::transport::Transport;
+use alloc::vec;
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+
+/// Lends smoltcp a received packet in place, in the driver's receive buffer.
+///
+/// The buffer goes back to the device on the next call to [`VirtIONet::receive`], which
+/// [`phy::Device::receive`] makes.
+pub struct RxToken<'a> {
+    packet: &'a mut [u8],
+}
+
+/// Sends a packet smoltcp writes into a temporary buffer, blocking until the device has sent it.
+pub struct TxToken<'a, H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> {
+    net: &'a mut VirtIONetRaw<H, T, QUEUE_SIZE>,
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> phy::Device
+    for VirtIONet<H, T, QUEUE_SIZE>
+{
+    type RxToken<'a>
+        = RxToken<'a>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'a, H, T, QUEUE_SIZE>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let (token, packet) = match self.receive() {
+            Ok(rx_buf) => rx_buf.defer(),
+            Err(VirtIoError::NotReady) => return None,
+            Err(e) => {
+                warn!("failed to receive packet: {}", e);
+                return None;
+            }
+        };
+        Some((
+            RxToken {
+                packet: &mut self.rx_buffers[usize::from(token)][packet],
+            },
+            TxToken {
+                net: &mut self.inner,
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        match self.inner.can_send() {
+            Ok(true) => Some(TxToken {
+                net: &mut self.inner,
+            }),
+            _ => None,
+        }
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        // smoltcp counts the Ethernet header, and the receive buffers are sized to fit it.
+        caps.max_transmission_unit = ETH_HLEN + usize::from(self.mtu());
+        caps.medium = Medium::Ethernet;
+        caps
+    }
+}
+
+impl phy::RxToken for RxToken<'_> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(self.packet)
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> phy::TxToken
+    for TxToken<'_, H, T, QUEUE_SIZE>
+{
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        // smoltcp has no way to hear about a failed transmission, and treats it like a lost
+        // packet anyway.
+        if let Err(e) = self.net.send(&packet) {
+            warn!("failed to send packet: {}", e);
+        }
+        result
+    }
+}
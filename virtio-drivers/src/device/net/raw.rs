@@ -1,12 +1,17 @@
+use super::stats::NetStats;
 use super::ty::*;
-use crate::error::{VirtIoError, VirtIoResult};
-use crate::hal::Hal;
-use crate::queue::{DescFlag, Descriptor, VirtIoQueue};
-use crate::transport::Transport;
+use super::vlan::{ethernet_header_len, VlanTag};
+use crate::device::common::request_response;
+use crate::device::{DeviceIdentity, VirtIoDriver};
+use crate::error::{InitStep, VirtIoError, VirtIoResult};
+use crate::hal::{BufferDirection, DevicePage, DmaBuf, Hal, MemoryRequirements};
+use crate::queue::{Buffer, EventSuppression, QueueInfo, QueueStats, VirtIoQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
 use crate::volatile::ReadVolatile;
 use alloc::vec;
+use alloc::vec::Vec;
 use core::mem::size_of;
-use log::{debug, info, warn};
+use core::ops::Range;
 
 /// Raw driver for a VirtIO block device.
 ///
@@ -18,57 +23,398 @@ use log::{debug, info, warn};
 /// [`VirtIONet`]: super::VirtIONet
 pub struct VirtIONetRaw<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> {
     transport: T,
+    features: Features,
     mac: EthernetAddress,
     recv_queue: VirtIoQueue<H, QUEUE_SIZE>,
     send_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    /// Only present if [`Features::CTRL_VQ`] was negotiated.
+    ctrl_queue: Option<VirtIoQueue<H, QUEUE_SIZE>>,
+    /// 1 unless [`Features::MQ`] was negotiated.
+    max_queue_pairs: u16,
+    /// The MTU the device reported, if [`Features::MTU`] was negotiated.
+    mtu: Option<u16>,
+    /// Copied from the queues when they were created, for [`Self::queues`].
+    queue_info: Vec<QueueInfo>,
+    /// The features the driver was created to accept, kept for [`Self::reset`].
+    supported_features: Features,
+    stats: NetStats,
+}
+
+/// What [`VirtIONetRaw::setup`] negotiates, reads and allocates: everything but the transport.
+struct Setup<H: Hal<QUEUE_SIZE>, const QUEUE_SIZE: usize> {
+    features: Features,
+    mac: EthernetAddress,
+    recv_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    send_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    ctrl_queue: Option<VirtIoQueue<H, QUEUE_SIZE>>,
+    max_queue_pairs: u16,
+    mtu: Option<u16>,
+}
+
+impl<H: Hal<QUEUE_SIZE>, const QUEUE_SIZE: usize> Setup<H, QUEUE_SIZE> {
+    fn queue_info(&self) -> Vec<QueueInfo> {
+        let mut queue_info = vec![self.recv_queue.info(), self.send_queue.info()];
+        queue_info.extend(self.ctrl_queue.as_ref().map(VirtIoQueue::info));
+        queue_info
+    }
 }
 
 impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZE> {
+    /// The most descriptors each queue has, as the device may offer fewer.
+    pub const QUEUE_SIZE: usize = QUEUE_SIZE;
+    /// The virtqueue index of the receive queue.
+    pub const RECEIVE_QUEUE: u16 = QUEUE_RECEIVE;
+    /// The virtqueue index of the transmit queue.
+    pub const TRANSMIT_QUEUE: u16 = QUEUE_TRANSMIT;
+    /// The virtqueue index of the control queue, if [`Self::num_queues`] includes it and the device
+    /// has a single queue pair. See [`Self::control_queue`] for the index on any device.
+    pub const CONTROL_QUEUE: u16 = QUEUE_CTRL;
+
+    /// The memory [`Self::new`] allocates: the receive and transmit queues.
+    ///
+    /// [`Self::new_with_vlan_filtering`] and [`Self::new_with_control_queue`] may also allocate a
+    /// control queue, see [`Self::vlan_memory_requirements`].
+    pub const fn memory_requirements() -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(2)
+    }
+
+    /// The memory [`Self::new_with_vlan_filtering`] allocates if the device offers VLAN
+    /// filtering.
+    pub const fn vlan_memory_requirements() -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(3)
+    }
+
     /// Create a new VirtIO-Net driver.
-    pub fn new(mut transport: T) -> VirtIoResult<Self> {
-        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+    pub fn new(transport: T) -> VirtIoResult<Self> {
+        Self::with_features(transport, SUPPORTED_FEATURES)
+    }
+
+    /// Create a new VirtIO-Net driver which also negotiates VLAN filtering, if the device offers
+    /// it.
+    ///
+    /// Once negotiated, the device drops tagged packets unless their VLAN was added with
+    /// [`vlan_filter_add`](Self::vlan_filter_add). Untagged packets are unaffected.
+    pub fn new_with_vlan_filtering(transport: T) -> VirtIoResult<Self> {
+        Self::with_features(transport, SUPPORTED_FEATURES | VLAN_FEATURES)
+    }
+
+    /// Create a new VirtIO-Net driver which also negotiates the control queue, if the device offers
+    /// it, for [`set_promiscuous`], [`set_allmulti`], [`set_mac_address`] and
+    /// [`set_queue_pairs`].
+    ///
+    /// The driver still only uses the first pair of receive and transmit queues.
+    ///
+    /// [`set_promiscuous`]: Self::set_promiscuous
+    /// [`set_allmulti`]: Self::set_allmulti
+    /// [`set_mac_address`]: Self::set_mac_address
+    /// [`set_queue_pairs`]: Self::set_queue_pairs
+    pub fn new_with_control_queue(transport: T) -> VirtIoResult<Self> {
+        Self::with_features(transport, SUPPORTED_FEATURES | CONTROL_FEATURES)
+    }
+
+    /// Create a new VirtIO-Net driver which also negotiates the given checksum and segmentation
+    /// offloads, those of them the device offers.
+    ///
+    /// `offloads` must be a subset of [`OFFLOAD_FEATURES`], or this returns
+    /// [`VirtIoError::InvalidParam`]. Offloads also need the ones they depend on, e.g.
+    /// [`Features::HOST_TSO4`] needs [`Features::CSUM`], or negotiation fails with
+    /// [`VirtIoError::MissingFeatureDependency`]. With receive segmentation offload, receive
+    /// buffers must hold at least [`MIN_TSO_BUFFER_LEN`] bytes.
+    ///
+    /// Check [`Self::offloads`] for which were negotiated, and request them per packet with
+    /// [`Self::tx_header_builder`].
+    pub fn new_with_offloads(transport: T, offloads: Features) -> VirtIoResult<Self> {
+        if !OFFLOAD_FEATURES.contains(offloads) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Self::with_features(transport, SUPPORTED_FEATURES | offloads)
+    }
+
+    /// Create a new VirtIO-Net driver which only negotiates the features in `wanted`, those of
+    /// them the device offers, e.g. [`SUPPORTED_FEATURES`] without [`Features::RING_EVENT_IDX`]
+    /// to have every packet notified.
+    ///
+    /// `wanted` may hold any of [`SUPPORTED_FEATURES`], [`VLAN_FEATURES`], [`CONTROL_FEATURES`]
+    /// and [`OFFLOAD_FEATURES`], or this returns [`VirtIoError::InvalidParam`]. The caveats of
+    /// [`Self::new_with_vlan_filtering`] and [`Self::new_with_offloads`] apply to the features
+    /// they negotiate.
+    pub fn new_with_features(transport: T, wanted: Features) -> VirtIoResult<Self> {
+        let negotiable = SUPPORTED_FEATURES | VLAN_FEATURES | CONTROL_FEATURES | OFFLOAD_FEATURES;
+        if !negotiable.contains(wanted) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Self::with_features(transport, wanted)
+    }
+
+    fn with_features(mut transport: T, supported_features: Features) -> VirtIoResult<Self> {
+        let setup = Self::setup(&mut transport, supported_features)?;
+        Ok(VirtIONetRaw {
+            transport,
+            queue_info: setup.queue_info(),
+            features: setup.features,
+            mac: setup.mac,
+            recv_queue: setup.recv_queue,
+            send_queue: setup.send_queue,
+            ctrl_queue: setup.ctrl_queue,
+            max_queue_pairs: setup.max_queue_pairs,
+            mtu: setup.mtu,
+            supported_features,
+            stats: NetStats::default(),
+        })
+    }
+
+    /// Negotiates features, reads the config space and sets up the queues, through to
+    /// `DRIVER_OK`.
+    fn setup(
+        transport: &mut T,
+        supported_features: Features,
+    ) -> VirtIoResult<Setup<H, QUEUE_SIZE>> {
+        let negotiated_features = transport
+            .init_step(InitStep::Negotiation, |t| {
+                t.begin_init_with_fallback(Features::empty(), supported_features)
+            })?
+            .features;
         info!("negotiated_features {:?}", negotiated_features);
         // read configuration space
         let config = NetConfig::default();
-        let io_region = transport.io_region();
-        let mac = config.mac.read(io_region)?;
-        // Safe because config points to a valid MMIO region for the config space.
-        debug!(
-            "Got MAC={:02x?}, status={:?}",
-            mac,
-            config.status.read(io_region)
-        );
+        let (mac, max_queue_pairs, mtu) = transport.init_step(InitStep::ReadConfig, |t| {
+            // The MAC address is always there, the other fields depend on features.
+            t.check_config_space(size_of::<EthernetAddress>())?;
+            let mac = config.mac.read(t.io_region())?;
+            debug!(
+                "Got MAC={:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}, status={:?}",
+                mac[0],
+                mac[1],
+                mac[2],
+                mac[3],
+                mac[4],
+                mac[5],
+                config
+                    .status
+                    .read_optional(t, negotiated_features.contains(Features::STATUS))
+            );
+            let max_queue_pairs = match config
+                .max_virtqueue_pairs
+                .read_optional(t, negotiated_features.contains(Features::MQ))?
+            {
+                None => 1,
+                Some(pairs @ 1..=CTRL_MQ_VQ_PAIRS_MAX) => pairs,
+                Some(pairs) => {
+                    warn!("Invalid max_virtqueue_pairs {}", pairs);
+                    return Err(VirtIoError::IoError);
+                }
+            };
+            let mtu = match config
+                .mtu
+                .read_optional(t, negotiated_features.contains(Features::MTU))?
+            {
+                Some(mtu) if mtu < MIN_MTU => {
+                    // The device shouldn't have offered the feature, so carry on without it.
+                    warn!("Ignoring invalid MTU {}", mtu);
+                    None
+                }
+                mtu => mtu,
+            };
+            Ok((mac, max_queue_pairs, mtu))
+        })?;
+        // The control queue comes after every receive and transmit queue.
+        let ctrl_queue_index = max_queue_pairs * 2;
 
-        let recv_queue = VirtIoQueue::new(&mut transport, QUEUE_RECEIVE)?;
-        let send_queue = VirtIoQueue::new(&mut transport, QUEUE_TRANSMIT)?;
+        let event_idx = negotiated_features.contains(Features::RING_EVENT_IDX);
+        let (recv_queue, send_queue, ctrl_queue) =
+            transport.init_step(InitStep::QueueSetup, |t| {
+                let mut recv_queue = VirtIoQueue::new(t, QUEUE_RECEIVE)?;
+                recv_queue.set_event_idx(event_idx);
+                let mut send_queue = VirtIoQueue::new(t, QUEUE_TRANSMIT)?;
+                send_queue.set_event_idx(event_idx);
+                let ctrl_queue = if negotiated_features.contains(Features::CTRL_VQ) {
+                    let mut ctrl_queue = VirtIoQueue::new(t, ctrl_queue_index)?;
+                    ctrl_queue.set_event_idx(event_idx);
+                    Some(ctrl_queue)
+                } else {
+                    None
+                };
+                Ok((recv_queue, send_queue, ctrl_queue))
+            })?;
 
-        transport.finish_init()?;
+        transport.init_step(InitStep::DriverOk, T::finish_init)?;
 
-        Ok(VirtIONetRaw {
-            transport,
+        Ok(Setup {
+            features: negotiated_features,
             mac: mac.into(),
             recv_queue,
             send_queue,
+            ctrl_queue,
+            max_queue_pairs,
+            mtu,
         })
     }
 
-    /// Acknowledge interrupt.
-    pub fn ack_interrupt(&mut self) -> VirtIoResult<bool> {
-        self.transport.ack_interrupt()
+    /// Resets the device and initializes it again with the same features on offer, e.g. to
+    /// recover once it has set `DEVICE_NEEDS_RESET`, without recreating the transport.
+    ///
+    /// Every receive buffer and pending transmission is abandoned, and their tokens are no longer
+    /// valid, so receive buffers have to be added again. The statistics are kept. If this fails the
+    /// device is marked `FAILED`, and the driver may only be reset again or dropped.
+    pub fn reset(&mut self) -> VirtIoResult<()> {
+        // The device must let go of the old queues before they are freed.
+        self.transport.reset()?;
+        let setup = Self::setup(&mut self.transport, self.supported_features)?;
+        self.queue_info = setup.queue_info();
+        self.features = setup.features;
+        self.mac = setup.mac;
+        self.recv_queue = setup.recv_queue;
+        self.send_queue = setup.send_queue;
+        self.ctrl_queue = setup.ctrl_queue;
+        self.max_queue_pairs = setup.max_queue_pairs;
+        self.mtu = setup.mtu;
+        Ok(())
+    }
+
+    /// Acknowledge interrupt, returning its causes, which are empty if there was none.
+    ///
+    /// On [`InterruptStatus::CONFIGURATION_CHANGE`], [`Self::handle_config_change`] says whether
+    /// the link went up or down.
+    pub fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        let status = self.transport.ack_interrupt()?;
+        if status.contains(InterruptStatus::USED_RING_UPDATE) {
+            self.recv_queue.collect_used();
+            self.send_queue.collect_used();
+        }
+        Ok(status)
+    }
+
+    /// Reads the link status after an interrupt with [`InterruptStatus::CONFIGURATION_CHANGE`].
+    ///
+    /// Without [`Features::STATUS`] the device can't report the link status, so the link is
+    /// always up.
+    pub fn handle_config_change(&mut self) -> VirtIoResult<Status> {
+        let status = NetConfig::default()
+            .status
+            .read_optional(&self.transport, self.features.contains(Features::STATUS))?
+            .map_or(Status::LINK_UP, Status::from_bits_truncate);
+        debug!("link status {:?}", status);
+        Ok(status)
+    }
+
+    /// Like [`Self::ack_interrupt`], but only checks the interrupt status without acknowledging
+    /// it, for polling the device before its interrupt is routed.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        let used = self
+            .transport
+            .peek_interrupt_status()?
+            .contains(InterruptStatus::USED_RING_UPDATE);
+        if used {
+            self.recv_queue.collect_used();
+            self.send_queue.collect_used();
+        }
+        Ok(used)
     }
 
-    /// Disable interrupts.
+    /// Processes the completions on a single queue, for transports which route the interrupts of
+    /// each queue to its own vector, see [`queue_vector`](crate::transport::queue_vector).
+    ///
+    /// Returns whether the device used any buffers of the queue since the last check: received
+    /// packets for [`Self::RECEIVE_QUEUE`], finished transmissions for [`Self::TRANSMIT_QUEUE`].
+    /// Returns [`VirtIoError::InvalidParam`] if there is no such queue.
+    pub fn handle_queue_interrupt(&mut self, queue: u16) -> VirtIoResult<bool> {
+        let ctrl_queue_index = self.max_queue_pairs * 2;
+        let queue = match (queue, &mut self.ctrl_queue) {
+            (QUEUE_RECEIVE, _) => &mut self.recv_queue,
+            (QUEUE_TRANSMIT, _) => &mut self.send_queue,
+            (_, Some(ctrl_queue)) if queue == ctrl_queue_index => ctrl_queue,
+            _ => return Err(VirtIoError::InvalidParam),
+        };
+        Ok(queue.collect_used() > 0)
+    }
+
+    // Disable interrupts.
     // pub fn disable_interrupts(&mut self) -> VirtIoResult<()> {
     //     self.send_queue.set_dev_notify(false)?;
     //     self.recv_queue.set_dev_notify(false)?;
     // }
 
-    /// Enable interrupts.
+    // Enable interrupts.
     // pub fn enable_interrupts(&mut self) {
     //     self.send_queue.set_dev_notify(true);
     //     self.recv_queue.set_dev_notify(true);
     // }
 
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> Features {
+        self.features
+    }
+
+    /// Returns the transport, e.g. to read the device status from a power management hook.
+    ///
+    /// Only shared access is given, so the device can't be reconfigured behind the driver's back.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        &self.queue_info
+    }
+
+    /// Marks the device as FAILED after a step of initializing it failed, see
+    /// [`Transport::fail_init`].
+    pub(super) fn fail_init(&mut self, step: InitStep, error: VirtIoError) -> VirtIoError {
+        self.transport.fail_init(step, error)
+    }
+
+    /// Returns how many virtqueues the driver uses: 3 if it has a control queue, otherwise 2.
+    pub fn num_queues(&self) -> u16 {
+        self.queue_info.len() as u16
+    }
+
+    /// Returns the virtqueue index of the control queue, if the driver has one.
+    ///
+    /// This is [`Self::CONTROL_QUEUE`] unless the device has several queue pairs.
+    pub fn control_queue(&self) -> Option<u16> {
+        self.ctrl_queue.as_ref().map(|_| self.max_queue_pairs * 2)
+    }
+
+    /// Returns how many pairs of receive and transmit queues the device has, 1 unless it
+    /// negotiated [`Features::MQ`].
+    pub fn max_queue_pairs(&self) -> u16 {
+        self.max_queue_pairs
+    }
+
+    /// Returns the checksum and segmentation offloads negotiated with the device.
+    ///
+    /// With [`Features::CSUM`], stacks can leave transmitted checksums for the device to fill in,
+    /// and with [`Features::GUEST_CSUM`] the device may leave received ones partial, see
+    /// [`VirtioNetHdr::rx_checksum`].
+    pub fn offloads(&self) -> Features {
+        self.features & OFFLOAD_FEATURES
+    }
+
+    /// Returns the MTU the device reported, or [`DEFAULT_MTU`] if it didn't.
+    ///
+    /// Once reported, packets longer than the MTU plus their Ethernet header are refused rather
+    /// than sent, unless they are to be segmented by the device, and receive buffers must hold
+    /// packets that long, see [`Self::min_rx_buffer_len`].
+    pub fn mtu(&self) -> u16 {
+        self.mtu.unwrap_or(DEFAULT_MTU)
+    }
+
+    /// Returns how long receive buffers must be, including the header, for the device's MTU and
+    /// the negotiated offloads.
+    pub fn min_rx_buffer_len(&self) -> usize {
+        let mut min_len = MIN_BUFFER_LEN;
+        if let Some(mtu) = self.mtu {
+            min_len = min_len.max(NET_HDR_SIZE + ETH_HLEN + usize::from(mtu));
+        }
+        if self
+            .features
+            .intersects(Features::GUEST_TSO4 | Features::GUEST_TSO6)
+        {
+            min_len = min_len.max(MIN_TSO_BUFFER_LEN);
+        }
+        min_len
+    }
+
     /// Get MAC address.
     pub fn mac_address(&self) -> VirtIoResult<[u8; 6]> {
         Ok(self.mac.into())
@@ -78,19 +424,129 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H,
     pub fn can_send(&self) -> VirtIoResult<bool> {
         Ok(self.send_queue.available_desc() >= 2)
     }
-    /// Whether can receive packet. If can, return (token, packet length).
-    pub fn can_recv(&self) -> VirtIoResult<Option<(u16, usize)>> {
-        let token = self.recv_queue.peek_used();
-        if let None = token {
-            return Ok(None);
+
+    /// Whether the device is filtering packets by VLAN, see [`Self::new_with_vlan_filtering`].
+    pub fn vlan_filtering(&self) -> bool {
+        self.features.contains(VLAN_FEATURES)
+    }
+
+    /// Lets packets tagged with the given VLAN ID through the device's VLAN filter.
+    ///
+    /// Returns [`VirtIoError::Unsupported`] if VLAN filtering wasn't negotiated, and
+    /// [`VirtIoError::InvalidParam`] if `vid` isn't a 12-bit VLAN ID.
+    pub fn vlan_filter_add(&mut self, vid: u16) -> VirtIoResult<()> {
+        self.vlan_filter_command(CTRL_VLAN_ADD, vid)
+    }
+
+    /// Removes the given VLAN ID from the device's VLAN filter, so packets tagged with it are
+    /// dropped.
+    pub fn vlan_filter_remove(&mut self, vid: u16) -> VirtIoResult<()> {
+        self.vlan_filter_command(CTRL_VLAN_DEL, vid)
+    }
+
+    fn vlan_filter_command(&mut self, command: u8, vid: u16) -> VirtIoResult<()> {
+        if !self.vlan_filtering() {
+            return Err(VirtIoError::Unsupported);
+        }
+        if vid > VlanTag::MAX_VID {
+            return Err(VirtIoError::InvalidParam);
         }
-        let token = token.unwrap();
-        Ok(Some((token, self.recv_queue.get_desc_len(token))))
+        self.ctrl_command(CTRL_CLASS_VLAN, command, &vid.to_le_bytes())
+    }
+
+    /// Turns promiscuous mode on or off, in which the device passes up every packet it sees.
+    ///
+    /// Returns [`VirtIoError::Unsupported`] unless [`Features::CTRL_RX`] was negotiated, see
+    /// [`Self::new_with_control_queue`].
+    pub fn set_promiscuous(&mut self, on: bool) -> VirtIoResult<()> {
+        self.rx_mode_command(CTRL_RX_PROMISC, on)
+    }
+
+    /// Turns all-multicast mode on or off, in which the device passes up every multicast packet.
+    ///
+    /// Returns [`VirtIoError::Unsupported`] unless [`Features::CTRL_RX`] was negotiated.
+    pub fn set_allmulti(&mut self, on: bool) -> VirtIoResult<()> {
+        self.rx_mode_command(CTRL_RX_ALLMULTI, on)
+    }
+
+    fn rx_mode_command(&mut self, command: u8, on: bool) -> VirtIoResult<()> {
+        if !self.features.contains(Features::CTRL_RX) {
+            return Err(VirtIoError::Unsupported);
+        }
+        self.ctrl_command(CTRL_CLASS_RX, command, &[u8::from(on)])
+    }
+
+    /// Programs the MAC address the device filters received packets by and sends packets from.
+    ///
+    /// Returns [`VirtIoError::Unsupported`] unless [`Features::CTL_MAC_ADDR`] was negotiated.
+    /// [`Self::mac_address`] returns the new address once the device accepts it.
+    pub fn set_mac_address(&mut self, mac: [u8; 6]) -> VirtIoResult<()> {
+        if !self.features.contains(Features::CTL_MAC_ADDR) {
+            return Err(VirtIoError::Unsupported);
+        }
+        self.ctrl_command(CTRL_CLASS_MAC, CTRL_MAC_ADDR_SET, &mac)?;
+        self.mac = mac.into();
+        Ok(())
+    }
+
+    /// Tells the device how many queue pairs to steer packets across.
+    ///
+    /// Returns [`VirtIoError::Unsupported`] unless [`Features::MQ`] was negotiated, and
+    /// [`VirtIoError::InvalidParam`] unless `pairs` is between 1 and [`Self::max_queue_pairs`].
+    /// This driver only services the first pair, so packets steered to the others wait until
+    /// another driver services them.
+    pub fn set_queue_pairs(&mut self, pairs: u16) -> VirtIoResult<()> {
+        if !self.features.contains(Features::MQ) {
+            return Err(VirtIoError::Unsupported);
+        }
+        if !(1..=self.max_queue_pairs).contains(&pairs) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        self.ctrl_command(CTRL_CLASS_MQ, CTRL_MQ_VQ_PAIRS_SET, &pairs.to_le_bytes())
+    }
+
+    /// Sends a command on the control queue and blocks until the device acknowledges it.
+    fn ctrl_command(&mut self, class: u8, command: u8, data: &[u8]) -> VirtIoResult<()> {
+        let queue = self.ctrl_queue.as_mut().ok_or(VirtIoError::Unsupported)?;
+        let header = CtrlHeader { class, command };
+        request_response(
+            queue,
+            &mut self.transport,
+            &header,
+            vec![DmaBuf::readable(data)],
+            [CTRL_ACK_ERR],
+            |ack| match ack {
+                [CTRL_ACK_OK] => Ok(()),
+                _ => Err(VirtIoError::IoError),
+            },
+        )
+    }
+    /// Returns the packet counters of the receive and transmit queues since the driver was
+    /// created.
+    ///
+    /// Packets are counted when they complete, through [`Self::transmit_complete`],
+    /// [`Self::receive_complete`] or the blocking and async methods built on them. Receive drops
+    /// are counted by [`VirtIONet`](super::VirtIONet), which discards malformed packets itself.
+    pub fn net_stats(&self) -> NetStats {
+        self.stats
+    }
+
+    /// Counts a received packet which was discarded because its header was malformed.
+    pub(super) fn record_rx_drop(&mut self) {
+        self.stats.rx.dropped += 1;
+    }
+
+    /// Whether can receive packet. If can, return (token, packet length).
+    pub fn can_recv(&mut self) -> VirtIoResult<Option<(u16, usize)>> {
+        Ok(self
+            .recv_queue
+            .peek_used()
+            .map(|token| (token, self.recv_queue.get_desc_len(token))))
     }
 
     /// Whether the length of the receive buffer is valid.
-    fn check_rx_buf_len(rx_buf: &[u8]) -> VirtIoResult<()> {
-        if rx_buf.len() < MIN_BUFFER_LEN {
+    fn check_rx_buf_len(&self, rx_buf: &[u8]) -> VirtIoResult<()> {
+        if rx_buf.len() < self.min_rx_buffer_len() {
             warn!("Receive buffer len {} is too small", rx_buf.len());
             Err(VirtIoError::InvalidParam)
         } else {
@@ -98,30 +554,56 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H,
         }
     }
 
-    /// Whether the header of the transmit buffer is correct.
-    fn check_tx_buf_header(tx_buf: &[u8]) -> VirtIoResult<()> {
+    /// Whether the header of the transmit buffer is valid for the negotiated features.
+    fn check_tx_buf_header(&self, tx_buf: &[u8]) -> VirtIoResult<()> {
         if tx_buf.len() < NET_HDR_SIZE {
             warn!("Transmit buffer len {} is too small", tx_buf.len());
             return Err(VirtIoError::InvalidParam);
         }
-        if VirtioNetHdr::default().equal(tx_buf)? {
-            Ok(())
-        } else {
-            Err(VirtIoError::InvalidParam)
+        let header = VirtioNetHdr::read_from(tx_buf)?;
+        header.validate_tx(self.features)?;
+        let packet = tx_buf
+            .get(NET_HDR_SIZE..)
+            .ok_or(VirtIoError::InvalidParam)?;
+        self.check_tx_len(&header, packet)
+    }
+
+    /// Whether the packet fits the MTU the device reported, unless the device is to segment it.
+    ///
+    /// Ref: 5.1.6.2.1 Driver Requirements: Packet Transmission
+    fn check_tx_len(&self, header: &VirtioNetHdr, packet: &[u8]) -> VirtIoResult<()> {
+        let Some(mtu) = self.mtu else {
+            return Ok(());
+        };
+        if header.gso_type == GsoType::NONE
+            && packet.len() > ethernet_header_len(packet) + usize::from(mtu)
+        {
+            warn!("Packet len {} exceeds MTU {}", packet.len(), mtu);
+            return Err(VirtIoError::InvalidParam);
         }
+        Ok(())
     }
 
     /// Fill the header of the `buffer` with [`VirtioNetHdr`].
     ///
     /// If the `buffer` is not large enough, it returns [`Error::InvalidParam`].
     pub fn fill_buffer_header(&self, buffer: &mut [u8]) -> VirtIoResult<usize> {
-        if buffer.len() < NET_HDR_SIZE {
-            return Err(VirtIoError::InvalidParam);
-        }
-        VirtioNetHdr::default().write_to(&mut buffer[..NET_HDR_SIZE]);
+        let header = buffer
+            .get_mut(..NET_HDR_SIZE)
+            .ok_or(VirtIoError::InvalidParam)?;
+        VirtioNetHdr::default().write_to(header)?;
         Ok(NET_HDR_SIZE)
     }
 
+    /// Returns a builder for a transmit header requesting checksum or segmentation offloads.
+    ///
+    /// This is an alternative to [`fill_buffer_header`] for packets which need offloads.
+    ///
+    /// [`fill_buffer_header`]: Self::fill_buffer_header
+    pub fn tx_header_builder(&self) -> NetTxHeaderBuilder {
+        NetTxHeaderBuilder::new(self.features)
+    }
+
     /// Submits a request to transmit a buffer immediately without waiting for
     /// the transmission to complete.
     ///
@@ -147,13 +629,39 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H,
     /// [`poll_transmit`]: Self::poll_transmit
     /// [`transmit_complete`]: Self::transmit_complete
     pub fn transmit_begin(&mut self, tx_buf: &[u8]) -> VirtIoResult<u16> {
-        Self::check_tx_buf_header(tx_buf)?;
-        let desc = Descriptor::new::<QUEUE_SIZE, H>(
-            tx_buf.as_ptr() as _,
-            tx_buf.len() as _,
-            DescFlag::EMPTY,
-        );
-        let token = self.send_queue.add(vec![desc])?;
+        let desc = DmaBuf::from(Buffer::Read(tx_buf));
+        let result = self.try_transmit_begin(tx_buf, desc);
+        if let Err(e) = result {
+            self.stats.tx.record_failure(e);
+        }
+        result
+    }
+
+    /// Like [`Self::transmit_begin`], for the header and packet in `range` of `page`, which the
+    /// device is given by the physical address the page was allocated at rather than through
+    /// [`Hal::share`].
+    ///
+    /// The range of the page must not be touched until the request is completed.
+    pub(crate) fn transmit_begin_dma(
+        &mut self,
+        page: &dyn DevicePage,
+        range: Range<usize>,
+    ) -> VirtIoResult<u16> {
+        let tx_buf = page.as_slice().get(range.clone());
+        let buf = DmaBuf::from_page(page, range, BufferDirection::DriverToDevice);
+        let result = match tx_buf.zip(buf) {
+            Some((tx_buf, buf)) => self.try_transmit_begin(tx_buf, buf),
+            None => Err(VirtIoError::InvalidParam),
+        };
+        if let Err(e) = result {
+            self.stats.tx.record_failure(e);
+        }
+        result
+    }
+
+    fn try_transmit_begin(&mut self, tx_buf: &[u8], buf: DmaBuf) -> VirtIoResult<u16> {
+        self.check_tx_buf_header(tx_buf)?;
+        let token = self.send_queue.add(vec![buf])?;
         if self.send_queue.should_notify() {
             self.transport.notify(QUEUE_TRANSMIT)?;
         }
@@ -177,8 +685,20 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H,
     ///
     /// [`transmit_begin`]: Self::transmit_begin
     pub fn transmit_complete(&mut self, token: u16) -> VirtIoResult<usize> {
-        let len = self.send_queue.pop_used(token)?;
-        Ok(len as usize)
+        // The buffer holds the header and the packet, see `transmit_begin`.
+        let buf_len = self.send_queue.get_desc_len(token);
+        match self.send_queue.pop_used(token) {
+            Ok(len) => {
+                self.stats
+                    .tx
+                    .record_packet(buf_len.saturating_sub(NET_HDR_SIZE));
+                Ok(len as usize)
+            }
+            Err(e) => {
+                self.stats.tx.errors += 1;
+                Err(e)
+            }
+        }
     }
 
     /// Submits a request to receive a buffer immediately without waiting for
@@ -204,12 +724,8 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H,
     /// [`poll_receive`]: Self::poll_receive
     /// [`receive_complete`]: Self::receive_complete
     pub fn receive_begin(&mut self, rx_buf: &mut [u8]) -> VirtIoResult<u16> {
-        Self::check_rx_buf_len(rx_buf)?;
-        let desc = Descriptor::new::<QUEUE_SIZE, H>(
-            rx_buf.as_ptr() as _,
-            rx_buf.len() as _,
-            DescFlag::WRITE,
-        );
+        self.check_rx_buf_len(rx_buf)?;
+        let desc = DmaBuf::from(Buffer::Write(rx_buf));
         let token = self.recv_queue.add(vec![desc])?;
         if self.recv_queue.should_notify() {
             self.transport.notify(QUEUE_RECEIVE)?;
@@ -220,7 +736,7 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H,
     /// Fetches the token of the next completed reception request from the
     /// used ring and returns it, without removing it from the used ring. If
     /// there are no pending completed requests it returns [`None`].
-    pub fn poll_receive(&self, token: u16) -> VirtIoResult<bool> {
+    pub fn poll_receive(&mut self, token: u16) -> VirtIoResult<bool> {
         self.recv_queue.can_pop(token)
     }
 
@@ -237,38 +753,138 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H,
     ///
     /// [`receive_begin`]: Self::receive_begin
     pub fn receive_complete(&mut self, token: u16) -> VirtIoResult<(usize, usize)> {
-        let len = self.recv_queue.pop_used(token)? as usize;
-        let packet_len = len.checked_sub(NET_HDR_SIZE).ok_or(VirtIoError::IoError)?;
-        Ok((NET_HDR_SIZE, packet_len))
+        let packet_len = self.recv_queue.pop_used(token).and_then(|len| {
+            (len as usize)
+                .checked_sub(NET_HDR_SIZE)
+                .ok_or(VirtIoError::IoError)
+        });
+        match packet_len {
+            Ok(packet_len) => {
+                self.stats.rx.record_packet(packet_len);
+                Ok((NET_HDR_SIZE, packet_len))
+            }
+            Err(e) => {
+                self.stats.rx.errors += 1;
+                Err(e)
+            }
+        }
+    }
+
+    /// Parses the header the device wrote at the start of a receive buffer completed by
+    /// [`receive_complete`], which returned `packet_len` as the length of the packet.
+    ///
+    /// Returns [`VirtIoError::IoError`] if the header asks for offloads which weren't negotiated
+    /// or points outside the packet, so a malformed packet can be dropped rather than passed to
+    /// the stack. See [`VirtioNetHdr::rx_checksum`] for whether the checksum still needs checking.
+    ///
+    /// [`receive_complete`]: Self::receive_complete
+    pub fn receive_header(&self, rx_buf: &[u8], packet_len: usize) -> VirtIoResult<VirtioNetHdr> {
+        if rx_buf.len() < NET_HDR_SIZE + packet_len {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let hdr = VirtioNetHdr::read_from(rx_buf)?;
+        if let Err(e) = hdr.validate_rx(self.features, packet_len) {
+            warn!("Dropping packet with malformed header {:?}", hdr);
+            return Err(e);
+        }
+        Ok(hdr)
+    }
+
+    /// Transmits a buffer like [`transmit_begin`], yielding to the executor until the device
+    /// completes the transmission, and returns the number of bytes transmitted.
+    ///
+    /// The task is woken when [`ack_interrupt`] finds the request completed, so the interrupt
+    /// handler must call it for the future to make progress. If the future is dropped before it
+    /// completes, dropping it blocks until the device has read `tx_buf`, or resets the device if
+    /// it asks to be reset instead, so the device never accesses `tx_buf` afterwards.
+    ///
+    /// [`transmit_begin`]: Self::transmit_begin
+    /// [`ack_interrupt`]: Self::ack_interrupt
+    pub async fn transmit_async(&mut self, tx_buf: &[u8]) -> VirtIoResult<usize> {
+        let token = self.transmit_begin(tx_buf)?;
+        let guard = CancelOnDrop {
+            net: self,
+            queue: QUEUE_TRANSMIT,
+            token,
+        };
+        guard.net.send_queue.wait_used(token).await?;
+        guard.net.transmit_complete(token)
+    }
+
+    /// Receives a packet into a buffer like [`receive_begin`], yielding to the executor until one
+    /// arrives, and returns the length of the header and the length of the packet.
+    ///
+    /// Woken like [`transmit_async`]. As no packet may arrive for a long time, dropping the future
+    /// before it completes resets the device, which stops it from writing to `rx_buf`; the driver
+    /// must then be created again.
+    ///
+    /// [`receive_begin`]: Self::receive_begin
+    /// [`transmit_async`]: Self::transmit_async
+    pub async fn receive_async(&mut self, rx_buf: &mut [u8]) -> VirtIoResult<(usize, usize)> {
+        let token = self.receive_begin(rx_buf)?;
+        let guard = CancelOnDrop {
+            net: self,
+            queue: QUEUE_RECEIVE,
+            token,
+        };
+        guard.net.recv_queue.wait_used(token).await?;
+        guard.net.receive_complete(token)
+    }
+
+    /// Takes back a request of [`Self::transmit_async`] or [`Self::receive_async`] whose future
+    /// was dropped, see [`VirtIoQueue::cancel`].
+    fn cancel(&mut self, queue: u16, token: u16) {
+        if queue == QUEUE_TRANSMIT {
+            self.send_queue.cancel(&mut self.transport, token);
+        } else if self.recv_queue.is_outstanding(token)
+            && !matches!(self.recv_queue.can_pop(token), Ok(true))
+        {
+            if let Err(e) = self.reset() {
+                error!("Failed to reset device after abandoned receive: {:?}", e);
+            }
+        } else {
+            self.recv_queue.cancel(&mut self.transport, token);
+        }
     }
 
     /// Sends a packet to the network, and blocks until the request completed.
     pub fn send(&mut self, tx_buf: &[u8]) -> VirtIoResult<()> {
+        self.send_with_header(&VirtioNetHdr::default(), tx_buf)
+    }
+
+    /// Sends a packet to the network with the given header, e.g. one built by
+    /// [`Self::tx_header_builder`] asking for offloads, and blocks until the request completed.
+    ///
+    /// Returns [`VirtIoError::Unsupported`] if the header asks for offloads which weren't
+    /// negotiated, see [`VirtioNetHdr::validate_tx`].
+    pub fn send_with_header(&mut self, header: &VirtioNetHdr, tx_buf: &[u8]) -> VirtIoResult<()> {
+        match self.try_send_with_header(header, tx_buf) {
+            Ok(()) => {
+                self.stats.tx.record_packet(tx_buf.len());
+                Ok(())
+            }
+            Err(e) => {
+                self.stats.tx.record_failure(e);
+                Err(e)
+            }
+        }
+    }
+
+    fn try_send_with_header(&mut self, header: &VirtioNetHdr, tx_buf: &[u8]) -> VirtIoResult<()> {
+        header.validate_tx(self.features)?;
+        self.check_tx_len(header, tx_buf)?;
         let mut header_buf = [0u8; size_of::<VirtioNetHdr>()];
-        VirtioNetHdr::default().write_to(&mut header_buf);
-
-        let header_desc = Descriptor::new::<QUEUE_SIZE, H>(
-            header_buf.as_ptr() as _,
-            header_buf.len() as _,
-            if tx_buf.is_empty() {
-                DescFlag::EMPTY
-            } else {
-                DescFlag::NEXT
-            },
-        );
-        let v;
-        if !tx_buf.is_empty() {
+        header.write_to(&mut header_buf)?;
+
+        let header_desc = DmaBuf::readable(&header_buf);
+        let v = if !tx_buf.is_empty() {
             // Special case sending an empty packet, to avoid adding an empty buffer to the
             // virtqueue.
-            let desc = Descriptor::new::<QUEUE_SIZE, H>(
-                tx_buf.as_ptr() as _,
-                tx_buf.len() as _,
-                DescFlag::EMPTY,
-            );
-            v = vec![header_desc, desc];
+            let desc = DmaBuf::readable(tx_buf);
+            vec![header_desc, desc]
         } else {
-            v = vec![header_desc];
-        }
+            vec![header_desc]
+        };
         self.send_queue
             .add_notify_wait_pop(&mut self.transport, v)?;
         Ok(())
@@ -282,9 +898,78 @@ impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H,
     /// the packet.
     pub fn receive_wait(&mut self, rx_buf: &mut [u8]) -> VirtIoResult<(usize, usize)> {
         let token = self.receive_begin(rx_buf)?;
-        while !self.poll_receive(token)? {
-            core::hint::spin_loop();
-        }
+        self.recv_queue.wait_for(&self.transport, token)?;
         self.receive_complete(token)
     }
 }
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> VirtIoDriver
+    for VirtIONetRaw<H, T, QUEUE_SIZE>
+{
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Network
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        VirtIONetRaw::ack_interrupt(self)
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        VirtIONetRaw::poll_interrupt(self)
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            device_type: DeviceType::Network,
+            bus_addr: self.transport.io_region().paddr(),
+            features: self.features.bits(),
+        }
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        let mut stats = vec![self.recv_queue.stats(), self.send_queue.stats()];
+        stats.extend(self.ctrl_queue.as_ref().map(VirtIoQueue::stats));
+        stats
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        let ctrl_queue_index = self.max_queue_pairs * 2;
+        match (queue, &mut self.ctrl_queue) {
+            (QUEUE_RECEIVE, _) => self.recv_queue.set_event_suppression(suppression),
+            (QUEUE_TRANSMIT, _) => self.send_queue.set_event_suppression(suppression),
+            (_, Some(ctrl_queue)) if queue == ctrl_queue_index => {
+                ctrl_queue.set_event_suppression(suppression)
+            }
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.transport.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.transport.reset()
+    }
+}
+
+/// Cancels the request of an async transmission or reception when its future is dropped, before
+/// the borrow of the buffer ends. Once the request has been completed this does nothing.
+struct CancelOnDrop<'a, H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> {
+    net: &'a mut VirtIONetRaw<H, T, QUEUE_SIZE>,
+    queue: u16,
+    token: u16,
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport, const QUEUE_SIZE: usize> Drop
+    for CancelOnDrop<'_, H, T, QUEUE_SIZE>
+{
+    fn drop(&mut self) {
+        self.net.cancel(self.queue, self.token);
+    }
+}
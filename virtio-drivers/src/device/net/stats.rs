@@ -0,0 +1,92 @@
+//! Packet counters of the network driver.
+
+use crate::error::VirtIoError;
+
+/// Counters of one direction of a queue pair.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NetQueueStats {
+    /// The number of packets the device completed.
+    pub packets: u64,
+    /// The number of bytes in those packets, without the virtio header.
+    pub bytes: u64,
+    /// The number of requests which failed, or which the device completed with a malformed
+    /// result.
+    pub errors: u64,
+    /// The number of packets the driver discarded: transmissions refused because the queue was
+    /// full or the packet invalid, and received packets with a malformed header.
+    pub dropped: u64,
+}
+
+impl NetQueueStats {
+    /// Counts a completed packet of `bytes` bytes.
+    pub(super) fn record_packet(&mut self, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+
+    /// Counts a packet which couldn't be passed on because of `error`.
+    pub(super) fn record_failure(&mut self, error: VirtIoError) {
+        match error {
+            VirtIoError::QueueFull | VirtIoError::InvalidParam | VirtIoError::Unsupported => {
+                self.dropped += 1
+            }
+            _ => self.errors += 1,
+        }
+    }
+
+    /// Returns the counts since `earlier`, a snapshot of the same counters.
+    pub fn delta(&self, earlier: &Self) -> Self {
+        Self {
+            packets: self.packets.wrapping_sub(earlier.packets),
+            bytes: self.bytes.wrapping_sub(earlier.bytes),
+            errors: self.errors.wrapping_sub(earlier.errors),
+            dropped: self.dropped.wrapping_sub(earlier.dropped),
+        }
+    }
+
+    /// Returns the throughput in bits per second, taking these counters as a [`Self::delta`]
+    /// over `elapsed_ms` milliseconds.
+    pub fn bits_per_second(&self, elapsed_ms: u64) -> u64 {
+        if elapsed_ms == 0 {
+            return 0;
+        }
+        (u128::from(self.bytes) * 8 * 1000 / u128::from(elapsed_ms)) as u64
+    }
+
+    /// Estimates how much of a link of `link_mbps` megabits per second the throughput over
+    /// `elapsed_ms` used, in percent, or `None` if the link speed is unknown.
+    ///
+    /// Virtio devices don't report the speed of the link behind them, so the caller has to know
+    /// it. The estimate counts packets without Ethernet framing overhead, and may exceed 100 if
+    /// the host is faster than the given speed.
+    pub fn utilization_percent(&self, elapsed_ms: u64, link_mbps: u32) -> Option<u64> {
+        if link_mbps == 0 {
+            return None;
+        }
+        Some(self.bits_per_second(elapsed_ms) / (u64::from(link_mbps) * 10_000))
+    }
+}
+
+/// A snapshot of the packet counters of a network driver, see
+/// [`VirtIONetRaw::net_stats`](super::VirtIONetRaw::net_stats).
+///
+/// The driver only uses the first queue pair, so these cover all its traffic.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NetStats {
+    /// Counters of the receive queue.
+    pub rx: NetQueueStats,
+    /// Counters of the transmit queue.
+    pub tx: NetQueueStats,
+}
+
+impl NetStats {
+    /// Returns the counts since `earlier`, e.g. to report rates over an interval.
+    pub fn delta(&self, earlier: &Self) -> Self {
+        Self {
+            rx: self.rx.delta(&earlier.rx),
+            tx: self.tx.delta(&earlier.tx),
+        }
+    }
+}
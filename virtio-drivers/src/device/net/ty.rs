@@ -1,14 +1,26 @@
 use crate::common::Array;
-use crate::error::VirtIoResult;
-use crate::transport::mmio::CONFIG_OFFSET;
+use crate::error::{VirtIoError, VirtIoResult};
+use crate::transport::{DeviceFeatures, FeatureDependency};
 use core::mem::size_of;
 
-use crate::volatile::ReadOnly;
+use crate::volatile::virtio_config;
 use bitflags::bitflags;
 
-pub const MAX_BUFFER_LEN: usize = 65535;
 pub const MIN_BUFFER_LEN: usize = 1526;
+/// The smallest receive buffer which holds a packet the device coalesced for receive segmentation
+/// offload, i.e. with [`Features::GUEST_TSO4`] or [`Features::GUEST_TSO6`] negotiated.
+///
+/// Ref: 5.1.6.3.1 Driver Requirements: Setting Up Receive Buffers
+pub const MIN_TSO_BUFFER_LEN: usize = NET_HDR_SIZE + 65550;
 pub const NET_HDR_SIZE: usize = core::mem::size_of::<VirtioNetHdr>();
+/// The MTU of a device which doesn't report one with [`Features::MTU`].
+pub const DEFAULT_MTU: u16 = 1500;
+/// The smallest MTU a device may report.
+///
+/// Ref: 5.1.4.1 Device Requirements: Device configuration layout
+pub const MIN_MTU: u16 = 68;
+/// The length of an untagged Ethernet header, which the MTU doesn't include.
+pub const ETH_HLEN: usize = 14;
 
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -72,31 +84,57 @@ bitflags! {
     }
 }
 
+format_flags!(Features, Status);
+
+impl DeviceFeatures for Features {
+    /// Ref: 5.1.3.1 Feature bit requirements
+    const DEPENDENCIES: &'static [FeatureDependency] = &[
+        FeatureDependency::new(Self::GUEST_TSO4.bits(), Self::GUEST_CSUM.bits()),
+        FeatureDependency::new(Self::GUEST_TSO6.bits(), Self::GUEST_CSUM.bits()),
+        FeatureDependency::new(
+            Self::GUEST_ECN.bits(),
+            Self::GUEST_TSO4.union(Self::GUEST_TSO6).bits(),
+        ),
+        FeatureDependency::new(Self::GUEST_UFO.bits(), Self::GUEST_CSUM.bits()),
+        FeatureDependency::new(Self::HOST_TSO4.bits(), Self::CSUM.bits()),
+        FeatureDependency::new(Self::HOST_TSO6.bits(), Self::CSUM.bits()),
+        FeatureDependency::new(
+            Self::HOST_ECN.bits(),
+            Self::HOST_TSO4.union(Self::HOST_TSO6).bits(),
+        ),
+        FeatureDependency::new(Self::HOST_UFO.bits(), Self::CSUM.bits()),
+        FeatureDependency::new(Self::CTRL_RX.bits(), Self::CTRL_VQ.bits()),
+        FeatureDependency::new(Self::CTRL_VLAN.bits(), Self::CTRL_VQ.bits()),
+        FeatureDependency::new(Self::CTRL_RX_EXTRA.bits(), Self::CTRL_RX.bits()),
+        FeatureDependency::new(Self::GUEST_ANNOUNCE.bits(), Self::CTRL_VQ.bits()),
+        FeatureDependency::new(Self::MQ.bits(), Self::CTRL_VQ.bits()),
+        FeatureDependency::new(Self::CTL_MAC_ADDR.bits(), Self::CTRL_VQ.bits()),
+    ];
+
+    // Version 1 devices put `num_buffers` in every header, which `VirtioNetHdr` doesn't have yet.
+    const ACCEPTS_VERSION_1: bool = false;
+}
+
 bitflags! {
+    /// The status of the device, see [`VirtIONetRaw::handle_config_change`](super::VirtIONetRaw::handle_config_change).
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
     pub struct Status: u16 {
+        /// The link is up.
         const LINK_UP = 1;
+        /// The device asks the driver to announce itself, e.g. after a live migration.
         const ANNOUNCE = 2;
     }
 }
 
-bitflags! {
-    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
-    pub struct InterruptStatus : u32 {
-        const USED_RING_UPDATE = 1 << 0;
-        const CONFIGURATION_CHANGE = 1 << 1;
+virtio_config! {
+    pub struct NetConfig {
+        pub mac: ReadOnly<EthernetAddress> @ 0x0,
+        pub status: ReadOnly<u16> @ 0x6,
+        pub max_virtqueue_pairs: ReadOnly<u16> @ 0x8,
+        pub mtu: ReadOnly<u16> @ 0xa,
     }
 }
 
-#[repr(C)]
-#[derive(Debug, Default)]
-pub struct NetConfig {
-    pub mac: ReadOnly<CONFIG_OFFSET, EthernetAddress>,
-    pub status: ReadOnly<{ CONFIG_OFFSET + 6 }, u16>,
-    pub max_virtqueue_pairs: ReadOnly<{ CONFIG_OFFSET + 8 }, u16>,
-    pub mtu: ReadOnly<{ CONFIG_OFFSET + 10 }, u16>,
-}
-
 pub type EthernetAddress = Array<6, u8>;
 
 /// VirtIO 5.1.6 Device Operation:
@@ -105,7 +143,8 @@ pub type EthernetAddress = Array<6, u8>;
 /// and buffers for incoming packets are placed in the receiveq1. . .receiveqN.
 /// In each case, the packet itself is preceded by a header.
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct VirtioNetHdr {
     pub flags: Flags,
     pub gso_type: GsoType,
@@ -117,8 +156,13 @@ pub struct VirtioNetHdr {
     // payload starts from here
 }
 impl VirtioNetHdr {
-    pub fn write_to(&self, target: &mut [u8]) {
-        assert!(target.len() >= size_of::<Self>());
+    /// Writes the header to the start of `target`.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `target` is shorter than the header.
+    pub fn write_to(&self, target: &mut [u8]) -> VirtIoResult<()> {
+        if target.len() < size_of::<Self>() {
+            return Err(VirtIoError::InvalidParam);
+        }
         target[0] = self.flags.0;
         target[1] = self.gso_type.0;
         // (&mut target[2..4]).copy_from_slice(&self.hdr_len.to_le_bytes());
@@ -134,25 +178,217 @@ impl VirtioNetHdr {
         target[7] = (self.csum_start >> 8) as _;
         target[8] = self.csum_offset as _;
         target[9] = (self.csum_offset >> 8) as _;
+        Ok(())
+    }
+    /// Reads a header from the start of `source`.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `source` is shorter than the header.
+    pub fn read_from(source: &[u8]) -> VirtIoResult<Self> {
+        if source.len() < size_of::<Self>() {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Ok(Self {
+            flags: Flags(source[0]),
+            gso_type: GsoType(source[1]),
+            hdr_len: u16::from_le_bytes([source[2], source[3]]),
+            gso_size: u16::from_le_bytes([source[4], source[5]]),
+            csum_start: u16::from_le_bytes([source[6], source[7]]),
+            csum_offset: u16::from_le_bytes([source[8], source[9]]),
+        })
+    }
+    /// Checks that a header built by the driver for transmission only asks for offloads which
+    /// were negotiated.
+    ///
+    /// Ref: 5.1.6.2.1 Driver Requirements: Packet Transmission
+    pub fn validate_tx(&self, features: Features) -> VirtIoResult<()> {
+        if self.flags.intersects(Flags::DATA_VALID | Flags::RSC_INFO) {
+            // These are only ever set by the device.
+            return Err(VirtIoError::InvalidParam);
+        }
+        if self.flags.contains(Flags::NEEDS_CSUM) && !features.contains(Features::CSUM) {
+            return Err(VirtIoError::Unsupported);
+        }
+        let gso = self.gso_type.without_ecn();
+        if gso == GsoType::NONE {
+            return if self.gso_type.has_ecn() {
+                Err(VirtIoError::InvalidParam)
+            } else {
+                Ok(())
+            };
+        }
+        let required = match gso {
+            GsoType::TCPV4 => Features::HOST_TSO4,
+            GsoType::TCPV6 => Features::HOST_TSO6,
+            GsoType::UDP => Features::HOST_UFO,
+            _ => return Err(VirtIoError::InvalidParam),
+        };
+        if !features.contains(required)
+            || (self.gso_type.has_ecn() && !features.contains(Features::HOST_ECN))
+        {
+            return Err(VirtIoError::Unsupported);
+        }
+        // Segmentation offload always relies on checksum offload.
+        if !self.flags.contains(Flags::NEEDS_CSUM) || self.gso_size == 0 {
+            return Err(VirtIoError::InvalidParam);
+        }
+        Ok(())
     }
-    pub fn equal(&self, target: &[u8]) -> VirtIoResult<bool> {
-        assert!(target.len() >= size_of::<Self>());
-        let mut flag = true;
-        flag &= target[0] == self.flags.0;
-        flag &= target[1] == self.gso_type.0;
-        flag &= target[2] == self.hdr_len as _;
-        flag &= target[3] == (self.hdr_len >> 8) as _;
-        flag &= target[4] == self.gso_size as _;
-        flag &= target[5] == (self.gso_size >> 8) as _;
-        flag &= target[6] == self.csum_start as _;
-        flag &= target[7] == (self.csum_start >> 8) as _;
-        flag &= target[8] == self.csum_offset as _;
-        flag &= target[9] == (self.csum_offset >> 8) as _;
-        Ok(flag)
+    /// Checks that the header the device wrote before a received packet of `packet_len` bytes
+    /// only uses offloads which were negotiated, and that its checksum range lies within the
+    /// packet.
+    ///
+    /// Returns [`VirtIoError::IoError`] if not, in which case the packet shouldn't be trusted.
+    ///
+    /// Ref: 5.1.6.4.1 Device Requirements: Processing of Incoming Packets
+    pub fn validate_rx(&self, features: Features, packet_len: usize) -> VirtIoResult<()> {
+        if Flags::from_bits(self.flags.0).is_none() || self.flags.contains(Flags::RSC_INFO) {
+            // The driver never negotiates receive segment coalescing.
+            return Err(VirtIoError::IoError);
+        }
+        if !self.flags.is_empty() && !features.contains(Features::GUEST_CSUM) {
+            return Err(VirtIoError::IoError);
+        }
+        if self.flags.contains(Flags::NEEDS_CSUM) {
+            let end = self.csum_start as usize + self.csum_offset as usize + 2;
+            if end > packet_len {
+                return Err(VirtIoError::IoError);
+            }
+        }
+        let required = match self.gso_type.without_ecn() {
+            GsoType::NONE if !self.gso_type.has_ecn() => return Ok(()),
+            GsoType::TCPV4 => Features::GUEST_TSO4,
+            GsoType::TCPV6 => Features::GUEST_TSO6,
+            GsoType::UDP => Features::GUEST_UFO,
+            _ => return Err(VirtIoError::IoError),
+        };
+        if !features.contains(required)
+            || (self.gso_type.has_ecn() && !features.contains(Features::GUEST_ECN))
+        {
+            return Err(VirtIoError::IoError);
+        }
+        Ok(())
+    }
+
+    /// Returns what the device reported about the checksum of the packet following this header.
+    pub fn rx_checksum(&self) -> RxChecksum {
+        if self.flags.contains(Flags::NEEDS_CSUM) {
+            RxChecksum::Partial
+        } else if self.flags.contains(Flags::DATA_VALID) {
+            RxChecksum::Valid
+        } else {
+            RxChecksum::Unchecked
+        }
+    }
+
+    /// Completes the partial checksum of a received packet in software, so stacks which check
+    /// checksums accept it. Does nothing unless [`Flags::NEEDS_CSUM`] is set.
+    ///
+    /// The device leaves the checksum of the pseudo-header at `csum_start + csum_offset`, expecting
+    /// the checksum of everything from `csum_start` to be folded into it.
+    ///
+    /// Returns [`VirtIoError::IoError`] if the checksum range doesn't lie within `packet`.
+    pub fn complete_checksum(&self, packet: &mut [u8]) -> VirtIoResult<()> {
+        if !self.flags.contains(Flags::NEEDS_CSUM) {
+            return Ok(());
+        }
+        let start = self.csum_start as usize;
+        let field = start + self.csum_offset as usize;
+        if field + 2 > packet.len() {
+            return Err(VirtIoError::IoError);
+        }
+        let mut sum = 0u32;
+        for chunk in packet.get(start..).ok_or(VirtIoError::IoError)?.chunks(2) {
+            let word = match *chunk {
+                [high, low] => u16::from_be_bytes([high, low]),
+                [high] => u16::from_be_bytes([high, 0]),
+                _ => 0,
+            };
+            sum += word as u32;
+            // Fold as we go, so even a 64 KiB packet can't overflow.
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        packet
+            .get_mut(field..field + 2)
+            .ok_or(VirtIoError::IoError)?
+            .copy_from_slice(&(!(sum as u16)).to_be_bytes());
+        Ok(())
+    }
+}
+
+/// What the device reported about the checksum of a received packet, see
+/// [`VirtioNetHdr::rx_checksum`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RxChecksum {
+    /// The device didn't check the checksum, so the stack must.
+    Unchecked,
+    /// The device validated the checksum, so the stack needn't.
+    Valid,
+    /// The packet is valid, but its checksum was left partial, e.g. because it was sent by
+    /// another guest on the same host. It must be completed with
+    /// [`VirtioNetHdr::complete_checksum`] before a stack which checks checksums sees it.
+    Partial,
+}
+
+/// A builder for the [`VirtioNetHdr`] which precedes a transmitted packet.
+///
+/// The offloads requested are checked against the features negotiated with the device when the
+/// header is written, see [`VirtioNetHdr::validate_tx`].
+#[derive(Debug)]
+pub struct NetTxHeaderBuilder {
+    hdr: VirtioNetHdr,
+    features: Features,
+}
+
+impl NetTxHeaderBuilder {
+    pub(crate) fn new(features: Features) -> Self {
+        Self {
+            hdr: VirtioNetHdr::default(),
+            features,
+        }
+    }
+
+    /// Asks the device to checksum the packet from `csum_start`, storing the result at
+    /// `csum_start + csum_offset`.
+    pub fn checksum(mut self, csum_start: u16, csum_offset: u16) -> Self {
+        self.hdr.flags |= Flags::NEEDS_CSUM;
+        self.hdr.csum_start = csum_start;
+        self.hdr.csum_offset = csum_offset;
+        self
+    }
+
+    /// Asks the device to segment the packet into segments of `gso_size` bytes, each preceded by
+    /// a copy of the first `hdr_len` bytes of headers.
+    pub fn segmentation(mut self, gso_type: GsoType, gso_size: u16, hdr_len: u16) -> Self {
+        self.hdr.gso_type = GsoType(gso_type.0 | (self.hdr.gso_type.0 & GsoType::ECN.0));
+        self.hdr.gso_size = gso_size;
+        self.hdr.hdr_len = hdr_len;
+        self
+    }
+
+    /// Marks the segmented packet as requiring ECN support.
+    pub fn ecn(mut self) -> Self {
+        self.hdr.gso_type = GsoType(self.hdr.gso_type.0 | GsoType::ECN.0);
+        self
+    }
+
+    /// Validates the header and returns it.
+    pub fn build(self) -> VirtIoResult<VirtioNetHdr> {
+        self.hdr.validate_tx(self.features)?;
+        Ok(self.hdr)
+    }
+
+    /// Validates the header and writes it to the start of `buffer`, returning its length.
+    pub fn write_to(self, buffer: &mut [u8]) -> VirtIoResult<usize> {
+        let header = buffer
+            .get_mut(..NET_HDR_SIZE)
+            .ok_or(VirtIoError::InvalidParam)?;
+        self.build()?.write_to(header)?;
+        Ok(NET_HDR_SIZE)
     }
 }
 
 #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(transparent)]
 pub struct Flags(u8);
 
@@ -166,17 +402,82 @@ bitflags! {
 
 #[repr(transparent)]
 #[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct GsoType(u8);
 
 impl GsoType {
-    const NONE: GsoType = GsoType(0);
-    const TCPV4: GsoType = GsoType(1);
-    const UDP: GsoType = GsoType(3);
-    const TCPV6: GsoType = GsoType(4);
-    const ECN: GsoType = GsoType(0x80);
+    pub const NONE: GsoType = GsoType(0);
+    pub const TCPV4: GsoType = GsoType(1);
+    pub const UDP: GsoType = GsoType(3);
+    pub const TCPV6: GsoType = GsoType(4);
+    pub const ECN: GsoType = GsoType(0x80);
+
+    fn without_ecn(self) -> Self {
+        GsoType(self.0 & !Self::ECN.0)
+    }
+
+    fn has_ecn(self) -> bool {
+        self.0 & Self::ECN.0 != 0
+    }
 }
 
 pub const QUEUE_RECEIVE: u16 = 0;
 pub const QUEUE_TRANSMIT: u16 = 1;
-pub const SUPPORTED_FEATURES: Features = Features::MAC.union(Features::STATUS);
-// .union(Features::RING_EVENT_IDX);
+/// The control queue, when there is only a single pair of receive and transmit queues.
+pub const QUEUE_CTRL: u16 = 2;
+/// The features [`VirtIONetRaw::new`](super::VirtIONetRaw::new) negotiates if the device offers
+/// them.
+pub const SUPPORTED_FEATURES: Features = Features::MAC
+    .union(Features::MTU)
+    .union(Features::STATUS)
+    .union(Features::RING_EVENT_IDX);
+/// Features needed for VLAN filtering. The device drops tagged packets whose VLAN isn't in its
+/// filter table once these are negotiated, so they are only requested on demand.
+pub const VLAN_FEATURES: Features = Features::CTRL_VQ.union(Features::CTRL_VLAN);
+/// Checksum and segmentation offloads the driver can negotiate on request, see
+/// [`VirtIONetRaw::new_with_offloads`](super::VirtIONetRaw::new_with_offloads).
+pub const OFFLOAD_FEATURES: Features = Features::CSUM
+    .union(Features::GUEST_CSUM)
+    .union(Features::HOST_TSO4)
+    .union(Features::HOST_TSO6)
+    .union(Features::HOST_ECN)
+    .union(Features::GUEST_TSO4)
+    .union(Features::GUEST_TSO6)
+    .union(Features::GUEST_ECN);
+/// Features for the other control queue commands: receive modes, the MAC address and the number
+/// of queue pairs.
+pub const CONTROL_FEATURES: Features = Features::CTRL_VQ
+    .union(Features::CTRL_RX)
+    .union(Features::CTL_MAC_ADDR)
+    .union(Features::MQ);
+
+/// The header of a control queue command.
+///
+/// Ref: 5.1.6.5 Control Virtqueue
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CtrlHeader {
+    pub class: u8,
+    pub command: u8,
+}
+
+pub const CTRL_CLASS_RX: u8 = 0;
+pub const CTRL_RX_PROMISC: u8 = 0;
+pub const CTRL_RX_ALLMULTI: u8 = 1;
+
+pub const CTRL_CLASS_MAC: u8 = 1;
+pub const CTRL_MAC_ADDR_SET: u8 = 1;
+
+pub const CTRL_CLASS_VLAN: u8 = 2;
+pub const CTRL_VLAN_ADD: u8 = 0;
+pub const CTRL_VLAN_DEL: u8 = 1;
+
+pub const CTRL_CLASS_MQ: u8 = 4;
+pub const CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+/// The most queue pairs a device may have.
+///
+/// Ref: 5.1.6.5.5 Device operation in multiqueue mode
+pub const CTRL_MQ_VQ_PAIRS_MAX: u16 = 0x8000;
+
+pub const CTRL_ACK_OK: u8 = 0;
+pub const CTRL_ACK_ERR: u8 = 1;
@@ -0,0 +1,72 @@
+//! Parsing and stripping of 802.1Q VLAN tags in received Ethernet frames.
+
+/// The EtherType which marks an 802.1Q tag.
+const ETH_P_8021Q: u16 = 0x8100;
+/// The length of the destination and source MAC addresses, which precede the tag.
+const MAC_ADDRS_LEN: usize = 12;
+/// The length of the tag: its EtherType followed by the tag control information.
+const TAG_LEN: usize = 4;
+
+/// An 802.1Q VLAN tag.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VlanTag {
+    /// Priority code point.
+    pub pcp: u8,
+    /// Drop eligible indicator.
+    pub dei: bool,
+    /// VLAN identifier.
+    pub vid: u16,
+}
+
+impl VlanTag {
+    /// The largest valid VLAN ID.
+    pub const MAX_VID: u16 = 0xfff;
+
+    /// Decodes the tag control information field.
+    pub fn from_tci(tci: u16) -> Self {
+        Self {
+            pcp: (tci >> 13) as u8,
+            dei: tci & (1 << 12) != 0,
+            vid: tci & Self::MAX_VID,
+        }
+    }
+
+    /// Encodes the tag control information field.
+    pub fn tci(&self) -> u16 {
+        ((self.pcp as u16 & 0x7) << 13) | ((self.dei as u16) << 12) | (self.vid & Self::MAX_VID)
+    }
+}
+
+/// Returns the outermost 802.1Q tag of an Ethernet frame, or `None` if it is untagged.
+pub fn parse_vlan_tag(frame: &[u8]) -> Option<VlanTag> {
+    if frame.len() < MAC_ADDRS_LEN + TAG_LEN {
+        return None;
+    }
+    let ethertype = u16::from_be_bytes([frame[MAC_ADDRS_LEN], frame[MAC_ADDRS_LEN + 1]]);
+    if ethertype != ETH_P_8021Q {
+        return None;
+    }
+    let tci = u16::from_be_bytes([frame[MAC_ADDRS_LEN + 2], frame[MAC_ADDRS_LEN + 3]]);
+    Some(VlanTag::from_tci(tci))
+}
+
+/// Returns the length of the Ethernet header of a frame: the MAC addresses, the EtherType and, if
+/// it is tagged, the outermost 802.1Q tag.
+pub(super) fn ethernet_header_len(frame: &[u8]) -> usize {
+    let tag_len = if parse_vlan_tag(frame).is_some() {
+        TAG_LEN
+    } else {
+        0
+    };
+    MAC_ADDRS_LEN + 2 + tag_len
+}
+
+/// Removes the outermost 802.1Q tag from an Ethernet frame in place.
+///
+/// The MAC addresses are moved up over the tag, so the untagged frame starts at the returned
+/// offset into `frame`. Returns `None` and leaves `frame` unchanged if it is untagged.
+pub fn strip_vlan_tag(frame: &mut [u8]) -> Option<(VlanTag, usize)> {
+    let tag = parse_vlan_tag(frame)?;
+    frame.copy_within(..MAC_ADDRS_LEN, TAG_LEN);
+    Some((tag, TAG_LEN))
+}
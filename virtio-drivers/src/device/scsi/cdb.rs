@@ -0,0 +1,205 @@
+//! Builders of the CDBs of common SCSI commands, for
+//! [`VirtIOScsi::execute`](super::VirtIOScsi::execute), and parsers of the data they return.
+//!
+//! Multi-byte fields of CDBs and of the returned data are big-endian.
+
+use alloc::vec::Vec;
+
+/// The peripheral device type of a disk, see [`InquiryData::device_type`].
+pub const TYPE_DISK: u8 = 0x00;
+/// The peripheral device type of a CD-ROM or DVD drive, see [`InquiryData::device_type`].
+pub const TYPE_CDROM: u8 = 0x05;
+
+/// `TEST UNIT READY`, which succeeds if the logical unit can take commands.
+pub fn test_unit_ready() -> [u8; 6] {
+    [0x00, 0, 0, 0, 0, 0]
+}
+
+/// `REQUEST SENSE`, which returns up to `allocation_length` bytes of sense data.
+pub fn request_sense(allocation_length: u8) -> [u8; 6] {
+    [0x03, 0, 0, 0, allocation_length, 0]
+}
+
+/// `INQUIRY` for the standard inquiry data, of which up to `allocation_length` bytes are returned.
+/// See [`InquiryData::parse`].
+pub fn inquiry(allocation_length: u16) -> [u8; 6] {
+    let [hi, lo] = allocation_length.to_be_bytes();
+    [0x12, 0, 0, hi, lo, 0]
+}
+
+/// `START STOP UNIT`, which spins a disk up or down, or with `load_eject` loads or ejects the
+/// medium of a removable drive.
+pub fn start_stop_unit(start: bool, load_eject: bool) -> [u8; 6] {
+    [
+        0x1b,
+        0,
+        0,
+        0,
+        (u8::from(load_eject) << 1) | u8::from(start),
+        0,
+    ]
+}
+
+/// `READ CAPACITY (10)`, which returns 8 bytes. See [`Capacity::parse_10`].
+pub fn read_capacity_10() -> [u8; 10] {
+    [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+/// `READ CAPACITY (16)`, of which up to `allocation_length` bytes are returned, at least 12 of
+/// which are needed. See [`Capacity::parse_16`].
+pub fn read_capacity_16(allocation_length: u32) -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = 0x9e;
+    // The service action.
+    cdb[1] = 0x10;
+    cdb[10..14].copy_from_slice(&allocation_length.to_be_bytes());
+    cdb
+}
+
+/// `READ (10)` of `blocks` logical blocks starting at `lba`.
+pub fn read_10(lba: u32, blocks: u16) -> [u8; 10] {
+    rw_10(0x28, lba, blocks)
+}
+
+/// `WRITE (10)` of `blocks` logical blocks starting at `lba`.
+pub fn write_10(lba: u32, blocks: u16) -> [u8; 10] {
+    rw_10(0x2a, lba, blocks)
+}
+
+fn rw_10(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cdb = [0; 10];
+    cdb[0] = opcode;
+    cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cdb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+/// `READ (16)` of `blocks` logical blocks starting at `lba`, for disks too large for
+/// [`read_10`].
+pub fn read_16(lba: u64, blocks: u32) -> [u8; 16] {
+    rw_16(0x88, lba, blocks)
+}
+
+/// `WRITE (16)` of `blocks` logical blocks starting at `lba`, for disks too large for
+/// [`write_10`].
+pub fn write_16(lba: u64, blocks: u32) -> [u8; 16] {
+    rw_16(0x8a, lba, blocks)
+}
+
+fn rw_16(opcode: u8, lba: u64, blocks: u32) -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = opcode;
+    cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+    cdb[10..14].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+/// `SYNCHRONIZE CACHE (10)` of the whole logical unit.
+pub fn synchronize_cache_10() -> [u8; 10] {
+    [0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+/// `REPORT LUNS`, of which up to `allocation_length` bytes are returned. See [`parse_luns`].
+pub fn report_luns(allocation_length: u32) -> [u8; 12] {
+    let mut cdb = [0; 12];
+    cdb[0] = 0xa0;
+    cdb[6..10].copy_from_slice(&allocation_length.to_be_bytes());
+    cdb
+}
+
+/// The parts of the standard inquiry data which identify a logical unit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InquiryData {
+    /// What kind of device it is, e.g. [`TYPE_DISK`] or [`TYPE_CDROM`].
+    pub device_type: u8,
+    /// Whether the target reports that no device is connected at this LUN.
+    pub not_connected: bool,
+    /// Whether the medium can be removed.
+    pub removable: bool,
+    /// The vendor identification, ASCII padded with spaces.
+    pub vendor: [u8; 8],
+    /// The product identification, ASCII padded with spaces.
+    pub product: [u8; 16],
+}
+
+impl InquiryData {
+    /// Parses the data returned by [`inquiry`], of which it needs the first 32 bytes.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (peripheral, flags) = (*data.first()?, *data.get(1)?);
+        Some(Self {
+            device_type: peripheral & 0x1f,
+            // A qualifier of 0 means there is a device, 1 that it's not connected and 3 that
+            // there can't be one.
+            not_connected: peripheral >> 5 != 0,
+            removable: flags & 0x80 != 0,
+            vendor: data.get(8..16)?.try_into().ok()?,
+            product: data.get(16..32)?.try_into().ok()?,
+        })
+    }
+}
+
+/// The size of a logical unit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Capacity {
+    /// The address of the last logical block.
+    pub last_lba: u64,
+    /// The size of a logical block in bytes.
+    pub block_size: u32,
+}
+
+impl Capacity {
+    /// Parses the data returned by [`read_capacity_10`].
+    ///
+    /// A `last_lba` of `0xffffffff` means the logical unit is too large for the command, and
+    /// [`read_capacity_16`] has to be used instead.
+    pub fn parse_10(data: &[u8]) -> Option<Self> {
+        Some(Self {
+            last_lba: u32::from_be_bytes(data.get(0..4)?.try_into().ok()?).into(),
+            block_size: u32::from_be_bytes(data.get(4..8)?.try_into().ok()?),
+        })
+    }
+
+    /// Parses the data returned by [`read_capacity_16`].
+    pub fn parse_16(data: &[u8]) -> Option<Self> {
+        Some(Self {
+            last_lba: u64::from_be_bytes(data.get(0..8)?.try_into().ok()?),
+            block_size: u32::from_be_bytes(data.get(8..12)?.try_into().ok()?),
+        })
+    }
+
+    /// The number of logical blocks.
+    pub fn blocks(&self) -> u64 {
+        self.last_lba + 1
+    }
+
+    /// The size in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.blocks() * u64::from(self.block_size)
+    }
+}
+
+/// Parses the data returned by [`report_luns`] into the LUNs it lists, skipping any which aren't
+/// in the single-level format [`VirtIOScsi`](super::VirtIOScsi) addresses LUNs with.
+///
+/// If the list was longer than the allocation length, only the LUNs which fit are returned.
+pub fn parse_luns(data: &[u8]) -> Vec<u16> {
+    let Some(len) = data.get(..4) else {
+        return Vec::new();
+    };
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    let end = data.len().min(8usize.saturating_add(len));
+    data.get(8..end)
+        .unwrap_or_default()
+        .chunks_exact(8)
+        .filter(|lun| {
+            // Peripheral addressing of bus 0, or flat space addressing.
+            let single_level = match lun[0] >> 6 {
+                0 => lun[0] == 0,
+                1 => true,
+                _ => false,
+            };
+            single_level && lun[2..].iter().all(|&b| b == 0)
+        })
+        .map(|lun| u16::from_be_bytes([lun[0] & 0x3f, lun[1]]))
+        .collect()
+}
@@ -0,0 +1,533 @@
+//! Driver for the VirtIO SCSI host device, through which many disks and CD-ROMs are attached.
+
+pub mod cdb;
+pub mod sense;
+mod ty;
+
+use crate::device::{DeviceIdentity, VirtIoDriver};
+use crate::error::{expect_ok, InitStep, VirtIoError, VirtIoResult};
+use crate::hal::{DmaBuf, Hal, MemoryRequirements};
+use crate::queue::{Buffer, EventSuppression, QueueInfo, QueueStats, VirtIoQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
+use crate::volatile::{ReadVolatile, WriteVolatile};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use sense::SenseData;
+use ty::*;
+
+pub use ty::{ScsiFeatures, CDB_SIZE, SENSE_SIZE};
+
+const QUEUE_CONTROL: u16 = 0;
+const QUEUE_EVENT: u16 = 1;
+/// The first request queue, the only one the driver uses.
+const QUEUE_REQUEST: u16 = 2;
+/// The most descriptors each queue has, which a [`Hal`] for the driver is generic over.
+pub const QUEUE_SIZE: usize = 16;
+/// The number of buffers kept on the event queue.
+const EVENT_BUFFERS: usize = 4;
+/// The features [`VirtIOScsi::new`] negotiates if the device offers them.
+pub const SUPPORTED_FEATURES: ScsiFeatures = ScsiFeatures::HOTPLUG.union(ScsiFeatures::CHANGE);
+/// The size of the sectors `max_sectors` counts in.
+const SECTOR_SIZE: usize = 512;
+/// The highest LUN the single-level format of the request's LUN field can address.
+const MAX_LUN: u16 = 0x3fff;
+
+/// A SCSI host bus adapter, through which the host attaches logical units of any number of
+/// targets, such as disks and CD-ROM drives.
+///
+/// Commands are sent to a logical unit as a CDB with [`Self::execute`]. The [`cdb`] module builds
+/// the CDBs of common commands, and [`sense`] parses the sense data returned when they fail.
+pub struct VirtIOScsi<H: Hal<QUEUE_SIZE>, T: Transport> {
+    transport: T,
+    negotiated_features: ScsiFeatures,
+    control_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    event_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    request_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    /// Copied from the queues when they were created, for [`Self::queues`].
+    queue_info: Vec<QueueInfo>,
+    event_buf: Box<[RawEvent; EVENT_BUFFERS]>,
+    max_target: u16,
+    max_lun: u16,
+    /// The most bytes a single command may transfer.
+    max_transfer: usize,
+}
+
+/// The outcome of a command the device delivered to its logical unit.
+#[derive(Clone, Debug)]
+pub struct CommandResult {
+    /// The SCSI status of the command.
+    pub status: ScsiStatus,
+    /// How many bytes of the data buffer weren't transferred.
+    pub residual: u32,
+    sense: [u8; SENSE_SIZE],
+    sense_len: usize,
+}
+
+impl CommandResult {
+    /// Whether the command succeeded.
+    pub fn is_good(&self) -> bool {
+        self.status == ScsiStatus::GOOD
+    }
+
+    /// Returns the raw sense data, which is empty unless the status is
+    /// [`ScsiStatus::CHECK_CONDITION`].
+    pub fn sense_bytes(&self) -> &[u8] {
+        self.sense.get(..self.sense_len).unwrap_or(&self.sense)
+    }
+
+    /// Parses the sense data, if there is any.
+    pub fn sense(&self) -> Option<SenseData> {
+        SenseData::parse(self.sense_bytes())
+    }
+}
+
+/// The SCSI status of a command, see [`CommandResult::status`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScsiStatus(pub u8);
+
+impl ScsiStatus {
+    pub const GOOD: Self = Self(0x00);
+    /// The command failed, and the sense data says why.
+    pub const CHECK_CONDITION: Self = Self(0x02);
+    pub const CONDITION_MET: Self = Self(0x04);
+    pub const BUSY: Self = Self(0x08);
+    pub const RESERVATION_CONFLICT: Self = Self(0x18);
+    pub const TASK_SET_FULL: Self = Self(0x28);
+    pub const ACA_ACTIVE: Self = Self(0x30);
+    pub const TASK_ABORTED: Self = Self(0x40);
+}
+
+/// An error from the SCSI device, returned as [`VirtIoError::ScsiDeviceError`] when a command or
+/// task management function couldn't be delivered or completed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScsiError {
+    /// The logical unit returned more data than fits in the buffer.
+    Overrun,
+    /// The command was aborted by a task management function.
+    Aborted,
+    /// There is no such target.
+    BadTarget,
+    /// The command was aborted by a reset of the target or the device.
+    Reset,
+    /// The device is busy, the command can be retried.
+    Busy,
+    /// The path to the target failed, but another path may work.
+    TransportFailure,
+    /// The target failed, and retrying won't help.
+    TargetFailure,
+    /// The target is reserved for another initiator.
+    NexusFailure,
+    /// Some other failure.
+    Failure,
+    /// The task management function is not supported.
+    FunctionRejected,
+    /// There is no such logical unit.
+    IncorrectLun,
+    /// The device returned a response code the driver doesn't know.
+    UnknownResponse(u8),
+}
+
+impl ScsiError {
+    /// Checks the response code of a request.
+    fn check(response: u8) -> Result<(), Self> {
+        Err(match response {
+            RESPONSE_OK | RESPONSE_FUNCTION_SUCCEEDED => return Ok(()),
+            RESPONSE_OVERRUN => Self::Overrun,
+            RESPONSE_ABORTED => Self::Aborted,
+            RESPONSE_BAD_TARGET => Self::BadTarget,
+            RESPONSE_RESET => Self::Reset,
+            RESPONSE_BUSY => Self::Busy,
+            RESPONSE_TRANSPORT_FAILURE => Self::TransportFailure,
+            RESPONSE_TARGET_FAILURE => Self::TargetFailure,
+            RESPONSE_NEXUS_FAILURE => Self::NexusFailure,
+            RESPONSE_FAILURE => Self::Failure,
+            RESPONSE_FUNCTION_REJECTED => Self::FunctionRejected,
+            RESPONSE_INCORRECT_LUN => Self::IncorrectLun,
+            response => Self::UnknownResponse(response),
+        })
+    }
+}
+
+/// Something the device reported on the event queue, returned by [`VirtIOScsi::poll_event`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScsiEvent {
+    /// A logical unit was added or removed, or a target was reset.
+    TransportReset {
+        target: u8,
+        lun: u16,
+        reason: ResetReason,
+    },
+    /// An asynchronous notification the driver subscribed to through the control queue.
+    AsyncNotify { target: u8, lun: u16, events: u32 },
+    /// A parameter of a logical unit changed, e.g. its capacity, with the additional sense code
+    /// and qualifier of the unit attention the logical unit reports for it.
+    ParamChange {
+        target: u8,
+        lun: u16,
+        asc: u8,
+        ascq: u8,
+    },
+    /// The device dropped events because there were no buffers for them. Every logical unit
+    /// should be scanned again.
+    EventsMissed,
+}
+
+/// Why a [`ScsiEvent::TransportReset`] was sent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResetReason {
+    /// The target was reset.
+    Hard,
+    /// A logical unit was added, and should be scanned.
+    Rescan,
+    /// The logical unit was removed.
+    Removed,
+    Unknown(u32),
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOScsi<H, T> {
+    /// The memory [`Self::new`] allocates: the control, event and request queues, and the buffers
+    /// kept on the event queue.
+    pub const fn memory_requirements() -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(3)
+            .with_shared_heap(EVENT_BUFFERS * size_of::<RawEvent>())
+    }
+
+    /// Create a new VirtIO SCSI driver.
+    pub fn new(transport: T) -> VirtIoResult<Self> {
+        Self::new_with_features(transport, SUPPORTED_FEATURES)
+    }
+
+    /// Creates a new VirtIO SCSI driver which only negotiates the features in `wanted`, e.g.
+    /// without [`ScsiFeatures::HOTPLUG`] to not be told about targets coming and going.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `wanted` isn't a subset of
+    /// [`SUPPORTED_FEATURES`].
+    pub fn new_with_features(mut transport: T, wanted: ScsiFeatures) -> VirtIoResult<Self> {
+        if !SUPPORTED_FEATURES.contains(wanted) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let negotiated_features =
+            transport.init_step(InitStep::Negotiation, |t| t.begin_init(wanted))?;
+        let (max_target, max_lun, max_transfer) =
+            transport.init_step(InitStep::ReadConfig, |t| {
+                t.check_config_space(size_of::<u32>() * 9)?;
+                let config = ScsiConfig::default();
+                let io_region = t.io_region();
+                if config.num_queues.read(io_region)? == 0 {
+                    warn!("SCSI device has no request queues");
+                    return Err(VirtIoError::IoError);
+                }
+                // The spec's defaults, written in case the device starts out with others.
+                config.cdb_size.write(CDB_SIZE as u32, io_region)?;
+                config.sense_size.write(SENSE_SIZE as u32, io_region)?;
+                let max_sectors = config.max_sectors.read(io_region)?;
+                let max_transfer = match max_sectors {
+                    0 => usize::MAX,
+                    max_sectors => (max_sectors as usize).saturating_mul(SECTOR_SIZE),
+                };
+                let max_target = config.max_target.read(io_region)?;
+                let max_lun = config.max_lun.read(io_region)?.min(MAX_LUN.into()) as u16;
+                info!("SCSI targets up to {}, LUNs up to {}", max_target, max_lun);
+                Ok((max_target, max_lun, max_transfer))
+            })?;
+        let mut event_buf = Box::new([RawEvent::default(); EVENT_BUFFERS]);
+        let (control_queue, mut event_queue, request_queue) =
+            transport.init_step(InitStep::QueueSetup, |t| {
+                Ok((
+                    VirtIoQueue::new(t, QUEUE_CONTROL)?,
+                    VirtIoQueue::new(t, QUEUE_EVENT)?,
+                    VirtIoQueue::new(t, QUEUE_REQUEST)?,
+                ))
+            })?;
+        transport.init_step(InitStep::InitialBuffers, |_| {
+            // Only as many buffers as the queue turned out to have room for.
+            let events = usize::from(event_queue.size());
+            for (i, event) in event_buf.iter_mut().enumerate().take(events) {
+                let token = event_queue.add(vec![DmaBuf::writable(event)])?;
+                if token != i as u16 {
+                    return Err(VirtIoError::WrongToken);
+                }
+            }
+            Ok(())
+        })?;
+        // Buffers may be added early, but notifications have to wait for DRIVER_OK.
+        transport.init_step(InitStep::DriverOk, T::finish_init)?;
+        transport.init_step(InitStep::InitialBuffers, |t| {
+            if event_queue.should_notify() {
+                t.notify(QUEUE_EVENT)?;
+            }
+            Ok(())
+        })?;
+
+        let queue_info = vec![
+            control_queue.info(),
+            event_queue.info(),
+            request_queue.info(),
+        ];
+        Ok(Self {
+            transport,
+            negotiated_features,
+            control_queue,
+            event_queue,
+            request_queue,
+            queue_info,
+            event_buf,
+            max_target,
+            max_lun,
+            max_transfer,
+        })
+    }
+
+    /// Acknowledges an interrupt, returning its causes, which are empty if there was none.
+    pub fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        self.transport.ack_interrupt()
+    }
+
+    /// Like [`Self::ack_interrupt`], but only checks the interrupt status without acknowledging
+    /// it, for polling the device before its interrupt is routed.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        Ok(self
+            .transport
+            .peek_interrupt_status()?
+            .contains(InterruptStatus::USED_RING_UPDATE))
+    }
+
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> ScsiFeatures {
+        self.negotiated_features
+    }
+
+    /// Returns the transport, e.g. to read the device status from a power management hook.
+    ///
+    /// Only shared access is given, so the device can't be reconfigured behind the driver's back.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        &self.queue_info
+    }
+
+    /// Returns the highest target number, so targets `0..=max_target` may exist.
+    ///
+    /// Targets are addressed with a single byte, so targets above 255 can't be reached.
+    pub fn max_target(&self) -> u16 {
+        self.max_target
+    }
+
+    /// Returns the highest LUN a target may have.
+    pub fn max_lun(&self) -> u16 {
+        self.max_lun
+    }
+
+    /// Sends the command `cdb` to logical unit `lun` of `target`, with an optional buffer for
+    /// the data it reads or writes, and waits for it to complete.
+    ///
+    /// A command which reached the logical unit returns its status, which the caller should
+    /// check, e.g. with [`CommandResult::is_good`]. If it couldn't be delivered or completed,
+    /// [`VirtIoError::ScsiDeviceError`] is returned instead.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if the CDB is longer than [`CDB_SIZE`], the LUN
+    /// can't be addressed or the buffer is larger than the device can transfer at once.
+    pub fn execute(
+        &mut self,
+        target: u8,
+        lun: u16,
+        cdb: &[u8],
+        data: Option<Buffer>,
+    ) -> VirtIoResult<CommandResult> {
+        if cdb.len() > CDB_SIZE
+            || lun > MAX_LUN
+            || data.as_ref().map_or(0, Buffer::len) > self.max_transfer
+        {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let mut padded = [0; CDB_SIZE];
+        padded
+            .get_mut(..cdb.len())
+            .ok_or(VirtIoError::InvalidParam)?
+            .copy_from_slice(cdb);
+        let request = CmdReq::new(lun_bytes(target, lun), 0, padded);
+        let mut response = CmdResp::default();
+        // Data for the device to read follows the request, and data it writes follows the
+        // response.
+        let mut descriptors = vec![DmaBuf::readable(&request)];
+        let data_in = match data {
+            Some(Buffer::Read(buf)) => {
+                descriptors.push(DmaBuf::readable(buf));
+                None
+            }
+            Some(Buffer::Write(buf)) => Some(buf),
+            None => None,
+        };
+        descriptors.push(DmaBuf::writable(&mut response));
+        if let Some(buf) = data_in {
+            descriptors.push(DmaBuf::writable(buf));
+        }
+        self.request_queue
+            .add_notify_wait_pop(&mut self.transport, descriptors)?;
+        ScsiError::check(response.response)?;
+        Ok(CommandResult {
+            status: ScsiStatus(response.status),
+            residual: response.resid.get(),
+            sense_len: (response.sense_len.get() as usize).min(SENSE_SIZE),
+            sense: response.sense,
+        })
+    }
+
+    /// Resets logical unit `lun` of `target`, aborting any commands it is executing.
+    pub fn reset_lun(&mut self, target: u8, lun: u16) -> VirtIoResult<()> {
+        if lun > MAX_LUN {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let request = CtrlTmfReq::new(T_TMF_LOGICAL_UNIT_RESET, lun_bytes(target, lun));
+        let mut response = CtrlTmfResp::default();
+        self.control_queue.add_notify_wait_pop(
+            &mut self.transport,
+            vec![DmaBuf::readable(&request), DmaBuf::writable(&mut response)],
+        )?;
+        ScsiError::check(response.response)?;
+        Ok(())
+    }
+
+    /// Returns the next event from the device, if there is one.
+    ///
+    /// Hotplug and parameter change events are only sent if [`ScsiFeatures::HOTPLUG`] and
+    /// [`ScsiFeatures::CHANGE`] were negotiated.
+    pub fn poll_event(&mut self) -> VirtIoResult<Option<ScsiEvent>> {
+        while let Some(token) = self.event_queue.peek_used() {
+            self.event_queue.pop_used(token)?;
+            let raw = self.event_buf[usize::from(token)];
+            let buffer = DmaBuf::writable(&mut self.event_buf[usize::from(token)]);
+            let new_token = self.event_queue.add(vec![buffer])?;
+            if new_token != token {
+                return Err(VirtIoError::WrongToken);
+            }
+            if self.event_queue.should_notify() {
+                self.transport.notify(QUEUE_EVENT)?;
+            }
+            if let Some(event) = Self::decode_event(&raw) {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decodes an event, or returns `None` for an empty one.
+    fn decode_event(raw: &RawEvent) -> Option<ScsiEvent> {
+        let event = raw.event.get();
+        if event & T_EVENTS_MISSED != 0 {
+            // Whatever event came with the flag, a rescan also covers it.
+            return Some(ScsiEvent::EventsMissed);
+        }
+        let target = raw.lun[1];
+        let lun = u16::from_be_bytes([raw.lun[2], raw.lun[3]]) & MAX_LUN;
+        let reason = raw.reason.get();
+        match event {
+            T_NO_EVENT => None,
+            T_TRANSPORT_RESET => Some(ScsiEvent::TransportReset {
+                target,
+                lun,
+                reason: match reason {
+                    0 => ResetReason::Hard,
+                    1 => ResetReason::Rescan,
+                    2 => ResetReason::Removed,
+                    reason => ResetReason::Unknown(reason),
+                },
+            }),
+            T_ASYNC_NOTIFY => Some(ScsiEvent::AsyncNotify {
+                target,
+                lun,
+                events: reason,
+            }),
+            T_PARAM_CHANGE => Some(ScsiEvent::ParamChange {
+                target,
+                lun,
+                asc: reason as u8,
+                ascq: (reason >> 8) as u8,
+            }),
+            event => {
+                warn!("Ignoring unknown SCSI event {:#x}", event);
+                None
+            }
+        }
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIoDriver for VirtIOScsi<H, T> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::ScsiHost
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        VirtIOScsi::ack_interrupt(self)
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        VirtIOScsi::poll_interrupt(self)
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            device_type: DeviceType::ScsiHost,
+            bus_addr: self.transport.io_region().paddr(),
+            features: self.negotiated_features.bits(),
+        }
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        vec![
+            self.control_queue.stats(),
+            self.event_queue.stats(),
+            self.request_queue.stats(),
+        ]
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        match queue {
+            QUEUE_CONTROL => self.control_queue.set_event_suppression(suppression),
+            QUEUE_EVENT => self.event_queue.set_event_suppression(suppression),
+            QUEUE_REQUEST => self.request_queue.set_event_suppression(suppression),
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.transport.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.transport.reset()
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> Drop for VirtIOScsi<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        expect_ok(
+            self.transport.queue_unset(QUEUE_CONTROL),
+            "failed to unset control queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_EVENT),
+            "failed to unset event queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_REQUEST),
+            "failed to unset request queue",
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Parsing of the sense data a target returns with `CHECK CONDITION`.
+
+/// The broad category of a check condition, from the sense data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SenseKey {
+    NoSense,
+    RecoveredError,
+    /// The logical unit can't be accessed yet, e.g. a CD-ROM drive without a disc.
+    NotReady,
+    MediumError,
+    HardwareError,
+    /// The command or its parameters aren't supported.
+    IllegalRequest,
+    /// Something changed on the logical unit, e.g. it was reset or its medium changed. The command
+    /// wasn't executed and can be retried.
+    UnitAttention,
+    DataProtect,
+    BlankCheck,
+    VendorSpecific,
+    CopyAborted,
+    AbortedCommand,
+    Reserved,
+    VolumeOverflow,
+    Miscompare,
+    Completed,
+}
+
+impl SenseKey {
+    /// Returns the sense key with the given 4-bit value.
+    pub const fn from_bits(bits: u8) -> Self {
+        match bits & 0xf {
+            0x0 => Self::NoSense,
+            0x1 => Self::RecoveredError,
+            0x2 => Self::NotReady,
+            0x3 => Self::MediumError,
+            0x4 => Self::HardwareError,
+            0x5 => Self::IllegalRequest,
+            0x6 => Self::UnitAttention,
+            0x7 => Self::DataProtect,
+            0x8 => Self::BlankCheck,
+            0x9 => Self::VendorSpecific,
+            0xa => Self::CopyAborted,
+            0xb => Self::AbortedCommand,
+            0xc => Self::Reserved,
+            0xd => Self::VolumeOverflow,
+            0xe => Self::Miscompare,
+            _ => Self::Completed,
+        }
+    }
+}
+
+/// The parts of sense data which say what went wrong.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SenseData {
+    pub key: SenseKey,
+    /// The additional sense code, or 0 if the sense data doesn't include it.
+    pub asc: u8,
+    /// The additional sense code qualifier, or 0 if the sense data doesn't include it.
+    pub ascq: u8,
+    /// Whether the error is from an earlier command, rather than the one it was returned for.
+    pub deferred: bool,
+}
+
+impl SenseData {
+    /// Parses sense data in either the fixed or the descriptor format.
+    ///
+    /// Returns `None` if `sense` is too short or has a response code of neither format.
+    pub fn parse(sense: &[u8]) -> Option<Self> {
+        let response_code = sense.first()? & 0x7f;
+        match response_code {
+            // Fixed format: the key in byte 2, and the codes in bytes 12 and 13 if the additional
+            // length in byte 7 covers them.
+            0x70 | 0x71 => {
+                let len = sense
+                    .len()
+                    .min(8 + usize::from(sense.get(7).copied().unwrap_or(0)));
+                let byte = |index: usize| if index < len { sense[index] } else { 0 };
+                Some(Self {
+                    key: SenseKey::from_bits(*sense.get(2)?),
+                    asc: byte(12),
+                    ascq: byte(13),
+                    deferred: response_code == 0x71,
+                })
+            }
+            // Descriptor format: everything in the header.
+            0x72 | 0x73 if sense.len() >= 4 => Some(Self {
+                key: SenseKey::from_bits(sense[1]),
+                asc: sense[2],
+                ascq: sense[3],
+                deferred: response_code == 0x73,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether the logical unit reports that it has no medium, e.g. an empty CD-ROM drive.
+    pub fn is_medium_not_present(&self) -> bool {
+        self.key == SenseKey::NotReady && self.asc == 0x3a
+    }
+}
@@ -0,0 +1,193 @@
+use crate::endian::{Le16, Le32, Le64};
+use crate::transport::DeviceFeatures;
+use crate::volatile::virtio_config;
+use bitflags::bitflags;
+
+virtio_config! {
+    pub struct ScsiConfig {
+        /// The number of request queues.
+        pub(super) num_queues: ReadOnly<u32> @ 0x0,
+        /// The most 512 byte sectors a command may transfer.
+        pub(super) max_sectors: ReadOnly<u32> @ 0x8,
+        /// The size of the sense data in a response, which the driver may change.
+        pub(super) sense_size: ReadWrite<u32> @ 0x14,
+        /// The size of the CDB in a request, which the driver may change.
+        pub(super) cdb_size: ReadWrite<u32> @ 0x18,
+        pub(super) max_target: ReadOnly<u16> @ 0x1e,
+        pub(super) max_lun: ReadOnly<u32> @ 0x20,
+    }
+}
+
+/// The size of the CDB in every request, the spec's default.
+pub const CDB_SIZE: usize = 32;
+/// The size of the sense data in every response, the spec's default.
+pub const SENSE_SIZE: usize = 96;
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct ScsiFeatures: u64 {
+        /// A single command can include both data-in and data-out buffers.
+        const INOUT                 = 1 << 0;
+        /// The host reports hotplug and hot-unplug of targets and LUNs on the event queue.
+        const HOTPLUG               = 1 << 1;
+        /// The host reports changes to LUN parameters on the event queue.
+        const CHANGE                = 1 << 2;
+        /// The extended fields for T10 protection information are in the request.
+        const T10_PI                = 1 << 3;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+format_flags!(ScsiFeatures);
+
+impl DeviceFeatures for ScsiFeatures {}
+
+/// Encodes the LUN field of a request for `lun` of `target`, in the single-level format the
+/// device understands.
+pub(crate) fn lun_bytes(target: u8, lun: u16) -> [u8; 8] {
+    let [hi, lo] = (0x4000 | lun).to_be_bytes();
+    [1, target, hi, lo, 0, 0, 0, 0]
+}
+
+/// The header of a command request, followed by any data-out buffers.
+///
+/// The header is 51 bytes, so `id` is kept as bytes to keep the struct from being padded to a
+/// multiple of 8, which the device would take as the start of the data.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct CmdReq {
+    lun: [u8; 8],
+    id: [u8; 8],
+    /// `SIMPLE`, the only attribute the driver uses.
+    task_attr: u8,
+    prio: u8,
+    crn: u8,
+    cdb: [u8; CDB_SIZE],
+}
+
+impl CmdReq {
+    pub(crate) fn new(lun: [u8; 8], id: u64, cdb: [u8; CDB_SIZE]) -> Self {
+        Self {
+            lun,
+            id: id.to_le_bytes(),
+            task_attr: 0,
+            prio: 0,
+            crn: 0,
+            cdb,
+        }
+    }
+}
+
+/// The response to a command request, which comes before any data-in buffers.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct CmdResp {
+    pub(crate) sense_len: Le32,
+    pub(crate) resid: Le32,
+    pub(crate) status_qualifier: Le16,
+    pub(crate) status: u8,
+    pub(crate) response: u8,
+    pub(crate) sense: [u8; SENSE_SIZE],
+}
+
+impl Default for CmdResp {
+    fn default() -> Self {
+        Self {
+            sense_len: Le32::new(0),
+            resid: Le32::new(0),
+            status_qualifier: Le16::new(0),
+            status: 0,
+            // Anything but `S_OK`, in case the device doesn't fill in the response.
+            response: RESPONSE_FAILURE,
+            sense: [0; SENSE_SIZE],
+        }
+    }
+}
+
+/// The response codes of command and task management requests.
+pub(crate) const RESPONSE_OK: u8 = 0;
+pub(crate) const RESPONSE_OVERRUN: u8 = 1;
+pub(crate) const RESPONSE_ABORTED: u8 = 2;
+pub(crate) const RESPONSE_BAD_TARGET: u8 = 3;
+pub(crate) const RESPONSE_RESET: u8 = 4;
+pub(crate) const RESPONSE_BUSY: u8 = 5;
+pub(crate) const RESPONSE_TRANSPORT_FAILURE: u8 = 6;
+pub(crate) const RESPONSE_TARGET_FAILURE: u8 = 7;
+pub(crate) const RESPONSE_NEXUS_FAILURE: u8 = 8;
+pub(crate) const RESPONSE_FAILURE: u8 = 9;
+pub(crate) const RESPONSE_FUNCTION_SUCCEEDED: u8 = 10;
+pub(crate) const RESPONSE_FUNCTION_REJECTED: u8 = 11;
+pub(crate) const RESPONSE_INCORRECT_LUN: u8 = 12;
+
+/// The type of a task management request on the control queue.
+const T_TMF: u32 = 0;
+/// The task management function resetting a logical unit.
+pub(crate) const T_TMF_LOGICAL_UNIT_RESET: u32 = 5;
+
+/// A task management request.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct CtrlTmfReq {
+    type_: Le32,
+    subtype: Le32,
+    lun: [u8; 8],
+    id: Le64,
+}
+
+impl CtrlTmfReq {
+    pub(crate) fn new(subtype: u32, lun: [u8; 8]) -> Self {
+        Self {
+            type_: Le32::new(T_TMF),
+            subtype: Le32::new(subtype),
+            lun,
+            id: Le64::new(0),
+        }
+    }
+}
+
+/// The response to a task management request.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct CtrlTmfResp {
+    pub(crate) response: u8,
+}
+
+impl Default for CtrlTmfResp {
+    fn default() -> Self {
+        Self {
+            response: RESPONSE_FAILURE,
+        }
+    }
+}
+
+/// Event types of [`RawEvent::event`].
+pub(crate) const T_NO_EVENT: u32 = 0;
+pub(crate) const T_TRANSPORT_RESET: u32 = 1;
+pub(crate) const T_ASYNC_NOTIFY: u32 = 2;
+pub(crate) const T_PARAM_CHANGE: u32 = 3;
+/// Set on an event if the driver didn't have buffers for some earlier ones.
+pub(crate) const T_EVENTS_MISSED: u32 = 0x8000_0000;
+
+/// An event as the device writes it into a buffer of the event queue.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RawEvent {
+    pub(crate) event: Le32,
+    pub(crate) lun: [u8; 8],
+    pub(crate) reason: Le32,
+}
@@ -0,0 +1,141 @@
+//! A registry for several devices of the same kind, e.g. multiple virtio-blk disks.
+
+use crate::error::{VirtIoError, VirtIoResult};
+use crate::PhysAddr;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Where a device was found, used to look it up again e.g. from an interrupt handler.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct DeviceLocation {
+    /// The physical address of the device's registers, e.g. its MMIO region.
+    pub bus_addr: PhysAddr,
+    /// The interrupt line of the device, which may be shared by several devices.
+    pub irq: usize,
+}
+
+/// A set of devices indexed in the order they were inserted.
+///
+/// Indices stay the same when other devices are removed, so names derived from them (see
+/// [`device_name`]) are consistent for as long as the device is present.
+pub struct DeviceSet<T> {
+    devices: Vec<Option<(DeviceLocation, T)>>,
+}
+
+impl<T> DeviceSet<T> {
+    /// Creates an empty set.
+    pub const fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Adds a device found at `location` and returns its index.
+    ///
+    /// Returns [`VirtIoError::AlreadyUsed`] if a device is already registered at the same bus
+    /// address.
+    pub fn insert(&mut self, location: DeviceLocation, device: T) -> VirtIoResult<usize> {
+        if self.index_of(location.bus_addr).is_some() {
+            return Err(VirtIoError::AlreadyUsed);
+        }
+        self.devices.push(Some((location, device)));
+        Ok(self.devices.len() - 1)
+    }
+
+    /// Removes the device with the given index. Its index isn't reused.
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        self.devices
+            .get_mut(index)?
+            .take()
+            .map(|(_, device)| device)
+    }
+
+    /// Returns the device with the given index.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.devices.get(index)?.as_ref().map(|(_, device)| device)
+    }
+
+    /// Returns the device with the given index.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.devices
+            .get_mut(index)?
+            .as_mut()
+            .map(|(_, device)| device)
+    }
+
+    /// Returns where the device with the given index was found.
+    pub fn location(&self, index: usize) -> Option<DeviceLocation> {
+        self.devices
+            .get(index)?
+            .as_ref()
+            .map(|(location, _)| *location)
+    }
+
+    /// Returns the index of the device at the given bus address.
+    pub fn index_of(&self, bus_addr: PhysAddr) -> Option<usize> {
+        self.iter()
+            .find(|(_, location, _)| location.bus_addr == bus_addr)
+            .map(|(index, _, _)| index)
+    }
+
+    /// Returns the index of the device at the given location.
+    pub fn find(&self, location: DeviceLocation) -> Option<usize> {
+        self.iter()
+            .find(|(_, l, _)| *l == location)
+            .map(|(index, _, _)| index)
+    }
+
+    /// Returns the indices of all devices using the given interrupt line.
+    pub fn indices_for_irq(&self, irq: usize) -> impl Iterator<Item = usize> + '_ {
+        self.iter()
+            .filter(move |(_, location, _)| location.irq == irq)
+            .map(|(index, _, _)| index)
+    }
+
+    /// The number of devices in the set.
+    pub fn len(&self) -> usize {
+        self.devices.iter().flatten().count()
+    }
+
+    /// Whether the set has no devices.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over the devices in index order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, DeviceLocation, &T)> {
+        self.devices
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| entry.as_ref().map(|(l, device)| (index, *l, device)))
+    }
+
+    /// Iterates over the devices in index order.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, DeviceLocation, &mut T)> {
+        self.devices
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, entry)| entry.as_mut().map(|(l, device)| (index, *l, device)))
+    }
+}
+
+impl<T> Default for DeviceSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the conventional name of the device with the given index, following the Linux scheme of
+/// `prefix` followed by letters: `vda`, `vdb`, ..., `vdz`, `vdaa`, ...
+pub fn device_name(prefix: &str, index: usize) -> String {
+    let mut suffix = Vec::new();
+    let mut n = index + 1;
+    while n > 0 {
+        n -= 1;
+        suffix.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    let mut name = String::from(prefix);
+    name.extend(suffix.iter().rev().map(|&c| c as char));
+    name
+}
@@ -0,0 +1,685 @@
+use crate::device::{DeviceIdentity, VirtIoDriver};
+use crate::endian::Le32;
+use crate::error::{expect_ok, InitStep, VirtIoError, VirtIoResult};
+use crate::hal::{DmaBuf, Hal, MemoryRequirements};
+use crate::queue::{EventSuppression, QueueInfo, QueueStats, VirtIoQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
+use crate::volatile::ReadVolatile;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+mod ty;
+
+use ty::*;
+
+pub use ty::SocketFeature;
+
+const QUEUE_RX: u16 = 0;
+const QUEUE_TX: u16 = 1;
+const QUEUE_EVENT: u16 = 2;
+const QUEUE_SIZE: usize = 16;
+/// The features [`VirtIOSocket::new`] negotiates if the device offers them.
+pub const SUPPORTED_FEATURES: SocketFeature = SocketFeature::STREAM;
+
+/// The size of each receive buffer, including the packet header.
+const RX_BUFFER_SIZE: usize = 2048;
+/// How many bytes of received data the driver buffers for each connection. This is the credit
+/// advertised to the peer, so it never sends more than fits.
+const CONNECTION_BUFFER_SIZE: usize = 16 * 1024;
+
+/// The CID of the host.
+pub const VMADDR_CID_HOST: u64 = 2;
+
+/// The address of one end of a connection.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VsockAddr {
+    /// The context ID of the guest or host.
+    pub cid: u64,
+    pub port: u32,
+}
+
+/// Identifies a connection by the peer's address and the local port.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnectionId {
+    pub peer: VsockAddr,
+    pub local_port: u32,
+}
+
+/// Why a connection was closed by the peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DisconnectReason {
+    /// The peer reset the connection, or refused it.
+    Reset,
+    /// The peer shut the connection down.
+    Shutdown,
+}
+
+/// Something that happened on the device, returned by [`VirtIOSocket::poll`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VsockEvent {
+    /// A peer connected to a port which is [listened on](VirtIOSocket::listen), and the
+    /// connection was accepted.
+    Accepted(ConnectionId),
+    /// The peer accepted a connection made with [`VirtIOSocket::connect`].
+    Connected(ConnectionId),
+    /// The peer closed the connection. Data which was already received can still be read, until
+    /// the connection is [closed](VirtIOSocket::close).
+    Disconnected {
+        id: ConnectionId,
+        reason: DisconnectReason,
+    },
+    /// Data was received, and can be read with [`VirtIOSocket::recv`].
+    Received { id: ConnectionId, length: usize },
+    /// The peer told the driver how much space it has for data.
+    CreditUpdate(ConnectionId),
+    /// The device reset every connection, e.g. after the guest was migrated, and the guest CID may
+    /// have changed.
+    TransportReset,
+}
+
+/// An error from the socket device, returned as [`VirtIoError::SocketDeviceError`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SocketError {
+    /// There is already a connection with the same peer and local port.
+    ConnectionExists,
+    /// There is no such connection, or it hasn't been accepted by the peer yet.
+    NotConnected,
+    /// The peer closed the connection, so no more data can be sent.
+    PeerSocketShutdown,
+    /// The peer doesn't have space for the data, see [`VirtIOSocket::peer_credit`].
+    InsufficientBufferSpaceInPeer,
+    /// The device sent a packet with an unknown operation.
+    UnknownOperation(u16),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    /// A request was sent, but the peer hasn't responded.
+    Connecting,
+    Connected,
+    /// The peer shut down or reset the connection.
+    Closed,
+}
+
+struct Connection {
+    state: State,
+    /// Received data which hasn't been read yet.
+    buffer: VecDeque<u8>,
+    /// How many bytes were read from `buffer` in total, sent to the peer as `fwd_cnt`.
+    fwd_cnt: u32,
+    /// The `fwd_cnt` the peer was last told about, to decide when to tell it about freed space.
+    last_fwd_cnt_sent: u32,
+    /// How many bytes were sent in total.
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    fn new(state: State) -> Self {
+        Self {
+            state,
+            buffer: VecDeque::new(),
+            fwd_cnt: 0,
+            last_fwd_cnt_sent: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// How many more bytes the peer has space for.
+    ///
+    /// Ref: 5.10.6.3 Flow Control
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    /// Updates the peer's credit from the header of a packet it sent.
+    fn update_peer(&mut self, hdr: &VsockHdr) {
+        self.peer_buf_alloc = hdr.buf_alloc;
+        self.peer_fwd_cnt = hdr.fwd_cnt;
+    }
+}
+
+/// A driver for a virtio socket (vsock) device, with stream connections to the host.
+///
+/// Connections are identified by the peer's address and the local port, and are made with
+/// [`Self::connect`] or accepted on ports passed to [`Self::listen`]. Packets from the device are
+/// only processed by [`Self::poll`], which should be called whenever the device interrupts.
+pub struct VirtIOSocket<H: Hal<QUEUE_SIZE>, T: Transport> {
+    transport: T,
+    negotiated_features: SocketFeature,
+    guest_cid: u64,
+    rx: VirtIoQueue<H, QUEUE_SIZE>,
+    tx: VirtIoQueue<H, QUEUE_SIZE>,
+    event_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    /// Copied from the queues when they were created, for [`Self::queues`].
+    queue_info: Vec<QueueInfo>,
+    /// One buffer per receive queue slot, indexed by token.
+    rx_buf: Vec<[u8; RX_BUFFER_SIZE]>,
+    /// One event per event queue slot, indexed by token.
+    event_buf: Box<[Le32; QUEUE_SIZE]>,
+    connections: BTreeMap<ConnectionId, Connection>,
+    listening: BTreeSet<u32>,
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOSocket<H, T> {
+    /// The memory [`Self::new`] allocates: the receive, transmit and event queues, and a buffer
+    /// for each receive and event slot.
+    pub const fn memory_requirements() -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(3)
+            .with_shared_heap(QUEUE_SIZE * (RX_BUFFER_SIZE + size_of::<Le32>()))
+    }
+
+    /// Create a new VirtIO-Vsock driver.
+    pub fn new(transport: T) -> VirtIoResult<Self> {
+        Self::new_with_features(transport, SUPPORTED_FEATURES)
+    }
+
+    /// Creates a new VirtIO-Vsock driver which only negotiates the features in `wanted`, those of
+    /// them the device offers.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `wanted` isn't a subset of
+    /// [`SUPPORTED_FEATURES`].
+    pub fn new_with_features(mut transport: T, wanted: SocketFeature) -> VirtIoResult<Self> {
+        if !SUPPORTED_FEATURES.contains(wanted) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let negotiated_features = transport
+            .init_step(InitStep::Negotiation, |t| {
+                t.begin_init_with_fallback(SocketFeature::empty(), wanted)
+            })?
+            .features;
+        let guest_cid = transport.init_step(InitStep::ReadConfig, |t| {
+            VsockConfig::default().guest_cid.read(t.io_region())
+        })?;
+        info!("guest cid: {}", guest_cid);
+
+        let (rx, tx, event_queue) = transport.init_step(InitStep::QueueSetup, |t| {
+            Ok((
+                VirtIoQueue::new(t, QUEUE_RX)?,
+                VirtIoQueue::new(t, QUEUE_TX)?,
+                VirtIoQueue::new(t, QUEUE_EVENT)?,
+            ))
+        })?;
+        let queue_info = vec![rx.info(), tx.info(), event_queue.info()];
+        let mut socket = Self {
+            transport,
+            negotiated_features,
+            guest_cid,
+            rx,
+            tx,
+            event_queue,
+            queue_info,
+            rx_buf: vec![[0; RX_BUFFER_SIZE]; QUEUE_SIZE],
+            event_buf: Box::new([Le32::default(); QUEUE_SIZE]),
+            connections: BTreeMap::new(),
+            listening: BTreeSet::new(),
+        };
+        // The device must not be notified before it is ready.
+        socket
+            .transport
+            .init_step(InitStep::DriverOk, T::finish_init)?;
+        if let Err(e) = socket.add_initial_buffers() {
+            return Err(socket.transport.fail_init(InitStep::InitialBuffers, e));
+        }
+        Ok(socket)
+    }
+
+    /// Gives the device a buffer for every receive and event slot.
+    fn add_initial_buffers(&mut self) -> VirtIoResult<()> {
+        // Only as many buffers as each queue turned out to have room for.
+        for i in 0..self.rx.size() {
+            self.add_rx_buffer(i)?;
+        }
+        for i in 0..self.event_queue.size() {
+            self.add_event_buffer(i)?;
+        }
+        if self.rx.should_notify() {
+            self.transport.notify(QUEUE_RX)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> SocketFeature {
+        self.negotiated_features
+    }
+
+    /// Returns the transport, e.g. to read the device status from a power management hook.
+    ///
+    /// Only shared access is given, so the device can't be reconfigured behind the driver's back.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        &self.queue_info
+    }
+
+    /// Returns the context ID of the guest.
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
+    /// Accepts connections to the given local port from now on.
+    pub fn listen(&mut self, port: u32) {
+        self.listening.insert(port);
+    }
+
+    /// Refuses new connections to the given local port. Connections already accepted are kept.
+    pub fn unlisten(&mut self, port: u32) {
+        self.listening.remove(&port);
+    }
+
+    /// Requests a connection from the given local port to the peer.
+    ///
+    /// [`Self::poll`] returns [`VsockEvent::Connected`] once the peer accepts, or
+    /// [`VsockEvent::Disconnected`] if it refuses.
+    pub fn connect(&mut self, peer: VsockAddr, local_port: u32) -> VirtIoResult<ConnectionId> {
+        let id = ConnectionId { peer, local_port };
+        if self.connections.contains_key(&id) {
+            return Err(SocketError::ConnectionExists.into());
+        }
+        self.connections
+            .insert(id, Connection::new(State::Connecting));
+        self.send_op(id, Op::Request, ShutdownFlags::empty())?;
+        Ok(id)
+    }
+
+    /// Sends data on a connection, blocking until the device has taken it.
+    ///
+    /// Returns [`SocketError::InsufficientBufferSpaceInPeer`] without sending anything if the peer
+    /// doesn't have space for all of it, see [`Self::peer_credit`].
+    pub fn send(&mut self, id: ConnectionId, data: &[u8]) -> VirtIoResult<()> {
+        let conn = self.connection(id)?;
+        match conn.state {
+            State::Connecting => return Err(SocketError::NotConnected.into()),
+            State::Closed => return Err(SocketError::PeerSocketShutdown.into()),
+            State::Connected => {}
+        }
+        if data.len() > conn.peer_credit() as usize {
+            return Err(SocketError::InsufficientBufferSpaceInPeer.into());
+        }
+        let hdr = self.header(id, Op::Rw, data.len() as u32, ShutdownFlags::empty());
+        self.send_packet(&hdr, data)?;
+        if let Some(conn) = self.connections.get_mut(&id) {
+            conn.tx_cnt = conn.tx_cnt.wrapping_add(data.len() as u32);
+        }
+        Ok(())
+    }
+
+    /// Returns how many bytes the peer currently has space for on a connection.
+    ///
+    /// The peer advertises more as it reads, and [`Self::request_credit`] asks it to.
+    pub fn peer_credit(&self, id: ConnectionId) -> VirtIoResult<u32> {
+        Ok(self.connection(id)?.peer_credit())
+    }
+
+    /// Asks the peer to send a credit update, which [`Self::poll`] reports as
+    /// [`VsockEvent::CreditUpdate`].
+    pub fn request_credit(&mut self, id: ConnectionId) -> VirtIoResult<()> {
+        self.connection(id)?;
+        self.send_op(id, Op::CreditRequest, ShutdownFlags::empty())
+    }
+
+    /// Returns how many received bytes are waiting to be read from a connection.
+    pub fn recv_buffer_len(&self, id: ConnectionId) -> VirtIoResult<usize> {
+        Ok(self.connection(id)?.buffer.len())
+    }
+
+    /// Reads received data from a connection into `buf`, and returns how many bytes were read.
+    ///
+    /// Returns 0 if there is no data waiting, rather than blocking. Once enough data has been read
+    /// the peer is told it may send more.
+    pub fn recv(&mut self, id: ConnectionId, buf: &mut [u8]) -> VirtIoResult<usize> {
+        let conn = self
+            .connections
+            .get_mut(&id)
+            .ok_or(SocketError::NotConnected)?;
+        let len = buf.len().min(conn.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(conn.buffer.drain(..len)) {
+            *dst = src;
+        }
+        conn.fwd_cnt = conn.fwd_cnt.wrapping_add(len as u32);
+        let unannounced = conn.fwd_cnt.wrapping_sub(conn.last_fwd_cnt_sent) as usize;
+        if conn.state == State::Connected && unannounced >= CONNECTION_BUFFER_SIZE / 2 {
+            self.send_op(id, Op::CreditUpdate, ShutdownFlags::empty())?;
+        }
+        Ok(len)
+    }
+
+    /// Tells the peer that no more data will be sent or received on a connection.
+    ///
+    /// The peer is expected to reset the connection in response, which [`Self::poll`] reports as
+    /// [`VsockEvent::Disconnected`]. The connection must still be [closed](Self::close).
+    pub fn shutdown(&mut self, id: ConnectionId) -> VirtIoResult<()> {
+        self.connection(id)?;
+        self.send_op(id, Op::Shutdown, ShutdownFlags::all())
+    }
+
+    /// Forgets a connection, discarding any data which wasn't read, and resets it if the peer
+    /// hasn't closed it already.
+    pub fn close(&mut self, id: ConnectionId) -> VirtIoResult<()> {
+        let conn = self
+            .connections
+            .remove(&id)
+            .ok_or(SocketError::NotConnected)?;
+        if conn.state != State::Closed {
+            self.send_rst(id)?;
+        }
+        Ok(())
+    }
+
+    /// Processes packets and events from the device until one of them is something the caller
+    /// needs to know about, and returns it. Returns `None` once there is nothing left to process.
+    ///
+    /// Credit requests and packets for unknown connections are answered without returning an
+    /// event.
+    pub fn poll(&mut self) -> VirtIoResult<Option<VsockEvent>> {
+        loop {
+            if let Some(token) = self.event_queue.peek_used() {
+                self.event_queue.pop_used(token)?;
+                let event = self.event_buf[token as usize].get();
+                self.add_event_buffer(token)?;
+                if event == EVENT_TRANSPORT_RESET {
+                    self.connections.clear();
+                    self.guest_cid = VsockConfig::default()
+                        .guest_cid
+                        .read(self.transport.io_region())?;
+                    warn!("transport reset, guest cid is now {}", self.guest_cid);
+                    return Ok(Some(VsockEvent::TransportReset));
+                }
+                continue;
+            }
+            let Some(token) = self.rx.peek_used() else {
+                return Ok(None);
+            };
+            let len = self.rx.pop_used(token)? as usize;
+            let event = self.handle_packet(token as usize, len);
+            self.add_rx_buffer(token)?;
+            if self.rx.should_notify() {
+                self.transport.notify(QUEUE_RX)?;
+            }
+            if let Some(event) = event? {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    /// Acknowledges a pending interrupt, if any, and collects the packets and events the device
+    /// sent, for [`Self::poll`] to process.
+    ///
+    /// Returns the causes of the interrupt, which are empty if there was none.
+    pub fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        let status = self.transport.ack_interrupt()?;
+        if status.contains(InterruptStatus::USED_RING_UPDATE) {
+            self.rx.collect_used();
+            self.event_queue.collect_used();
+        }
+        Ok(status)
+    }
+
+    /// Like [`Self::ack_interrupt`], but only checks the interrupt status without acknowledging
+    /// it, for polling the device before its interrupt is routed.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        let used = self
+            .transport
+            .peek_interrupt_status()?
+            .contains(InterruptStatus::USED_RING_UPDATE);
+        if used {
+            self.rx.collect_used();
+            self.event_queue.collect_used();
+        }
+        Ok(used)
+    }
+
+    /// Handles the packet the device wrote into the given receive buffer.
+    fn handle_packet(&mut self, index: usize, len: usize) -> VirtIoResult<Option<VsockEvent>> {
+        let buf = self.rx_buf.get(index).ok_or(VirtIoError::WrongToken)?;
+        // Neither the device nor the header can make the packet longer than the buffer.
+        let packet = buf.get(..len).unwrap_or(buf);
+        let hdr = VsockHdr::read_from(packet)?;
+        let payload = packet.get(HDR_SIZE..).unwrap_or_default();
+        let payload = payload.get(..hdr.len as usize).unwrap_or(payload);
+        if hdr.dst_cid != self.guest_cid {
+            warn!("dropping packet for cid {}", hdr.dst_cid);
+            return Ok(None);
+        }
+        let id = ConnectionId {
+            peer: VsockAddr {
+                cid: hdr.src_cid,
+                port: hdr.src_port,
+            },
+            local_port: hdr.dst_port,
+        };
+        let op = Op::try_from(hdr.op).map_err(SocketError::UnknownOperation)?;
+        if hdr.type_ != TYPE_STREAM {
+            // Only stream sockets are supported, so refuse anything else.
+            if op != Op::Rst {
+                self.send_rst(id)?;
+            }
+            return Ok(None);
+        }
+
+        if op == Op::Request {
+            if self.connections.contains_key(&id) || !self.listening.contains(&id.local_port) {
+                self.send_rst(id)?;
+                return Ok(None);
+            }
+            let mut conn = Connection::new(State::Connected);
+            conn.update_peer(&hdr);
+            self.connections.insert(id, conn);
+            self.send_op(id, Op::Response, ShutdownFlags::empty())?;
+            return Ok(Some(VsockEvent::Accepted(id)));
+        }
+
+        let Some(conn) = self.connections.get_mut(&id) else {
+            // Don't answer a reset with another, or the two ends could keep resetting.
+            if op != Op::Rst {
+                self.send_rst(id)?;
+            }
+            return Ok(None);
+        };
+        conn.update_peer(&hdr);
+        let mut reply = None;
+        let event = match op {
+            Op::Response if conn.state == State::Connecting => {
+                conn.state = State::Connected;
+                Some(VsockEvent::Connected(id))
+            }
+            Op::Rst | Op::Shutdown if conn.state != State::Closed => {
+                conn.state = State::Closed;
+                let reason = if op == Op::Rst {
+                    DisconnectReason::Reset
+                } else {
+                    // Reset a connection the peer shut down completely, as it expects.
+                    if ShutdownFlags::from_bits_truncate(hdr.flags) == ShutdownFlags::all() {
+                        reply = Some(Op::Rst);
+                    }
+                    DisconnectReason::Shutdown
+                };
+                Some(VsockEvent::Disconnected { id, reason })
+            }
+            Op::Rw if conn.state == State::Connected => {
+                let space = CONNECTION_BUFFER_SIZE - conn.buffer.len();
+                if payload.len() > space {
+                    warn!(
+                        "peer {:?} sent {} bytes with only {} bytes of credit, dropping the rest",
+                        id.peer,
+                        payload.len(),
+                        space
+                    );
+                }
+                let length = payload.len().min(space);
+                conn.buffer.extend(payload.iter().take(length));
+                Some(VsockEvent::Received { id, length })
+            }
+            Op::CreditUpdate => Some(VsockEvent::CreditUpdate(id)),
+            Op::CreditRequest => {
+                reply = Some(Op::CreditUpdate);
+                None
+            }
+            // Packets which don't fit the state of the connection are ignored.
+            _ => None,
+        };
+        if let Some(op) = reply {
+            self.send_op(id, op, ShutdownFlags::empty())?;
+        }
+        Ok(event)
+    }
+
+    /// Returns the connection with the given ID.
+    fn connection(&self, id: ConnectionId) -> VirtIoResult<&Connection> {
+        Ok(self.connections.get(&id).ok_or(SocketError::NotConnected)?)
+    }
+
+    /// Builds the header of a packet on the given connection, with our credit if it is known.
+    fn header(&self, id: ConnectionId, op: Op, len: u32, flags: ShutdownFlags) -> VsockHdr {
+        VsockHdr {
+            src_cid: self.guest_cid,
+            dst_cid: id.peer.cid,
+            src_port: id.local_port,
+            dst_port: id.peer.port,
+            len,
+            type_: TYPE_STREAM,
+            op: op as u16,
+            flags: flags.bits(),
+            buf_alloc: CONNECTION_BUFFER_SIZE as u32,
+            fwd_cnt: self.connections.get(&id).map_or(0, |conn| conn.fwd_cnt),
+        }
+    }
+
+    /// Sends a packet without payload on the given connection.
+    fn send_op(&mut self, id: ConnectionId, op: Op, flags: ShutdownFlags) -> VirtIoResult<()> {
+        let hdr = self.header(id, op, 0, flags);
+        self.send_packet(&hdr, &[])?;
+        if let Some(conn) = self.connections.get_mut(&id) {
+            conn.last_fwd_cnt_sent = conn.fwd_cnt;
+        }
+        Ok(())
+    }
+
+    /// Resets the given connection, which may not be known to the driver.
+    fn send_rst(&mut self, id: ConnectionId) -> VirtIoResult<()> {
+        self.send_op(id, Op::Rst, ShutdownFlags::empty())
+    }
+
+    /// Sends a packet to the device and waits for it to be taken.
+    fn send_packet(&mut self, hdr: &VsockHdr, payload: &[u8]) -> VirtIoResult<()> {
+        let mut header = [0; HDR_SIZE];
+        hdr.write_to(&mut header);
+        let mut descriptors = vec![DmaBuf::readable(&header)];
+        if !payload.is_empty() {
+            descriptors.push(DmaBuf::readable(payload));
+        }
+        self.tx
+            .add_notify_wait_pop(&mut self.transport, descriptors)?;
+        Ok(())
+    }
+
+    /// Gives the receive buffer for the given slot to the device.
+    fn add_rx_buffer(&mut self, token: u16) -> VirtIoResult<()> {
+        let buf = self
+            .rx_buf
+            .get_mut(usize::from(token))
+            .ok_or(VirtIoError::WrongToken)?;
+        let new_token = self.rx.add(vec![DmaBuf::writable(buf)])?;
+        if new_token != token {
+            return Err(VirtIoError::WrongToken);
+        }
+        Ok(())
+    }
+
+    /// Gives the event buffer for the given slot to the device.
+    fn add_event_buffer(&mut self, token: u16) -> VirtIoResult<()> {
+        let new_token = self
+            .event_queue
+            .add(vec![DmaBuf::writable(&mut self.event_buf[token as usize])])?;
+        if new_token != token {
+            return Err(VirtIoError::WrongToken);
+        }
+        if self.event_queue.should_notify() {
+            self.transport.notify(QUEUE_EVENT)?;
+        }
+        Ok(())
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIoDriver for VirtIOSocket<H, T> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Socket
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        VirtIOSocket::ack_interrupt(self)
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        VirtIOSocket::poll_interrupt(self)
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            device_type: DeviceType::Socket,
+            bus_addr: self.transport.io_region().paddr(),
+            features: self.negotiated_features.bits(),
+        }
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        vec![self.rx.stats(), self.tx.stats(), self.event_queue.stats()]
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        match queue {
+            QUEUE_RX => self.rx.set_event_suppression(suppression),
+            QUEUE_TX => self.tx.set_event_suppression(suppression),
+            QUEUE_EVENT => self.event_queue.set_event_suppression(suppression),
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.transport.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.transport.reset()
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> Drop for VirtIOSocket<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        expect_ok(
+            self.transport.queue_unset(QUEUE_RX),
+            "failed to unset receive queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_TX),
+            "failed to unset transmit queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_EVENT),
+            "failed to unset event queue",
+        );
+    }
+}
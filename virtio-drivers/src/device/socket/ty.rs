@@ -0,0 +1,149 @@
+use crate::error::{VirtIoError, VirtIoResult};
+use crate::transport::DeviceFeatures;
+use crate::volatile::virtio_config;
+use bitflags::bitflags;
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct SocketFeature: u64 {
+        /// Stream socket type is supported.
+        const STREAM    = 1 << 0;
+        /// Sequential packet socket type is supported.
+        const SEQPACKET = 1 << 1;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+format_flags!(SocketFeature);
+
+impl DeviceFeatures for SocketFeature {}
+
+virtio_config! {
+    pub(super) struct VsockConfig {
+        pub(super) guest_cid: ReadOnly<u64> @ 0x0,
+    }
+}
+
+/// The only socket type the driver supports.
+pub(super) const TYPE_STREAM: u16 = 1;
+
+/// The event the device sends when the guest CID changed, e.g. after live migration, and every
+/// connection was reset.
+pub(super) const EVENT_TRANSPORT_RESET: u32 = 0;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u16)]
+pub(super) enum Op {
+    Request = 1,
+    Response = 2,
+    Rst = 3,
+    Shutdown = 4,
+    Rw = 5,
+    CreditUpdate = 6,
+    CreditRequest = 7,
+}
+
+impl TryFrom<u16> for Op {
+    type Error = u16;
+
+    fn try_from(op: u16) -> Result<Self, u16> {
+        Ok(match op {
+            1 => Self::Request,
+            2 => Self::Response,
+            3 => Self::Rst,
+            4 => Self::Shutdown,
+            5 => Self::Rw,
+            6 => Self::CreditUpdate,
+            7 => Self::CreditRequest,
+            _ => return Err(op),
+        })
+    }
+}
+
+bitflags! {
+    /// The directions a `SHUTDOWN` packet closes.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub(super) struct ShutdownFlags: u32 {
+        /// The sender will receive no more data.
+        const RECEIVE = 1 << 0;
+        /// The sender will send no more data.
+        const SEND    = 1 << 1;
+    }
+}
+
+/// The header of every packet on the receive and transmit queues.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(super) struct VsockHdr {
+    pub(super) src_cid: u64,
+    pub(super) dst_cid: u64,
+    pub(super) src_port: u32,
+    pub(super) dst_port: u32,
+    /// The length of the payload following the header.
+    pub(super) len: u32,
+    pub(super) type_: u16,
+    pub(super) op: u16,
+    pub(super) flags: u32,
+    /// The size of the sender's receive buffer for the connection.
+    pub(super) buf_alloc: u32,
+    /// How many bytes the sender has consumed from its receive buffer in total.
+    pub(super) fwd_cnt: u32,
+}
+
+/// The size of [`VsockHdr`] on the queues, which has no padding.
+pub(super) const HDR_SIZE: usize = 44;
+
+impl VsockHdr {
+    /// Writes the header in its little-endian wire format.
+    pub(super) fn write_to(&self, target: &mut [u8; HDR_SIZE]) {
+        target[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        target[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        target[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        target[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        target[24..28].copy_from_slice(&self.len.to_le_bytes());
+        target[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        target[30..32].copy_from_slice(&self.op.to_le_bytes());
+        target[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        target[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        target[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+    }
+
+    /// Reads a header from the start of `source`.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `source` is shorter than the header.
+    pub(super) fn read_from(source: &[u8]) -> VirtIoResult<Self> {
+        if source.len() < HDR_SIZE {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([source[i], source[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([source[i], source[i + 1], source[i + 2], source[i + 3]]);
+        let u64_at = |i: usize| u32_at(i) as u64 | (u32_at(i + 4) as u64) << 32;
+        Ok(Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            type_: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        })
+    }
+}
@@ -0,0 +1,683 @@
+//! Driver for the VirtIO sound device, which plays and captures PCM audio.
+
+mod ty;
+
+use crate::device::{DeviceIdentity, VirtIoDriver};
+use crate::endian::Le32;
+use crate::error::{expect_ok, InitStep, VirtIoError, VirtIoResult};
+use crate::hal::{DmaBuf, Hal, MemoryRequirements};
+use crate::queue::{EventSuppression, QueueInfo, QueueStats, VirtIoQueue};
+use crate::transport::{DeviceType, InterruptStatus, Transport};
+use crate::volatile::ReadVolatile;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use ty::*;
+
+pub use ty::{PcmFeatures, SoundFeatures, MAX_CHANNELS};
+
+const QUEUE_CONTROL: u16 = 0;
+const QUEUE_EVENT: u16 = 1;
+const QUEUE_TX: u16 = 2;
+const QUEUE_RX: u16 = 3;
+/// The most descriptors each queue has, which a [`Hal`] for the driver is generic over.
+pub const QUEUE_SIZE: usize = 16;
+/// The number of buffers kept on the event queue.
+const EVENT_BUFFERS: usize = 4;
+/// The features [`VirtIOSound::new`] negotiates if the device offers them.
+pub const SUPPORTED_FEATURES: SoundFeatures = SoundFeatures::empty();
+
+/// A sound card, with jacks, PCM streams and the channel maps of the streams.
+///
+/// The jacks, streams and channel maps are numbered from 0 up to the counts returned by
+/// [`Self::jacks`], [`Self::streams`] and [`Self::chmaps`], and can be queried with
+/// [`Self::jack_info`], [`Self::pcm_info`] and [`Self::chmap_info`].
+///
+/// A stream is played or captured by setting its parameters with [`Self::pcm_set_params`],
+/// preparing it with [`Self::pcm_prepare`] and starting it with [`Self::pcm_start`], then
+/// transferring a period of frames at a time with [`Self::pcm_write`] or [`Self::pcm_read`].
+pub struct VirtIOSound<H: Hal<QUEUE_SIZE>, T: Transport> {
+    transport: T,
+    negotiated_features: SoundFeatures,
+    control_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    event_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    tx_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    rx_queue: VirtIoQueue<H, QUEUE_SIZE>,
+    /// Copied from the queues when they were created, for [`Self::queues`].
+    queue_info: Vec<QueueInfo>,
+    event_buf: Box<[RawEvent; EVENT_BUFFERS]>,
+    jacks: u32,
+    streams: u32,
+    chmaps: u32,
+}
+
+/// An error status from the sound device, returned as [`VirtIoError::SoundDeviceError`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SoundError {
+    /// The request was malformed, or isn't valid in the stream's current state.
+    BadMessage,
+    /// The request or its parameters aren't supported.
+    NotSupported,
+    /// The device failed to carry out the request.
+    IoError,
+    /// The device returned a status code the driver doesn't know.
+    UnknownStatus(u32),
+}
+
+impl SoundError {
+    /// Checks the status code of a response.
+    fn check(status: u32) -> Result<(), Self> {
+        Err(match status {
+            S_OK => return Ok(()),
+            S_BAD_MSG => Self::BadMessage,
+            S_NOT_SUPP => Self::NotSupported,
+            S_IO_ERR => Self::IoError,
+            status => Self::UnknownStatus(status),
+        })
+    }
+}
+
+/// Whether a stream or channel map is for playback or capture.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    /// Playback, with frames from the driver on the tx queue.
+    Output,
+    /// Capture, with frames from the device on the rx queue.
+    Input,
+}
+
+impl Direction {
+    fn from_raw(direction: u8) -> VirtIoResult<Self> {
+        match direction {
+            0 => Ok(Self::Output),
+            1 => Ok(Self::Input),
+            direction => {
+                warn!("Invalid sound direction {}", direction);
+                Err(VirtIoError::IoError)
+            }
+        }
+    }
+}
+
+/// A sample format, see [`PcmInfo::supports_format`].
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[allow(missing_docs)]
+pub enum PcmFormat {
+    ImaAdpcm = 0,
+    MuLaw,
+    ALaw,
+    S8,
+    U8,
+    S16,
+    U16,
+    S18_3,
+    U18_3,
+    S20_3,
+    U20_3,
+    S24_3,
+    U24_3,
+    S20,
+    U20,
+    S24,
+    U24,
+    S32,
+    U32,
+    Float,
+    Float64,
+    DsdU8,
+    DsdU16,
+    DsdU32,
+    Iec958Subframe,
+}
+
+/// A frame rate, see [`PcmInfo::supports_rate`].
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[allow(missing_docs)]
+pub enum PcmRate {
+    Rate5512 = 0,
+    Rate8000,
+    Rate11025,
+    Rate16000,
+    Rate22050,
+    Rate32000,
+    Rate44100,
+    Rate48000,
+    Rate64000,
+    Rate88200,
+    Rate96000,
+    Rate176400,
+    Rate192000,
+    Rate384000,
+}
+
+/// The information about a jack, returned by [`VirtIOSound::jack_info`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JackInfo {
+    /// The HDA function group the jack belongs to.
+    pub hda_fn_nid: u32,
+    /// Optional features of the jack, of which none are defined yet.
+    pub features: u32,
+    /// The HDA pin configuration default, describing e.g. the jack's location and color.
+    pub hda_reg_defconf: u32,
+    /// The HDA pin capabilities.
+    pub hda_reg_caps: u32,
+    /// Whether something is plugged in.
+    pub connected: bool,
+}
+
+/// The information about a PCM stream, returned by [`VirtIOSound::pcm_info`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PcmInfo {
+    /// The HDA function group the stream belongs to.
+    pub hda_fn_nid: u32,
+    pub features: PcmFeatures,
+    /// The supported sample formats, one bit per [`PcmFormat`].
+    pub formats: u64,
+    /// The supported frame rates, one bit per [`PcmRate`].
+    pub rates: u64,
+    pub direction: Direction,
+    pub channels_min: u8,
+    pub channels_max: u8,
+}
+
+impl PcmInfo {
+    /// Whether the stream supports the sample format.
+    pub fn supports_format(&self, format: PcmFormat) -> bool {
+        self.formats & (1 << format as u8) != 0
+    }
+
+    /// Whether the stream supports the frame rate.
+    pub fn supports_rate(&self, rate: PcmRate) -> bool {
+        self.rates & (1 << rate as u8) != 0
+    }
+}
+
+/// The information about a channel map, returned by [`VirtIOSound::chmap_info`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChmapInfo {
+    /// The HDA function group the channel map belongs to.
+    pub hda_fn_nid: u32,
+    /// Whether the channel map is for output or input streams.
+    pub direction: Direction,
+    channels: u8,
+    positions: [u8; MAX_CHANNELS],
+}
+
+impl ChmapInfo {
+    /// Returns the position of each channel, e.g. 3 for front left and 4 for front right.
+    pub fn positions(&self) -> &[u8] {
+        &self.positions[..usize::from(self.channels).min(MAX_CHANNELS)]
+    }
+}
+
+/// The parameters of a PCM stream, set with [`VirtIOSound::pcm_set_params`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PcmParameters {
+    /// The size of the stream's buffer on the host, a multiple of `period_bytes`.
+    pub buffer_bytes: u32,
+    /// The size of a period, the unit in which frames are transferred.
+    pub period_bytes: u32,
+    /// The optional features to use, which must be among those of [`PcmInfo::features`].
+    pub features: PcmFeatures,
+    pub channels: u8,
+    pub format: PcmFormat,
+    pub rate: PcmRate,
+}
+
+/// Something the device reported on the event queue, returned by [`VirtIOSound::poll_event`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SoundEvent {
+    /// Something was plugged into the jack.
+    JackConnected { jack_id: u32 },
+    /// Something was unplugged from the jack.
+    JackDisconnected { jack_id: u32 },
+    /// The host consumed or produced a period of the stream.
+    PcmPeriodElapsed { stream_id: u32 },
+    /// The stream underran or overran, because frames weren't transferred in time.
+    PcmXrun { stream_id: u32 },
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIOSound<H, T> {
+    /// The memory [`Self::new`] allocates: the control, event, tx and rx queues, and the buffers
+    /// kept on the event queue.
+    pub const fn memory_requirements() -> MemoryRequirements {
+        MemoryRequirements::queues::<QUEUE_SIZE>(4)
+            .with_shared_heap(EVENT_BUFFERS * size_of::<RawEvent>())
+    }
+
+    /// Create a new VirtIO sound driver.
+    pub fn new(transport: T) -> VirtIoResult<Self> {
+        Self::new_with_features(transport, SUPPORTED_FEATURES)
+    }
+
+    /// Creates a new VirtIO sound driver which only negotiates the features in `wanted`, those of
+    /// them the device offers.
+    ///
+    /// Returns [`VirtIoError::InvalidParam`] if `wanted` isn't a subset of
+    /// [`SUPPORTED_FEATURES`], which is empty for now.
+    pub fn new_with_features(mut transport: T, wanted: SoundFeatures) -> VirtIoResult<Self> {
+        if !SUPPORTED_FEATURES.contains(wanted) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let negotiated_features =
+            transport.init_step(InitStep::Negotiation, |t| t.begin_init(wanted))?;
+        let (jacks, streams, chmaps) = transport.init_step(InitStep::ReadConfig, |t| {
+            t.check_config_space(size_of::<u32>() * 3)?;
+            let config = SoundConfig::default();
+            let io_region = t.io_region();
+            let jacks = config.jacks.read(io_region)?;
+            let streams = config.streams.read(io_region)?;
+            let chmaps = config.chmaps.read(io_region)?;
+            info!(
+                "sound device with {} jacks, {} streams and {} channel maps",
+                jacks, streams, chmaps
+            );
+            Ok((jacks, streams, chmaps))
+        })?;
+        let mut event_buf = Box::new([RawEvent::default(); EVENT_BUFFERS]);
+        let (control_queue, mut event_queue, tx_queue, rx_queue) =
+            transport.init_step(InitStep::QueueSetup, |t| {
+                Ok((
+                    VirtIoQueue::new(t, QUEUE_CONTROL)?,
+                    VirtIoQueue::new(t, QUEUE_EVENT)?,
+                    VirtIoQueue::new(t, QUEUE_TX)?,
+                    VirtIoQueue::new(t, QUEUE_RX)?,
+                ))
+            })?;
+        transport.init_step(InitStep::InitialBuffers, |_| {
+            // Only as many buffers as the queue turned out to have room for.
+            let events = usize::from(event_queue.size());
+            for (i, event) in event_buf.iter_mut().enumerate().take(events) {
+                let token = event_queue.add(vec![DmaBuf::writable(event)])?;
+                if token != i as u16 {
+                    return Err(VirtIoError::WrongToken);
+                }
+            }
+            Ok(())
+        })?;
+        // Buffers may be added early, but notifications have to wait for DRIVER_OK.
+        transport.init_step(InitStep::DriverOk, T::finish_init)?;
+        transport.init_step(InitStep::InitialBuffers, |t| {
+            if event_queue.should_notify() {
+                t.notify(QUEUE_EVENT)?;
+            }
+            Ok(())
+        })?;
+
+        let queue_info = vec![
+            control_queue.info(),
+            event_queue.info(),
+            tx_queue.info(),
+            rx_queue.info(),
+        ];
+        Ok(Self {
+            transport,
+            negotiated_features,
+            control_queue,
+            event_queue,
+            tx_queue,
+            rx_queue,
+            queue_info,
+            event_buf,
+            jacks,
+            streams,
+            chmaps,
+        })
+    }
+
+    /// Acknowledges an interrupt, returning its causes, which are empty if there was none.
+    pub fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        self.transport.ack_interrupt()
+    }
+
+    /// Like [`Self::ack_interrupt`], but only checks the interrupt status without acknowledging
+    /// it, for polling the device before its interrupt is routed.
+    pub fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        Ok(self
+            .transport
+            .peek_interrupt_status()?
+            .contains(InterruptStatus::USED_RING_UPDATE))
+    }
+
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> SoundFeatures {
+        self.negotiated_features
+    }
+
+    /// Returns the transport, e.g. to read the device status from a power management hook.
+    ///
+    /// Only shared access is given, so the device can't be reconfigured behind the driver's back.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the index and location of each virtqueue used by the driver.
+    pub fn queues(&self) -> &[QueueInfo] {
+        &self.queue_info
+    }
+
+    /// Returns the number of jacks.
+    pub fn jacks(&self) -> u32 {
+        self.jacks
+    }
+
+    /// Returns the number of PCM streams.
+    pub fn streams(&self) -> u32 {
+        self.streams
+    }
+
+    /// Returns the number of channel maps.
+    pub fn chmaps(&self) -> u32 {
+        self.chmaps
+    }
+
+    /// Returns the information about `count` jacks, starting at `start_id`.
+    pub fn jack_info(&mut self, start_id: u32, count: u32) -> VirtIoResult<Vec<JackInfo>> {
+        let infos: Vec<RawJackInfo> = self.query_info(R_JACK_INFO, self.jacks, start_id, count)?;
+        Ok(infos
+            .iter()
+            .map(|info| JackInfo {
+                hda_fn_nid: info.hda_fn_nid.get(),
+                features: info.features.get(),
+                hda_reg_defconf: info.hda_reg_defconf.get(),
+                hda_reg_caps: info.hda_reg_caps.get(),
+                connected: info.connected != 0,
+            })
+            .collect())
+    }
+
+    /// Returns the information about `count` PCM streams, starting at `start_id`.
+    pub fn pcm_info(&mut self, start_id: u32, count: u32) -> VirtIoResult<Vec<PcmInfo>> {
+        let infos: Vec<RawPcmInfo> = self.query_info(R_PCM_INFO, self.streams, start_id, count)?;
+        infos
+            .iter()
+            .map(|info| {
+                Ok(PcmInfo {
+                    hda_fn_nid: info.hda_fn_nid.get(),
+                    features: PcmFeatures::from_bits_retain(info.features.get()),
+                    formats: info.formats.get(),
+                    rates: info.rates.get(),
+                    direction: Direction::from_raw(info.direction)?,
+                    channels_min: info.channels_min,
+                    channels_max: info.channels_max,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the information about `count` channel maps, starting at `start_id`.
+    pub fn chmap_info(&mut self, start_id: u32, count: u32) -> VirtIoResult<Vec<ChmapInfo>> {
+        let infos: Vec<RawChmapInfo> =
+            self.query_info(R_CHMAP_INFO, self.chmaps, start_id, count)?;
+        infos
+            .iter()
+            .map(|info| {
+                Ok(ChmapInfo {
+                    hda_fn_nid: info.hda_fn_nid.get(),
+                    direction: Direction::from_raw(info.direction)?,
+                    channels: info.channels,
+                    positions: info.positions,
+                })
+            })
+            .collect()
+    }
+
+    /// Queries the information about `count` items of the kind `code` is for, of which there are
+    /// `total`, starting at `start_id`.
+    fn query_info<I: Copy + Default>(
+        &mut self,
+        code: u32,
+        total: u32,
+        start_id: u32,
+        count: u32,
+    ) -> VirtIoResult<Vec<I>> {
+        if count == 0 || start_id.checked_add(count).is_none_or(|end| end > total) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let request = QueryInfo::new(code, start_id, count, size_of::<I>());
+        let mut response = CtrlHdr::response();
+        let mut infos = vec![I::default(); count as usize];
+        self.control_queue.add_notify_wait_pop(
+            &mut self.transport,
+            vec![
+                DmaBuf::readable(&request),
+                DmaBuf::writable(&mut response),
+                DmaBuf::writable(&mut infos[..]),
+            ],
+        )?;
+        SoundError::check(response.code.get())?;
+        Ok(infos)
+    }
+
+    /// Sets the parameters of a stream, which must not be started.
+    pub fn pcm_set_params(&mut self, stream_id: u32, params: PcmParameters) -> VirtIoResult<()> {
+        if params.period_bytes == 0 || !params.buffer_bytes.is_multiple_of(params.period_bytes) {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let request = PcmSetParams {
+            hdr: PcmHdr::new(R_PCM_SET_PARAMS, stream_id),
+            buffer_bytes: Le32::new(params.buffer_bytes),
+            period_bytes: Le32::new(params.period_bytes),
+            features: Le32::new(params.features.bits()),
+            channels: params.channels,
+            format: params.format as u8,
+            rate: params.rate as u8,
+            padding: 0,
+        };
+        self.control_request(stream_id, &request)
+    }
+
+    /// Prepares a stream whose parameters are set, allocating its resources on the host.
+    pub fn pcm_prepare(&mut self, stream_id: u32) -> VirtIoResult<()> {
+        self.control_request(stream_id, &PcmHdr::new(R_PCM_PREPARE, stream_id))
+    }
+
+    /// Releases the resources of a stopped or prepared stream.
+    ///
+    /// The host returns any transfers still queued for the stream before completing this.
+    pub fn pcm_release(&mut self, stream_id: u32) -> VirtIoResult<()> {
+        self.control_request(stream_id, &PcmHdr::new(R_PCM_RELEASE, stream_id))
+    }
+
+    /// Starts a prepared stream.
+    ///
+    /// For playback, the first period should be transferred before starting, so the host doesn't
+    /// underrun straight away.
+    pub fn pcm_start(&mut self, stream_id: u32) -> VirtIoResult<()> {
+        self.control_request(stream_id, &PcmHdr::new(R_PCM_START, stream_id))
+    }
+
+    /// Stops a started stream.
+    pub fn pcm_stop(&mut self, stream_id: u32) -> VirtIoResult<()> {
+        self.control_request(stream_id, &PcmHdr::new(R_PCM_STOP, stream_id))
+    }
+
+    /// Sends a request about a stream which is answered with just a status.
+    fn control_request<R>(&mut self, stream_id: u32, request: &R) -> VirtIoResult<()> {
+        if stream_id >= self.streams {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let mut response = CtrlHdr::response();
+        self.control_queue.add_notify_wait_pop(
+            &mut self.transport,
+            vec![DmaBuf::readable(request), DmaBuf::writable(&mut response)],
+        )?;
+        SoundError::check(response.code.get())?;
+        Ok(())
+    }
+
+    /// Sends `frames` to an output stream, and waits for the host to consume them, returning how
+    /// many bytes it still has queued to play.
+    ///
+    /// The host only completes a transfer once it has played it, so writing a period at a time
+    /// paces playback.
+    pub fn pcm_write(&mut self, stream_id: u32, frames: &[u8]) -> VirtIoResult<u32> {
+        if stream_id >= self.streams || frames.is_empty() {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let xfer = PcmXfer {
+            stream_id: Le32::new(stream_id),
+        };
+        let mut status = PcmStatus::default();
+        self.tx_queue.add_notify_wait_pop(
+            &mut self.transport,
+            vec![
+                DmaBuf::readable(&xfer),
+                DmaBuf::readable(frames),
+                DmaBuf::writable(&mut status),
+            ],
+        )?;
+        SoundError::check(status.status.get())?;
+        Ok(status.latency_bytes.get())
+    }
+
+    /// Waits for the host to capture frames of an input stream into `frames`, returning how
+    /// many bytes it wrote.
+    pub fn pcm_read(&mut self, stream_id: u32, frames: &mut [u8]) -> VirtIoResult<usize> {
+        if stream_id >= self.streams || frames.is_empty() {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let xfer = PcmXfer {
+            stream_id: Le32::new(stream_id),
+        };
+        let mut status = PcmStatus::default();
+        let len = frames.len();
+        let used = self.rx_queue.add_notify_wait_pop(
+            &mut self.transport,
+            vec![
+                DmaBuf::readable(&xfer),
+                DmaBuf::writable(frames),
+                DmaBuf::writable(&mut status),
+            ],
+        )?;
+        SoundError::check(status.status.get())?;
+        // The used length covers the status too.
+        Ok((used as usize)
+            .saturating_sub(size_of::<PcmStatus>())
+            .min(len))
+    }
+
+    /// Returns the next event from the device, if there is one.
+    pub fn poll_event(&mut self) -> VirtIoResult<Option<SoundEvent>> {
+        while let Some(token) = self.event_queue.peek_used() {
+            self.event_queue.pop_used(token)?;
+            let raw = self.event_buf[usize::from(token)];
+            let buffer = DmaBuf::writable(&mut self.event_buf[usize::from(token)]);
+            let new_token = self.event_queue.add(vec![buffer])?;
+            if new_token != token {
+                return Err(VirtIoError::WrongToken);
+            }
+            if self.event_queue.should_notify() {
+                self.transport.notify(QUEUE_EVENT)?;
+            }
+            if let Some(event) = Self::decode_event(&raw) {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decodes an event, or returns `None` for one the driver doesn't know.
+    fn decode_event(raw: &RawEvent) -> Option<SoundEvent> {
+        let data = raw.data.get();
+        match raw.code.get() {
+            EVT_JACK_CONNECTED => Some(SoundEvent::JackConnected { jack_id: data }),
+            EVT_JACK_DISCONNECTED => Some(SoundEvent::JackDisconnected { jack_id: data }),
+            EVT_PCM_PERIOD_ELAPSED => Some(SoundEvent::PcmPeriodElapsed { stream_id: data }),
+            EVT_PCM_XRUN => Some(SoundEvent::PcmXrun { stream_id: data }),
+            code => {
+                warn!("Ignoring unknown sound event {:#x}", code);
+                None
+            }
+        }
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> VirtIoDriver for VirtIOSound<H, T> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Sound
+    }
+
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        VirtIOSound::ack_interrupt(self)
+    }
+
+    fn poll_interrupt(&mut self) -> VirtIoResult<bool> {
+        VirtIOSound::poll_interrupt(self)
+    }
+
+    fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            device_type: DeviceType::Sound,
+            bus_addr: self.transport.io_region().paddr(),
+            features: self.negotiated_features.bits(),
+        }
+    }
+
+    fn stats(&self) -> Vec<QueueStats> {
+        vec![
+            self.control_queue.stats(),
+            self.event_queue.stats(),
+            self.tx_queue.stats(),
+            self.rx_queue.stats(),
+        ]
+    }
+
+    fn set_event_suppression(
+        &mut self,
+        queue: u16,
+        suppression: EventSuppression,
+    ) -> VirtIoResult<()> {
+        match queue {
+            QUEUE_CONTROL => self.control_queue.set_event_suppression(suppression),
+            QUEUE_EVENT => self.event_queue.set_event_suppression(suppression),
+            QUEUE_TX => self.tx_queue.set_event_suppression(suppression),
+            QUEUE_RX => self.rx_queue.set_event_suppression(suppression),
+            _ => return Err(VirtIoError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        self.transport.check_needs_reset()
+    }
+
+    fn shutdown(&mut self) -> VirtIoResult<()> {
+        self.transport.reset()
+    }
+}
+
+impl<H: Hal<QUEUE_SIZE>, T: Transport> Drop for VirtIOSound<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        expect_ok(
+            self.transport.queue_unset(QUEUE_CONTROL),
+            "failed to unset control queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_EVENT),
+            "failed to unset event queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_TX),
+            "failed to unset tx queue",
+        );
+        expect_ok(
+            self.transport.queue_unset(QUEUE_RX),
+            "failed to unset rx queue",
+        );
+    }
+}
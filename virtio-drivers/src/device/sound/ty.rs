@@ -0,0 +1,228 @@
+use crate::endian::{Le32, Le64};
+use crate::transport::DeviceFeatures;
+use crate::volatile::virtio_config;
+use bitflags::bitflags;
+
+virtio_config! {
+    pub struct SoundConfig {
+        /// The number of jacks.
+        pub(super) jacks: ReadOnly<u32> @ 0x0,
+        /// The number of PCM streams.
+        pub(super) streams: ReadOnly<u32> @ 0x4,
+        /// The number of channel maps.
+        pub(super) chmaps: ReadOnly<u32> @ 0x8,
+    }
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct SoundFeatures: u64 {
+        /// The device has control elements, such as volume controls.
+        const CTLS                  = 1 << 0;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+format_flags!(SoundFeatures);
+
+impl DeviceFeatures for SoundFeatures {}
+
+bitflags! {
+    /// Optional features of a PCM stream, see [`PcmInfo::features`](super::PcmInfo::features).
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct PcmFeatures: u32 {
+        /// Frames can be transferred through memory shared by the host.
+        const SHMEM_HOST            = 1 << 0;
+        /// Frames can be transferred through memory shared by the guest.
+        const SHMEM_GUEST           = 1 << 1;
+        /// The device can poll the queues instead of being notified.
+        const MSG_POLLING           = 1 << 2;
+        /// The device sends period elapsed events with shared memory.
+        const EVT_SHMEM_PERIODS     = 1 << 3;
+        /// The device sends xrun events.
+        const EVT_XRUNS             = 1 << 4;
+    }
+}
+
+format_flags!(PcmFeatures);
+
+/// Request codes on the control queue.
+pub(crate) const R_JACK_INFO: u32 = 1;
+pub(crate) const R_PCM_INFO: u32 = 0x0100;
+pub(crate) const R_PCM_SET_PARAMS: u32 = 0x0101;
+pub(crate) const R_PCM_PREPARE: u32 = 0x0102;
+pub(crate) const R_PCM_RELEASE: u32 = 0x0103;
+pub(crate) const R_PCM_START: u32 = 0x0104;
+pub(crate) const R_PCM_STOP: u32 = 0x0105;
+pub(crate) const R_CHMAP_INFO: u32 = 0x0200;
+
+/// Event codes on the event queue.
+pub(crate) const EVT_JACK_CONNECTED: u32 = 0x1000;
+pub(crate) const EVT_JACK_DISCONNECTED: u32 = 0x1001;
+pub(crate) const EVT_PCM_PERIOD_ELAPSED: u32 = 0x1100;
+pub(crate) const EVT_PCM_XRUN: u32 = 0x1101;
+
+/// Status codes of responses.
+pub(crate) const S_OK: u32 = 0x8000;
+pub(crate) const S_BAD_MSG: u32 = 0x8001;
+pub(crate) const S_NOT_SUPP: u32 = 0x8002;
+pub(crate) const S_IO_ERR: u32 = 0x8003;
+
+/// The header of every control request, and the response to one.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct CtrlHdr {
+    pub(crate) code: Le32,
+}
+
+impl CtrlHdr {
+    /// A response the device hasn't filled in yet.
+    pub(crate) fn response() -> Self {
+        Self {
+            code: Le32::new(S_IO_ERR),
+        }
+    }
+}
+
+/// A request for the information about `count` items starting at `start_id`, which the device
+/// writes after the response header.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct QueryInfo {
+    hdr: CtrlHdr,
+    start_id: Le32,
+    count: Le32,
+    size: Le32,
+}
+
+impl QueryInfo {
+    pub(crate) fn new(code: u32, start_id: u32, count: u32, size: usize) -> Self {
+        Self {
+            hdr: CtrlHdr {
+                code: Le32::new(code),
+            },
+            start_id: Le32::new(start_id),
+            count: Le32::new(count),
+            size: Le32::new(size as u32),
+        }
+    }
+}
+
+/// The information about a jack.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RawJackInfo {
+    pub(crate) hda_fn_nid: Le32,
+    pub(crate) features: Le32,
+    pub(crate) hda_reg_defconf: Le32,
+    pub(crate) hda_reg_caps: Le32,
+    pub(crate) connected: u8,
+    padding: [u8; 7],
+}
+
+/// The information about a PCM stream.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RawPcmInfo {
+    pub(crate) hda_fn_nid: Le32,
+    pub(crate) features: Le32,
+    pub(crate) formats: Le64,
+    pub(crate) rates: Le64,
+    pub(crate) direction: u8,
+    pub(crate) channels_min: u8,
+    pub(crate) channels_max: u8,
+    padding: [u8; 5],
+}
+
+/// The most channels a channel map describes.
+pub const MAX_CHANNELS: usize = 18;
+
+/// The information about a channel map.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RawChmapInfo {
+    pub(crate) hda_fn_nid: Le32,
+    pub(crate) direction: u8,
+    pub(crate) channels: u8,
+    pub(crate) positions: [u8; MAX_CHANNELS],
+}
+
+/// A request about one PCM stream, without any other fields.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct PcmHdr {
+    hdr: CtrlHdr,
+    stream_id: Le32,
+}
+
+impl PcmHdr {
+    pub(crate) fn new(code: u32, stream_id: u32) -> Self {
+        Self {
+            hdr: CtrlHdr {
+                code: Le32::new(code),
+            },
+            stream_id: Le32::new(stream_id),
+        }
+    }
+}
+
+/// A request setting the parameters of a PCM stream.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct PcmSetParams {
+    pub(crate) hdr: PcmHdr,
+    pub(crate) buffer_bytes: Le32,
+    pub(crate) period_bytes: Le32,
+    pub(crate) features: Le32,
+    pub(crate) channels: u8,
+    pub(crate) format: u8,
+    pub(crate) rate: u8,
+    pub(crate) padding: u8,
+}
+
+/// The header of a transfer on the tx or rx queue, followed by the frames.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct PcmXfer {
+    pub(crate) stream_id: Le32,
+}
+
+/// The status of a transfer, which the device writes after the frames.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct PcmStatus {
+    pub(crate) status: Le32,
+    pub(crate) latency_bytes: Le32,
+}
+
+impl Default for PcmStatus {
+    fn default() -> Self {
+        Self {
+            status: Le32::new(S_IO_ERR),
+            latency_bytes: Le32::new(0),
+        }
+    }
+}
+
+/// An event as the device writes it into a buffer of the event queue.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RawEvent {
+    pub(crate) code: Le32,
+    pub(crate) data: Le32,
+}
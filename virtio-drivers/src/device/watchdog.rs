@@ -0,0 +1,191 @@
+//! A watchdog which notices devices that stopped completing requests.
+//!
+//! A device which never completes a request usually hangs whoever is waiting for it, without any
+//! indication of why. The [`Watchdog`] is ticked periodically, e.g. from the kernel timer, and
+//! reports queues which had requests in flight but completed none of them for longer than a
+//! deadline, optionally resetting the device so the kernel can recover by probing it again.
+
+use super::{DeviceIdentity, VirtIoDriver};
+use crate::error::{VirtIoError, VirtIoResult};
+use crate::PhysAddr;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// What the watchdog does when it finds a stuck queue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StuckPolicy {
+    /// Only log and report the queue.
+    Report,
+    /// Also reset the device with [`VirtIoDriver::shutdown`].
+    ///
+    /// The driver can't be used afterwards, so the caller should drop it and probe the device
+    /// again, then [watch](Watchdog::watch) the new driver.
+    Reset,
+}
+
+/// A queue which the watchdog found stuck, returned by [`Watchdog::tick`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StuckQueue {
+    pub device: DeviceIdentity,
+    pub queue: u16,
+    /// How many requests were in flight on the queue.
+    pub in_flight: usize,
+    /// How long the queue went without completing anything, in the units passed to
+    /// [`Watchdog::tick`].
+    pub stalled_for: u64,
+    /// Whether the device asked to be reset, which is why it stopped completing requests.
+    pub needs_reset: bool,
+    /// The result of resetting the device, if [`StuckPolicy::Reset`] was used.
+    pub reset: Option<VirtIoResult<()>>,
+}
+
+/// The progress of a queue when it was last seen making any.
+struct Progress {
+    completions: u64,
+    since: u64,
+    /// Whether the queue was already reported as stuck, so it isn't reported on every tick.
+    reported: bool,
+}
+
+struct Watched {
+    deadline: u64,
+    policy: StuckPolicy,
+    queues: BTreeMap<u16, Progress>,
+    /// Whether the device was reset, after which it is no longer checked.
+    reset: bool,
+}
+
+/// Watches drivers for queues which stopped completing requests.
+///
+/// Drivers are identified by the bus address of their device, so the watchdog doesn't need to
+/// own them; they are passed to [`Self::tick`] like to [`shutdown_all`](super::shutdown_all).
+/// Time is whatever monotonic counter the caller ticks with, e.g. timer interrupts or
+/// milliseconds, as long as the deadlines are in the same units.
+pub struct Watchdog {
+    watched: BTreeMap<PhysAddr, Watched>,
+}
+
+impl Watchdog {
+    /// Creates a watchdog which isn't watching any drivers.
+    pub const fn new() -> Self {
+        Self {
+            watched: BTreeMap::new(),
+        }
+    }
+
+    /// Starts watching a driver, reporting its queues if they have requests in flight but
+    /// complete none for `deadline`.
+    ///
+    /// Watching a driver for a device which was already watched, e.g. after it was reset and
+    /// probed again, starts over with the new deadline and policy.
+    pub fn watch(&mut self, driver: &dyn VirtIoDriver, deadline: u64, policy: StuckPolicy) {
+        self.watched.insert(
+            driver.identity().bus_addr,
+            Watched {
+                deadline,
+                policy,
+                queues: BTreeMap::new(),
+                reset: false,
+            },
+        );
+    }
+
+    /// Stops watching the driver for the device at the given bus address, e.g. before removing
+    /// it.
+    pub fn unwatch(&mut self, bus_addr: PhysAddr) {
+        self.watched.remove(&bus_addr);
+    }
+
+    /// Checks the queues of the given drivers at time `now`, and returns those which are newly
+    /// stuck.
+    ///
+    /// Each stuck queue is logged together with the driver's [state](VirtIoDriver::dump_state),
+    /// and its device reset if the driver is watched with [`StuckPolicy::Reset`]. Drivers which
+    /// aren't watched are ignored. A queue is only reported again after it made progress.
+    pub fn tick<'a>(
+        &mut self,
+        now: u64,
+        drivers: impl IntoIterator<Item = &'a mut dyn VirtIoDriver>,
+    ) -> Vec<StuckQueue> {
+        let mut stuck = Vec::new();
+        for driver in drivers {
+            let device = driver.identity();
+            let Some(watched) = self.watched.get_mut(&device.bus_addr) else {
+                continue;
+            };
+            if watched.reset {
+                continue;
+            }
+            let first = stuck.len();
+            for stats in driver.stats() {
+                let progress = watched.queues.entry(stats.index).or_insert(Progress {
+                    completions: stats.completions,
+                    since: now,
+                    reported: false,
+                });
+                if stats.in_flight == 0 || stats.completions != progress.completions {
+                    *progress = Progress {
+                        completions: stats.completions,
+                        since: now,
+                        reported: false,
+                    };
+                    continue;
+                }
+                let stalled_for = now.saturating_sub(progress.since);
+                if stalled_for >= watched.deadline && !progress.reported {
+                    progress.reported = true;
+                    stuck.push(StuckQueue {
+                        device,
+                        queue: stats.index,
+                        in_flight: stats.in_flight,
+                        stalled_for,
+                        needs_reset: false,
+                        reset: None,
+                    });
+                }
+            }
+            if stuck.len() == first {
+                continue;
+            }
+            let needs_reset = driver.check_needs_reset() == Err(VirtIoError::NeedsReset);
+            let mut dump = String::new();
+            // Writing to a string can't fail.
+            let _ = driver.dump_state(&mut dump);
+            for queue in stuck.iter_mut().skip(first) {
+                queue.needs_reset = needs_reset;
+                error!(
+                    "{:?} at {:#x}: queue {} completed none of {} requests in {}",
+                    device.device_type,
+                    device.bus_addr,
+                    queue.queue,
+                    queue.in_flight,
+                    queue.stalled_for
+                );
+            }
+            if needs_reset {
+                error!("{:?} asked to be reset", device.device_type);
+            }
+            error!("{}", dump);
+            if watched.policy == StuckPolicy::Reset {
+                let result = driver.shutdown();
+                if let Err(e) = result {
+                    error!("failed to reset {:?}: {:?}", device.device_type, e);
+                }
+                watched.reset = true;
+                for queue in stuck.iter_mut().skip(first) {
+                    queue.reset = Some(result);
+                }
+            }
+        }
+        stuck
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,97 @@
+//! Little-endian integers for the structures shared with the device.
+//!
+//! Virtio 1.0 devices expect every field of the virtqueues and of the requests placed in them to
+//! be little-endian, whatever the endianness of the driver. Fields of these types can only be
+//! read and written through a conversion, so the drivers are also correct on big-endian targets.
+//!
+//! Legacy devices use the driver's native endianness instead, so they still only work on
+//! little-endian targets.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+macro_rules! le_int {
+    ($(#[$attr:meta])* $name:ident, $int:ty) => {
+        $(#[$attr])*
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Default, Eq, PartialEq)]
+        pub(crate) struct $name($int);
+
+        impl $name {
+            pub(crate) const fn new(value: $int) -> Self {
+                Self(value.to_le())
+            }
+
+            pub(crate) const fn get(self) -> $int {
+                <$int>::from_le(self.0)
+            }
+        }
+
+        impl From<$int> for $name {
+            fn from(value: $int) -> Self {
+                Self::new(value)
+            }
+        }
+
+        impl From<$name> for $int {
+            fn from(value: $name) -> Self {
+                value.get()
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Debug::fmt(&self.get(), f)
+            }
+        }
+
+        #[cfg(feature = "defmt")]
+        impl defmt::Format for $name {
+            fn format(&self, f: defmt::Formatter) {
+                self.get().format(f)
+            }
+        }
+    };
+}
+
+le_int!(
+    /// A `le16` field.
+    Le16,
+    u16
+);
+le_int!(
+    /// A `le32` field.
+    Le32,
+    u32
+);
+le_int!(
+    /// A `le64` field.
+    Le64,
+    u64
+);
+
+/// A `le16` field which the driver and device access concurrently, like the ring indices.
+#[repr(transparent)]
+#[derive(Default)]
+pub(crate) struct AtomicLe16(AtomicU16);
+
+impl AtomicLe16 {
+    #[cfg(feature = "heap-dma")]
+    pub(crate) const fn new(value: u16) -> Self {
+        Self(AtomicU16::new(value.to_le()))
+    }
+
+    pub(crate) fn load(&self, order: Ordering) -> u16 {
+        u16::from_le(self.0.load(order))
+    }
+
+    pub(crate) fn store(&self, value: u16, order: Ordering) {
+        self.0.store(value.to_le(), order)
+    }
+}
+
+impl fmt::Debug for AtomicLe16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.load(Ordering::Relaxed), f)
+    }
+}
@@ -5,7 +5,12 @@ use core::fmt::{Display, Formatter};
 pub type VirtIoResult<T> = Result<T, VirtIoError>;
 
 /// The error type of VirtIO drivers.
+///
+/// Some variants only exist with the feature of the driver which returns them, so matches must
+/// have a wildcard arm to build whichever drivers are enabled.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
 pub enum VirtIoError {
     /// There are not enough descriptors available in the virtqueue, try again later.
     QueueFull,
@@ -27,13 +32,70 @@ pub enum VirtIoError {
     ConfigSpaceTooSmall,
     /// The device doesn't have any config space, but the driver expects some.
     ConfigSpaceMissing,
+    /// The device cleared FEATURES_OK, so it doesn't support the negotiated set of features.
+    FeaturesNotAccepted,
+    /// Feature bit `feature` would have been negotiated without any of the features in the
+    /// `requires` mask, which the spec says it depends on. The device offered it without them.
+    MissingFeatureDependency {
+        feature: u32,
+        requires: u64,
+    },
+    /// The device set DEVICE_NEEDS_RESET, e.g. because its backend on the host restarted, so it
+    /// won't use any more buffers until the driver resets it.
+    NeedsReset,
     MmioError(MmioError),
-    // Error from the socket device.
-    // SocketDeviceError(device::socket::SocketError),
+    /// Error from the socket device.
+    #[cfg(feature = "socket")]
+    SocketDeviceError(crate::device::socket::SocketError),
+    /// Error from the SCSI device.
+    #[cfg(feature = "scsi")]
+    ScsiDeviceError(crate::device::scsi::ScsiError),
+    /// Error from the sound device.
+    #[cfg(feature = "sound")]
+    SoundDeviceError(crate::device::sound::SoundError),
+}
+
+/// Handles an error which can't be returned to the caller, such as one from `Drop`.
+///
+/// This panics with `msg`, unless the `no-panic` feature is enabled in which case the error is
+/// only logged.
+#[track_caller]
+pub(crate) fn expect_ok<T>(result: VirtIoResult<T>, msg: &str) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        #[cfg(not(feature = "no-panic"))]
+        Err(e) => panic!("{msg}: {e:?}"),
+        #[cfg(feature = "no-panic")]
+        Err(e) => {
+            error!("{}: {:?}", msg, e);
+            None
+        }
+    }
+}
+
+/// A step of a driver initializing its device, see [`InitMilestone::Failed`].
+///
+/// Ref: 3.1.1 Driver Requirements: Device Initialization
+///
+/// [`InitMilestone::Failed`]: crate::transport::InitMilestone::Failed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InitStep {
+    /// Resetting the device and negotiating features.
+    Negotiation,
+    /// Reading the device-specific configuration space.
+    ReadConfig,
+    /// Setting up the virtqueues.
+    QueueSetup,
+    /// Setting DRIVER_OK.
+    DriverOk,
+    /// Giving the device the buffers it needs from the start, e.g. for receiving.
+    InitialBuffers,
 }
 
 /// An error encountered initialising a VirtIO MMIO transport.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MmioError {
     /// The header doesn't start with the expected magic value 0x74726976.
     BadMagic(u32),
@@ -67,14 +129,43 @@ impl Display for VirtIoError {
                     "The device doesn't have any config space, but the driver expects some"
                 )
             }
+            Self::FeaturesNotAccepted => {
+                write!(f, "The device did not accept the negotiated features")
+            }
+            Self::MissingFeatureDependency { feature, requires } => write!(
+                f,
+                "Feature bit {feature} was offered without any of the features {requires:#x} it \
+                 depends on"
+            ),
+            Self::NeedsReset => write!(f, "Device needs to be reset"),
             Self::MmioError(e) => write!(f, "Error from MMIO transport: {e:?}"),
-            // Self::SocketDeviceError(e) => write!(f, "Error from the socket device: {e:?}"),
+            #[cfg(feature = "socket")]
+            Self::SocketDeviceError(e) => write!(f, "Error from the socket device: {e:?}"),
+            #[cfg(feature = "scsi")]
+            Self::ScsiDeviceError(e) => write!(f, "Error from the SCSI device: {e:?}"),
+            #[cfg(feature = "sound")]
+            Self::SoundDeviceError(e) => write!(f, "Error from the sound device: {e:?}"),
         }
     }
 }
 
-// impl From<device::socket::SocketError> for Error {
-//     fn from(e: device::socket::SocketError) -> Self {
-//         Self::SocketDeviceError(e)
-//     }
-// }
+#[cfg(feature = "socket")]
+impl From<crate::device::socket::SocketError> for VirtIoError {
+    fn from(e: crate::device::socket::SocketError) -> Self {
+        Self::SocketDeviceError(e)
+    }
+}
+
+#[cfg(feature = "scsi")]
+impl From<crate::device::scsi::ScsiError> for VirtIoError {
+    fn from(e: crate::device::scsi::ScsiError) -> Self {
+        Self::ScsiDeviceError(e)
+    }
+}
+
+#[cfg(feature = "sound")]
+impl From<crate::device::sound::SoundError> for VirtIoError {
+    fn from(e: crate::device::sound::SoundError) -> Self {
+        Self::SoundDeviceError(e)
+    }
+}
@@ -0,0 +1,39 @@
+//! Logging macros which go to `defmt` if the `defmt` feature is enabled, or to `log` otherwise.
+//!
+//! The module is declared first with `#[macro_use]`, so the macros are in scope in the whole
+//! crate without imports.
+//!
+//! Messages must use format strings both understand: positional `{}`, `{:?}` and integer hints
+//! like `{:#x}` or `{:02x}`, with arguments which implement `defmt::Format` as well as `Debug` or
+//! `Display`.
+
+/// Defines a macro for each of the given log levels. `$dollar` is a literal `$`, which nested
+/// macro definitions can't write directly.
+macro_rules! log_to {
+    ($dollar:tt $($level:ident)*) => {$(
+        #[allow(unused_macros)]
+        macro_rules! $level {
+            ($dollar($dollar arg:tt)*) => {{
+                #[cfg(feature = "defmt")]
+                ::defmt::$level!($dollar($dollar arg)*);
+                #[cfg(not(feature = "defmt"))]
+                ::log::$level!($dollar($dollar arg)*);
+            }};
+        }
+    )*};
+}
+
+log_to!($ trace debug info warn error);
+
+/// Implements `defmt::Format` for bitflags types, which can't derive it, as the type name and the
+/// raw bits.
+macro_rules! format_flags {
+    ($($ty:ty),* $(,)?) => {$(
+        #[cfg(feature = "defmt")]
+        impl ::defmt::Format for $ty {
+            fn format(&self, f: ::defmt::Formatter) {
+                ::defmt::write!(f, "{=str}({:#x})", stringify!($ty), self.bits())
+            }
+        }
+    )*};
+}
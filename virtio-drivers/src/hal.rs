@@ -1,14 +1,36 @@
 use crate::error::VirtIoResult;
 use crate::queue::{QueueLayout, QueueMutRef};
+use crate::{pages, PAGE_SIZE};
 use crate::{PhysAddr, VirtAddr};
 use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::mem::size_of_val;
+use core::ops::Range;
 
 pub trait VirtIoDeviceIo: Send + Sync + Debug {
     fn read_volatile_u32_at(&self, off: usize) -> VirtIoResult<u32>;
     fn read_volatile_u8_at(&self, off: usize) -> VirtIoResult<u8>;
     fn write_volatile_u32_at(&self, off: usize, data: u32) -> VirtIoResult<()>;
     fn write_volatile_u8_at(&self, off: usize, data: u8) -> VirtIoResult<()>;
+    /// Reads a little-endian 16-bit register.
+    ///
+    /// By default it is read a byte at a time, which virtio devices allow for their configuration
+    /// space. Override it if 16-bit accesses are cheaper.
+    fn read_volatile_u16_at(&self, off: usize) -> VirtIoResult<u16> {
+        Ok(u16::from_le_bytes([
+            self.read_volatile_u8_at(off)?,
+            self.read_volatile_u8_at(off + 1)?,
+        ]))
+    }
+    /// Writes a little-endian 16-bit register, by default a byte at a time.
+    fn write_volatile_u16_at(&self, off: usize, data: u16) -> VirtIoResult<()> {
+        let [low, high] = data.to_le_bytes();
+        self.write_volatile_u8_at(off, low)?;
+        self.write_volatile_u8_at(off + 1, high)
+    }
     fn paddr(&self) -> PhysAddr;
     fn vaddr(&self) -> VirtAddr;
 }
@@ -26,6 +48,12 @@ impl VirtIoDeviceIo for Box<dyn VirtIoDeviceIo> {
     fn write_volatile_u8_at(&self, off: usize, data: u8) -> VirtIoResult<()> {
         self.as_ref().write_volatile_u8_at(off, data)
     }
+    fn read_volatile_u16_at(&self, off: usize) -> VirtIoResult<u16> {
+        self.as_ref().read_volatile_u16_at(off)
+    }
+    fn write_volatile_u16_at(&self, off: usize, data: u16) -> VirtIoResult<()> {
+        self.as_ref().write_volatile_u16_at(off, data)
+    }
     fn paddr(&self) -> PhysAddr {
         self.as_ref().paddr()
     }
@@ -35,6 +63,12 @@ impl VirtIoDeviceIo for Box<dyn VirtIoDeviceIo> {
     }
 }
 
+/// DMA memory allocated by [`Hal::dma_alloc_buf`] or, for a virtqueue, [`Hal::dma_alloc`].
+///
+/// There is no deallocation function: dropping a page gives it back, so implementations return
+/// the memory to their allocator in `Drop`. Drivers only drop a page once the device can no longer
+/// access it, after resetting the device, unsetting the queue it holds or detaching it from the
+/// device.
 pub trait DevicePage: Send + Sync {
     fn as_mut_slice(&mut self) -> &mut [u8];
     fn as_slice(&self) -> &[u8];
@@ -42,14 +76,161 @@ pub trait DevicePage: Send + Sync {
     fn vaddr(&self) -> VirtAddr;
 }
 
+/// DMA memory holding a single virtqueue.
 pub trait QueuePage<const SIZE: usize>: DevicePage {
-    fn queue_ref_mut(&mut self, layout: &QueueLayout) -> QueueMutRef<SIZE>;
+    /// Returns references to the descriptor table and rings described by `layout`.
+    ///
+    /// This is the only place a HAL has to turn raw pointers into references. Implementations
+    /// should do exactly that with the pointers from
+    /// [`QueueLayout::ring_pointers`]`(self.vaddr())`; the driver checks that the returned
+    /// references match them and fails queue creation otherwise. The aliasing contract is:
+    ///
+    /// - It is called once per page, and the returned references are the only way the driver
+    ///   accesses the page. Nothing else in the guest may access it while the queue exists.
+    /// - The page must stay mapped, at least [`QueueLayout::size`] bytes long, until it is dropped.
+    ///   The driver drops the references before the page.
+    /// - The device writes to the used ring concurrently with the driver, which only reads the
+    ///   fields it updates through atomics.
+    fn queue_ref_mut(&mut self, layout: &QueueLayout<SIZE>) -> QueueMutRef<SIZE>;
 }
 
 pub trait Hal<const SIZE: usize>: Send + Sync {
+    /// Allocates `pages` zeroed, physically contiguous pages for a virtqueue, which are freed
+    /// when the returned page is dropped, see [`DevicePage`].
     fn dma_alloc(pages: usize) -> Box<dyn QueuePage<SIZE>>;
+    /// Allocates `pages` zeroed, physically contiguous pages for the device to access, which are
+    /// freed when the returned page is dropped, see [`DevicePage`].
     fn dma_alloc_buf(pages: usize) -> Box<dyn DevicePage>;
     fn to_paddr(va: usize) -> usize;
+
+    /// Returns the ranges of physical memory, in order, which `len` bytes from `vaddr` occupy,
+    /// for a buffer the driver shares with the device such as a request header or the caller's
+    /// data.
+    ///
+    /// Each range gets a descriptor of its own in the same chain, so kernels whose heap isn't
+    /// physically contiguous can pass any buffer, at the cost of more descriptors per request.
+    /// The lengths must add up to `len`. By default the buffer is taken to be contiguous and
+    /// translated with [`Self::to_paddr`].
+    ///
+    /// The buffer is passed by address rather than as a slice because drivers also share typed
+    /// values, which they can't view as bytes without `unsafe`.
+    ///
+    /// A platform whose device can't reach the buffer directly, behind an IOMMU, without cache
+    /// coherent DMA or with `VIRTIO_F_ACCESS_PLATFORM`, may instead map it, sync the caches, or
+    /// return a bounce buffer it copied the buffer into unless `direction` is
+    /// [`BufferDirection::DeviceToDriver`]. Every call is paired with one of [`Self::unshare`]
+    /// once the device has used the buffer.
+    fn share(vaddr: VirtAddr, len: usize, _direction: BufferDirection) -> Vec<(PhysAddr, usize)> {
+        vec![(Self::to_paddr(vaddr), len)]
+    }
+
+    /// Called once the device has used a buffer translated with [`Self::share`], with the ranges
+    /// it returned, before the driver looks at the buffer again.
+    ///
+    /// Unless `direction` is [`BufferDirection::DriverToDevice`], this is where a bounce buffer is
+    /// copied back into the buffer or the caches are invalidated, before any mapping or bounce
+    /// buffer is released. It does nothing by default.
+    ///
+    /// Buffers still in flight when their queue is dropped are never unshared, as the device may
+    /// still access them.
+    fn unshare(
+        _vaddr: VirtAddr,
+        _len: usize,
+        _direction: BufferDirection,
+        _ranges: &[(PhysAddr, usize)],
+    ) {
+    }
+
+    /// Called by drivers in their blocking functions each time they find the device hasn't used
+    /// the buffers they are waiting for yet.
+    ///
+    /// By default it only hints to the CPU that it is spinning. An OS may instead put the hart to
+    /// sleep until the next interrupt, e.g. with `wfi`, or yield to another task. The driver checks
+    /// the used ring again after every call, so returning early is harmless, but it must return
+    /// eventually: queues only ask for interrupts while buffers are in flight unless their
+    /// [`EventSuppression`](crate::queue::EventSuppression) says otherwise, and with
+    /// [`EventSuppression::Always`](crate::queue::EventSuppression::Always) none arrive at all.
+    fn wait_for_used() {
+        core::hint::spin_loop();
+    }
+
+    /// Returns the current value of a monotonic clock, for the deadlines taken by drivers'
+    /// blocking functions. The units are up to the HAL, e.g. timer ticks, as long as callers
+    /// compute deadlines in the same ones.
+    ///
+    /// By default there is no clock and every deadline has already passed, so such functions
+    /// check the device once and give up rather than block.
+    fn now() -> u64 {
+        u64::MAX
+    }
+
+    /// Takes a 4 KiB page of guest memory away from the OS for the memory balloon, and returns
+    /// its physical address, or `None` if no page can be spared.
+    ///
+    /// The page stays the balloon's until it is handed back to [`Self::balloon_free_page`]; the
+    /// guest must not touch it in between, as the host may have reclaimed it. By default no page
+    /// is ever spared, so the balloon can't inflate.
+    fn balloon_alloc_page() -> Option<PhysAddr> {
+        None
+    }
+
+    /// Gives a page taken by [`Self::balloon_alloc_page`] back to the OS, after the host was told
+    /// it left the balloon.
+    fn balloon_free_page(_paddr: PhysAddr) {}
+}
+
+/// The memory a driver allocates to work with a device, so it can be budgeted before the driver is
+/// created.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryRequirements {
+    /// Pages allocated with [`Hal::dma_alloc`], one allocation per virtqueue.
+    pub queue_pages: usize,
+    /// Pages allocated with [`Hal::dma_alloc_buf`].
+    pub buffer_pages: usize,
+    /// Bytes from the global allocator which are shared with the device through
+    /// [`Hal::share`], so must be reachable by it.
+    pub shared_heap_bytes: usize,
+    /// The alignment every DMA allocation needs, in bytes.
+    pub alignment: usize,
+}
+
+impl MemoryRequirements {
+    /// The requirements of `count` virtqueues of `SIZE` descriptors.
+    pub(crate) const fn queues<const SIZE: usize>(count: usize) -> Self {
+        Self {
+            queue_pages: count * pages(QueueLayout::<SIZE>::new().size()),
+            buffer_pages: 0,
+            shared_heap_bytes: 0,
+            alignment: PAGE_SIZE,
+        }
+    }
+
+    /// Adds DMA buffers of `bytes` bytes in total.
+    #[cfg(any(feature = "block", feature = "gpu", feature = "net"))]
+    pub(crate) const fn with_buffer(mut self, bytes: usize) -> Self {
+        self.buffer_pages += pages(bytes);
+        self
+    }
+
+    /// Adds `bytes` bytes of shared heap memory.
+    #[cfg(any(
+        feature = "balloon",
+        feature = "console",
+        feature = "input",
+        feature = "net",
+        feature = "scsi",
+        feature = "socket",
+        feature = "sound"
+    ))]
+    pub(crate) const fn with_shared_heap(mut self, bytes: usize) -> Self {
+        self.shared_heap_bytes += bytes;
+        self
+    }
+
+    /// The total number of DMA pages.
+    pub const fn dma_pages(&self) -> usize {
+        self.queue_pages + self.buffer_pages
+    }
 }
 
 /// The direction in which a buffer is passed.
@@ -62,3 +243,114 @@ pub enum BufferDirection {
     /// The buffer may be read or written by both the device and the driver.
     Both,
 }
+
+/// Memory the driver shares with the device: where the driver sees it, where the device does if
+/// that is already known, how long it is and which way the data flows.
+///
+/// Every descriptor a queue hands to the device is built from one. Memory from
+/// [`Hal::dma_alloc_buf`] carries the physical address it was allocated at; anything else, such as
+/// a caller's buffer or a request header, is translated with [`Hal::share`] when it is added to a
+/// queue. It can only be made from references or DMA pages, never from a bare address, and it
+/// borrows what it was made from, so that can't be freed or touched while the description is
+/// still around to be given to the device:
+///
+/// ```compile_fail
+/// use virtio_drivers::hal::DmaBuf;
+///
+/// let buf = {
+///     let local = [0u8; 16];
+///     DmaBuf::readable(&local)
+/// };
+/// assert_eq!(buf.len(), 16);
+/// ```
+#[derive(Debug, Eq, PartialEq)]
+pub struct DmaBuf<'a> {
+    vaddr: VirtAddr,
+    paddr: Option<PhysAddr>,
+    len: usize,
+    direction: BufferDirection,
+    _borrow: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> DmaBuf<'a> {
+    /// Describes a value which the device reads, such as a request header.
+    pub fn readable<T: ?Sized>(value: &'a T) -> Self {
+        Self {
+            vaddr: value as *const T as *const u8 as VirtAddr,
+            paddr: None,
+            len: size_of_val(value),
+            direction: BufferDirection::DriverToDevice,
+            _borrow: PhantomData,
+        }
+    }
+
+    /// Describes a value which the device writes, such as a response.
+    ///
+    /// Taking it mutably keeps a response from being described as readable by mistake, in which
+    /// case the device would never fill it in.
+    pub fn writable<T: ?Sized>(value: &'a mut T) -> Self {
+        Self {
+            vaddr: value as *mut T as *mut u8 as VirtAddr,
+            paddr: None,
+            len: size_of_val(value),
+            direction: BufferDirection::DeviceToDriver,
+            _borrow: PhantomData,
+        }
+    }
+
+    /// Describes `range` of a DMA page, by the physical address it was allocated at.
+    ///
+    /// Returns `None` if `range` isn't within the page.
+    pub fn from_page(
+        page: &'a dyn DevicePage,
+        range: Range<usize>,
+        direction: BufferDirection,
+    ) -> Option<Self> {
+        page.as_slice().get(range.clone())?;
+        Some(Self {
+            vaddr: page.vaddr() + range.start,
+            paddr: Some(page.paddr() + range.start),
+            len: range.len(),
+            direction,
+            _borrow: PhantomData,
+        })
+    }
+
+    /// The address the driver sees the buffer at.
+    pub fn vaddr(&self) -> VirtAddr {
+        self.vaddr
+    }
+
+    /// The physical address of the buffer, if it is known without [`Hal::share`].
+    pub fn paddr(&self) -> Option<PhysAddr> {
+        self.paddr
+    }
+
+    /// The length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Which way the data flows.
+    pub fn direction(&self) -> BufferDirection {
+        self.direction
+    }
+
+    /// Whether the device may write to the buffer.
+    pub fn device_writes(&self) -> bool {
+        self.direction != BufferDirection::DriverToDevice
+    }
+
+    /// Returns the ranges of physical memory the buffer occupies, in order, see [`Hal::share`].
+    pub fn ranges<H: Hal<SIZE>, const SIZE: usize>(&self) -> Vec<(PhysAddr, usize)> {
+        match self.paddr {
+            Some(paddr) => vec![(paddr, self.len)],
+            None => H::share(self.vaddr, self.len, self.direction),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! A [`Hal`] backed by ordinary heap memory rather than DMA memory.
+//!
+//! Addresses handed to the "device" are just virtual addresses, so nothing but the driver side of
+//! the queues can run on it. That is enough to exercise the queue bookkeeping (`add`, `can_pop`,
+//! descriptor accounting) under tools like miri, which can't follow physical addresses.
+//!
+//! Queue pages are leaked, because their rings are handed out as `'static` references. Run miri
+//! with `-Zmiri-ignore-leaks`.
+
+use crate::hal::{DevicePage, Hal, QueuePage};
+use crate::queue::{AvailRing, Descriptor, QueueLayout, QueueMutRef, UsedRing};
+use crate::{PhysAddr, VirtAddr, PAGE_SIZE};
+use alloc::boxed::Box;
+use alloc::vec;
+
+/// A [`Hal`] which allocates from the global allocator.
+pub struct HeapHal;
+
+impl<const SIZE: usize> Hal<SIZE> for HeapHal {
+    fn dma_alloc(_pages: usize) -> Box<dyn QueuePage<SIZE>> {
+        Box::new(HeapQueuePage::<SIZE>::new())
+    }
+
+    fn dma_alloc_buf(pages: usize) -> Box<dyn DevicePage> {
+        Box::new(HeapPage {
+            buf: vec![0; pages * PAGE_SIZE].into_boxed_slice(),
+        })
+    }
+
+    fn to_paddr(va: usize) -> usize {
+        va
+    }
+}
+
+/// A zeroed heap buffer.
+struct HeapPage {
+    buf: Box<[u8]>,
+}
+
+impl DevicePage for HeapPage {
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+
+    fn paddr(&self) -> PhysAddr {
+        self.buf.as_ptr() as _
+    }
+
+    fn vaddr(&self) -> VirtAddr {
+        self.buf.as_ptr() as _
+    }
+}
+
+/// The descriptor table and available ring, at the start of the queue.
+#[repr(C)]
+struct DriverArea<const SIZE: usize> {
+    descriptor_table: [Descriptor; SIZE],
+    avail_ring: AvailRing<SIZE>,
+}
+
+/// The used ring, on the page after the driver area as [`QueueLayout`] expects.
+#[repr(C, align(4096))]
+struct DeviceArea<const SIZE: usize> {
+    used_ring: UsedRing<SIZE>,
+}
+
+#[repr(C, align(4096))]
+struct HeapQueue<const SIZE: usize> {
+    driver: DriverArea<SIZE>,
+    device: DeviceArea<SIZE>,
+}
+
+/// A queue page whose parts are typed fields, so references to them can be handed out safely.
+pub(crate) struct HeapQueuePage<const SIZE: usize> {
+    /// Taken by the one call to `queue_ref_mut`.
+    queue: Option<&'static mut HeapQueue<SIZE>>,
+    vaddr: VirtAddr,
+}
+
+impl<const SIZE: usize> HeapQueue<SIZE> {
+    fn leak() -> &'static mut Self {
+        Box::leak(Box::new(HeapQueue {
+            driver: DriverArea {
+                descriptor_table: core::array::from_fn(|_| Descriptor::default()),
+                avail_ring: AvailRing::new(),
+            },
+            device: DeviceArea {
+                used_ring: UsedRing::new(),
+            },
+        }))
+    }
+}
+
+impl<const SIZE: usize> HeapQueuePage<SIZE> {
+    pub(crate) fn new() -> Self {
+        let queue = HeapQueue::leak();
+        let vaddr = queue as *const HeapQueue<SIZE> as VirtAddr;
+        Self {
+            queue: Some(queue),
+            vaddr,
+        }
+    }
+}
+
+impl<const SIZE: usize> DevicePage for HeapQueuePage<SIZE> {
+    /// The queue memory is only reachable through [`QueuePage::queue_ref_mut`].
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    /// The queue memory is only reachable through [`QueuePage::queue_ref_mut`].
+    fn as_slice(&self) -> &[u8] {
+        &[]
+    }
+
+    fn paddr(&self) -> PhysAddr {
+        self.vaddr
+    }
+
+    fn vaddr(&self) -> VirtAddr {
+        self.vaddr
+    }
+}
+
+impl<const SIZE: usize> QueuePage<SIZE> for HeapQueuePage<SIZE> {
+    fn queue_ref_mut(&mut self, _layout: &QueueLayout<SIZE>) -> QueueMutRef<SIZE> {
+        // The driver checks the references against the layout, so if this is called a second time
+        // the fresh queue handed out fails that check rather than aliasing the first one.
+        let queue = self.queue.take().unwrap_or_else(HeapQueue::leak);
+        QueueMutRef {
+            descriptor_table: &mut queue.driver.descriptor_table,
+            avail_ring: &mut queue.driver.avail_ring,
+            used_ring: &mut queue.device.used_ring,
+        }
+    }
+}
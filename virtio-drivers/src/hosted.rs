@@ -0,0 +1,159 @@
+//! A reference [`Hal`] and register space for running drivers in an ordinary process, e.g. to try
+//! the crate out on a desktop or to test a driver against a device emulated in the same process.
+//!
+//! "Physical" addresses are identical to virtual ones, which is what an emulated device in the
+//! same address space expects. Beyond that, [`HostedHal`] shows what any correct `Hal` must do:
+//!
+//! - [`Hal::dma_alloc`] returns zeroed, page aligned memory which is only accessed through the
+//!   references handed out by [`QueuePage::queue_ref_mut`], and stays valid until it is dropped.
+//! - [`Hal::dma_alloc_buf`] returns zeroed, page aligned and physically contiguous memory of the
+//!   requested number of pages.
+//! - [`Hal::to_paddr`] translates the address of any memory the driver shares with the device,
+//!   including buffers from the global allocator, to the address the device sees. A kernel whose
+//!   heap isn't physically contiguous overrides [`Hal::share`] as well, to split such buffers.
+//! - Since the host's DMA is cache coherent and there is no IOMMU in the way, [`Hal::unshare`] has
+//!   nothing to do. A platform which bounces buffers copies them back there.
+
+use crate::error::{VirtIoError, VirtIoResult};
+use crate::hal::{DevicePage, Hal, QueuePage, VirtIoDeviceIo};
+use crate::heap::HeapQueuePage;
+use crate::{PhysAddr, VirtAddr, PAGE_SIZE};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::Instant;
+
+/// A [`Hal`] which allocates from the global allocator and uses identity "physical" addresses.
+pub struct HostedHal;
+
+impl<const SIZE: usize> Hal<SIZE> for HostedHal {
+    fn dma_alloc(_pages: usize) -> Box<dyn QueuePage<SIZE>> {
+        Box::new(HeapQueuePage::<SIZE>::new())
+    }
+
+    fn dma_alloc_buf(pages: usize) -> Box<dyn DevicePage> {
+        Box::new(AlignedPages::new(pages))
+    }
+
+    fn to_paddr(va: usize) -> usize {
+        va
+    }
+
+    /// Nanoseconds since the clock was first read.
+    fn now() -> u64 {
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+    }
+}
+
+/// Zeroed, page aligned heap memory.
+struct AlignedPages {
+    /// One page longer than needed, so an aligned range of the requested length fits.
+    buf: Vec<u8>,
+    range: Range<usize>,
+}
+
+impl AlignedPages {
+    fn new(pages: usize) -> Self {
+        let len = pages * PAGE_SIZE;
+        let buf = vec![0; len + PAGE_SIZE];
+        let start = buf.as_ptr().align_offset(PAGE_SIZE);
+        Self {
+            buf,
+            range: start..start + len,
+        }
+    }
+}
+
+impl DevicePage for AlignedPages {
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.range.clone()]
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buf[self.range.clone()]
+    }
+
+    fn paddr(&self) -> PhysAddr {
+        self.vaddr()
+    }
+
+    fn vaddr(&self) -> VirtAddr {
+        self.as_slice().as_ptr() as _
+    }
+}
+
+/// A register space in ordinary memory, which an emulated device can read and write from another
+/// thread while a driver uses it.
+///
+/// Accesses outside the space return [`VirtIoError::InvalidParam`]. Registers are little-endian,
+/// like those of real virtio devices.
+#[derive(Debug)]
+pub struct HostedIo {
+    regs: Mutex<Vec<u8>>,
+}
+
+impl HostedIo {
+    /// Creates a zeroed register space of `len` bytes.
+    pub fn new(len: usize) -> Self {
+        Self {
+            regs: Mutex::new(vec![0; len]),
+        }
+    }
+
+    /// Overwrites registers starting at `off`, as the device would.
+    pub fn write_bytes(&self, off: usize, data: &[u8]) -> VirtIoResult<()> {
+        self.lock()
+            .get_mut(off..off + data.len())
+            .ok_or(VirtIoError::InvalidParam)?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Reads registers starting at `off`, e.g. to check what the driver wrote.
+    pub fn read_bytes(&self, off: usize, data: &mut [u8]) -> VirtIoResult<()> {
+        data.copy_from_slice(
+            self.lock()
+                .get(off..off + data.len())
+                .ok_or(VirtIoError::InvalidParam)?,
+        );
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<u8>> {
+        // The registers are plain bytes, so they are still usable if another thread panicked.
+        self.regs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl VirtIoDeviceIo for HostedIo {
+    fn read_volatile_u32_at(&self, off: usize) -> VirtIoResult<u32> {
+        let mut bytes = [0; 4];
+        self.read_bytes(off, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_volatile_u8_at(&self, off: usize) -> VirtIoResult<u8> {
+        let mut bytes = [0; 1];
+        self.read_bytes(off, &mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    fn write_volatile_u32_at(&self, off: usize, data: u32) -> VirtIoResult<()> {
+        self.write_bytes(off, &data.to_le_bytes())
+    }
+
+    fn write_volatile_u8_at(&self, off: usize, data: u8) -> VirtIoResult<()> {
+        self.write_bytes(off, &[data])
+    }
+
+    fn paddr(&self) -> PhysAddr {
+        self.vaddr()
+    }
+
+    fn vaddr(&self) -> VirtAddr {
+        self.lock().as_ptr() as _
+    }
+}
@@ -2,11 +2,21 @@
 #![forbid(unsafe_code)]
 // #![allow(unused)]
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+#[macro_use]
+mod fmt;
 mod common;
 pub mod device;
+mod endian;
 pub mod error;
 pub mod hal;
+#[cfg(feature = "heap-dma")]
+pub mod heap;
+#[cfg(feature = "std")]
+pub mod hosted;
 pub mod queue;
+pub mod spec;
 pub mod transport;
 mod volatile;
 
@@ -17,7 +27,7 @@ pub type PhysAddr = usize;
 
 /// The number of pages required to store `size` bytes, rounded up to a whole number of pages.
 const fn pages(size: usize) -> usize {
-    (size + PAGE_SIZE - 1) / PAGE_SIZE
+    size.div_ceil(PAGE_SIZE)
 }
 /// Align `size` up to a page.
 const fn align_up(size: usize) -> usize {
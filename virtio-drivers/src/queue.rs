@@ -1,71 +1,418 @@
+use crate::endian::{AtomicLe16, Le16, Le32, Le64};
 use crate::error::{VirtIoError, VirtIoResult};
-use crate::hal::{Hal, QueuePage};
-use crate::transport::Transport;
-use crate::{align_up, pages};
+use crate::hal::{BufferDirection, DmaBuf, Hal, QueuePage};
+use crate::transport::{queue_vector, InitMilestone, Transport};
+use crate::{align_up, pages, PhysAddr, VirtAddr};
 use alloc::boxed::Box;
-use alloc::collections::{BTreeSet, VecDeque};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
 use alloc::vec::Vec;
-use core::hint::spin_loop;
+use core::cmp::Reverse;
+use core::fmt;
+#[cfg(any(
+    feature = "block",
+    feature = "console",
+    feature = "input",
+    feature = "net"
+))]
+use core::future::poll_fn;
 use core::marker::PhantomData;
 use core::mem::size_of;
-use core::sync::atomic::{fence, AtomicU16, Ordering};
+use core::sync::atomic::{fence, Ordering};
+use core::task::Waker;
+#[cfg(any(
+    feature = "block",
+    feature = "console",
+    feature = "input",
+    feature = "net"
+))]
+use core::task::{Context, Poll};
+
+/// A buffer translated with [`Hal::share`], with the physical ranges it was translated to.
+struct SharedBuf {
+    vaddr: VirtAddr,
+    len: usize,
+    direction: BufferDirection,
+    ranges: Vec<(PhysAddr, usize)>,
+}
 
 pub struct VirtIoQueue<H: Hal<SIZE>, const SIZE: usize> {
-    queue_page: Box<dyn QueuePage<SIZE>>,
+    /// References into `queue_page`, declared first so they are dropped before it.
     queue_ref: QueueMutRef<SIZE>,
+    queue_page: Box<dyn QueuePage<SIZE>>,
+    /// The number of descriptors the device was told the queue has, at most `SIZE`, see
+    /// [`Self::new`].
+    size: usize,
     // storage available descriptor indexes
     avail_desc_index: VecDeque<u16>,
+    /// The index in the used ring up to which entries have been copied into `completed`.
     last_seen_used: u16,
-    poped_used: BTreeSet<u16>,
+    /// The length the device reported for each token which completed but wasn't popped yet,
+    /// indexed by token, so checking for a completion doesn't need to search the used ring.
+    completed: Vec<Option<u32>>,
+    /// The tokens in `completed`, in the order the device completed them.
+    ready: VecDeque<u16>,
     /// The index of queue
     queue_idx: u16,
+    /// The most descriptors that have been in use at the same time.
+    high_water_mark: usize,
+    /// The number of tokens popped so far.
+    completions: u64,
+    /// The only token which hasn't been popped yet, with the value of `completions` when it was
+    /// added, while `outstanding` is empty.
+    ///
+    /// Most requests are synchronous, so this saves the map updates on every one of them. Once a
+    /// second token is added, both go into `outstanding` until it drains.
+    sole_outstanding: Option<(u16, u64)>,
+    /// Tokens which haven't been popped yet, with the value of `completions` when they were added,
+    /// unless one is in `sole_outstanding`.
+    outstanding: BTreeMap<u16, u64>,
+    /// Tasks waiting for a token to complete, see [`Self::poll_used`].
+    wakers: BTreeMap<u16, Waker>,
+    /// A task waiting for any token to complete, see [`Self::poll_any_used`].
+    any_waker: Option<Waker>,
+    /// Buffers added with [`Self::add_with_return_buffers`] which haven't been popped yet, by
+    /// token.
+    owned: BTreeMap<u16, Vec<OwnedBuffer>>,
+    /// The buffers translated with [`Hal::share`] which the device hasn't used yet, with the
+    /// ranges they were translated to, indexed by token, to give to [`Hal::unshare`] once it has.
+    shared: Vec<Vec<SharedBuf>>,
+    /// See [`Self::set_event_suppression`].
+    suppression: EventSuppression,
+    /// Whether `VIRTIO_F_EVENT_IDX` was negotiated, see [`Self::set_event_idx`].
+    event_idx: bool,
+    /// The index of the available ring when [`Self::should_notify`] was last called.
+    notified_avail: u16,
     _hal: PhantomData<H>,
 }
 
-impl<H: Hal<SIZE>, const SIZE: usize> VirtIoQueue<H, SIZE> {
-    const AVAIL_RING_OFFSET: usize = size_of::<Descriptor>() * SIZE;
-    const DESCRIPTOR_TABLE_OFFSET: usize = 0;
-    const USED_RING_OFFSET: usize =
-        align_up(size_of::<Descriptor>() * SIZE + size_of::<AvailRing<SIZE>>());
+/// Checks the device status for DEVICE_NEEDS_RESET every so often while blocking on the device,
+/// which would otherwise never finish if the device stopped using buffers until it is reset.
+#[derive(Default)]
+pub(crate) struct NeedsResetCheck {
+    waits: u32,
+}
+
+impl NeedsResetCheck {
+    /// How many waits there are between checks, as reading the status is a slower register
+    /// access than checking the used ring.
+    const INTERVAL: u32 = 1024;
+
+    /// Counts one more wait, returning [`VirtIoError::NeedsReset`] if it's time to check the
+    /// status and the device asked to be reset.
+    pub(crate) fn tick<T: Transport>(&mut self, transport: &T) -> VirtIoResult<()> {
+        self.waits = self.waits.wrapping_add(1);
+        if self.waits.is_multiple_of(Self::INTERVAL) {
+            transport.check_needs_reset()?;
+        }
+        Ok(())
+    }
+}
+
+/// The flag in the available ring asking the device not to interrupt after using buffers.
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// The flag in the used ring asking the driver not to notify the device after adding buffers.
+const USED_F_NO_NOTIFY: u16 = 1;
+
+/// Which notifications a queue suppresses, see [`VirtIoQueue::set_event_suppression`].
+///
+/// Without `VIRTIO_F_EVENT_IDX` both directions are only hints, so the device may still interrupt
+/// while asked not to, and drivers have to handle that. With it, see
+/// [`VirtIoQueue::set_event_idx`], the device and driver tell each other exactly which ring index
+/// they want to hear about next instead.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum EventSuppression {
+    /// Notify the device after every add, even if it asked not to be, and ask it to interrupt
+    /// for every used buffer, for latency-critical queues such as a console's.
+    Never,
+    /// Ask the device never to interrupt, for queues which are polled, and notify it only if it
+    /// asks to be.
+    Always,
+    /// Notify the device only if it asks to be, and ask it to interrupt only while buffers are
+    /// in flight.
+    #[default]
+    Adaptive,
+}
+
+/// A token which was added to a queue but hasn't been popped, see [`VirtIoQueue::leak_report`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutstandingToken {
+    pub token: u16,
+    /// How many other tokens were popped since this one was added.
+    pub completions_since: u64,
+}
+
+/// Where a virtqueue is, for integrators which route its interrupts or map it in an IOMMU
+/// themselves, see [`VirtIoQueue::info`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QueueInfo {
+    /// The index of the queue on its device.
+    pub index: u16,
+    /// The number of descriptors in the queue.
+    pub size: u16,
+    /// The physical address of the descriptor table.
+    pub descriptors: PhysAddr,
+    /// The physical address of the available ring.
+    pub driver_area: PhysAddr,
+    /// The physical address of the used ring.
+    pub device_area: PhysAddr,
+}
+
+/// Counters of a virtqueue, see [`VirtIoQueue::stats`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct QueueStats {
+    pub index: u16,
+    /// The number of tokens popped so far.
+    pub completions: u64,
+    /// The number of tokens added but not popped yet.
+    pub in_flight: usize,
+    /// See [`VirtIoQueue::high_water_mark`].
+    pub high_water_mark: usize,
+}
+
+/// Checksums over the descriptor table and rings of a queue, see [`VirtIoQueue::snapshot`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RingSnapshot {
+    descriptors: Vec<u32>,
+    avail_header: u32,
+    avail_ring: Vec<u32>,
+    used_header: u32,
+    used_ring: Vec<u32>,
+}
+
+/// The parts of a queue which changed since a [`RingSnapshot`] was taken.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RingDiff {
+    /// The indices of the descriptors which changed.
+    pub descriptors: Vec<u16>,
+    /// Whether the flags, index or used event of the available ring changed.
+    pub avail_header: bool,
+    /// The slots of the available ring which changed.
+    pub avail_ring: Vec<u16>,
+    /// Whether the flags, index or available event of the used ring changed.
+    pub used_header: bool,
+    /// The slots of the used ring which changed.
+    pub used_ring: Vec<u16>,
+}
+
+impl RingDiff {
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.descriptors.is_empty()
+            && !self.avail_header
+            && self.avail_ring.is_empty()
+            && !self.used_header
+            && self.used_ring.is_empty()
+    }
+
+    /// Returns the changed descriptors which aren't in `chain`.
+    pub fn descriptors_outside(&self, chain: &[u16]) -> Vec<u16> {
+        self.descriptors
+            .iter()
+            .copied()
+            .filter(|id| !chain.contains(id))
+            .collect()
+    }
+}
+
+/// Returns whether moving a ring index from `old` to `new` passed `event`, i.e. whether the other
+/// side asked to be told about one of the entries in between.
+///
+/// Ref: 2.7.10 Available Buffer Notification Suppression, linux virtio_ring.h vring_need_event
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
 
+/// Returns the indices at which the checksums differ.
+fn changed(before: &[u32], after: &[u32]) -> Vec<u16> {
+    before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+        .map(|(i, _)| i as u16)
+        .collect()
+}
+
+/// The CRC-32 (IEEE 802.3) of the given bytes.
+fn crc32(bytes: impl IntoIterator<Item = u8>) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+impl<H: Hal<SIZE>, const SIZE: usize> VirtIoQueue<H, SIZE> {
+    /// Sets up the given queue of the device with up to `SIZE` descriptors.
+    ///
+    /// `SIZE` only bounds the queue, and is what its memory is allocated for. If the device can't
+    /// handle that many, the queue gets the largest power of two it can, see [`Self::size`], so a
+    /// driver works with hypervisors which only offer small queues. Legacy devices place the rings
+    /// by the queue size, so they only get the full size, and like devices which have no such
+    /// queue at all get [`VirtIoError::InvalidParam`] otherwise.
     pub fn new<T: Transport>(transport: &mut T, queue_idx: u16) -> VirtIoResult<Self> {
         if transport.queue_used(queue_idx)? {
             return Err(VirtIoError::AlreadyUsed);
         }
-        if !SIZE.is_power_of_two()
-            || SIZE > u16::MAX.into()
-            || transport.max_queue_size(queue_idx)? < SIZE as u32
-        {
+        if !SIZE.is_power_of_two() || SIZE > u16::MAX.into() {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let max = transport.max_queue_size(queue_idx)? as usize;
+        let size = if transport.requires_legacy_layout() {
+            Some(SIZE).filter(|&size| size <= max)
+        } else {
+            // Split queues must be a power of two long.
+            SIZE.min(max).checked_ilog2().map(|log| 1 << log)
+        };
+        let Some(size) = size else {
             return Err(VirtIoError::InvalidParam);
+        };
+        if size < SIZE {
+            debug!(
+                "queue {} shortened to {} descriptors for the device",
+                queue_idx, size
+            );
         }
-        let size = SIZE as u16;
-        let mut queue_page = H::dma_alloc(pages(Self::total_size()));
+        let layout = QueueLayout::<SIZE>::new();
+        let mut queue_page = H::dma_alloc(pages(layout.size()));
+        let queue_ref_mut = queue_page.queue_ref_mut(&layout);
+        layout.check(queue_page.vaddr(), &queue_ref_mut)?;
         let descriptors_paddr = queue_page.paddr();
-        let driver_area_paddr = descriptors_paddr + Self::AVAIL_RING_OFFSET;
-        let device_area_paddr = descriptors_paddr + Self::USED_RING_OFFSET;
+        let driver_area_paddr = descriptors_paddr + layout.avail_ring_offset;
+        let device_area_paddr = descriptors_paddr + layout.used_ring_offset;
+        // The vector has to be set before the queue is enabled.
+        transport.set_queue_msix_vector(queue_idx, queue_vector(queue_idx))?;
         transport.queue_set(
             queue_idx,
-            size as _,
+            size as u32,
             descriptors_paddr,
             driver_area_paddr,
             device_area_paddr,
         )?;
-        let avail_desc_index = VecDeque::from_iter(0..SIZE as u16);
-        let queue_ref_mut = queue_page.queue_ref_mut(&QueueLayout::new::<SIZE>());
-        Ok(VirtIoQueue {
+        transport.report_init(InitMilestone::QueueProgrammed {
+            queue: queue_idx,
+            size: size as u32,
+            descriptors: descriptors_paddr,
+            driver_area: driver_area_paddr,
+            device_area: device_area_paddr,
+        });
+        let avail_desc_index = VecDeque::from_iter(0..size as u16);
+        let queue = VirtIoQueue {
             queue_page,
+            size,
             queue_idx,
             queue_ref: queue_ref_mut,
             avail_desc_index,
             last_seen_used: 0,
-            poped_used: BTreeSet::new(),
+            completed: vec![None; size],
+            ready: VecDeque::new(),
+            high_water_mark: 0,
+            completions: 0,
+            sole_outstanding: None,
+            outstanding: BTreeMap::new(),
+            wakers: BTreeMap::new(),
+            any_waker: None,
+            owned: BTreeMap::new(),
+            shared: core::iter::repeat_with(Vec::new).take(size).collect(),
+            suppression: EventSuppression::default(),
+            event_idx: false,
+            notified_avail: 0,
             _hal: PhantomData,
-        })
+        };
+        queue.update_interrupt_suppression();
+        Ok(queue)
+    }
+
+    /// Sets which notifications the queue suppresses, taking effect immediately.
+    ///
+    /// Interrupts which were already requested may still arrive after suppressing them.
+    pub fn set_event_suppression(&mut self, suppression: EventSuppression) {
+        self.suppression = suppression;
+        self.update_interrupt_suppression();
     }
 
-    const fn total_size() -> usize {
-        align_up(size_of::<Descriptor>() * SIZE + size_of::<AvailRing<SIZE>>())
-            + align_up(size_of::<UsedRing<SIZE>>())
+    /// Returns which notifications the queue suppresses.
+    pub fn event_suppression(&self) -> EventSuppression {
+        self.suppression
+    }
+
+    /// Switches between the `used_event` and `avail_event` fields of the rings and the flags
+    /// words for suppressing notifications, depending on whether the device negotiated
+    /// `VIRTIO_F_EVENT_IDX`.
+    ///
+    /// Must be called before any buffers are added, with the same value for every queue of the
+    /// device.
+    ///
+    /// Ref: 2.7.7 Used Buffer Notification Suppression, 2.7.10 Available Buffer Notification
+    /// Suppression
+    pub fn set_event_idx(&mut self, negotiated: bool) {
+        self.event_idx = negotiated;
+        self.update_interrupt_suppression();
+    }
+
+    /// Returns whether the queue suppresses notifications with event indices.
+    pub fn event_idx(&self) -> bool {
+        self.event_idx
+    }
+
+    /// Asks the device not to interrupt according to the policy and whether anything is in
+    /// flight, with the flag or, if negotiated, the used event index.
+    fn update_interrupt_suppression(&self) {
+        let suppress = match self.suppression {
+            EventSuppression::Never => false,
+            EventSuppression::Always => true,
+            EventSuppression::Adaptive => self.in_flight() == 0,
+        };
+        let avail_ring = &self.queue_ref.avail_ring;
+        if self.event_idx {
+            // The device interrupts once the used index moves past the event index, so one just
+            // behind the entries already seen is only reached again after the index wraps.
+            let used_event = if suppress {
+                self.last_seen_used.wrapping_sub(1)
+            } else {
+                self.last_seen_used
+            };
+            avail_ring
+                .used_event(self.size)
+                .store(used_event, Ordering::Release);
+            // The device ignores the flags, and the driver must leave them clear.
+            avail_ring.flags.store(0, Ordering::Release);
+        } else {
+            let flags = if suppress { AVAIL_F_NO_INTERRUPT } else { 0 };
+            avail_ring.flags.store(flags, Ordering::Release);
+            // Harmless without the feature, and keeps the field meaningful in dumps.
+            avail_ring
+                .used_event(self.size)
+                .store(self.last_seen_used, Ordering::Release);
+        }
+    }
+
+    /// Returns the index of the queue and the physical addresses of its parts.
+    pub fn info(&self) -> QueueInfo {
+        let layout = QueueLayout::<SIZE>::new();
+        let descriptors = self.queue_page.paddr();
+        QueueInfo {
+            index: self.queue_idx,
+            size: self.size as u16,
+            descriptors,
+            driver_area: descriptors + layout.avail_ring_offset,
+            device_area: descriptors + layout.used_ring_offset,
+        }
+    }
+
+    /// Returns the number of descriptors in the queue, which is less than `SIZE` if the device
+    /// couldn't handle that many, see [`Self::new`].
+    pub fn size(&self) -> u16 {
+        self.size as u16
     }
 
     /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
@@ -73,43 +420,126 @@ impl<H: Hal<SIZE>, const SIZE: usize> VirtIoQueue<H, SIZE> {
     ///
     /// This assumes that the device isn't processing any other buffers at the same time.
     ///
-    /// The buffers must not be empty.
+    /// The buffers must not be empty. Returns [`VirtIoError::NeedsReset`] if the device asks to be
+    /// reset while waiting.
     pub fn add_notify_wait_pop<T: Transport>(
         &mut self,
         transport: &mut T,
-        descriptors: Vec<Descriptor>,
+        buffers: Vec<DmaBuf<'_>>,
     ) -> VirtIoResult<u32> {
-        let token = self.add(descriptors)?;
+        let token = self.add(buffers)?;
         // Notify the queue.
         if self.should_notify() {
             transport.notify(self.queue_idx)?;
         }
-        // Wait until there is at least one element in the used ring.
+        self.wait_for(transport, token)?;
+        self.pop_used(token)
+    }
+
+    /// Blocks until the device has used the given token, so it can be popped.
+    ///
+    /// Returns [`VirtIoError::NeedsReset`] instead of waiting forever if the device asks to be
+    /// reset in the meantime, as it won't use the token then.
+    pub(crate) fn wait_for<T: Transport>(&mut self, transport: &T, token: u16) -> VirtIoResult<()> {
+        let mut check = NeedsResetCheck::default();
         while !self.can_pop(token)? {
-            spin_loop();
+            check.tick(transport)?;
+            H::wait_for_used();
+        }
+        Ok(())
+    }
+
+    /// Takes back a token whose request was abandoned, e.g. by dropping the future waiting for it,
+    /// before the borrow of its buffers ends.
+    ///
+    /// Blocks until the device has used the token and pops it. If the device asks to be reset
+    /// instead, it is reset, which also stops it from accessing the buffers. Does nothing if the
+    /// token isn't in flight.
+    #[cfg(any(feature = "block", feature = "net"))]
+    pub(crate) fn cancel<T: Transport>(&mut self, transport: &mut T, token: u16) {
+        if !self.is_outstanding(token) {
+            return;
+        }
+        if self.wait_for(transport, token).is_ok() && self.pop_used(token).is_ok() {
+            return;
+        }
+        if let Err(e) = transport.reset() {
+            error!("Failed to reset device with abandoned request: {:?}", e);
+        }
+    }
+
+    /// Adds buffers which the queue owns until the device has used them, and returns a token for
+    /// [`Self::pop_used_with_buffers`].
+    ///
+    /// Unlike with borrowed buffers, nothing can touch or free these while the device may access
+    /// them, so their safety doesn't rest on the caller waiting for the token. If the queue is
+    /// dropped while they are still in flight they are leaked instead of freed, as the device may
+    /// still be using them.
+    ///
+    /// The same rules as for every request apply: returns [`VirtIoError::InvalidParam`] if no
+    /// buffers are given or a [`OwnedBuffer::Read`] follows a [`OwnedBuffer::Write`], and
+    /// [`VirtIoError::QueueFull`] if there aren't enough free descriptors, in which case the
+    /// buffers are dropped.
+    pub fn add_with_return_buffers(&mut self, buffers: Vec<OwnedBuffer>) -> VirtIoResult<u16> {
+        let mut buffers = buffers;
+        let dma_bufs = buffers.iter_mut().map(OwnedBuffer::dma_buf).collect();
+        let token = self.add(dma_bufs)?;
+        self.owned.insert(token, buffers);
+        Ok(token)
+    }
+
+    /// Pops a token added with [`Self::add_with_return_buffers`], giving its buffers back with
+    /// the number of bytes the device wrote to them.
+    ///
+    /// Returns [`VirtIoError::NotReady`] if the device hasn't used them yet, and
+    /// [`VirtIoError::WrongToken`] if the token isn't one of them.
+    pub fn pop_used_with_buffers(&mut self, token: u16) -> VirtIoResult<(Vec<OwnedBuffer>, u32)> {
+        let buffers = self.owned.remove(&token).ok_or(VirtIoError::WrongToken)?;
+        match self.pop_used(token) {
+            Ok(len) => Ok((buffers, len)),
+            Err(e) => {
+                self.owned.insert(token, buffers);
+                Err(e)
+            }
         }
-        self.pop_used(token)
     }
 
     /// Returns whether the driver should notify the device after adding a new buffer to the
     /// virtqueue.
     ///
-    /// This will be false if the device has supressed notifications.
-    pub fn should_notify(&self) -> bool {
-        // if self.event_idx {
-        //     // instance of UsedRing.
-        //     let avail_event = unsafe { (*self.used.as_ptr()).avail_event };
-        //     self.avail_idx >= avail_event.wrapping_add(1)
-        // } else {
-        //     // instance of UsedRing.
-        //     unsafe { (*self.used.as_ptr()).flags & 0x0001 == 0 }
-        // }
-        self.queue_ref.used_ring.flags.load(Ordering::Acquire) & 0x0001 == 0
+    /// This will be false if the device has supressed notifications, unless the queue
+    /// [never suppresses](EventSuppression::Never) them. With event indices, it is only true if
+    /// the buffers added since the last call include the one the device asked to hear about, so
+    /// the caller must notify the device whenever it returns true.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_kick_prepare_split
+    pub fn should_notify(&mut self) -> bool {
+        // The device must see the new available index before we read its event index or flags.
+        fence(Ordering::SeqCst);
+        let new = self.queue_ref.avail_ring.idx.load(Ordering::Acquire);
+        let old = core::mem::replace(&mut self.notified_avail, new);
+        if self.suppression == EventSuppression::Never {
+            return true;
+        }
+        if self.event_idx {
+            let avail_event = self.queue_ref.used_ring.avail_event(self.size);
+            need_event(avail_event, new, old)
+        } else {
+            self.queue_ref.used_ring.flags.load(Ordering::Acquire) & USED_F_NO_NOTIFY == 0
+        }
     }
 
     /// Add buffers to the virtqueue, return a token.
     ///
-    /// The buffers must not be empty.
+    /// Returns [`VirtIoError::InvalidParam`] if no buffers are given, if a device-readable buffer
+    /// follows a device-writable one, or if the buffers add up to more than 4 GiB. The `NEXT` flag
+    /// is set on every descriptor but the last, whatever the caller passed.
+    ///
+    /// Each buffer is translated with [`Hal::share`], taking up a descriptor for every range of
+    /// physical memory it occupies.
+    ///
+    /// Ref: 2.7.4.2 Driver Requirements: Message Framing, 2.7.5.2 Driver Requirements: The
+    /// Virtqueue Descriptor Table
     ///
     /// Ref: linux virtio_ring.c virtqueue_add
     ///
@@ -117,59 +547,231 @@ impl<H: Hal<SIZE>, const SIZE: usize> VirtIoQueue<H, SIZE> {
     ///
     /// The input and output buffers must remain valid and not be accessed until a call to
     /// `pop_used` with the returned token succeeds.
-    pub(super) fn add(&mut self, data: Vec<Descriptor>) -> VirtIoResult<u16> {
-        assert_ne!(data.len(), 0);
+    pub(super) fn add(&mut self, buffers: Vec<DmaBuf<'_>>) -> VirtIoResult<u16> {
+        if buffers.is_empty() {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let first_writable = buffers
+            .iter()
+            .position(DmaBuf::device_writes)
+            .unwrap_or(buffers.len());
+        if !buffers
+            .iter()
+            .skip(first_writable)
+            .all(DmaBuf::device_writes)
+        {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let total_len: u64 = buffers.iter().map(|buf| buf.len() as u64).sum();
+        if total_len > u32::MAX.into() {
+            return Err(VirtIoError::InvalidParam);
+        }
+        let mut data = Vec::with_capacity(buffers.len());
+        let mut shared = Vec::new();
+        for buf in buffers {
+            let ranges = buf.ranges::<H, SIZE>();
+            let complete = ranges.iter().map(|&(_, len)| len).sum::<usize>() == buf.len();
+            data.extend(
+                ranges
+                    .iter()
+                    .map(|&(paddr, len)| Descriptor::new(paddr, len, buf.direction())),
+            );
+            if buf.paddr().is_none() {
+                shared.push(SharedBuf {
+                    vaddr: buf.vaddr(),
+                    len: buf.len(),
+                    direction: buf.direction(),
+                    ranges,
+                });
+            }
+            if !complete {
+                Self::unshare(shared);
+                return Err(VirtIoError::DmaError);
+            }
+        }
         if self.avail_desc_index.len() < data.len() {
+            Self::unshare(shared);
             return Err(VirtIoError::QueueFull);
         }
         let mut last = None;
         let desc = &mut self.queue_ref.descriptor_table;
-        let avail_ring = &mut self.queue_ref.avail_ring;
         for mut d in data.into_iter().rev() {
-            let id = self.avail_desc_index.pop_front().unwrap();
-            if let Some(nex) = last {
-                d.next = nex;
-            }
-            desc[id as usize % SIZE] = d;
+            let id = self
+                .avail_desc_index
+                .pop_front()
+                .ok_or(VirtIoError::QueueFull)?;
+            let flags = d.flags.get();
+            d.flags = Le16::new(match last {
+                Some(nex) => {
+                    d.next = Le16::new(nex);
+                    flags | DescFlag::NEXT
+                }
+                None => flags & !DescFlag::NEXT,
+            });
+            desc[id as usize % self.size] = d;
             last = Some(id);
         }
         fence(Ordering::SeqCst);
-        let head = last.unwrap();
+        let head = last.ok_or(VirtIoError::InvalidParam)?;
+        self.shared[head as usize] = shared;
+        self.high_water_mark = self
+            .high_water_mark
+            .max(self.size - self.avail_desc_index.len());
+        if let Some((token, added_at)) = self.sole_outstanding.take() {
+            self.outstanding.insert(token, added_at);
+            self.outstanding.insert(head, self.completions);
+        } else if self.outstanding.is_empty() {
+            self.sole_outstanding = Some((head, self.completions));
+        } else {
+            self.outstanding.insert(head, self.completions);
+        }
+        // Ask for an interrupt before the device can see the buffers, so it can't be missed.
+        self.update_interrupt_suppression();
         // change the avail ring
-        avail_ring.push(head)?;
+        self.queue_ref.avail_ring.push(head, self.size)?;
         Ok(head)
     }
 
-    pub(crate) fn can_pop(&self, id: u16) -> VirtIoResult<bool> {
+    /// Gives buffers translated with [`Hal::share`] back to the HAL, see [`Hal::unshare`].
+    fn unshare(shared: Vec<SharedBuf>) {
+        for buf in shared {
+            H::unshare(buf.vaddr, buf.len, buf.direction, &buf.ranges);
+        }
+    }
+
+    /// Copies the entries the device added to the used ring since the last call into the
+    /// completion table, and returns how many there were.
+    ///
+    /// Each entry is only visited once, so this is cheap to call on every check and from interrupt
+    /// handlers.
+    pub(crate) fn collect_used(&mut self) -> usize {
         let used_ring = &self.queue_ref.used_ring;
         let idx = used_ring.idx.load(Ordering::Acquire);
-        if self.last_seen_used == idx {
-            return Ok(false);
-        }
-        let skip = idx.wrapping_sub(self.last_seen_used);
-        let mut current_index = self.last_seen_used;
-        for _ in 0..skip {
-            if used_ring.ring[current_index as usize % SIZE].id == id as u32 {
-                return Ok(true);
+        let new = idx.wrapping_sub(self.last_seen_used);
+        while self.last_seen_used != idx {
+            let elem = used_ring.ring[self.last_seen_used as usize % self.size];
+            let token = elem.id.get() as u16;
+            // Ignore a misbehaving device reporting tokens which aren't in flight, rather than
+            // freeing descriptors which are still in use.
+            if self.is_outstanding(token)
+                && self.completed[token as usize]
+                    .replace(elem.len.get())
+                    .is_none()
+            {
+                self.ready.push_back(token);
+                if let Some(waker) = self.wakers.remove(&token) {
+                    waker.wake();
+                }
+            }
+            self.last_seen_used = self.last_seen_used.wrapping_add(1);
+        }
+        if !self.ready.is_empty() {
+            if let Some(waker) = self.any_waker.take() {
+                waker.wake();
             }
-            current_index = current_index.wrapping_add(1);
         }
-        Ok(false)
+        // The entries are no longer needed, so the device can interrupt for the next one.
+        self.update_interrupt_suppression();
+        new as usize
     }
-    /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
-    /// `None` if the used ring is empty.
-    pub(crate) fn peek_used(&self) -> Option<u16> {
-        let used_ring = &self.queue_ref.used_ring;
-        if self.last_seen_used == used_ring.idx.load(Ordering::Acquire) {
-            return None;
+
+    /// Returns whether the device has completed the given token, so it can be popped.
+    pub(crate) fn can_pop(&mut self, id: u16) -> VirtIoResult<bool> {
+        self.collect_used();
+        Ok(self
+            .completed
+            .get(id as usize)
+            .ok_or(VirtIoError::WrongToken)?
+            .is_some())
+    }
+
+    /// Returns whether the token was added and hasn't been popped yet.
+    pub(crate) fn is_outstanding(&self, token: u16) -> bool {
+        match self.sole_outstanding {
+            Some((sole, _)) => sole == token,
+            None => self.outstanding.contains_key(&token),
+        }
+    }
+
+    /// Returns the number of tokens which were added and haven't been popped yet.
+    fn in_flight(&self) -> usize {
+        usize::from(self.sole_outstanding.is_some()) + self.outstanding.len()
+    }
+
+    /// Returns the tokens which were added and haven't been popped yet, with the value of
+    /// `completions` when they were added.
+    fn outstanding_tokens(&self) -> impl Iterator<Item = (u16, u64)> + '_ {
+        self.sole_outstanding.into_iter().chain(
+            self.outstanding
+                .iter()
+                .map(|(&token, &added_at)| (token, added_at)),
+        )
+    }
+
+    /// Returns the descriptor index (a.k.a. token) of the oldest completion without popping it, or
+    /// `None` if there is none.
+    #[cfg(any(
+        feature = "block",
+        feature = "input",
+        feature = "net",
+        feature = "scsi",
+        feature = "socket",
+        feature = "sound"
+    ))]
+    pub(crate) fn peek_used(&mut self) -> Option<u16> {
+        self.collect_used();
+        self.ready.front().copied()
+    }
+
+    /// Returns whether the device has completed the given token like [`Self::can_pop`], and if it
+    /// hasn't, registers the task to be woken once [`Self::collect_used`] finds it, e.g. when the
+    /// driver acknowledges an interrupt.
+    ///
+    /// Returns [`VirtIoError::WrongToken`] if the token isn't in flight.
+    #[cfg(any(feature = "block", feature = "console", feature = "net"))]
+    pub(crate) fn poll_used(&mut self, token: u16, cx: &mut Context) -> Poll<VirtIoResult<()>> {
+        if !self.is_outstanding(token) {
+            return Poll::Ready(Err(VirtIoError::WrongToken));
+        }
+        // Drop the waker from an earlier poll first, so collecting the completion here doesn't
+        // wake the task which is already running.
+        self.wakers.remove(&token);
+        if let Ok(true) = self.can_pop(token) {
+            return Poll::Ready(Ok(()));
+        }
+        self.wakers.insert(token, cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Waits until the device has completed the given token, without popping it.
+    #[cfg(any(feature = "block", feature = "console", feature = "net"))]
+    pub(crate) async fn wait_used(&mut self, token: u16) -> VirtIoResult<()> {
+        poll_fn(|cx| self.poll_used(token, cx)).await
+    }
+
+    /// Returns the oldest completion like [`Self::peek_used`], and if there is none, registers the
+    /// task to be woken once [`Self::collect_used`] finds one.
+    #[cfg(feature = "input")]
+    pub(crate) fn poll_any_used(&mut self, cx: &mut Context) -> Poll<u16> {
+        self.any_waker = None;
+        match self.peek_used() {
+            Some(token) => Poll::Ready(token),
+            None => {
+                self.any_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
         }
-        let id = used_ring.ring[self.last_seen_used as usize % SIZE].id;
-        Some(id as _)
+    }
+
+    /// Waits until the device has completed any token, and returns the oldest without popping it.
+    #[cfg(feature = "input")]
+    pub(crate) async fn wait_any_used(&mut self) -> u16 {
+        poll_fn(|cx| self.poll_any_used(cx)).await
     }
 
     pub fn get_desc_len(&self, id: u16) -> usize {
         let descs = &self.queue_ref.descriptor_table;
-        descs[id as usize].len as _
+        descs[id as usize].len.get() as _
     }
 
     /// Returns the number of free descriptors.
@@ -185,76 +787,326 @@ impl<H: Hal<SIZE>, const SIZE: usize> VirtIoQueue<H, SIZE> {
         self.avail_desc_index.len()
     }
 
-    /// If the given token is next on the device used queue, pops it and returns the total buffer
-    /// length which was used (written) by the device.
+    /// Writes the descriptor table, ring indices and flags, free list and outstanding tokens as a
+    /// compact table, for debugging requests the device never seems to complete.
+    pub fn dump_state(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let avail_ring = &self.queue_ref.avail_ring;
+        let used_ring = &self.queue_ref.used_ring;
+        writeln!(out, "queue {} (size {})", self.queue_idx, self.size)?;
+        writeln!(
+            out,
+            "avail: idx={} flags={:#x} used_event={}",
+            avail_ring.idx.load(Ordering::Acquire),
+            avail_ring.flags.load(Ordering::Acquire),
+            avail_ring.used_event(self.size).load(Ordering::Acquire),
+        )?;
+        writeln!(
+            out,
+            "used:  idx={} flags={:#x} avail_event={} last_seen={}",
+            used_ring.idx.load(Ordering::Acquire),
+            used_ring.flags.load(Ordering::Acquire),
+            used_ring.avail_event(self.size),
+            self.last_seen_used,
+        )?;
+        writeln!(
+            out,
+            "{:>4} {:>18} {:>8} {:>5} {:>4}",
+            "desc", "addr", "len", "flags", "next"
+        )?;
+        for (i, desc) in self
+            .queue_ref
+            .descriptor_table
+            .iter()
+            .take(self.size)
+            .enumerate()
+        {
+            writeln!(
+                out,
+                "{:>4} {:#18x} {:>8} {:>5} {:>4}",
+                i,
+                desc.addr.get(),
+                desc.len.get(),
+                DescFlag::describe(desc.flags.get()),
+                desc.next.get(),
+            )?;
+        }
+        write!(out, "free:")?;
+        for id in &self.avail_desc_index {
+            write!(out, " {}", id)?;
+        }
+        writeln!(out)?;
+        write!(out, "completed:")?;
+        for token in &self.ready {
+            write!(out, " {}", token)?;
+        }
+        writeln!(out)?;
+        write!(out, "outstanding:")?;
+        for (token, added_at) in self.outstanding_tokens() {
+            write!(out, " {}(+{})", token, self.completions - added_at)?;
+        }
+        writeln!(out)
+    }
+
+    /// Takes checksums of every descriptor and ring entry, to later find out with [`Self::diff`]
+    /// which of them a request changed.
+    pub fn snapshot(&self) -> RingSnapshot {
+        let avail_ring = &self.queue_ref.avail_ring;
+        let used_ring = &self.queue_ref.used_ring;
+        RingSnapshot {
+            descriptors: self
+                .queue_ref
+                .descriptor_table
+                .iter()
+                .take(self.size)
+                .map(Descriptor::crc)
+                .collect(),
+            avail_header: crc32(
+                [
+                    avail_ring.flags.load(Ordering::Acquire),
+                    avail_ring.idx.load(Ordering::Acquire),
+                    avail_ring.used_event(self.size).load(Ordering::Acquire),
+                ]
+                .iter()
+                .flat_map(|v| v.to_le_bytes()),
+            ),
+            avail_ring: avail_ring
+                .ring
+                .iter()
+                .take(self.size)
+                .map(|id| crc32(id.load(Ordering::Acquire).to_le_bytes()))
+                .collect(),
+            used_header: crc32(
+                [
+                    used_ring.flags.load(Ordering::Acquire),
+                    used_ring.idx.load(Ordering::Acquire),
+                    used_ring.avail_event(self.size),
+                ]
+                .iter()
+                .flat_map(|v| v.to_le_bytes()),
+            ),
+            used_ring: used_ring
+                .ring
+                .iter()
+                .take(self.size)
+                .map(|elem| {
+                    crc32(
+                        elem.id
+                            .get()
+                            .to_le_bytes()
+                            .into_iter()
+                            .chain(elem.len.get().to_le_bytes()),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Compares the queue against an earlier [`snapshot`](Self::snapshot).
+    ///
+    /// After a single request completes, only the descriptors of its chain (see
+    /// [`Self::descriptor_chain`]), one slot of each ring and the ring headers should have
+    /// changed. Anything else points to the device or the HAL writing outside the request.
+    pub fn diff(&self, snapshot: &RingSnapshot) -> RingDiff {
+        let now = self.snapshot();
+        RingDiff {
+            descriptors: changed(&snapshot.descriptors, &now.descriptors),
+            avail_header: snapshot.avail_header != now.avail_header,
+            avail_ring: changed(&snapshot.avail_ring, &now.avail_ring),
+            used_header: snapshot.used_header != now.used_header,
+            used_ring: changed(&snapshot.used_ring, &now.used_ring),
+        }
+    }
+
+    /// Returns the indices of the descriptors chained from `head`, in order.
+    ///
+    /// The descriptor table isn't cleared when a token is popped, so this also works for requests
+    /// which already completed, as long as the descriptors weren't reused.
+    pub fn descriptor_chain(&self, head: u16) -> Vec<u16> {
+        let desc = &self.queue_ref.descriptor_table;
+        let mut chain = Vec::new();
+        let mut now = head as usize % self.size;
+        // A corrupted table may contain a loop, but no valid chain is longer than the queue.
+        while chain.len() < self.size {
+            chain.push(now as u16);
+            if desc[now].flags.get() & DescFlag::NEXT == 0 {
+                break;
+            }
+            now = desc[now].next.get() as usize % self.size;
+        }
+        chain
+    }
+
+    /// Returns the most descriptors which have been in use at the same time.
+    ///
+    /// If this reaches the queue size while the device is idle, buffers are being added without
+    /// ever being popped.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    /// Returns the number of completions, the number of requests in flight and the high water mark
+    /// of the queue.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            index: self.queue_idx,
+            completions: self.completions,
+            in_flight: self.in_flight(),
+            high_water_mark: self.high_water_mark,
+        }
+    }
+
+    /// Returns the tokens which are still outstanding after more than `completions` later tokens
+    /// were popped, oldest first.
+    ///
+    /// Devices may complete buffers out of order, but a token which stays outstanding while many
+    /// others complete usually means the caller forgot to pop it.
+    pub fn leak_report(&self, completions: u64) -> Vec<OutstandingToken> {
+        let mut report: Vec<_> = self
+            .outstanding_tokens()
+            .map(|(token, added_at)| OutstandingToken {
+                token,
+                completions_since: self.completions - added_at,
+            })
+            .filter(|t| t.completions_since > completions)
+            .collect();
+        report.sort_by_key(|t| Reverse(t.completions_since));
+        report
+    }
+
+    /// If the device has completed the given token, pops it and returns the total buffer length
+    /// which was used (written) by the device.
+    ///
+    /// Returns [`VirtIoError::NotReady`] if it hasn't completed yet. Tokens may be popped in any
+    /// order.
     ///
     /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
     ///
     /// # Safety
     ///
     /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
-    /// queue by `add` when it returned the token being passed in here.
+    /// queue by `add` when it returned the token being passed in here. Tokens from
+    /// [`Self::add_with_return_buffers`] have no such requirement.
     pub(crate) fn pop_used(&mut self, id: u16) -> VirtIoResult<u32> {
-        if !self.can_pop(id)? {
-            return Err(VirtIoError::NotReady);
+        self.collect_used();
+        let len = self
+            .completed
+            .get_mut(id as usize)
+            .ok_or(VirtIoError::WrongToken)?
+            .take()
+            .ok_or(VirtIoError::NotReady)?;
+        let ready = &mut self.ready;
+        // Tokens are usually popped in the order they completed, so this is almost always the
+        // first one.
+        if let Some(pos) = ready.iter().position(|&token| token == id) {
+            ready.remove(pos);
         }
-        let used_ring = &mut self.queue_ref.used_ring;
-        let desc = &self.queue_ref.descriptor_table;
-        let idx = used_ring.idx.load(Ordering::Acquire);
-        assert_ne!(self.last_seen_used, idx);
-        let mut header = self.last_seen_used.wrapping_sub(1);
-        let skip = idx.wrapping_sub(self.last_seen_used);
-        let mut tmp_index = self.last_seen_used;
-        for _ in 0..skip {
-            if used_ring.ring[tmp_index as usize % SIZE].id == id as u32 {
-                header = tmp_index;
-                break;
+        match self.sole_outstanding {
+            Some((token, _)) if token == id => self.sole_outstanding = None,
+            _ => {
+                self.outstanding.remove(&id);
             }
-            tmp_index = tmp_index.wrapping_add(1);
         }
-        // make sure we find the header
-        assert_ne!(header, self.last_seen_used.wrapping_sub(1));
-        self.poped_used.insert(header);
+        self.wakers.remove(&id);
+        // The device is done with the buffers, so bounce buffers can be copied back before the
+        // caller sees them, and buffers the queue owns freed if the caller didn't want them back.
+        Self::unshare(core::mem::take(&mut self.shared[id as usize]));
+        self.owned.remove(&id);
+        self.completions += 1;
+        self.update_interrupt_suppression();
 
+        let desc = &self.queue_ref.descriptor_table;
         let mut now = id as usize;
-        // todo!(fix it)
-        let len = used_ring.ring[header as usize % SIZE].len;
         self.avail_desc_index.push_back(now as _);
-        while (desc[now].flags & DescFlag::NEXT) != 0 {
-            now = desc[now % SIZE].next as _;
+        while (desc[now].flags.get() & DescFlag::NEXT) != 0 {
+            now = desc[now % self.size].next.get() as _;
             self.avail_desc_index.push_back(now as _);
         }
-        // update last_seen_used
-        while self.poped_used.contains(&self.last_seen_used) {
-            self.poped_used.remove(&self.last_seen_used);
-            self.last_seen_used = self.last_seen_used.wrapping_add(1);
-            self.queue_ref
-                .avail_ring
-                .used_event
-                .store(self.last_seen_used, Ordering::Release);
-        }
         Ok(len)
     }
 }
 
-pub struct QueueLayout {
-    pub descriptor_table_offset: usize,
-    pub avail_ring_offset: usize,
-    pub used_ring_offset: usize,
+impl<H: Hal<SIZE>, const SIZE: usize> Drop for VirtIoQueue<H, SIZE> {
+    fn drop(&mut self) {
+        // The queue page goes back to the HAL when it is dropped after this, so the driver must
+        // have reset the device or unset the queue first. Buffers the device hasn't used may
+        // still be in a request it is processing though, so they must not go back to the
+        // allocator.
+        for (_, buffers) in core::mem::take(&mut self.owned) {
+            core::mem::forget(buffers);
+        }
+    }
+}
+
+/// Where the parts of a virtqueue live within its [`QueuePage`].
+///
+/// Only the driver creates layouts; a HAL receives one in [`QueuePage::queue_ref_mut`] and turns
+/// the pointers from [`QueueLayout::ring_pointers`] into references.
+#[derive(Debug)]
+pub struct QueueLayout<const SIZE: usize> {
+    descriptor_table_offset: usize,
+    avail_ring_offset: usize,
+    used_ring_offset: usize,
+    size: usize,
 }
 
-impl QueueLayout {
-    pub fn new<const SIZE: usize>() -> Self {
+impl<const SIZE: usize> QueueLayout<SIZE> {
+    pub(crate) const fn new() -> Self {
+        let used_ring_offset =
+            align_up(size_of::<Descriptor>() * SIZE + size_of::<AvailRing<SIZE>>());
         Self {
             descriptor_table_offset: 0,
             avail_ring_offset: size_of::<Descriptor>() * SIZE,
-            used_ring_offset: align_up(
-                size_of::<Descriptor>() * SIZE + size_of::<AvailRing<SIZE>>(),
-            ),
+            used_ring_offset,
+            size: used_ring_offset + align_up(size_of::<UsedRing<SIZE>>()),
         }
     }
+
+    /// The number of bytes the queue needs, starting at the beginning of the page.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns pointers to the descriptor table and both rings for a queue page mapped at `base`.
+    ///
+    /// The pointers are suitably aligned if `base` is page aligned, and don't overlap each other.
+    pub fn ring_pointers(&self, base: VirtAddr) -> RingPointers<SIZE> {
+        RingPointers {
+            descriptor_table: (base + self.descriptor_table_offset) as *mut Descriptor,
+            avail_ring: (base + self.avail_ring_offset) as *mut AvailRing<SIZE>,
+            used_ring: (base + self.used_ring_offset) as *mut UsedRing<SIZE>,
+        }
+    }
+
+    /// Checks that the references a HAL returned are the ones described by this layout.
+    fn check(&self, base: VirtAddr, queue_ref: &QueueMutRef<SIZE>) -> VirtIoResult<()> {
+        let expected = self.ring_pointers(base);
+        if queue_ref.descriptor_table.len() != SIZE
+            || !core::ptr::eq(
+                queue_ref.descriptor_table.as_ptr(),
+                expected.descriptor_table,
+            )
+            || !core::ptr::eq(&*queue_ref.avail_ring, expected.avail_ring)
+            || !core::ptr::eq(&*queue_ref.used_ring, expected.used_ring)
+        {
+            return Err(VirtIoError::DmaError);
+        }
+        Ok(())
+    }
+}
+
+/// Typed pointers to the parts of a virtqueue, see [`QueueLayout::ring_pointers`].
+#[derive(Debug)]
+pub struct RingPointers<const SIZE: usize> {
+    /// The first of `SIZE` descriptors.
+    pub descriptor_table: *mut Descriptor,
+    pub avail_ring: *mut AvailRing<SIZE>,
+    pub used_ring: *mut UsedRing<SIZE>,
 }
 
+/// References to the parts of a virtqueue, handed out by [`QueuePage::queue_ref_mut`].
+///
+/// The `'static` lifetimes really last as long as the [`QueuePage`] they point into, which the
+/// queue keeps alive for longer than these references.
 pub struct QueueMutRef<const SIZE: usize> {
     pub descriptor_table: &'static mut [Descriptor],
     pub avail_ring: &'static mut AvailRing<SIZE>,
@@ -262,72 +1114,231 @@ pub struct QueueMutRef<const SIZE: usize> {
 }
 
 #[repr(C, align(16))]
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Descriptor {
-    addr: u64,
-    len: u32,
-    flags: u16,
-    next: u16,
+    addr: Le64,
+    len: Le32,
+    flags: Le16,
+    next: Le16,
 }
-impl Default for Descriptor {
-    fn default() -> Self {
+impl Descriptor {
+    /// Describes `len` bytes of physical memory from `paddr` on, with the flags of the buffer they
+    /// are part of.
+    fn new(paddr: PhysAddr, len: usize, direction: BufferDirection) -> Self {
         Self {
-            addr: Default::default(),
-            len: Default::default(),
-            flags: Default::default(),
-            next: Default::default(),
+            addr: Le64::new(paddr as _),
+            // Saturated rather than wrapped, so the device is never told a buffer is longer than it
+            // is.
+            len: Le32::new(u32::try_from(len).unwrap_or(u32::MAX)),
+            flags: match direction {
+                BufferDirection::DriverToDevice => DescFlag::EMPTY,
+                BufferDirection::DeviceToDriver | BufferDirection::Both => DescFlag::WRITE,
+            }
+            .into(),
+            next: Le16::new(0),
         }
     }
+
+    /// The checksum of the descriptor, for [`RingSnapshot`].
+    fn crc(&self) -> u32 {
+        crc32(
+            self.addr
+                .get()
+                .to_le_bytes()
+                .into_iter()
+                .chain(self.len.get().to_le_bytes())
+                .chain(self.flags.get().to_le_bytes())
+                .chain(self.next.get().to_le_bytes()),
+        )
+    }
 }
-impl Descriptor {
-    pub(crate) fn new<const SIZE: usize, H: Hal<SIZE>>(vaddr: usize, len: u32, flags: u16) -> Self {
-        Self {
-            addr: H::to_paddr(vaddr) as _,
-            len,
-            flags,
-            next: 0,
+/// A buffer to give to the device, by whether the device reads or writes it.
+///
+/// The direction is part of the type, so a buffer can only be given to the device to write if the
+/// caller may mutate it.
+#[derive(Debug)]
+pub enum Buffer<'a> {
+    /// A buffer which the device reads.
+    Read(&'a [u8]),
+    /// A buffer which the device writes.
+    Write(&'a mut [u8]),
+}
+
+/// A buffer which a queue owns while the device uses it, see
+/// [`VirtIoQueue::add_with_return_buffers`].
+#[derive(Debug, Eq, PartialEq)]
+pub enum OwnedBuffer {
+    /// A buffer which the device reads.
+    Read(Box<[u8]>),
+    /// A buffer which the device writes.
+    Write(Box<[u8]>),
+}
+
+impl OwnedBuffer {
+    /// The contents of the buffer, whichever direction it is.
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Self::Read(buf) | Self::Write(buf) => buf,
+        }
+    }
+
+    /// Takes the contents of the buffer back, whichever direction it is.
+    pub fn into_inner(self) -> Box<[u8]> {
+        match self {
+            Self::Read(buf) | Self::Write(buf) => buf,
+        }
+    }
+
+    /// Describes the buffer. Its heap allocation doesn't move when the buffer does, so the
+    /// description stays valid while the queue holds the buffer.
+    fn dma_buf(&mut self) -> DmaBuf<'_> {
+        match self {
+            Self::Read(buf) => DmaBuf::readable(&buf[..]),
+            Self::Write(buf) => DmaBuf::writable(&mut buf[..]),
+        }
+    }
+}
+
+impl<'a> From<Buffer<'a>> for DmaBuf<'a> {
+    fn from(buffer: Buffer<'a>) -> Self {
+        match buffer {
+            Buffer::Read(buf) => Self::readable(buf),
+            Buffer::Write(buf) => Self::writable(buf),
+        }
+    }
+}
+
+impl<'a> Buffer<'a> {
+    /// The length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Read(buf) => buf.len(),
+            Self::Write(buf) => buf.len(),
+        }
+    }
+
+    /// Whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The address of the start of the buffer.
+    #[cfg(feature = "block")]
+    pub(crate) fn addr(&self) -> usize {
+        match self {
+            Self::Read(buf) => buf.as_ptr() as usize,
+            Self::Write(buf) => buf.as_ptr() as usize,
+        }
+    }
+
+    /// Splits the buffer in two at `mid`, keeping the direction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mid > len`.
+    pub fn split_at(self, mid: usize) -> (Self, Self) {
+        match self {
+            Self::Read(buf) => {
+                let (head, tail) = buf.split_at(mid);
+                (Self::Read(head), Self::Read(tail))
+            }
+            Self::Write(buf) => {
+                let (head, tail) = buf.split_at_mut(mid);
+                (Self::Write(head), Self::Write(tail))
+            }
         }
     }
 }
-pub struct DescFlag;
+
+struct DescFlag;
 impl DescFlag {
-    pub(crate) const EMPTY: u16 = 0;
-    pub(crate) const NEXT: u16 = 1;
-    pub(crate) const WRITE: u16 = 2;
+    const EMPTY: u16 = 0;
+    const NEXT: u16 = 1;
+    const WRITE: u16 = 2;
     const INDIRECT: u16 = 4;
+
+    /// A short form of the flags for dumps, e.g. `NW` for `NEXT | WRITE`.
+    fn describe(flags: u16) -> &'static str {
+        match flags & (Self::NEXT | Self::WRITE | Self::INDIRECT) {
+            0 => "-",
+            Self::NEXT => "N",
+            Self::WRITE => "W",
+            f if f == Self::NEXT | Self::WRITE => "NW",
+            _ => "I",
+        }
+    }
 }
 #[repr(C)]
 #[derive(Debug)]
 pub struct AvailRing<const SIZE: usize> {
-    flags: AtomicU16,
+    flags: AtomicLe16,
     /// A driver MUST NOT decrement the idx.
-    idx: AtomicU16,
-    ring: [u16; SIZE],
+    idx: AtomicLe16,
+    ring: [AtomicLe16; SIZE],
     /// Only used if `VIRTIO_F_EVENT_IDX` is negotiated.
-    used_event: AtomicU16,
+    used_event: AtomicLe16,
 }
 impl<const SIZE: usize> AvailRing<SIZE> {
-    fn push(&mut self, id: u16) -> VirtIoResult<u16> {
+    #[cfg(feature = "heap-dma")]
+    pub(crate) fn new() -> Self {
+        Self {
+            flags: AtomicLe16::new(0),
+            idx: AtomicLe16::new(0),
+            ring: core::array::from_fn(|_| AtomicLe16::new(0)),
+            used_event: AtomicLe16::new(0),
+        }
+    }
+
+    /// Adds `id` to a ring of `size` entries.
+    fn push(&mut self, id: u16, size: usize) -> VirtIoResult<u16> {
         // have enough space, because (avail ring's len == desc's)
         let res = self.idx.load(Ordering::Acquire);
-        self.ring[res as usize % SIZE] = id;
+        self.ring[res as usize % size].store(id, Ordering::Relaxed);
         self.idx.store(res.wrapping_add(1), Ordering::Release);
         Ok(res)
     }
+
+    /// The `used_event` field of a ring of `size` entries, which the device expects right after
+    /// the last of them.
+    fn used_event(&self, size: usize) -> &AtomicLe16 {
+        self.ring.get(size).unwrap_or(&self.used_event)
+    }
 }
 #[repr(C)]
 #[derive(Debug)]
 pub struct UsedRing<const SIZE: usize> {
-    flags: AtomicU16,
-    idx: AtomicU16,
+    flags: AtomicLe16,
+    idx: AtomicLe16,
     ring: [UsedElem; SIZE],
     /// Only used if `VIRTIO_F_EVENT_IDX` is negotiated.
-    avail_event: AtomicU16,
+    avail_event: AtomicLe16,
+}
+
+impl<const SIZE: usize> UsedRing<SIZE> {
+    #[cfg(feature = "heap-dma")]
+    pub(crate) fn new() -> Self {
+        Self {
+            flags: AtomicLe16::new(0),
+            idx: AtomicLe16::new(0),
+            ring: [UsedElem::default(); SIZE],
+            avail_event: AtomicLe16::new(0),
+        }
+    }
+
+    /// The `avail_event` field of a ring of `size` entries, which the device writes right after
+    /// the last of them.
+    fn avail_event(&self, size: usize) -> u16 {
+        match self.ring.get(size) {
+            // Little-endian, so the field is the low half of the next entry's ID.
+            Some(elem) => elem.id.get() as u16,
+            None => self.avail_event.load(Ordering::Acquire),
+        }
+    }
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy)]
 struct UsedElem {
-    id: u32,
-    len: u32,
+    id: Le32,
+    len: Le32,
 }
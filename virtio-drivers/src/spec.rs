@@ -0,0 +1,64 @@
+//! Registers and constants defined by the virtio specification for every device type and
+//! transport.
+//!
+//! They are re-exported where the drivers use them, e.g. [`crate::transport::DeviceStatus`], so
+//! kernel and HAL code reading the registers directly can use the same definitions.
+
+use bitflags::bitflags;
+
+bitflags! {
+    /// The device status field. Writing 0 into this field resets the device.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct DeviceStatus: u32 {
+        /// Indicates that the guest OS has found the device and recognized it
+        /// as a valid virtio device.
+        const ACKNOWLEDGE = 1;
+        /// Indicates that the guest OS knows how to drive the device.
+        const DRIVER = 2;
+        /// Indicates that something went wrong in the guest, and it has given
+        /// up on the device. This could be an internal error, or the driver
+        /// didn’t like the device for some reason, or even a fatal error
+        /// during device operation.
+        const FAILED = 128;
+        /// Indicates that the driver has acknowledged all the features it
+        /// understands, and feature negotiation is complete.
+        const FEATURES_OK = 8;
+        /// Indicates that the driver is set up and ready to drive the device.
+        const DRIVER_OK = 4;
+        /// Indicates that the device has experienced an error from which it
+        /// can’t recover.
+        const DEVICE_NEEDS_RESET = 64;
+    }
+}
+
+bitflags! {
+    /// The causes of an interrupt, as read from the MMIO `InterruptStatus` register or the PCI ISR
+    /// status.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct InterruptStatus: u32 {
+        /// The device used a buffer in at least one of the virtqueues.
+        const USED_RING_UPDATE = 1 << 0;
+        /// The configuration of the device has changed.
+        const CONFIGURATION_CHANGE = 1 << 1;
+    }
+}
+
+format_flags!(DeviceStatus, InterruptStatus);
+
+/// The MSI-X vector value which disables interrupts for a queue or for configuration changes.
+pub const NO_VECTOR: u16 = 0xffff;
+
+/// The MSI-X vector configuration change interrupts are routed to during initialization.
+pub const CONFIG_VECTOR: u16 = 0;
+
+/// The MSI-X vector the interrupts of `queue` are routed to when it is set up: one per queue,
+/// after [`CONFIG_VECTOR`], so the vector alone says which queue an interrupt is for.
+pub const fn queue_vector(queue: u16) -> u16 {
+    queue.saturating_add(1)
+}
+
+/// The feature bit a device offers when it implements version 1 of the spec rather than only the
+/// legacy interface.
+///
+/// Ref: 6.1 Driver Requirements: Reserved Feature Bits
+pub const VERSION_1: u64 = 1 << 32;
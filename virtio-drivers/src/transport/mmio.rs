@@ -1,7 +1,9 @@
-use crate::error::{MmioError, VirtIoError, VirtIoResult};
+use crate::error::{expect_ok, MmioError, VirtIoError, VirtIoResult};
 use crate::hal::VirtIoDeviceIo;
 use crate::queue::Descriptor;
-use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::transport::{
+    DeviceStatus, DeviceType, InitMilestone, InitObserver, InterruptStatus, Transport,
+};
 use crate::volatile::{ReadOnly, ReadVolatile, ReadWrite, WriteOnly, WriteVolatile};
 use crate::{align_up, PhysAddr, PAGE_SIZE};
 use alloc::boxed::Box;
@@ -198,6 +200,8 @@ pub struct MmioTransport {
     header: VirtIOHeader,
     version: MmioVersion,
     io_region: Box<dyn VirtIoDeviceIo>,
+    config_space_size: Option<usize>,
+    init_observer: Option<InitObserver>,
 }
 
 impl MmioTransport {
@@ -217,9 +221,42 @@ impl MmioTransport {
             header,
             version,
             io_region,
+            config_space_size: None,
+            init_observer: None,
         })
     }
 
+    /// Probes the device like [`Self::new`], and tells `observer` about that and every later
+    /// milestone of initializing it, see [`Self::set_init_observer`].
+    pub fn new_with_observer(
+        io_region: Box<dyn VirtIoDeviceIo>,
+        observer: InitObserver,
+    ) -> VirtIoResult<Self> {
+        let mut transport = Self::new(io_region)?;
+        transport.set_init_observer(Some(observer));
+        transport.report_init(InitMilestone::Probed {
+            device_type: transport.device_type()?,
+            legacy: transport.version == MmioVersion::Legacy,
+        });
+        Ok(transport)
+    }
+
+    /// Sets the callback told about each milestone of initializing the device, or stops reporting
+    /// them with `None`, e.g. once booting is done.
+    pub fn set_init_observer(&mut self, observer: Option<InitObserver>) {
+        self.init_observer = observer;
+    }
+
+    /// Sets the size of the device-specific config space, which follows the registers at
+    /// [`CONFIG_OFFSET`].
+    ///
+    /// MMIO devices don't report it, but the platform may know it, e.g. as the size of the
+    /// device's region in the device tree minus [`CONFIG_OFFSET`]. Without it, drivers can't tell
+    /// whether optional config fields are present.
+    pub fn set_config_space_size(&mut self, size: usize) {
+        self.config_space_size = Some(size);
+    }
+
     /// Gets the version of the VirtIO MMIO transport.
     pub fn version(&self) -> MmioVersion {
         self.version
@@ -228,7 +265,11 @@ impl MmioTransport {
     /// Gets the vendor ID.
     pub fn vendor_id(&self) -> u32 {
         // Safe because self.header points to a valid VirtIO MMIO region.
-        self.header.vendor_id.read(&self.io_region).unwrap()
+        expect_ok(
+            self.header.vendor_id.read(&self.io_region),
+            "failed to read vendor ID",
+        )
+        .unwrap_or(0)
     }
 }
 
@@ -292,6 +333,10 @@ impl Transport for MmioTransport {
         self.version == MmioVersion::Legacy
     }
 
+    fn init_observer(&self) -> Option<InitObserver> {
+        self.init_observer
+    }
+
     fn queue_set(
         &mut self,
         queue: u16,
@@ -302,20 +347,20 @@ impl Transport for MmioTransport {
     ) -> VirtIoResult<()> {
         match self.version {
             MmioVersion::Legacy => {
-                assert_eq!(
-                    driver_area - descriptors,
-                    size_of::<Descriptor>() * size as usize
-                );
-                assert_eq!(
-                    device_area - descriptors,
-                    align_up(
-                        size_of::<Descriptor>() * size as usize
-                            + size_of::<u16>() * (size as usize + 3)
-                    )
-                );
+                // The legacy interface only takes the address of the descriptor table, and
+                // expects the rings to follow it at fixed offsets.
+                if driver_area.wrapping_sub(descriptors) != size_of::<Descriptor>() * size as usize
+                    || device_area.wrapping_sub(descriptors)
+                        != align_up(
+                            size_of::<Descriptor>() * size as usize
+                                + size_of::<u16>() * (size as usize + 3),
+                        )
+                    || !descriptors.is_multiple_of(PAGE_SIZE)
+                {
+                    return Err(VirtIoError::InvalidParam);
+                }
                 let align = PAGE_SIZE as u32;
                 let pfn = (descriptors / PAGE_SIZE) as u32;
-                debug_assert_eq!(pfn as usize * PAGE_SIZE, descriptors);
                 self.header.queue_sel.write(queue as _, &self.io_region)?;
                 self.header.queue_num.write(size, &self.io_region)?;
                 self.header
@@ -369,24 +414,45 @@ impl Transport for MmioTransport {
         }
     }
 
-    fn ack_interrupt(&mut self) -> VirtIoResult<bool> {
-        let status = self.header.interrupt_status.read(&self.io_region)?;
-        if status == 0 {
-            return Ok(false);
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus> {
+        let status = self.peek_interrupt_status()?;
+        if !status.is_empty() {
+            self.header
+                .interrupt_ack
+                .write(status.bits(), &self.io_region)?;
         }
-        self.header.interrupt_ack.write(status, &self.io_region)?;
-        Ok(true)
+        Ok(status)
+    }
+
+    fn peek_interrupt_status(&self) -> VirtIoResult<InterruptStatus> {
+        Ok(InterruptStatus::from_bits_retain(
+            self.header.interrupt_status.read(&self.io_region)?,
+        ))
     }
 
     fn io_region(&self) -> &dyn VirtIoDeviceIo {
         self.io_region.as_ref()
     }
+
+    fn config_space_size(&self) -> Option<usize> {
+        self.config_space_size
+    }
+
+    fn config_generation(&self) -> VirtIoResult<u32> {
+        match self.version {
+            // Legacy devices have no generation counter.
+            MmioVersion::Legacy => Ok(0),
+            MmioVersion::Modern => self.header.config_generation.read(&self.io_region),
+        }
+    }
 }
 
 impl Drop for MmioTransport {
     fn drop(&mut self) {
         // Reset the device when the transport is dropped.
-        self.set_status(DeviceStatus::empty())
-            .expect("failed to reset device")
+        expect_ok(
+            self.set_status(DeviceStatus::empty()),
+            "failed to reset device",
+        );
     }
 }
@@ -1,10 +1,12 @@
-use crate::error::VirtIoResult;
+use crate::error::{InitStep, VirtIoError, VirtIoResult};
 use crate::hal::VirtIoDeviceIo;
+use crate::spec::VERSION_1;
+pub use crate::spec::{queue_vector, DeviceStatus, InterruptStatus, CONFIG_VECTOR, NO_VECTOR};
 use crate::{PhysAddr, PAGE_SIZE};
-use bitflags::{bitflags, Flags};
+use bitflags::Flags;
 use core::fmt::Debug;
 use core::ops::BitAnd;
-use log::debug;
+
 pub mod mmio;
 // mod pci;
 
@@ -55,37 +57,127 @@ pub trait Transport {
     /// Returns whether the queue is in use, i.e. has a nonzero PFN or is marked as ready.
     fn queue_used(&mut self, queue: u16) -> VirtIoResult<bool>;
 
-    /// Acknowledges an interrupt.
+    /// Acknowledges the pending interrupt, if any, and returns its causes, which are empty if there
+    /// was none.
+    fn ack_interrupt(&mut self) -> VirtIoResult<InterruptStatus>;
+
+    /// Reads the causes of the pending interrupt, if any, without acknowledging it.
+    ///
+    /// This lets drivers poll for completions, e.g. during early boot before the interrupt
+    /// controller is set up, and leaves the interrupt pending for [`Self::ack_interrupt`] once it
+    /// is.
+    fn peek_interrupt_status(&self) -> VirtIoResult<InterruptStatus>;
+
+    /// Routes interrupts for the given queue to an MSI-X vector, or disables them with
+    /// [`NO_VECTOR`].
+    ///
+    /// Queues are set up with [`queue_vector`]. Transports with a single interrupt line, such as
+    /// MMIO, ignore this, which the default does.
+    fn set_queue_msix_vector(&mut self, _queue: u16, _vector: u16) -> VirtIoResult<()> {
+        Ok(())
+    }
+
+    /// Routes configuration change interrupts to an MSI-X vector, or disables them with
+    /// [`NO_VECTOR`].
     ///
-    /// Returns true on success.
-    fn ack_interrupt(&mut self) -> VirtIoResult<bool>;
+    /// [`Self::begin_init`] routes them to [`CONFIG_VECTOR`]. Transports with a single interrupt
+    /// line ignore this, which the default does.
+    fn config_msix_vector(&mut self, _vector: u16) -> VirtIoResult<()> {
+        Ok(())
+    }
 
     /// Begins initializing the device.
     ///
     /// Ref: virtio 3.1.1 Device Initialization
     ///
-    /// Returns the negotiated set of features.
-    fn begin_init<F: Flags<Bits = u64> + BitAnd<Output = F> + Debug>(
+    /// Returns the negotiated set of features, which includes VERSION_1 whenever the device
+    /// offers it unless [`DeviceFeatures::ACCEPTS_VERSION_1`] is false. Before setting
+    /// FEATURES_OK, they are checked against [`DeviceFeatures::DEPENDENCIES`].
+    fn begin_init<F: DeviceFeatures + BitAnd<Output = F> + Debug>(
         &mut self,
         supported_features: F,
     ) -> VirtIoResult<F> {
-        self.set_status(DeviceStatus::empty())?;
+        self.reset()?;
         self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER)?;
 
-        let device_features = F::from_bits_truncate(self.read_device_features()?);
-        debug!("Device features: {:?}", device_features);
-        let negotiated_features = device_features & supported_features;
+        let offered = self.read_device_features()?;
+        let device_features = F::from_bits_truncate(offered);
+        // Logged as bits, as `F` need not implement `defmt::Format`.
+        debug!("Device features: {:#x}", device_features.bits());
+        let mut negotiated_features = device_features & supported_features;
+        // A modern device may refuse to work with a driver which doesn't accept VERSION_1.
+        if F::ACCEPTS_VERSION_1 {
+            negotiated_features.insert(F::from_bits_truncate(offered & VERSION_1));
+        }
         self.write_driver_features(negotiated_features.bits())?;
+        if let Err(e) = negotiated_features.check_dependencies() {
+            self.set_status(self.get_status()? | DeviceStatus::FAILED)?;
+            return Err(e);
+        }
 
         self.set_status(
             DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK,
         )?;
+        // A modern device clears FEATURES_OK if it doesn't accept the features we wrote. Legacy
+        // devices don't know about FEATURES_OK, so there is nothing to check.
+        if !self.requires_legacy_layout() && !self.get_status()?.contains(DeviceStatus::FEATURES_OK)
+        {
+            // The driver must not clear any bits the device still reports, only add FAILED.
+            self.set_status(self.get_status()? | DeviceStatus::FAILED)?;
+            return Err(VirtIoError::FeaturesNotAccepted);
+        }
 
         self.set_guest_page_size(PAGE_SIZE as u32)?;
+        self.config_msix_vector(CONFIG_VECTOR)?;
 
+        self.report_init(InitMilestone::FeaturesNegotiated {
+            offered,
+            negotiated: negotiated_features.bits(),
+        });
         Ok(negotiated_features)
     }
 
+    /// Begins initializing the device like [`Self::begin_init`], but if the device rejects the
+    /// features, retries without the optional ones it offered, one at a time and highest bit
+    /// first, until it accepts.
+    ///
+    /// Features in `required` are never dropped, so if the device rejects even those
+    /// [`VirtIoError::FeaturesNotAccepted`] is still returned. There is at most one retry per
+    /// optional feature.
+    fn begin_init_with_fallback<F: DeviceFeatures + BitAnd<Output = F> + Copy + Debug>(
+        &mut self,
+        required: F,
+        optional: F,
+    ) -> VirtIoResult<Negotiated<F>> {
+        let mut dropped = F::empty();
+        loop {
+            match self.begin_init(required.union(optional.difference(dropped))) {
+                Ok(features) => {
+                    if !dropped.is_empty() {
+                        warn!(
+                            "device only accepted {:#x} after dropping {:#x}",
+                            features.bits(),
+                            dropped.bits()
+                        );
+                    }
+                    return Ok(Negotiated { features, dropped });
+                }
+                Err(VirtIoError::FeaturesNotAccepted) => {
+                    let droppable = F::from_bits_truncate(self.read_device_features()?)
+                        .intersection(optional)
+                        .difference(required)
+                        .difference(dropped);
+                    if droppable.is_empty() {
+                        return Err(VirtIoError::FeaturesNotAccepted);
+                    }
+                    let highest = 1 << (u64::BITS - 1 - droppable.bits().leading_zeros());
+                    dropped = dropped.union(F::from_bits_retain(highest));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     /// Finishes initializing the device.
     fn finish_init(&mut self) -> VirtIoResult<()> {
         self.set_status(
@@ -93,40 +185,220 @@ pub trait Transport {
                 | DeviceStatus::DRIVER
                 | DeviceStatus::FEATURES_OK
                 | DeviceStatus::DRIVER_OK,
-        )
+        )?;
+        self.report_init(InitMilestone::DriverOk);
+        Ok(())
+    }
+
+    /// Runs a step of initializing the device. If it fails, marks the device as FAILED like
+    /// [`Self::fail_init`], so it isn't left half initialized.
+    fn init_step<R>(
+        &mut self,
+        step: InitStep,
+        f: impl FnOnce(&mut Self) -> VirtIoResult<R>,
+    ) -> VirtIoResult<R>
+    where
+        Self: Sized,
+    {
+        f(self).map_err(|e| self.fail_init(step, e))
+    }
+
+    /// Logs that the given step of initializing the device failed with `error`, sets FAILED, and
+    /// reports [`InitMilestone::Failed`] with the step. Returns `error` for the driver to return.
+    ///
+    /// Ref: 3.1.1 Driver Requirements: Device Initialization
+    fn fail_init(&mut self, step: InitStep, error: VirtIoError) -> VirtIoError {
+        error!("device initialization failed at {:?}: {}", step, error);
+        // The driver must not clear any bits the device still reports. If even that fails the
+        // device is unreachable, so the original error is all there is to report.
+        if let Ok(status) = self.get_status() {
+            let _ = self.set_status(status | DeviceStatus::FAILED);
+        }
+        self.report_init(InitMilestone::Failed(step));
+        error
+    }
+
+    /// Returns the callback to tell about each milestone of initializing the device, if any.
+    ///
+    /// Transports without one, the default, report nothing.
+    fn init_observer(&self) -> Option<InitObserver> {
+        None
+    }
+
+    /// Tells the [`Self::init_observer`], if any, that initializing the device reached
+    /// `milestone`.
+    fn report_init(&self, milestone: InitMilestone) {
+        if let Some(observer) = self.init_observer() {
+            observer(milestone);
+        }
+    }
+
+    /// Resets the device, which stops it from accessing any queues or buffers.
+    ///
+    /// Ref: virtio 4.2.3.1 Device Initialization
+    fn reset(&mut self) -> VirtIoResult<()> {
+        self.set_status(DeviceStatus::empty())?;
+        // A modern device may take a while to finish resetting.
+        while !self.requires_legacy_layout() && !self.get_status()?.is_empty() {
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Returns [`VirtIoError::NeedsReset`] if the device set DEVICE_NEEDS_RESET, after which it
+    /// won't use any more buffers until it is reset and initialized again.
+    ///
+    /// Ref: virtio 2.1.2 Device Requirements: Device Status Field
+    fn check_needs_reset(&self) -> VirtIoResult<()> {
+        if self
+            .get_status()?
+            .contains(DeviceStatus::DEVICE_NEEDS_RESET)
+        {
+            Err(VirtIoError::NeedsReset)
+        } else {
+            Ok(())
+        }
     }
 
     fn io_region(&self) -> &dyn VirtIoDeviceIo;
+
+    /// Returns the size in bytes of the device-specific configuration space, or `None` if the
+    /// transport can't tell.
+    ///
+    /// Devices may have a shorter config space than the latest version of the spec describes, so
+    /// fields beyond it must not be read.
+    fn config_space_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the generation of the device-specific configuration space, which the device changes
+    /// whenever it changes any field.
+    ///
+    /// Transports without a generation counter, like legacy MMIO, always return 0.
+    fn config_generation(&self) -> VirtIoResult<u32> {
+        Ok(0)
+    }
+
+    /// Calls `read` until the configuration space doesn't change while it runs, so that fields
+    /// wider than 32 bits, or several fields which belong together, are read consistently.
+    ///
+    /// Ref: virtio 2.5.1 Driver Requirements: Device Configuration Space
+    fn read_config_consistent<R>(&self, read: impl Fn(&Self) -> VirtIoResult<R>) -> VirtIoResult<R>
+    where
+        Self: Sized,
+    {
+        loop {
+            let before = self.config_generation()?;
+            let value = read(self)?;
+            if self.config_generation()? == before {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Checks that the device-specific configuration space is at least `len` bytes long, as far
+    /// as the transport can tell.
+    fn check_config_space(&self, len: usize) -> VirtIoResult<()> {
+        match self.config_space_size() {
+            Some(size) if size < len => Err(VirtIoError::ConfigSpaceTooSmall),
+            _ => Ok(()),
+        }
+    }
 }
 
-bitflags! {
-    /// The device status field. Writing 0 into this field resets the device.
-    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
-    pub struct DeviceStatus: u32 {
-        /// Indicates that the guest OS has found the device and recognized it
-        /// as a valid virtio device.
-        const ACKNOWLEDGE = 1;
-        /// Indicates that the guest OS knows how to drive the device.
-        const DRIVER = 2;
-        /// Indicates that something went wrong in the guest, and it has given
-        /// up on the device. This could be an internal error, or the driver
-        /// didn’t like the device for some reason, or even a fatal error
-        /// during device operation.
-        const FAILED = 128;
-        /// Indicates that the driver has acknowledged all the features it
-        /// understands, and feature negotiation is complete.
-        const FEATURES_OK = 8;
-        /// Indicates that the driver is set up and ready to drive the device.
-        const DRIVER_OK = 4;
-        /// Indicates that the device has experienced an error from which it
-        /// can’t recover.
-        const DEVICE_NEEDS_RESET = 64;
+/// The feature bits of a device type, with the dependencies the spec defines between them.
+pub trait DeviceFeatures: Flags<Bits = u64> {
+    /// Features which may only be negotiated together with others.
+    const DEPENDENCIES: &'static [FeatureDependency] = &[];
+
+    /// Whether the driver implements the device as version 1 of the spec defines it, so
+    /// [`Transport::begin_init`] accepts VERSION_1 whenever the device offers it.
+    ///
+    /// Ref: 6.1 Driver Requirements: Reserved Feature Bits
+    const ACCEPTS_VERSION_1: bool = true;
+
+    /// Checks that every feature in the set comes with at least one of the features it depends
+    /// on.
+    fn check_dependencies(&self) -> VirtIoResult<()> {
+        let bits = self.bits();
+        match Self::DEPENDENCIES
+            .iter()
+            .find(|dependency| bits & dependency.feature != 0 && bits & dependency.requires == 0)
+        {
+            Some(dependency) => Err(VirtIoError::MissingFeatureDependency {
+                feature: dependency.feature.trailing_zeros(),
+                requires: dependency.requires,
+            }),
+            None => Ok(()),
+        }
     }
 }
 
+/// A feature which may only be negotiated together with at least one of the features it
+/// requires, see [`DeviceFeatures::DEPENDENCIES`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FeatureDependency {
+    /// The dependent feature, a single bit.
+    pub feature: u64,
+    /// The features it depends on, any one of which is enough.
+    pub requires: u64,
+}
+
+impl FeatureDependency {
+    /// Creates a dependency of `feature` on any of `requires`.
+    pub const fn new(feature: u64, requires: u64) -> Self {
+        Self { feature, requires }
+    }
+}
+
+/// A milestone of bringing up a device, reported to the transport's [`InitObserver`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InitMilestone {
+    /// The transport found a device it supports, e.g. MMIO checked the magic value and version.
+    Probed {
+        device_type: DeviceType,
+        /// Whether the device only has the legacy interface.
+        legacy: bool,
+    },
+    /// Features were negotiated and the device accepted them.
+    FeaturesNegotiated {
+        /// The features the device offered, including ones the driver doesn't know.
+        offered: u64,
+        /// The features the driver accepted.
+        negotiated: u64,
+    },
+    /// A virtqueue was given to the device, at the given physical addresses.
+    QueueProgrammed {
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    },
+    /// DRIVER_OK was set, so the device is live.
+    DriverOk,
+    /// Initialization failed at the given step, and the device was marked FAILED.
+    Failed(InitStep),
+}
+
+/// A callback for each [`InitMilestone`] a device reaches, e.g. to print them during early boot
+/// to find out why a device never comes up, without turning on logging for everything else.
+pub type InitObserver = fn(InitMilestone);
+
+/// The outcome of [`Transport::begin_init_with_fallback`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Negotiated<F> {
+    /// The features the device accepted.
+    pub features: F,
+    /// The optional features which were dropped for the device to accept the rest.
+    pub dropped: F,
+}
+
 /// Types of virtio devices.
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[allow(missing_docs)]
 pub enum DeviceType {
     Invalid = 0,
@@ -152,6 +424,7 @@ pub enum DeviceType {
     Pstore = 22,
     IOMMU = 23,
     Memory = 24,
+    Sound = 25,
 }
 
 impl From<u32> for DeviceType {
@@ -161,7 +434,7 @@ impl From<u32> for DeviceType {
             2 => DeviceType::Block,
             3 => DeviceType::Console,
             4 => DeviceType::EntropySource,
-            5 => DeviceType::MemoryBalloon,
+            5 => DeviceType::MemoryBallooning,
             6 => DeviceType::IoMemory,
             7 => DeviceType::Rpmsg,
             8 => DeviceType::ScsiHost,
@@ -179,6 +452,7 @@ impl From<u32> for DeviceType {
             22 => DeviceType::Pstore,
             23 => DeviceType::IOMMU,
             24 => DeviceType::Memory,
+            25 => DeviceType::Sound,
             _ => DeviceType::Invalid,
         }
     }
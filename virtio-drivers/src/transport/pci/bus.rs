@@ -6,7 +6,6 @@ use core::{
     convert::TryFrom,
     fmt::{self, Display, Formatter},
 };
-use log::warn;
 use crate::hal::VirtIoDeviceIo;
 
 const INVALID_READ: u32 = 0xffffffff;
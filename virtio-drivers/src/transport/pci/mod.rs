@@ -44,7 +44,7 @@ fn device_type(pci_device_id: u16) -> DeviceType {
     match pci_device_id {
         TRANSITIONAL_NETWORK => DeviceType::Network,
         TRANSITIONAL_BLOCK => DeviceType::Block,
-        TRANSITIONAL_MEMORY_BALLOONING => DeviceType::MemoryBalloon,
+        TRANSITIONAL_MEMORY_BALLOONING => DeviceType::MemoryBallooning,
         TRANSITIONAL_CONSOLE => DeviceType::Console,
         TRANSITIONAL_SCSI_HOST => DeviceType::ScsiHost,
         TRANSITIONAL_ENTROPY_SOURCE => DeviceType::EntropySource,
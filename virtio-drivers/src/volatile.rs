@@ -1,8 +1,12 @@
 use core::marker::PhantomData;
+#[cfg(any(feature = "block", feature = "console", feature = "net"))]
+use core::mem::size_of;
 
 use crate::common::Array;
 use crate::error::VirtIoResult;
 use crate::hal::VirtIoDeviceIo;
+#[cfg(any(feature = "block", feature = "console", feature = "net"))]
+use crate::transport::{mmio::CONFIG_OFFSET, Transport};
 
 #[derive(Debug, Default)]
 pub struct ReadOnly<const OFFSET: usize, T: Copy> {
@@ -27,86 +31,209 @@ pub trait WriteVolatile {
     fn write(&self, data: Self::T, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<()>;
 }
 
-// TODO: use macro to simpify code
-impl<const OFFSET: usize, const SIZE: usize> ReadVolatile for ReadOnly<OFFSET, Array<SIZE, u8>> {
-    type T = [u8; SIZE];
-    #[inline]
-    fn read(&self, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<Self::T> {
-        let mut res = [0; SIZE];
-        for i in 0..SIZE {
-            res[i] = io_region.read_volatile_u8_at(OFFSET + i)?;
+/// Implements [`ReadVolatile`] for the readable registers of one width, given how to read a value
+/// of that width at an offset.
+macro_rules! impl_read {
+    ($ty:ty, |$io:ident, $off:ident| $read:expr) => {
+        impl_read!(@impl ReadOnly, $ty, |$io, $off| $read);
+        impl_read!(@impl ReadWrite, $ty, |$io, $off| $read);
+    };
+    (@impl $reg:ident, $ty:ty, |$io:ident, $off:ident| $read:expr) => {
+        impl<const OFFSET: usize> ReadVolatile for $reg<OFFSET, $ty> {
+            type T = $ty;
+            #[inline]
+            fn read(&self, $io: &dyn VirtIoDeviceIo) -> VirtIoResult<Self::T> {
+                let $off = OFFSET;
+                $read
+            }
         }
-        Ok(res)
-    }
-}
-impl<const OFFSET: usize> ReadVolatile for ReadOnly<OFFSET, u32> {
-    type T = u32;
-    #[inline]
-    fn read(&self, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<Self::T> {
-        io_region.read_volatile_u32_at(OFFSET)
-    }
-}
-impl<const OFFSET: usize> ReadVolatile for ReadOnly<OFFSET, u16> {
-    type T = u16;
-    #[inline]
-    fn read(&self, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<Self::T> {
-        io_region.read_volatile_u32_at(OFFSET).map(|x| x as Self::T)
-    }
-}
-impl<const OFFSET: usize> ReadVolatile for ReadOnly<OFFSET, u8> {
-    type T = u8;
-    #[inline]
-    fn read(&self, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<Self::T> {
-        io_region.read_volatile_u8_at(OFFSET)
-    }
-}
-impl<const OFFSET: usize> WriteVolatile for WriteOnly<OFFSET, u64> {
-    type T = u64;
-    #[inline]
-    fn write(&self, data: u64, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<()> {
-        io_region.write_volatile_u32_at(OFFSET, data as u32)?;
-        io_region.write_volatile_u32_at(OFFSET + 0x4, (data >> 32) as u32)
-    }
-}
-impl<const OFFSET: usize> WriteVolatile for WriteOnly<OFFSET, u32> {
-    type T = u32;
-    #[inline]
-    fn write(&self, data: u32, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<()> {
-        io_region.write_volatile_u32_at(OFFSET, data)
-    }
+    };
 }
-impl<const OFFSET: usize> WriteVolatile for WriteOnly<OFFSET, u8> {
-    type T = u8;
-    #[inline]
-    fn write(&self, data: u8, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<()> {
-        io_region.write_volatile_u8_at(OFFSET, data)
-    }
+
+/// Implements [`WriteVolatile`] for the writable registers of one width, given how to write a
+/// value of that width at an offset.
+macro_rules! impl_write {
+    ($ty:ty, |$io:ident, $off:ident, $data:ident| $write:expr) => {
+        impl_write!(@impl WriteOnly, $ty, |$io, $off, $data| $write);
+        impl_write!(@impl ReadWrite, $ty, |$io, $off, $data| $write);
+    };
+    (@impl $reg:ident, $ty:ty, |$io:ident, $off:ident, $data:ident| $write:expr) => {
+        impl<const OFFSET: usize> WriteVolatile for $reg<OFFSET, $ty> {
+            type T = $ty;
+            #[inline]
+            fn write(&self, $data: $ty, $io: &dyn VirtIoDeviceIo) -> VirtIoResult<()> {
+                let $off = OFFSET;
+                $write
+            }
+        }
+    };
 }
-impl<const OFFSET: usize> ReadVolatile for ReadWrite<OFFSET, u32> {
-    type T = u32;
-    #[inline]
-    fn read(&self, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<Self::T> {
-        io_region.read_volatile_u32_at(OFFSET)
+
+impl_read!(u8, |io, off| io.read_volatile_u8_at(off));
+impl_read!(u16, |io, off| io.read_volatile_u16_at(off));
+impl_read!(u32, |io, off| io.read_volatile_u32_at(off));
+// 64-bit fields are read as two 32-bit halves, low first, as the transports only do 32-bit
+// accesses.
+impl_read!(u64, |io, off| {
+    let low = io.read_volatile_u32_at(off)?;
+    let high = io.read_volatile_u32_at(off + 0x4)?;
+    Ok((high as u64) << 32 | low as u64)
+});
+
+impl_write!(u8, |io, off, data| io.write_volatile_u8_at(off, data));
+impl_write!(u16, |io, off, data| io.write_volatile_u16_at(off, data));
+impl_write!(u32, |io, off, data| io.write_volatile_u32_at(off, data));
+impl_write!(u64, |io, off, data| {
+    io.write_volatile_u32_at(off, data as u32)?;
+    io.write_volatile_u32_at(off + 0x4, (data >> 32) as u32)
+});
+
+impl<const OFFSET: usize, T: Copy> ReadOnly<OFFSET, T>
+where
+    Self: ReadVolatile<T = T>,
+{
+    /// Reads a field of the device-specific config space which is only present if `negotiated`,
+    /// i.e. the device offered the feature it depends on, and the config space reaches past it.
+    ///
+    /// Returns `None` if it isn't present, rather than whatever the device has at its offset.
+    #[cfg(any(feature = "block", feature = "console", feature = "net"))]
+    pub fn read_optional(
+        &self,
+        transport: &impl Transport,
+        negotiated: bool,
+    ) -> VirtIoResult<Option<T>> {
+        let end = OFFSET - CONFIG_OFFSET + size_of::<T>();
+        if !negotiated || transport.check_config_space(end).is_err() {
+            return Ok(None);
+        }
+        self.read(transport.io_region()).map(Some)
     }
 }
-impl<const OFFSET: usize> ReadVolatile for ReadWrite<OFFSET, u8> {
-    type T = u8;
+
+impl<const OFFSET: usize, const SIZE: usize> ReadVolatile for ReadOnly<OFFSET, Array<SIZE, u8>> {
+    type T = [u8; SIZE];
     #[inline]
     fn read(&self, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<Self::T> {
-        io_region.read_volatile_u8_at(OFFSET)
+        let mut res = [0; SIZE];
+        for (i, byte) in res.iter_mut().enumerate() {
+            *byte = io_region.read_volatile_u8_at(OFFSET + i)?;
+        }
+        Ok(res)
     }
 }
-impl<const OFFSET: usize> WriteVolatile for ReadWrite<OFFSET, u32> {
-    type T = u32;
-    #[inline]
-    fn write(&self, data: u32, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<()> {
-        io_region.write_volatile_u32_at(OFFSET, data)
-    }
+
+/// Declares the registers of a device-specific config space, at the offsets the spec gives them.
+///
+/// Each field is written `name: Access<T> @ offset`, where `Access` is [`ReadOnly`], [`WriteOnly`]
+/// or [`ReadWrite`] and `offset` is relative to the start of the device-specific config space. The
+/// fields are checked at compile time to be in order, naturally aligned and not overlapping, so a
+/// typo in an offset doesn't silently read the wrong register.
+///
+/// A `=> struct Snapshot;` after the fields also declares a struct holding the value of every
+/// readable field, and a `snapshot` method on the config reading them all at once.
+macro_rules! virtio_config {
+    (@snapshot $head:tt [$($acc:tt)*] $field_vis:vis $field:ident: WriteOnly<$ty:ty>, $($rest:tt)*) => {
+        $crate::volatile::virtio_config!(@snapshot $head [$($acc)*] $($rest)*);
+    };
+    (@snapshot $head:tt [$($acc:tt)*] $field_vis:vis $field:ident: $access:ident<$ty:ty>, $($rest:tt)*) => {
+        $crate::volatile::virtio_config!(
+            @snapshot $head [$($acc)* $field_vis $field: $access<$ty>,] $($rest)*
+        );
+    };
+    (
+        @snapshot
+        [$(#[$attr:meta])* $vis:vis struct $snapshot:ident for $name:ident]
+        [$($field_vis:vis $field:ident: $access:ident<$ty:ty>,)*]
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug)]
+        $vis struct $snapshot {
+            $(
+                $field_vis $field:
+                    <$crate::volatile::$access<0, $ty> as $crate::volatile::ReadVolatile>::T,
+            )*
+        }
+
+        impl $name {
+            /// Reads every readable field of the config space.
+            ///
+            /// Fields which depend on a feature are read whether it was negotiated or not.
+            $vis fn snapshot(
+                &self,
+                io_region: &dyn $crate::hal::VirtIoDeviceIo,
+            ) -> $crate::error::VirtIoResult<$snapshot> {
+                use $crate::volatile::ReadVolatile;
+                Ok($snapshot {
+                    $($field: self.$field.read(io_region)?,)*
+                })
+            }
+        }
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_attr:meta])*
+                $field_vis:vis $field:ident: $access:ident<$ty:ty> @ $offset:expr
+            ),* $(,)?
+        }
+        $(#[$snapshot_attr:meta])*
+        => $snapshot_vis:vis struct $snapshot:ident;
+    ) => {
+        $crate::volatile::virtio_config! {
+            $(#[$attr])*
+            $vis struct $name {
+                $($(#[$field_attr])* $field_vis $field: $access<$ty> @ $offset,)*
+            }
+        }
+        $crate::volatile::virtio_config!(
+            @snapshot
+            [$(#[$snapshot_attr])* $snapshot_vis struct $snapshot for $name]
+            []
+            $($field_vis $field: $access<$ty>,)*
+        );
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_attr:meta])*
+                $field_vis:vis $field:ident: $access:ident<$ty:ty> @ $offset:expr
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Debug, Default)]
+        $vis struct $name {
+            $(
+                $(#[$field_attr])*
+                $field_vis $field: $crate::volatile::$access<
+                    { $crate::transport::mmio::CONFIG_OFFSET + $offset },
+                    $ty,
+                >,
+            )*
+        }
+
+        const _: () = $crate::volatile::check_config_layout(&[
+            $(($offset, core::mem::size_of::<$ty>(), core::mem::align_of::<$ty>()),)*
+        ]);
+    };
 }
-impl<const OFFSET: usize> WriteVolatile for ReadWrite<OFFSET, u8> {
-    type T = u8;
-    #[inline]
-    fn write(&self, data: u8, io_region: &dyn VirtIoDeviceIo) -> VirtIoResult<()> {
-        io_region.write_volatile_u8_at(OFFSET, data)
+pub(crate) use virtio_config;
+
+/// Checks the `(offset, size, alignment)` of each field declared by [`virtio_config!`].
+pub(crate) const fn check_config_layout(fields: &[(usize, usize, usize)]) {
+    let mut i = 0;
+    while i < fields.len() {
+        let (offset, _, align) = fields[i];
+        assert!(offset % align == 0, "config space field is misaligned");
+        if i > 0 {
+            let (prev_offset, prev_size, _) = fields[i - 1];
+            assert!(
+                prev_offset + prev_size <= offset,
+                "config space fields overlap or are out of order"
+            );
+        }
+        i += 1;
     }
 }